@@ -120,16 +120,23 @@ impl CodeParams {
 	}
 }
 
+/// Obtain the number of chunks required to recover the data, given `n_validators` chunks
+/// in total. This is one more than the number of validators which may be faulty.
+pub fn recovery_threshold(n_validators: usize) -> usize {
+	let n_faulty = n_validators.saturating_sub(1) / 3;
+	n_faulty + 1
+}
+
 fn code_params(n_validators: usize) -> Result<CodeParams, Error> {
 	if n_validators > MAX_VALIDATORS { return Err(Error::TooManyValidators) }
 	if n_validators <= 1 { return Err(Error::NotEnoughValidators) }
 
-	let n_faulty = n_validators.saturating_sub(1) / 3;
-	let n_good = n_validators - n_faulty;
+	let data_shards = recovery_threshold(n_validators);
+	let parity_shards = n_validators - data_shards;
 
 	Ok(CodeParams {
-		data_shards: n_faulty + 1,
-		parity_shards: n_good - 1,
+		data_shards,
+		parity_shards,
 	})
 }
 
@@ -431,6 +438,16 @@ mod tests {
 		}));
 	}
 
+	#[test]
+	fn recovery_threshold_tolerates_a_third_faulty() {
+		assert_eq!(recovery_threshold(2), 1);
+		assert_eq!(recovery_threshold(3), 1);
+		assert_eq!(recovery_threshold(4), 2);
+		assert_eq!(recovery_threshold(5), 2);
+		assert_eq!(recovery_threshold(10), 4);
+		assert_eq!(recovery_threshold(100), 34);
+	}
+
 	#[test]
 	fn shard_len_is_reasonable() {
 		let mut params = CodeParams {
@@ -0,0 +1,138 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Interactions with the availability store.
+//!
+//! Requests are sent and awaited on the task of the subsystem itself, rather than spawned, so
+//! a saturated or failing task executor can not cause stores of our own chunks to be dropped.
+//! A full channel to the store merely delays the subsystem until the request was sent.
+
+use futures::channel::oneshot;
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::FutureExt;
+
+use polkadot_primitives::v1::{CandidateHash, ErasureChunk, Hash, ValidatorIndex};
+use polkadot_subsystem::messages::{
+	AllMessages, AvailabilityDistributionMessage, AvailabilityStoreMessage,
+};
+use polkadot_subsystem::SubsystemContext;
+
+use crate::{Error, Result, LOG_TARGET};
+
+/// Query whether the availability store has the available data of a candidate.
+#[tracing::instrument(level = "trace", skip(ctx), fields(subsystem = LOG_TARGET))]
+pub(crate) async fn query_data_availability<Context>(
+	ctx: &mut Context,
+	candidate_hash: CandidateHash,
+) -> Result<bool>
+where
+	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
+{
+	let (tx, rx) = oneshot::channel();
+	ctx.send_message(AllMessages::AvailabilityStore(
+		AvailabilityStoreMessage::QueryDataAvailability(candidate_hash, tx),
+	)).await;
+
+	rx.await.map_err(|e| Error::QueryAvailabilityResponseChannel(e))
+}
+
+/// Query the erasure chunk of a candidate for a particular validator index.
+#[tracing::instrument(level = "trace", skip(ctx), fields(subsystem = LOG_TARGET))]
+pub(crate) async fn query_chunk<Context>(
+	ctx: &mut Context,
+	candidate_hash: CandidateHash,
+	validator_index: ValidatorIndex,
+) -> Result<Option<ErasureChunk>>
+where
+	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
+{
+	let (tx, rx) = oneshot::channel();
+	ctx.send_message(AllMessages::AvailabilityStore(
+		AvailabilityStoreMessage::QueryChunk(candidate_hash, validator_index, tx),
+	)).await;
+
+	rx.await.map_err(|e| Error::QueryChunkResponseChannel(e))
+}
+
+/// Store an erasure chunk of a candidate, returning the result reported by the store.
+#[tracing::instrument(level = "trace", skip(ctx, erasure_chunk), fields(subsystem = LOG_TARGET))]
+pub(crate) async fn store_chunk<Context>(
+	ctx: &mut Context,
+	candidate_hash: CandidateHash,
+	relay_parent: Hash,
+	validator_index: ValidatorIndex,
+	erasure_chunk: ErasureChunk,
+) -> Result<std::result::Result<(), ()>>
+where
+	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
+{
+	let (tx, rx) = oneshot::channel();
+	ctx.send_message(AllMessages::AvailabilityStore(
+		AvailabilityStoreMessage::StoreChunk {
+			candidate_hash,
+			relay_parent,
+			validator_index,
+			chunk: erasure_chunk,
+			tx,
+		}
+	)).await;
+
+	rx.await.map_err(|e| Error::StoreChunkResponseChannel(e))
+}
+
+/// Store erasure chunks of candidates, with at most `max_outstanding` stores awaiting
+/// their result at a time. Further stores are issued as earlier ones complete.
+///
+/// Returns the results reported by the store, in the order the chunks were passed in.
+#[tracing::instrument(level = "trace", skip(ctx, chunks), fields(subsystem = LOG_TARGET))]
+pub(crate) async fn store_chunks<Context>(
+	ctx: &mut Context,
+	chunks: Vec<(CandidateHash, Hash, ValidatorIndex, ErasureChunk)>,
+	max_outstanding: usize,
+) -> Result<Vec<std::result::Result<(), ()>>>
+where
+	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
+{
+	let mut results = vec![Err(()); chunks.len()];
+	let mut chunks = chunks.into_iter().enumerate();
+	let mut outstanding = FuturesUnordered::new();
+
+	loop {
+		while outstanding.len() < max_outstanding.max(1) {
+			let (i, (candidate_hash, relay_parent, validator_index, erasure_chunk)) = match chunks.next() {
+				Some(next) => next,
+				None => break,
+			};
+
+			let (tx, rx) = oneshot::channel();
+			ctx.send_message(AllMessages::AvailabilityStore(
+				AvailabilityStoreMessage::StoreChunk {
+					candidate_hash,
+					relay_parent,
+					validator_index,
+					chunk: erasure_chunk,
+					tx,
+				}
+			)).await;
+			outstanding.push(rx.map(move |result| (i, result)));
+		}
+
+		match outstanding.next().await {
+			Some((i, result)) => results[i] = result.map_err(|e| Error::StoreChunkResponseChannel(e))?,
+			None => return Ok(results),
+		}
+	}
+}
@@ -25,23 +25,24 @@
 #![deny(unused_crate_dependencies, unused_qualifications)]
 
 use parity_scale_codec::{Decode, Encode};
-use futures::{channel::oneshot, FutureExt, TryFutureExt};
+use futures::{channel::{mpsc, oneshot}, FutureExt, TryFutureExt};
+use futures_timer::Delay;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
 
-use sp_core::crypto::Public;
 use sp_keystore::{CryptoStore, SyncCryptoStorePtr};
 
 use polkadot_erasure_coding::branch_hash;
 use polkadot_node_network_protocol::{
-	v1 as protocol_v1, NetworkBridgeEvent, PeerId, ReputationChange as Rep, View,
+	v1 as protocol_v1, NetworkBridgeEvent, ObservedRole, PeerId, ReputationChange as Rep, View,
 };
 use polkadot_node_subsystem_util::metrics::{self, prometheus};
 use polkadot_primitives::v1::{
-	BlakeTwo256, CoreState, ErasureChunk, Hash, HashT,
-	SessionIndex, ValidatorId, ValidatorIndex, PARACHAIN_KEY_TYPE_ID, CandidateHash,
-	CandidateDescriptor,
+	AvailableData, BlakeTwo256, BlockNumber, CoreIndex, CoreState, ErasureChunk, GroupIndex,
+	GroupRotationInfo, Hash, HashT, Id as ParaId, OccupiedCore, SessionIndex, ValidatorId, ValidatorIndex, PARACHAIN_KEY_TYPE_ID,
+	CandidateHash, CandidateDescriptor,
 };
 use polkadot_subsystem::messages::{
-	AllMessages, AvailabilityDistributionMessage, AvailabilityStoreMessage, ChainApiMessage,
+	AllMessages, AvailabilityDistributionMessage, ChainApiMessage, MessagePriority,
 	NetworkBridgeMessage, RuntimeApiMessage, RuntimeApiRequest,
 };
 use polkadot_subsystem::{
@@ -50,11 +51,19 @@ use polkadot_subsystem::{
 	ActiveLeavesUpdate, FromOverseer, OverseerSignal, SpawnedSubsystem, Subsystem,
 	SubsystemContext, SubsystemError,
 };
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::collections::hash_map::Entry;
 use std::iter;
+use std::mem;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
+mod av_store;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
 #[cfg(test)]
 mod tests;
 
@@ -67,12 +76,19 @@ enum Error {
 
 	#[error("Response channel to obtain QueryChunk failed")]
 	QueryChunkResponseChannel(#[source] oneshot::Canceled),
+	#[error("Availability store holds the available data of candidate {0:?}, but not chunk {1}")]
+	MissingChunk(CandidateHash, ValidatorIndex),
 
 	#[error("Response channel to obtain QueryAncestors failed")]
 	QueryAncestorsResponseChannel(#[source] oneshot::Canceled),
 	#[error("RuntimeAPI to obtain QueryAncestors failed")]
 	QueryAncestors(#[source] ChainApiError),
 
+	#[error("Response channel to obtain QueryBlockNumber failed")]
+	QueryBlockNumberResponseChannel(#[source] oneshot::Canceled),
+	#[error("ChainAPI to obtain QueryBlockNumber failed")]
+	QueryBlockNumber(#[source] ChainApiError),
+
 	#[error("Response channel to obtain QuerySession failed")]
 	QuerySessionResponseChannel(#[source] oneshot::Canceled),
 	#[error("RuntimeAPI to obtain QuerySession failed")]
@@ -83,6 +99,11 @@ enum Error {
 	#[error("RuntimeAPI to obtain QueryValidators failed")]
 	QueryValidators(#[source] RuntimeApiError),
 
+	#[error("Response channel to obtain ValidatorGroups failed")]
+	ValidatorGroupsResponseChannel(#[source] oneshot::Canceled),
+	#[error("RuntimeAPI to obtain ValidatorGroups failed")]
+	ValidatorGroups(#[source] RuntimeApiError),
+
 	#[error("Response channel to obtain AvailabilityCores failed")]
 	AvailabilityCoresResponseChannel(#[source] oneshot::Canceled),
 	#[error("RuntimeAPI to obtain AvailabilityCores failed")]
@@ -93,6 +114,14 @@ enum Error {
 
 	#[error("Receive channel closed")]
 	IncomingMessageChannel(#[source] SubsystemError),
+
+	#[error("Chunks imply {implied_size} bytes of available data, exceeding the maximum of {max_size} bytes")]
+	ReconstructionTooLarge {
+		implied_size: usize,
+		max_size: usize,
+	},
+	#[error("Failed to reconstruct available data from chunks")]
+	Reconstruction(#[source] polkadot_erasure_coding::Error),
 }
 
 type Result<T> = std::result::Result<T, Error>;
@@ -100,9 +129,31 @@ type Result<T> = std::result::Result<T, Error>;
 const COST_MERKLE_PROOF_INVALID: Rep = Rep::new(-100, "Merkle proof was invalid");
 const COST_NOT_A_LIVE_CANDIDATE: Rep = Rep::new(-51, "Candidate is not live");
 const COST_PEER_DUPLICATE_MESSAGE: Rep = Rep::new(-500, "Peer sent identical messages");
+const COST_CHUNK_EQUIVOCATION: Rep = Rep::new(-1000, "Chunk contradicts a verified chunk");
+const COST_OVERSIZED_CHUNK: Rep = Rep::new(-500, "Chunk exceeds the size limits");
+const COST_OVERSIZED_BATCH: Rep = Rep::new(-500, "Batch exceeds the number of chunks allowed");
+const COST_FORGOTTEN_CANDIDATE: Rep = Rep::new(-5, "Candidate was forgotten recently");
+const COST_UNKNOWN_PARA: Rep = Rep::new(-100, "Candidate of a para without a core");
+const COST_CHUNK_REQUEST_RATE_EXCEEDED: Rep = Rep::new(-100, "Peer exceeded its chunk request rate");
+const COST_REPEATED_NOT_A_LIVE_CANDIDATE: Rep = Rep::new(-200, "Candidate is not live, repeatedly");
+const COST_VIEW_SUPERSEDED: Rep = Rep::new(-10, "View was superseded before it was processed");
+const COST_NOT_CONNECTED: Rep = Rep::new(-10, "Message of a peer which did not connect");
 const BENEFIT_VALID_MESSAGE_FIRST: Rep = Rep::new(15, "Valid message with new information");
 const BENEFIT_VALID_MESSAGE: Rep = Rep::new(10, "Valid message");
 
+/// Lower bounds of the buckets of `ProtocolState::age_histogram`, in seconds.
+const CANDIDATE_AGE_BUCKETS: [u64; 6] = [0, 6, 30, 60, 120, 600];
+
+/// Upper bound of the encoded size of all parts of `AvailableData` besides the `PoV`.
+const MAX_VALIDATION_DATA_OVERHEAD: usize = 64 * 1024;
+
+/// Upper bound of the size of a single node of the merkle proof of an erasure chunk.
+const MAX_PROOF_NODE_SIZE: usize = 1024;
+
+/// For how long messages of a disconnected peer are attributed to it being queued before
+/// the peer disconnected, rather than to the peer never having connected.
+const DISCONNECTED_PEER_GRACE: Duration = Duration::from_secs(10);
+
 /// Checked signed availability bitfield that is distributed
 /// to other peers.
 #[derive(Encode, Decode, Debug, Clone, PartialEq, Eq, Hash)]
@@ -119,10 +170,305 @@ impl From<AvailabilityGossipMessage> for protocol_v1::AvailabilityDistributionMe
 	}
 }
 
+/// Configuration of the availability distribution subsystem.
+#[derive(Debug, Clone)]
+pub struct Config {
+	/// Minimum time before the same erasure chunk is sent to the same peer again,
+	/// even if the peer expresses interest in it anew.
+	pub rebroadcast_cooldown: Duration,
+	/// Reputation changes applied to peers depending on the messages they send.
+	pub reputation: ReputationConfig,
+	/// Upper bound of the size in bytes of all erasure chunks held in the message vaults,
+	/// unbounded if `None`.
+	pub max_buffered_bytes: Option<usize>,
+	/// What to do with chunks received from peers which would exceed `max_buffered_bytes`.
+	pub memory_policy: MemoryPolicy,
+	/// Upper bound of the size of a PoV, which limits the size of erasure chunks
+	/// accepted from peers.
+	pub max_pov_size: u32,
+	/// Upper bound of the number of peers a chunk is gossiped to at once, all interested
+	/// peers are chosen if `None`.
+	pub max_gossip_fanout: Option<usize>,
+	/// Seed of the RNG choosing the peers to gossip to under `max_gossip_fanout`, which
+	/// makes the choice reproducible in tests. Seeded from entropy if `None`.
+	pub peer_selection_seed: Option<u64>,
+	/// Whether to gossip to the peers which changed their view most recently under
+	/// `max_gossip_fanout`, instead of choosing peers at random. Recently interested peers
+	/// are the most likely to still need the chunks.
+	pub prioritize_recently_interested_peers: bool,
+	/// The number of relay parents, including their ancestors, expected to be tracked at once.
+	/// Space for them is allocated upfront.
+	pub expected_relay_parents: usize,
+	/// The number of candidates expected to be tracked at once. Space for them is allocated upfront.
+	pub expected_candidates: usize,
+	/// The number of heads in the view of a peer beyond which the view is considered abnormal.
+	pub max_peer_view_heads: usize,
+	/// Whether to only keep `max_peer_view_heads` heads of abnormal views, bounding the
+	/// memory spent on the view of each peer.
+	pub cap_peer_views: bool,
+	/// Whether to only store our own chunk of candidates our validator group is responsible for.
+	/// Chunks of candidates of other groups are still relayed.
+	pub store_own_group_only: bool,
+	/// For how long chunks of candidates which were pruned as they left our view are
+	/// attributed to lagging peers rather than to misbehaving ones.
+	pub forgotten_candidate_grace: Duration,
+	/// Whether chunks of candidates which are not known yet are held back until the candidates
+	/// become live, instead of penalizing the peers sending them.
+	pub defer_unknown_candidates: bool,
+	/// Upper bound of the number of chunks held back under `defer_unknown_candidates`.
+	pub max_pending_chunks: usize,
+	/// Upper bound of the size in bytes of the chunks held back under `defer_unknown_candidates`,
+	/// including their proofs. Their size limits can only be checked once their candidates are known.
+	pub max_pending_chunk_bytes: usize,
+	/// For how long chunks are held back under `defer_unknown_candidates` before being dropped.
+	pub pending_chunk_ttl: Duration,
+	/// Whether to record where each chunk in the message vaults was first obtained from,
+	/// which helps tracing where bad data came from.
+	pub record_chunk_sources: bool,
+	/// The number of chunk requests a peer may leave unanswered before it is no longer
+	/// considered a source of chunks for `chunk_source_cooldown`.
+	pub chunk_request_retry_budget: u32,
+	/// For how long a peer which exhausted its retry budget is not requested chunks from.
+	pub chunk_source_cooldown: Duration,
+	/// After how long a chunk request counts as unanswered, checked whenever our view changes.
+	pub chunk_request_timeout: Duration,
+	/// The number of chunk requests served to a single peer within `chunk_request_window`.
+	/// In-process requests are trusted and not limited.
+	pub max_chunk_requests_per_peer: u32,
+	/// The window over which chunk requests of a peer are limited.
+	pub chunk_request_window: Duration,
+	/// The number of chunk requests a peer may send within `chunk_request_window` beyond
+	/// `max_chunk_requests_per_peer`, such that honest peers catching up are not penalized.
+	/// These are served and only logged, further requests are refused and penalized.
+	pub chunk_request_burst: u32,
+	/// Upper bound of the number of chunk stores awaiting their result from the
+	/// availability store at a time.
+	pub max_outstanding_chunk_stores: usize,
+	/// Whether to stop relaying chunks of candidates once chunks of all indices were observed,
+	/// as the candidate is then estimated to be available across the network already.
+	pub suppress_gossip_once_available: bool,
+	/// Whether to query our own chunk back after the availability store acknowledged storing it,
+	/// storing it again if the store does not hold it. Guards against stores dropped silently.
+	pub verify_chunk_stores: bool,
+	/// The only paras whose candidates are tracked, all paras if `None`.
+	pub para_allowlist: Option<HashSet<ParaId>>,
+	/// Paras whose candidates are not tracked, none if `None`.
+	pub para_denylist: Option<HashSet<ParaId>>,
+	/// Upper bound of the number of messages queued while relay parents are fetched.
+	/// Further messages of peers are dropped, other messages are always queued.
+	pub max_deferred_messages: usize,
+	/// Whether to derive all chunks of candidates of which we hold enough verified chunks
+	/// to reconstruct their available data, on every activated leaf. This lets us serve
+	/// chunks peers miss before receiving them.
+	pub backfill_reconstructable_candidates: bool,
+	/// Where to publish `AvailabilityEvent`s to, for observing the subsystem. Events are
+	/// dropped if the channel is full, so observers never hold up the subsystem.
+	pub event_sink: Option<mpsc::Sender<AvailabilityEvent>>,
+	/// Minimum interval between clean ups of the cache of candidates live under relay chain
+	/// blocks. View changes within the interval leave stale entries in the cache until the
+	/// next clean up, zero cleans up on every view change.
+	pub live_under_clean_up_interval: Duration,
+	/// Whether to gossip to peers which connected as authorities before other peers under the
+	/// fanout limit, as validators need chunks the most.
+	pub prioritize_authorities: bool,
+	/// Upper bound of the number of peer and candidate pairs remembered as rejected for the
+	/// candidate not being live, the least recently rejected are forgotten first. Repeated
+	/// chunks of remembered pairs are penalized once more and dropped afterwards.
+	/// Nothing is remembered if zero.
+	pub max_rejected_candidates: usize,
+	/// How long a rejected peer and candidate pair is remembered.
+	pub rejected_candidate_ttl: Duration,
+	/// Whether to gossip our own chunk of a candidate becoming live right away if the
+	/// availability store holds it, even though it lacks the available data.
+	pub gossip_stored_own_chunk: bool,
+	/// Upper bound of the rate of runtime requests issued while walking the ancestors of relay
+	/// parents, per second. Requests beyond it are delayed. Unbounded if `None`.
+	pub max_ancestor_walk_requests_per_second: Option<u32>,
+	/// Whether to fetch the live candidates of activated leaves before they enter our view.
+	/// This duplicates runtime requests for leaves which leave again before our view
+	/// includes them.
+	pub prefetch_activated_leaves: bool,
+	/// Whether chunks replayed to a peer are batched into `BatchChunks` messages. Nodes which
+	/// predate that message can not decode it, so this must only be enabled once the network
+	/// upgraded to a protocol version understanding it.
+	pub send_batched_chunks: bool,
+	/// Whether to request our own chunk of a candidate becoming live from peers if the
+	/// availability store lacks it. Nodes which predate `RequestChunk` can not decode it,
+	/// so this must only be enabled once the network upgraded to a protocol version
+	/// understanding it.
+	pub request_own_chunks: bool,
+	/// Whether to measure the chunk data held more than once across the message vaults on
+	/// every active leaves update. This hashes all buffered chunks, so is meant for diagnosis.
+	pub measure_duplicate_chunks: bool,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Self {
+			rebroadcast_cooldown: Duration::from_secs(30),
+			reputation: ReputationConfig::default(),
+			max_buffered_bytes: None,
+			memory_policy: MemoryPolicy::RejectNew,
+			max_pov_size: 50 * 1024 * 1024,
+			max_gossip_fanout: None,
+			peer_selection_seed: None,
+			prioritize_recently_interested_peers: false,
+			expected_relay_parents: 0,
+			expected_candidates: 0,
+			max_peer_view_heads: 64,
+			cap_peer_views: true,
+			store_own_group_only: false,
+			forgotten_candidate_grace: Duration::from_secs(30),
+			defer_unknown_candidates: false,
+			max_pending_chunks: 1024,
+			max_pending_chunk_bytes: 16 * 1024 * 1024,
+			pending_chunk_ttl: Duration::from_secs(30),
+			record_chunk_sources: false,
+			chunk_request_retry_budget: 3,
+			chunk_source_cooldown: Duration::from_secs(60),
+			chunk_request_timeout: Duration::from_secs(6),
+			max_chunk_requests_per_peer: 64,
+			chunk_request_window: Duration::from_secs(6),
+			chunk_request_burst: 0,
+			max_outstanding_chunk_stores: 16,
+			suppress_gossip_once_available: false,
+			verify_chunk_stores: false,
+			para_allowlist: None,
+			para_denylist: None,
+			max_deferred_messages: 1024,
+			backfill_reconstructable_candidates: false,
+			event_sink: None,
+			live_under_clean_up_interval: Duration::from_secs(0),
+			prioritize_authorities: false,
+			max_rejected_candidates: 0,
+			rejected_candidate_ttl: Duration::from_secs(30),
+			gossip_stored_own_chunk: false,
+			max_ancestor_walk_requests_per_second: None,
+			prefetch_activated_leaves: false,
+			send_batched_chunks: false,
+			request_own_chunks: false,
+			measure_duplicate_chunks: false,
+		}
+	}
+}
+
+impl Config {
+	/// Whether candidates of some paras are not tracked.
+	fn filters_paras(&self) -> bool {
+		self.para_allowlist.is_some() || self.para_denylist.is_some()
+	}
+
+	/// Whether candidates of the given para are tracked.
+	fn distributes_para(&self, para_id: &ParaId) -> bool {
+		self.para_allowlist.as_ref().map_or(true, |allowlist| allowlist.contains(para_id))
+			&& self.para_denylist.as_ref().map_or(true, |denylist| !denylist.contains(para_id))
+	}
+}
+
+/// Events published to `Config::event_sink` for observing the subsystem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AvailabilityEvent {
+	/// A candidate became live in one of the relay parents we track.
+	CandidateAdded {
+		/// The candidate.
+		candidate_hash: CandidateHash,
+		/// The relay parent the candidate became live in.
+		relay_parent: Hash,
+	},
+	/// Our own chunk of a candidate was stored in the availability store.
+	ChunkStored {
+		/// The candidate.
+		candidate_hash: CandidateHash,
+		/// The index of the chunk.
+		index: ValidatorIndex,
+	},
+	/// A candidate is no longer tracked.
+	CandidateRemoved {
+		/// The candidate.
+		candidate_hash: CandidateHash,
+	},
+	/// The available data of a candidate was reconstructed from the chunks we hold.
+	ReconstructionComplete {
+		/// The candidate.
+		candidate_hash: CandidateHash,
+	},
+}
+
+/// How chunks received from peers are handled once the message vaults are full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryPolicy {
+	/// Drop the received chunk.
+	RejectNew,
+	/// Evict the chunks buffered the longest to make room for the received chunk.
+	EvictOldest,
+}
+
+/// Reputation changes reported for peers, by the kind of message received from them.
+#[derive(Debug, Clone)]
+pub struct ReputationConfig {
+	/// Applied when a chunk's merkle proof does not match the erasure root.
+	pub cost_merkle_proof_invalid: Rep,
+	/// Applied when a chunk refers to a candidate that is not live.
+	pub cost_not_a_live_candidate: Rep,
+	/// Applied when a peer sends the same chunk more than once.
+	pub cost_peer_duplicate_message: Rep,
+	/// Applied when a chunk contradicts an already verified chunk of the same index.
+	pub cost_chunk_equivocation: Rep,
+	/// Applied when a chunk or its proof exceed the size limits of its candidate.
+	pub cost_oversized_chunk: Rep,
+	/// Applied when a batch carries more than `MAX_BATCHED_CHUNKS` chunks.
+	pub cost_oversized_batch: Rep,
+	/// Applied when a chunk refers to a candidate that was forgotten recently.
+	pub cost_forgotten_candidate: Rep,
+	/// Applied when a chunk refers to a candidate of a para which occupied none of the cores seen.
+	pub cost_unknown_para: Rep,
+	/// Applied once when a peer sends chunks of a candidate which is not live again, after
+	/// which further chunks of the candidate are dropped without a report for a while.
+	pub cost_repeated_not_a_live_candidate: Rep,
+	/// Applied when a chunk request is refused as the peer exceeded its request rate.
+	pub cost_chunk_request_rate_exceeded: Rep,
+	/// Applied when a peer changes its view again while its previous view is still queued.
+	pub cost_view_superseded: Rep,
+	/// Applied when a peer sends a message without having connected.
+	pub cost_not_connected: Rep,
+	/// Applied for a valid chunk we did not know about yet.
+	pub benefit_valid_message_first: Rep,
+	/// Applied for a valid chunk we already knew about.
+	pub benefit_valid_message: Rep,
+	/// Upper bound of the number of valid chunks of the same candidate a single peer is
+	/// rewarded for, such that benefits can not be farmed. Unbounded if `None`.
+	pub max_rewarded_chunks_per_candidate: Option<usize>,
+}
+
+impl Default for ReputationConfig {
+	fn default() -> Self {
+		Self {
+			cost_merkle_proof_invalid: COST_MERKLE_PROOF_INVALID,
+			cost_not_a_live_candidate: COST_NOT_A_LIVE_CANDIDATE,
+			cost_peer_duplicate_message: COST_PEER_DUPLICATE_MESSAGE,
+			cost_chunk_equivocation: COST_CHUNK_EQUIVOCATION,
+			cost_oversized_chunk: COST_OVERSIZED_CHUNK,
+			cost_oversized_batch: COST_OVERSIZED_BATCH,
+			cost_forgotten_candidate: COST_FORGOTTEN_CANDIDATE,
+			cost_unknown_para: COST_UNKNOWN_PARA,
+			cost_repeated_not_a_live_candidate: COST_REPEATED_NOT_A_LIVE_CANDIDATE,
+			cost_chunk_request_rate_exceeded: COST_CHUNK_REQUEST_RATE_EXCEEDED,
+			cost_view_superseded: COST_VIEW_SUPERSEDED,
+			cost_not_connected: COST_NOT_CONNECTED,
+			benefit_valid_message_first: BENEFIT_VALID_MESSAGE_FIRST,
+			benefit_valid_message: BENEFIT_VALID_MESSAGE,
+			max_rewarded_chunks_per_candidate: None,
+		}
+	}
+}
+
 /// Data used to track information of peers and relay parents the
 /// overseer ordered us to work on.
 #[derive(Default, Clone, Debug)]
 struct ProtocolState {
+	/// The configuration the subsystem was started with.
+	config: Config,
+
 	/// Track all active peers and their views
 	/// to determine what is relevant to them.
 	peer_views: HashMap<PeerId, View>,
@@ -135,11 +481,347 @@ struct ProtocolState {
 	/// Maps relay parent / ancestor -> candidate hashes.
 	live_under: HashMap<Hash, HashSet<CandidateHash>>,
 
+	/// When `live_under` was cleaned up last.
+	live_under_cleaned_up_at: Option<Instant>,
+
 	/// Track things needed to start and stop work on a particular relay parent.
 	per_relay_parent: HashMap<Hash, PerRelayParent>,
 
 	/// Track data that is specific to a candidate.
 	per_candidate: HashMap<CandidateHash, PerCandidate>,
+
+	/// Relay parents which are tracked in `per_relay_parent` ahead of entering our view.
+	prefetched: HashSet<Hash>,
+
+	/// The validator sets of the sessions of the candidates we track.
+	/// Maps session index -> validators.
+	session_validators: HashMap<SessionIndex, Arc<Vec<ValidatorId>>>,
+
+	/// The highest block number of all activated leaves, used to detect
+	/// candidates which timed out.
+	best_block_number: Option<BlockNumber>,
+
+	/// The block numbers of activated leaves which are tracked as relay parents.
+	/// Maps relay parent -> block number.
+	block_numbers: HashMap<Hash, BlockNumber>,
+
+	/// Chooses the peers to gossip to under the fanout limit.
+	peer_selection_rng: PeerSelectionRng,
+
+	/// When each connected peer changed its view last.
+	/// Maps peer -> instant.
+	last_view_changes: HashMap<PeerId, Instant>,
+
+	/// Connected peers which connected as authorities.
+	authorities: HashSet<PeerId>,
+
+	/// Candidates which were pruned, along with when they were pruned.
+	/// Maps candidate hash -> instant.
+	recently_forgotten: HashMap<CandidateHash, Instant>,
+
+	/// Peers which disconnected, along with when they disconnected.
+	/// Maps peer -> instant.
+	recently_disconnected: HashMap<PeerId, Instant>,
+
+	/// Chunks of candidates which were rejected for not being live, by the peer sending them.
+	/// Maps (peer, candidate hash) -> (last rejection, number of rejections).
+	recently_rejected: HashMap<(PeerId, CandidateHash), (Instant, u32)>,
+
+	/// Chunks of candidates which are not known yet, along with their origin and when they
+	/// were received, oldest first.
+	pending_chunks: VecDeque<(Instant, PeerId, AvailabilityGossipMessage)>,
+
+	/// The size in bytes of the chunks in `pending_chunks`, including their proofs.
+	pending_chunk_bytes: usize,
+
+	/// Chunk requests left unanswered per peer, to stop requesting chunks from unresponsive peers.
+	retry_budgets: HashMap<PeerId, RetryBudget>,
+
+	/// Requests of our own chunk of candidates, which the availability store lacks.
+	own_chunk_requests: HashMap<CandidateHash, OwnChunkRequest>,
+
+	/// Chunk requests served per peer within the current window, to rate limit requesters.
+	request_windows: HashMap<PeerId, RequestWindow>,
+
+	/// Counts of the chunks received per connected peer, to identify noisy peers.
+	peer_message_stats: PeerMessageCounts,
+
+	/// The paras which occupied a core at any of the relay parents tracked so far.
+	known_paras: HashSet<ParaId>,
+
+	/// Publishes events for observing the subsystem, if configured.
+	event_sink: EventSink,
+
+	/// Paces the runtime requests issued while walking the ancestors of relay parents.
+	ancestor_walk_requests: TokenBucket,
+}
+
+/// Publishes `AvailabilityEvent`s, if a channel was configured.
+#[derive(Debug, Clone, Default)]
+struct EventSink(Option<mpsc::Sender<AvailabilityEvent>>);
+
+impl EventSink {
+	/// Publish an event, dropping it if the channel is full or closed.
+	fn emit(&mut self, event: AvailabilityEvent) {
+		if let Some(sender) = &mut self.0 {
+			if let Err(e) = sender.try_send(event) {
+				tracing::trace!(
+					target: LOG_TARGET,
+					err = ?e,
+					"Dropping availability event",
+				);
+			}
+		}
+	}
+}
+
+/// Chunk requests a peer left unanswered since it last answered one.
+#[derive(Debug, Clone, Default)]
+struct RetryBudget {
+	/// The number of unanswered requests.
+	unanswered: u32,
+	/// When the budget was exhausted, if it was.
+	exhausted_at: Option<Instant>,
+}
+
+/// A request of our own chunk of a candidate.
+#[derive(Debug, Clone, Default)]
+struct OwnChunkRequest {
+	/// The peer the chunk was requested from last, unless no peer was left to request it from.
+	peer: Option<PeerId>,
+	/// When the chunk was requested from `peer`.
+	requested_at: Option<Instant>,
+	/// All peers the chunk was requested from so far.
+	requested_from: HashSet<PeerId>,
+}
+
+/// Counts of the chunks received from a single peer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct PeerMessageStats {
+	/// All chunks received from the peer.
+	received: u64,
+	/// Valid chunks the peer did not send before.
+	valid: u64,
+	/// Chunks the peer sent before.
+	duplicate: u64,
+	/// Chunks which were rejected, e.g. for being invalid or of candidates which are not live.
+	rejected: u64,
+}
+
+/// The outcome of processing a chunk received from a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MessageOutcome {
+	Valid,
+	Duplicate,
+	Rejected,
+}
+
+/// Counts of the chunks received per peer.
+#[derive(Debug, Clone, Default)]
+struct PeerMessageCounts(HashMap<PeerId, PeerMessageStats>);
+
+impl PeerMessageCounts {
+	/// Start accounting the chunks of a newly connected peer.
+	fn note_connected(&mut self, peer: &PeerId) {
+		self.0.entry(peer.clone()).or_default();
+	}
+
+	/// Account a chunk received from a peer, unless the peer disconnected in the meantime.
+	fn note_received(&mut self, peer: &PeerId) {
+		if let Some(stats) = self.0.get_mut(peer) {
+			stats.received += 1;
+		}
+	}
+
+	/// Account the outcome of processing a chunk received from a peer,
+	/// unless the peer disconnected in the meantime.
+	fn note_outcome(&mut self, peer: &PeerId, outcome: MessageOutcome) {
+		if let Some(stats) = self.0.get_mut(peer) {
+			match outcome {
+				MessageOutcome::Valid => stats.valid += 1,
+				MessageOutcome::Duplicate => stats.duplicate += 1,
+				MessageOutcome::Rejected => stats.rejected += 1,
+			}
+		}
+	}
+}
+
+/// Chunk requests served to a peer since its current window started.
+#[derive(Debug, Clone)]
+struct RequestWindow {
+	/// When the window started.
+	started_at: Instant,
+	/// The number of requests served within the window.
+	served: u32,
+}
+
+/// A token bucket pacing requests to a rate, holding up to a second worth of tokens.
+///
+/// Tokens are taken in advance, such that requests exceeding the rate queue up behind
+/// each other.
+#[derive(Debug, Clone, Default)]
+struct TokenBucket {
+	/// The tokens replenished per second, unbounded if `None`.
+	rate: Option<u32>,
+	/// The tokens available as of `refilled_at`, negative if taken in advance.
+	tokens: f64,
+	/// When the tokens were replenished last, `None` if the bucket was not used yet.
+	refilled_at: Option<Instant>,
+}
+
+impl TokenBucket {
+	fn new(rate: Option<u32>) -> Self {
+		Self { rate, ..Default::default() }
+	}
+
+	/// Take a token as of `now`, returning for how long the request has to be delayed.
+	fn take(&mut self, now: Instant) -> Duration {
+		let rate = match self.rate {
+			Some(rate) if rate > 0 => f64::from(rate),
+			_ => return Duration::from_secs(0),
+		};
+
+		let tokens = match self.refilled_at {
+			Some(refilled_at) => self.tokens + now.saturating_duration_since(refilled_at).as_secs_f64() * rate,
+			None => rate,
+		};
+		self.tokens = tokens.min(rate) - 1.0;
+		self.refilled_at = Some(now);
+
+		if self.tokens >= 0.0 {
+			Duration::from_secs(0)
+		} else {
+			Duration::from_secs_f64(-self.tokens / rate)
+		}
+	}
+
+	/// Wait until a request may be issued.
+	async fn pace(&mut self) {
+		let delay = self.take(Instant::now());
+		if delay > Duration::from_secs(0) {
+			Delay::new(delay).await;
+		}
+	}
+}
+
+/// How a chunk request relates to the request rate of a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RequestRate {
+	/// The request is within the rate limit.
+	Within,
+	/// The request exceeds the rate limit, but is within the burst allowance.
+	Burst,
+	/// The request exceeds both the rate limit and the burst allowance.
+	Exceeded,
+}
+
+/// The RNG choosing the peers to gossip to, seeded from entropy by default.
+#[derive(Clone, Debug)]
+struct PeerSelectionRng(StdRng);
+
+impl Default for PeerSelectionRng {
+	fn default() -> Self {
+		Self(StdRng::from_entropy())
+	}
+}
+
+impl PeerSelectionRng {
+	/// Choose up to `fanout` of the given peers, or all of them if `fanout` is `None`.
+	///
+	/// Peers are chosen at random, unless `last_view_changes` is given, in which case the peers
+	/// which changed their view most recently are chosen. If `authorities` is given, these are
+	/// chosen before all other peers.
+	fn select(
+		&mut self,
+		mut peers: Vec<PeerId>,
+		fanout: Option<usize>,
+		last_view_changes: Option<&HashMap<PeerId, Instant>>,
+		authorities: Option<&HashSet<PeerId>>,
+	) -> Vec<PeerId> {
+		match fanout {
+			Some(fanout) if peers.len() > fanout => {
+				// the order of peers stems from a `HashMap`, which would defeat a fixed seed
+				peers.sort_by_cached_key(|peer| peer.to_base58());
+				match last_view_changes {
+					Some(last_view_changes) => {
+						peers.sort_by_key(|peer| std::cmp::Reverse(last_view_changes.get(peer).cloned()));
+					}
+					None => peers.shuffle(&mut self.0),
+				}
+				if let Some(authorities) = authorities {
+					// stable, so the order among authorities and among other peers is retained
+					peers.sort_by_key(|peer| !authorities.contains(peer));
+				}
+				peers.truncate(fanout);
+				peers
+			}
+			_ => peers,
+		}
+	}
+}
+
+/// Sizes of the protocol state.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+	/// Number of erasure chunks held in all message vaults.
+	pub buffered_chunks: usize,
+	/// Number of connected peers.
+	pub connected_peers: usize,
+	/// Number of tracked candidates.
+	pub tracked_candidates: usize,
+	/// Number of tracked relay parents.
+	pub tracked_relay_parents: usize,
+	/// Size in bytes of all erasure chunks held in the message vaults, including their proofs.
+	pub buffered_bytes: usize,
+}
+
+/// Estimated memory in bytes used by the components of the protocol state.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryReport {
+	/// The cache of candidates live under relay chain blocks.
+	pub live_under: usize,
+	/// The tracked relay parents, along with their ancestors and live candidates.
+	pub per_relay_parent: usize,
+	/// The bookkeeping of tracked candidates, besides their message vaults.
+	pub per_candidate: usize,
+	/// The erasure chunks held in the message vaults, including their proofs.
+	pub message_vaults: usize,
+}
+
+impl MemoryReport {
+	/// The estimated memory used by all components.
+	pub fn total(&self) -> usize {
+		self.live_under + self.per_relay_parent + self.per_candidate + self.message_vaults
+	}
+}
+
+/// The sizes of the protocol state as last published by the subsystem, readable from other
+/// tasks such as a metrics scraper.
+///
+/// The protocol state itself is never shared, only a copy of its sizes is, so readers hold
+/// the lock just for the duration of copying a few integers.
+#[derive(Debug, Clone, Default)]
+pub struct SharedStateCounts(Arc<RwLock<MetricsSnapshot>>);
+
+impl SharedStateCounts {
+	/// Copy the sizes published last.
+	pub fn snapshot_counts(&self) -> MetricsSnapshot {
+		*self.0.read().unwrap_or_else(PoisonError::into_inner)
+	}
+
+	/// Replace the published sizes.
+	fn publish(&self, snapshot: MetricsSnapshot) {
+		*self.0.write().unwrap_or_else(PoisonError::into_inner) = snapshot;
+	}
+}
+
+/// Where an erasure chunk was obtained from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ChunkSource {
+	/// The chunk stems from available data we hold.
+	Local,
+	/// The chunk was received from the peer.
+	Peer(PeerId),
 }
 
 #[derive(Debug, Clone, Default)]
@@ -149,14 +831,41 @@ struct PerCandidate {
 	/// candidate hash + erasure chunk index -> gossip message
 	message_vault: HashMap<u32, AvailabilityGossipMessage>,
 
+	/// The size in bytes of the erasure chunks in the message vault, including their proofs.
+	buffered_bytes: usize,
+
+	/// Erasure chunk indices of the message vault which passed proof verification
+	/// and thus may be served to peers.
+	ready_to_serve: HashSet<ValidatorIndex>,
+
 	/// Track received erasure chunk indices per peer.
 	received_messages: HashMap<PeerId, HashSet<ValidatorIndex>>,
 
 	/// Track sent erasure chunk indices per peer.
 	sent_messages: HashMap<PeerId, HashSet<ValidatorIndex>>,
 
-	/// The set of validators.
-	validators: Vec<ValidatorId>,
+	/// When an erasure chunk index was last sent to a peer, to throttle re-sends.
+	last_sent: HashMap<(PeerId, ValidatorIndex), Instant>,
+
+	/// Erasure chunk indices a `StoreChunk` was already issued for,
+	/// so identical chunks arriving while the store is pending are not stored twice.
+	pending_stores: HashSet<ValidatorIndex>,
+
+	/// When the erasure chunks in the message vault were buffered.
+	buffered_at: HashMap<ValidatorIndex, Instant>,
+
+	/// Hashes of the proofs and chunks which passed verification, such that copies of a chunk
+	/// received from several peers are only verified once. Holds at most one entry per chunk
+	/// index and erasure root.
+	/// Maps (erasure root, chunk index) -> (proof hash, chunk hash).
+	verified_branches: HashMap<(Hash, ValidatorIndex), (Hash, Hash)>,
+
+	/// Where the erasure chunks in the message vault were first obtained from,
+	/// if recording them is enabled.
+	chunk_sources: HashMap<ValidatorIndex, ChunkSource>,
+
+	/// The set of validators, shared among all candidates of a session.
+	validators: Arc<Vec<ValidatorId>>,
 
 	/// If this node is a validator, note the index in the validator set.
 	validator_index: Option<ValidatorIndex>,
@@ -166,6 +875,18 @@ struct PerCandidate {
 
 	/// The set of relay chain blocks this appears to be live in.
 	live_in: HashSet<Hash>,
+
+	/// The relay chain block number this candidate times out at, if known.
+	time_out_at: Option<BlockNumber>,
+
+	/// The validator group responsible for making this candidate available, if known.
+	group_responsible: Option<GroupIndex>,
+
+	/// The core this candidate occupies, if known.
+	core_index: Option<CoreIndex>,
+
+	/// When this candidate became live in any of the relay parents we track, if it did.
+	first_seen: Option<Instant>,
 }
 
 impl PerCandidate {
@@ -174,17 +895,270 @@ impl PerCandidate {
 		self.received_messages.get(peer).map(|v| !v.contains(validator_index)).unwrap_or(true)
 			&& self.sent_messages.get(peer).map(|v| !v.contains(validator_index)).unwrap_or(true)
 	}
+
+	/// Insert an erasure chunk into the message vault. Only `verified` chunks are served to peers,
+	/// and a verified chunk is never replaced by an unverified one.
+	///
+	/// Returns `true` iff a verified chunk of the same index was present before.
+	fn insert_chunk(&mut self, message: AvailabilityGossipMessage, verified: bool) -> bool {
+		let index = message.erasure_chunk.index;
+		let was_verified = self.ready_to_serve.contains(&index);
+
+		if verified {
+			self.ready_to_serve.insert(index);
+		} else if was_verified {
+			return true;
+		}
+
+		self.buffered_bytes += chunk_size(&message.erasure_chunk);
+		if let Some(replaced) = self.message_vault.insert(index, message) {
+			self.buffered_bytes -= chunk_size(&replaced.erasure_chunk);
+		}
+		self.buffered_at.entry(index).or_insert_with(Instant::now);
+		was_verified
+	}
+
+	/// Replace the descriptor of this candidate. Chunks which were verified against a different
+	/// erasure root are kept, but are not served anymore unless they match the new one as well.
+	fn replace_descriptor(&mut self, descriptor: CandidateDescriptor) {
+		if descriptor.erasure_root != self.descriptor.erasure_root {
+			let message_vault = &self.message_vault;
+			self.ready_to_serve.retain(|index| message_vault
+				.get(index)
+				.map_or(false, |message| verify_chunk(&descriptor.erasure_root, &message.erasure_chunk)));
+		}
+		self.descriptor = descriptor;
+	}
+
+	/// Note where an erasure chunk was obtained from, unless it was obtained before.
+	fn note_chunk_source(&mut self, index: ValidatorIndex, source: ChunkSource) {
+		self.chunk_sources.entry(index).or_insert(source);
+	}
+
+	/// Obtain where an erasure chunk of the message vault was first obtained from, if recorded.
+	fn chunk_source(&self, index: ValidatorIndex) -> Option<ChunkSource> {
+		self.chunk_sources.get(&index).cloned()
+	}
+
+	/// Obtain an erasure chunk from the message vault, regardless of whether it was verified.
+	fn chunk(&self, index: u32) -> Option<&AvailabilityGossipMessage> {
+		self.message_vault.get(&index)
+	}
+
+	/// Obtain an erasure chunk from the message vault, iff it may be served to peers.
+	fn servable_chunk(&self, index: &ValidatorIndex) -> Option<&AvailabilityGossipMessage> {
+		if self.ready_to_serve.contains(index) {
+			self.chunk(*index)
+		} else {
+			None
+		}
+	}
+
+	/// Observe for how long each chunk in the message vault was buffered, as all of them are
+	/// about to be dropped.
+	fn observe_buffered_durations(&self, metrics: &Metrics) {
+		let now = Instant::now();
+		for buffered_at in self.buffered_at.values() {
+			metrics.on_chunk_evicted(now.saturating_duration_since(*buffered_at));
+		}
+	}
+
+	/// The indices of all chunks held by us or known to be held by any peer.
+	fn observed_indices(&self) -> HashSet<ValidatorIndex> {
+		self.message_vault
+			.keys()
+			.chain(self.received_messages.values().flatten())
+			.chain(self.sent_messages.values().flatten())
+			.copied()
+			.collect()
+	}
+
+	/// Returns `true` iff chunks of all indices were observed, in which case the candidate
+	/// is estimated to be available across the network.
+	fn is_estimated_available(&self) -> bool {
+		!self.validators.is_empty() && self.observed_indices().len() >= self.validators.len()
+	}
+
+	/// Estimate the memory in bytes used by the bookkeeping of this candidate, besides the
+	/// message vault. The validators are shared per session and thus not accounted.
+	fn bookkeeping_bytes(&self) -> usize {
+		let index_size = mem::size_of::<ValidatorIndex>();
+		let per_peer_bytes = |indices_per_peer: &HashMap<PeerId, HashSet<ValidatorIndex>>| {
+			indices_per_peer
+				.values()
+				.map(|indices| mem::size_of::<(PeerId, HashSet<ValidatorIndex>)>() + indices.len() * index_size)
+				.sum::<usize>()
+		};
+
+		mem::size_of::<(CandidateHash, PerCandidate)>()
+			+ self.ready_to_serve.len() * index_size
+			+ per_peer_bytes(&self.received_messages)
+			+ per_peer_bytes(&self.sent_messages)
+			+ self.last_sent.len() * mem::size_of::<((PeerId, ValidatorIndex), Instant)>()
+			+ self.pending_stores.len() * index_size
+			+ self.buffered_at.len() * mem::size_of::<(ValidatorIndex, Instant)>()
+			+ self.verified_branches.len() * mem::size_of::<((Hash, ValidatorIndex), (Hash, Hash))>()
+			+ self.chunk_sources.len() * mem::size_of::<(ValidatorIndex, ChunkSource)>()
+			+ self.live_in.len() * mem::size_of::<Hash>()
+	}
+
+	/// Estimate the memory in bytes used by the chunks in the message vault.
+	fn message_vault_bytes(&self) -> usize {
+		self.message_vault
+			.values()
+			.map(|message| {
+				mem::size_of::<(ValidatorIndex, AvailabilityGossipMessage)>() + chunk_size(&message.erasure_chunk)
+			})
+			.sum()
+	}
+
+	/// The number of chunks in the message vault which passed proof verification.
+	fn verified_chunk_count(&self) -> usize {
+		self.message_vault.keys().filter(|index| self.ready_to_serve.contains(index)).count()
+	}
+
+	/// Returns `true` iff the message carries a chunk that differs from the verified chunk
+	/// of the same index we hold already.
+	fn contradicts_verified_chunk(&self, message: &AvailabilityGossipMessage) -> bool {
+		self.servable_chunk(&message.erasure_chunk.index)
+			.map_or(false, |known| known.erasure_chunk.chunk != message.erasure_chunk.chunk)
+	}
+
+	/// Verify the merkle proof of an erasure chunk against the erasure root, skipping the
+	/// computation of the branch if an identical proof passed verification before.
+	fn verify_chunk_cached(&mut self, erasure_root: &Hash, erasure_chunk: &ErasureChunk) -> bool {
+		let key = (*erasure_root, erasure_chunk.index);
+		let proof_hash = BlakeTwo256::hash_of(&erasure_chunk.proof);
+
+		if let Some((verified_proof_hash, chunk_hash)) = self.verified_branches.get(&key) {
+			if *verified_proof_hash == proof_hash {
+				return *chunk_hash == BlakeTwo256::hash(&erasure_chunk.chunk);
+			}
+		}
+
+		let verified = verify_chunk(erasure_root, erasure_chunk);
+		if verified {
+			self.verified_branches.insert(key, (proof_hash, BlakeTwo256::hash(&erasure_chunk.chunk)));
+		}
+		verified
+	}
+
+	/// Returns `true` iff the candidate can no longer be included as of `block_number`.
+	fn is_timed_out(&self, block_number: BlockNumber) -> bool {
+		self.time_out_at.map_or(false, |time_out_at| times_out_by(time_out_at, block_number))
+	}
+
+	/// The validator group responsible for this candidate at the given relay parent.
+	///
+	/// Groups rotate across cores, thus the group is derived from the core the candidate
+	/// occupies if the rotation at the relay parent is known. Otherwise this falls back to
+	/// the group which was responsible when the candidate was fetched.
+	fn group_responsible_at(&self, per_relay_parent: &PerRelayParent) -> Option<GroupIndex> {
+		match (self.core_index, &per_relay_parent.group_assignment) {
+			(Some(core_index), Some(group_assignment)) => Some(group_assignment.group_for_core(core_index)),
+			_ => self.group_responsible,
+		}
+	}
 }
 
-#[derive(Debug, Clone, Default)]
+// The times chunks were buffered and sent at, when the candidate was first seen as well as
+// where chunks were obtained from are excluded, such that states built independently of each other compare equal
+// if they track the same.
+impl PartialEq for PerCandidate {
+	fn eq(&self, other: &Self) -> bool {
+		self.message_vault == other.message_vault
+			&& self.ready_to_serve == other.ready_to_serve
+			&& self.received_messages == other.received_messages
+			&& self.sent_messages == other.sent_messages
+			&& self.pending_stores == other.pending_stores
+			&& self.verified_branches == other.verified_branches
+			&& self.validators == other.validators
+			&& self.validator_index == other.validator_index
+			&& self.descriptor == other.descriptor
+			&& self.live_in == other.live_in
+			&& self.time_out_at == other.time_out_at
+			&& self.group_responsible == other.group_responsible
+			&& self.core_index == other.core_index
+	}
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
 struct PerRelayParent {
 	/// Set of `K` ancestors for this relay parent.
 	ancestors: Vec<Hash>,
 	/// Live candidates, according to this relay parent.
 	live_candidates: HashSet<CandidateHash>,
+	/// The validator group we are a member of at this relay parent, if known.
+	our_group: Option<GroupIndex>,
+	/// The assignment of validator groups to cores at this relay parent, if known.
+	group_assignment: Option<GroupAssignment>,
+	/// The session of the child of this relay parent, if known.
+	session_index: Option<SessionIndex>,
+}
+
+impl PerRelayParent {
+	/// Returns `true` iff the candidate is live according to this relay parent.
+	fn contains_candidate(&self, candidate_hash: &CandidateHash) -> bool {
+		self.live_candidates.contains(candidate_hash)
+	}
+}
+
+/// The assignment of validator groups to cores at a particular relay chain block.
+#[derive(Debug, Clone, PartialEq)]
+struct GroupAssignment {
+	/// How groups rotate across cores.
+	rotation: GroupRotationInfo,
+	/// The number of validator groups, each of which is assigned to one core at a time.
+	n_groups: usize,
+}
+
+impl GroupAssignment {
+	/// The group assigned to the given core.
+	fn group_for_core(&self, core_index: CoreIndex) -> GroupIndex {
+		self.rotation.group_for_core(core_index, self.n_groups)
+	}
+}
+
+// Only the logical state is compared, thus neither the configuration, peers, caches
+// nor the bookkeeping of timeouts.
+impl PartialEq for ProtocolState {
+	fn eq(&self, other: &Self) -> bool {
+		self.view == other.view
+			&& self.live_under == other.live_under
+			&& self.per_relay_parent == other.per_relay_parent
+			&& self.per_candidate == other.per_candidate
+	}
 }
 
 impl ProtocolState {
+	/// Create a new protocol state using the given configuration.
+	fn with_config(config: Config) -> Self {
+		let peer_selection_rng = match config.peer_selection_seed {
+			Some(seed) => PeerSelectionRng(StdRng::seed_from_u64(seed)),
+			None => PeerSelectionRng::default(),
+		};
+		let state = Self::with_capacity(config.expected_relay_parents, config.expected_candidates);
+
+		Self {
+			event_sink: EventSink(config.event_sink.clone()),
+			ancestor_walk_requests: TokenBucket::new(config.max_ancestor_walk_requests_per_second),
+			config,
+			peer_selection_rng,
+			..state
+		}
+	}
+
+	/// Create a new protocol state with the default configuration, which tracks up to
+	/// `relay_parents` relay parents and `candidates` candidates without reallocating.
+	fn with_capacity(relay_parents: usize, candidates: usize) -> Self {
+		Self {
+			live_under: HashMap::with_capacity(relay_parents),
+			per_relay_parent: HashMap::with_capacity(relay_parents),
+			per_candidate: HashMap::with_capacity(candidates),
+			..Default::default()
+		}
+	}
+
 	/// Unionize all live candidate hashes of the given relay parents and their recent
 	/// ancestors.
 	///
@@ -203,36 +1177,478 @@ impl ProtocolState {
 			.collect()
 	}
 
-	#[tracing::instrument(level = "trace", skip(candidates), fields(subsystem = LOG_TARGET))]
-	fn add_relay_parent(
-		&mut self,
-		relay_parent: Hash,
-		validators: Vec<ValidatorId>,
-		validator_index: Option<ValidatorIndex>,
-		candidates: HashMap<CandidateHash, FetchedLiveCandidate>,
-		ancestors: Vec<Hash>,
-	) {
-		let per_relay_parent = self.per_relay_parent.entry(relay_parent).or_default();
-		per_relay_parent.ancestors = ancestors;
-		per_relay_parent.live_candidates.extend(candidates.keys().cloned());
+	/// Returns `true` iff the candidate is live according to any of the given relay parents.
+	///
+	/// Cheaper than checking membership in `cached_live_candidates_unioned`, as no set is built.
+	fn is_candidate_live_in<'a>(
+		&self,
+		candidate_hash: &CandidateHash,
+		relay_parents: impl IntoIterator<Item = &'a Hash>,
+	) -> bool {
+		relay_parents
+			.into_iter()
+			.filter_map(|r| self.per_relay_parent.get(r))
+			.any(|per_relay_parent| per_relay_parent.contains_candidate(candidate_hash))
+	}
 
-		// register the relation of relay_parent to candidate..
-		for (receipt_hash, fetched) in candidates {
-			let per_candidate = self.per_candidate.entry(receipt_hash).or_default();
+	/// Returns `true` iff we are a member of the validator group responsible for the candidate
+	/// at any of the relay parents it is live in.
+	fn is_backed_by_our_group(&self, candidate_hash: &CandidateHash) -> bool {
+		let per_candidate = match self.per_candidate.get(candidate_hash) {
+			Some(per_candidate) => per_candidate,
+			None => return false,
+		};
 
-			// Cached candidates already have entries and thus don't need this
-			// information to be set.
-			if let FetchedLiveCandidate::Fresh(descriptor) = fetched {
-				per_candidate.validator_index = validator_index.clone();
-				per_candidate.validators = validators.clone();
-				per_candidate.descriptor = descriptor;
-			}
-			per_candidate.live_in.insert(relay_parent);
+		per_candidate.live_in
+			.iter()
+			.filter_map(|relay_parent| self.per_relay_parent.get(relay_parent))
+			.any(|per_relay_parent| per_relay_parent.our_group.map_or(false, |our_group| {
+				per_candidate.group_responsible_at(per_relay_parent) == Some(our_group)
+			}))
+	}
+
+	/// Returns `true` iff the candidate is live in at least one of the relay parents we track.
+	fn is_candidate_live(&self, candidate_hash: &CandidateHash) -> bool {
+		self.per_candidate
+			.get(candidate_hash)
+			.map_or(false, |per_candidate| !per_candidate.live_in.is_empty())
+	}
+
+	/// Returns `true` iff the para occupied a core at a relay parent tracked so far. Any para is
+	/// considered known as long as no cores were seen.
+	fn is_para_known(&self, para_id: &ParaId) -> bool {
+		self.known_paras.is_empty() || self.known_paras.contains(para_id)
+	}
+
+	/// Returns `true` iff the candidate was forgotten within the configured grace period.
+	fn is_candidate_recently_forgotten(&self, candidate_hash: &CandidateHash) -> bool {
+		self.recently_forgotten
+			.get(candidate_hash)
+			.map_or(false, |forgotten_at| forgotten_at.elapsed() < self.config.forgotten_candidate_grace)
+	}
+
+	/// Returns `true` iff the candidate timed out according to the best block we have seen.
+	/// Chunks of such candidates are not worth gossiping anymore.
+	fn is_candidate_timed_out(&self, candidate_hash: &CandidateHash) -> bool {
+		match (self.best_block_number, self.per_candidate.get(candidate_hash)) {
+			(Some(block_number), Some(per_candidate)) => per_candidate.is_timed_out(block_number),
+			_ => false,
 		}
 	}
 
-	#[tracing::instrument(level = "trace", skip(self), fields(subsystem = LOG_TARGET))]
-	fn remove_relay_parent(&mut self, relay_parent: &Hash) {
+	/// Obtain all values reported via gauges.
+	fn export_metrics_snapshot(&self) -> MetricsSnapshot {
+		MetricsSnapshot {
+			buffered_chunks: self.chunk_count(),
+			connected_peers: self.peer_count(),
+			tracked_candidates: self.per_candidate.len(),
+			tracked_relay_parents: self.per_relay_parent.len(),
+			buffered_bytes: self.total_buffered_bytes(),
+		}
+	}
+
+	/// The size in bytes of all erasure chunks held in the message vaults.
+	fn total_buffered_bytes(&self) -> usize {
+		self.per_candidate.values().map(|per_candidate| per_candidate.buffered_bytes).sum()
+	}
+
+	/// Ensure the message vaults stay within `max_buffered_bytes` when buffering the chunk
+	/// of `message`, according to the memory policy.
+	///
+	/// Returns `false` iff the chunk must be dropped. Our own chunk and chunks replacing
+	/// buffered ones are never dropped.
+	fn make_room_for_chunk(&mut self, message: &AvailabilityGossipMessage, metrics: &Metrics) -> bool {
+		let max_buffered_bytes = match self.config.max_buffered_bytes {
+			Some(max_buffered_bytes) => max_buffered_bytes,
+			None => return true,
+		};
+
+		let index = message.erasure_chunk.index;
+		if let Some(per_candidate) = self.per_candidate.get(&message.candidate_hash) {
+			if per_candidate.validator_index == Some(index) || per_candidate.message_vault.contains_key(&index) {
+				return true;
+			}
+		}
+
+		let required = chunk_size(&message.erasure_chunk);
+		let mut buffered = self.total_buffered_bytes();
+
+		match self.config.memory_policy {
+			MemoryPolicy::RejectNew => buffered + required <= max_buffered_bytes,
+			MemoryPolicy::EvictOldest => {
+				while buffered + required > max_buffered_bytes {
+					match self.evict_oldest_chunk(metrics) {
+						Some(evicted) => buffered -= evicted,
+						None => return false,
+					}
+				}
+				true
+			}
+		}
+	}
+
+	/// Remove the chunk buffered the longest from the message vaults, returning its size.
+	fn evict_oldest_chunk(&mut self, metrics: &Metrics) -> Option<usize> {
+		let (candidate_hash, index) = self.per_candidate
+			.iter()
+			.map(|(candidate_hash, per_candidate)| per_candidate.buffered_at
+				.iter()
+				.map(move |(index, buffered_at)| (*buffered_at, *candidate_hash, *index))
+			)
+			.flatten()
+			.min_by_key(|(buffered_at, _, _)| *buffered_at)
+			.map(|(_, candidate_hash, index)| (candidate_hash, index))?;
+
+		let per_candidate = self.per_candidate.get_mut(&candidate_hash)?;
+		if let Some(buffered_at) = per_candidate.buffered_at.remove(&index) {
+			metrics.on_chunk_evicted(buffered_at.elapsed());
+		}
+		per_candidate.ready_to_serve.remove(&index);
+		per_candidate.chunk_sources.remove(&index);
+		let evicted = per_candidate.message_vault
+			.remove(&index)
+			.map(|message| chunk_size(&message.erasure_chunk))?;
+		per_candidate.buffered_bytes -= evicted;
+		Some(evicted)
+	}
+
+	/// Drop all chunks buffered in the message vaults to reclaim memory.
+	///
+	/// Candidates and relay parents remain tracked, as does which chunks were exchanged
+	/// with which peer, so gossip routing is unaffected and the chunks can be obtained again.
+	fn clear_message_vaults(&mut self, metrics: &Metrics) {
+		for per_candidate in self.per_candidate.values_mut() {
+			per_candidate.observe_buffered_durations(metrics);
+			per_candidate.message_vault.clear();
+			per_candidate.buffered_bytes = 0;
+			per_candidate.ready_to_serve.clear();
+			per_candidate.buffered_at.clear();
+			per_candidate.chunk_sources.clear();
+		}
+	}
+
+	/// Note the block number of a newly activated leaf, such that candidates which timed out
+	/// already are not tracked at it.
+	fn note_leaf(&mut self, leaf: Hash, block_number: BlockNumber) {
+		self.block_numbers.insert(leaf, block_number);
+		self.note_block_number(block_number);
+	}
+
+	/// Note the block number of a newly activated leaf.
+	fn note_block_number(&mut self, block_number: BlockNumber) {
+		self.best_block_number = Some(
+			self.best_block_number.map_or(block_number, |best| best.max(block_number))
+		);
+	}
+
+	/// Reconstruct the `AvailableData` of a tracked candidate from the chunks we hold,
+	/// if these are enough and consistent with the erasure root of the candidate.
+	fn reconstructed_available_data(&self, candidate_hash: &CandidateHash) -> Option<AvailableData> {
+		self.reconstruct_and_check(candidate_hash).map(|(available_data, _)| available_data)
+	}
+
+	/// Reconstruct the `AvailableData` of a tracked candidate from the chunks we hold and
+	/// re-encode it, returning both iff the chunks re-encoded match the erasure root of the
+	/// candidate. Any subset of chunks with valid proofs decodes to some data, but only the
+	/// data of a properly encoded candidate is consistent with all of its chunks.
+	fn reconstruct_and_check(&self, candidate_hash: &CandidateHash) -> Option<(AvailableData, Vec<Vec<u8>>)> {
+		let per_candidate = self.per_candidate.get(candidate_hash)?;
+		let available_data = match reconstruct_available_data(per_candidate, self.config.max_pov_size) {
+			Ok(available_data) => available_data,
+			Err(e) => {
+				tracing::debug!(
+					target: LOG_TARGET,
+					err = ?e,
+					?candidate_hash,
+					"Failed to reconstruct available data",
+				);
+				return None;
+			}
+		};
+		let chunks = match polkadot_erasure_coding::obtain_chunks_v1(per_candidate.validators.len(), &available_data) {
+			Ok(chunks) => chunks,
+			Err(e) => {
+				tracing::debug!(
+					target: LOG_TARGET,
+					err = ?e,
+					?candidate_hash,
+					"Failed to derive chunks of reconstructed available data",
+				);
+				return None;
+			}
+		};
+
+		if polkadot_erasure_coding::branches(&chunks).root() != per_candidate.descriptor.erasure_root {
+			// any chunk used in the reconstruction may be at fault, name their sources if recorded
+			let mut chunk_sources = per_candidate.message_vault
+				.keys()
+				.map(|index| (*index, per_candidate.chunk_source(*index)))
+				.collect::<Vec<_>>();
+			chunk_sources.sort_by_key(|(index, _)| *index);
+			tracing::warn!(
+				target: LOG_TARGET,
+				?candidate_hash,
+				?chunk_sources,
+				"Reconstructed available data does not match the erasure root",
+			);
+			return None;
+		}
+
+		Some((available_data, chunks))
+	}
+
+	/// Derive the chunks of a candidate we miss from the available data reconstructed from
+	/// the chunks we hold, such that all of them can be served to peers.
+	///
+	/// Returns the number of chunks which were added to the message vault. Our own chunk is
+	/// added to `own_chunks` if it was derived, to be stored in the availability store.
+	fn backfill_chunks(
+		&mut self,
+		candidate_hash: &CandidateHash,
+		metrics: &Metrics,
+		own_chunks: &mut Vec<(CandidateHash, Hash, ValidatorIndex, ErasureChunk)>,
+	) -> usize {
+		let chunks = match self.reconstruct_and_check(candidate_hash) {
+			Some((_, chunks)) => chunks,
+			None => return 0,
+		};
+		let branches = polkadot_erasure_coding::branches(&chunks);
+		self.event_sink.emit(AvailabilityEvent::ReconstructionComplete { candidate_hash: *candidate_hash });
+
+		let store_own_chunk = !self.config.store_own_group_only || self.is_backed_by_our_group(candidate_hash);
+		let mut backfilled = 0;
+		for (index, (proof, chunk)) in branches.enumerate() {
+			let index = index as ValidatorIndex;
+			let servable = self.per_candidate
+				.get(candidate_hash)
+				.map_or(false, |per_candidate| per_candidate.servable_chunk(&index).is_some());
+			if servable {
+				continue;
+			}
+
+			let message = AvailabilityGossipMessage {
+				candidate_hash: *candidate_hash,
+				erasure_chunk: ErasureChunk { chunk: chunk.to_vec(), index, proof },
+			};
+			if !self.make_room_for_chunk(&message, metrics) {
+				break;
+			}
+
+			let per_candidate = self.per_candidate
+				.get_mut(candidate_hash)
+				.expect("Evicting chunks leaves candidates tracked; qed");
+			if Some(index) == per_candidate.validator_index
+				&& store_own_chunk
+				&& per_candidate.pending_stores.insert(index)
+			{
+				own_chunks.push((
+					*candidate_hash,
+					per_candidate.descriptor.relay_parent,
+					index,
+					message.erasure_chunk.clone(),
+				));
+			}
+			per_candidate.insert_chunk(message, true);
+			if self.config.record_chunk_sources {
+				per_candidate.note_chunk_source(index, ChunkSource::Local);
+			}
+			backfilled += 1;
+		}
+		backfilled
+	}
+
+	/// Backfill the chunks of all candidates of which we hold enough verified chunks
+	/// to reconstruct their available data, but not all chunks yet.
+	///
+	/// Returns the number of chunks which were added to the message vaults, adding our own
+	/// chunks which were derived to `own_chunks`.
+	fn backfill_reconstructable_candidates(
+		&mut self,
+		metrics: &Metrics,
+		own_chunks: &mut Vec<(CandidateHash, Hash, ValidatorIndex, ErasureChunk)>,
+	) -> usize {
+		let candidates = self.per_candidate
+			.iter()
+			.filter(|(_, per_candidate)| {
+				let n_validators = per_candidate.validators.len();
+				per_candidate.ready_to_serve.len() < n_validators
+					&& per_candidate.verified_chunk_count() >= polkadot_erasure_coding::recovery_threshold(n_validators)
+			})
+			.map(|(candidate_hash, _)| *candidate_hash)
+			.collect::<Vec<_>>();
+
+		candidates
+			.iter()
+			.map(|candidate_hash| self.backfill_chunks(candidate_hash, metrics, own_chunks))
+			.sum()
+	}
+
+	/// Obtain the validator set of a session, such that all candidates of the same
+	/// session share it. Validator sets of unknown sessions are not cached.
+	fn shared_validators(
+		&mut self,
+		session_index: Option<SessionIndex>,
+		validators: Vec<ValidatorId>,
+	) -> Arc<Vec<ValidatorId>> {
+		match session_index {
+			Some(session_index) => self.session_validators
+				.entry(session_index)
+				.or_insert_with(|| Arc::new(validators))
+				.clone(),
+			None => Arc::new(validators),
+		}
+	}
+
+	/// Track a relay parent along with its live candidates.
+	///
+	/// Returns the number of candidates which are not tracked, as they timed out already.
+	#[tracing::instrument(level = "trace", skip(candidates), fields(subsystem = LOG_TARGET))]
+	fn add_relay_parent(
+		&mut self,
+		relay_parent: Hash,
+		validators: Arc<Vec<ValidatorId>>,
+		validator_index: Option<ValidatorIndex>,
+		candidates: HashMap<CandidateHash, FetchedLiveCandidate>,
+		ancestors: Vec<Hash>,
+		metrics: &Metrics,
+	) -> usize {
+		let block_number = self.block_numbers.get(&relay_parent).copied();
+		let per_relay_parent = self.per_relay_parent.entry(relay_parent).or_default();
+		per_relay_parent.ancestors = ancestors;
+
+		let mut timed_out = 0;
+		// register the relation of relay_parent to candidate..
+		for (receipt_hash, fetched) in candidates {
+			// adding a relay parent anew leaves the candidates live in it untouched
+			let live_in_relay_parent = per_relay_parent.contains_candidate(&receipt_hash)
+				&& self.per_candidate
+					.get(&receipt_hash)
+					.map_or(false, |per_candidate| per_candidate.live_in.contains(&relay_parent));
+			if live_in_relay_parent {
+				tracing::trace!(
+					target: LOG_TARGET,
+					candidate_hash = ?receipt_hash,
+					?relay_parent,
+					"Candidate is live in the relay parent already",
+				);
+				continue;
+			}
+
+			// gossiping chunks of a candidate which can not become available anymore is pointless
+			if let (FetchedLiveCandidate::Fresh { time_out_at, .. }, Some(block_number)) = (&fetched, block_number) {
+				if times_out_by(*time_out_at, block_number) {
+					tracing::debug!(
+						target: LOG_TARGET,
+						candidate_hash = ?receipt_hash,
+						?relay_parent,
+						time_out_at,
+						block_number,
+						"Skipping candidate which timed out before the relay parent",
+					);
+					timed_out += 1;
+					continue;
+				}
+			}
+
+			// candidates of filtered paras are not tracked at all, so cached candidates
+			// which are not tracked were filtered when they were fetched
+			let filtered = match &fetched {
+				FetchedLiveCandidate::Fresh { descriptor, .. } => !self.config.distributes_para(&descriptor.para_id),
+				FetchedLiveCandidate::Cached => {
+					self.config.filters_paras() && !self.per_candidate.contains_key(&receipt_hash)
+				}
+			};
+			if filtered {
+				tracing::trace!(
+					target: LOG_TARGET,
+					candidate_hash = ?receipt_hash,
+					?relay_parent,
+					"Skipping candidate of a para which is not distributed",
+				);
+				continue;
+			}
+
+			// no chunk verifies against a zero erasure root, so peers sending chunks of
+			// such a candidate would be penalized for no fault of their own
+			if let FetchedLiveCandidate::Fresh { descriptor, .. } = &fetched {
+				if descriptor.erasure_root == Hash::default() {
+					tracing::warn!(
+						target: LOG_TARGET,
+						candidate_hash = ?receipt_hash,
+						?relay_parent,
+						"Skipping candidate without an erasure root",
+					);
+					metrics.on_malformed_candidate();
+					// the candidate must not be revived from the cache
+					for candidates in self.live_under.values_mut() {
+						candidates.remove(&receipt_hash);
+					}
+					continue;
+				}
+			}
+
+			per_relay_parent.live_candidates.insert(receipt_hash);
+			let per_candidate = self.per_candidate.entry(receipt_hash).or_default();
+
+			// Cached candidates already have entries and thus don't need this
+			// information to be set.
+			if let FetchedLiveCandidate::Fresh { descriptor, time_out_at, group_responsible, core_index } = fetched {
+				// A candidate hash commits to the para, so a candidate of another para
+				// sharing it hints at an inconsistent runtime. Stick with the one tracked.
+				if !per_candidate.live_in.is_empty() && per_candidate.descriptor.para_id != descriptor.para_id {
+					tracing::error!(
+						target: LOG_TARGET,
+						candidate_hash = ?receipt_hash,
+						?relay_parent,
+						tracked = ?per_candidate.descriptor.para_id,
+						fetched = ?descriptor.para_id,
+						"Candidate hash is shared by candidates of different paras",
+					);
+					metrics.on_candidate_hash_collision();
+					per_candidate.live_in.insert(relay_parent);
+					continue;
+				}
+
+				// The same candidate must commit to the same erasure root, regardless of
+				// the relay parent it was fetched at. Chunks we hold were verified against
+				// the root we learned first, so stick with it.
+				if !per_candidate.live_in.is_empty()
+					&& per_candidate.descriptor.erasure_root != descriptor.erasure_root
+				{
+					tracing::warn!(
+						target: LOG_TARGET,
+						candidate_hash = ?receipt_hash,
+						?relay_parent,
+						tracked = ?per_candidate.descriptor.erasure_root,
+						fetched = ?descriptor.erasure_root,
+						"Erasure root of candidate diverges across relay parents",
+					);
+					metrics.on_erasure_root_mismatch();
+					per_candidate.live_in.insert(relay_parent);
+					continue;
+				}
+
+				self.known_paras.insert(descriptor.para_id);
+				per_candidate.validator_index = validator_index.clone();
+				per_candidate.validators = validators.clone();
+				per_candidate.descriptor = descriptor;
+				per_candidate.time_out_at = Some(time_out_at);
+				per_candidate.group_responsible = Some(group_responsible);
+				per_candidate.core_index = Some(core_index);
+			}
+			if per_candidate.live_in.is_empty() {
+				per_candidate.first_seen.get_or_insert_with(Instant::now);
+				self.event_sink.emit(AvailabilityEvent::CandidateAdded { candidate_hash: receipt_hash, relay_parent });
+			}
+			per_candidate.live_in.insert(relay_parent);
+		}
+
+		timed_out
+	}
+
+	#[tracing::instrument(level = "trace", skip(self, metrics), fields(subsystem = LOG_TARGET))]
+	fn remove_relay_parent(&mut self, relay_parent: &Hash, metrics: &Metrics) {
+		self.block_numbers.remove(relay_parent);
 		if let Some(per_relay_parent) = self.per_relay_parent.remove(relay_parent) {
 			for candidate_hash in per_relay_parent.live_candidates {
 				// Prune the candidate if this was the last member of our view
@@ -240,11 +1656,17 @@ impl ProtocolState {
 				if let Entry::Occupied(mut occ) = self.per_candidate.entry(candidate_hash) {
 					occ.get_mut().live_in.remove(relay_parent);
 					if occ.get().live_in.is_empty() {
-						occ.remove();
+						occ.remove().observe_buffered_durations(metrics);
+						self.event_sink.emit(AvailabilityEvent::CandidateRemoved { candidate_hash });
+						// peers lagging behind may still send chunks of it for a while
+						self.recently_forgotten.insert(candidate_hash, Instant::now());
 					}
 				}
 			}
 		}
+
+		#[cfg(debug_assertions)]
+		self.assert_no_orphans();
 	}
 
 	// Removes all entries from live_under which aren't referenced in the ancestry of
@@ -257,150 +1679,875 @@ impl ProtocolState {
 
 		self.live_under.retain(|ancestor_hash, _| extended_view.contains(ancestor_hash));
 	}
-}
 
-/// Deal with network bridge updates and track what needs to be tracked
-/// which depends on the message type received.
-#[tracing::instrument(level = "trace", skip(ctx, keystore, metrics), fields(subsystem = LOG_TARGET))]
-async fn handle_network_msg<Context>(
-	ctx: &mut Context,
-	keystore: &SyncCryptoStorePtr,
-	state: &mut ProtocolState,
-	metrics: &Metrics,
-	bridge_message: NetworkBridgeEvent<protocol_v1::AvailabilityDistributionMessage>,
-) -> Result<()>
-where
-	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
-{
-	match bridge_message {
-		NetworkBridgeEvent::PeerConnected(peerid, _role) => {
-			// insert if none already present
-			state.peer_views.entry(peerid).or_default();
+	/// Clean up `live_under`, unless it was cleaned up within the configured interval already.
+	/// Returns whether the cache was cleaned up.
+	fn clean_up_live_under_cache_if_due(&mut self, now: Instant) -> bool {
+		let interval = self.config.live_under_clean_up_interval;
+		let due = self.live_under_cleaned_up_at
+			.map_or(true, |cleaned_up_at| now.saturating_duration_since(cleaned_up_at) >= interval);
+		if due {
+			self.clean_up_live_under_cache();
+			self.live_under_cleaned_up_at = Some(now);
 		}
-		NetworkBridgeEvent::PeerDisconnected(peerid) => {
-			// get rid of superfluous data
-			state.peer_views.remove(&peerid);
+		due
+	}
+
+	/// Note that a chunk of a candidate which is not live was rejected, returning the number of
+	/// times chunks of the candidate were rejected for the peer within the configured TTL before.
+	fn note_rejected_candidate(&mut self, peer: &PeerId, candidate_hash: CandidateHash, now: Instant) -> u32 {
+		if self.config.max_rejected_candidates == 0 {
+			return 0;
 		}
-		NetworkBridgeEvent::PeerViewChange(peerid, view) => {
-			handle_peer_view_change(ctx, state, peerid, view, metrics).await;
+
+		let ttl = self.config.rejected_candidate_ttl;
+		let key = (peer.clone(), candidate_hash);
+		let previous = match self.recently_rejected.get(&key) {
+			Some((rejected_at, rejections)) if now.saturating_duration_since(*rejected_at) < ttl => *rejections,
+			_ => 0,
+		};
+
+		if previous == 0 && !self.recently_rejected.contains_key(&key) {
+			self.recently_rejected.retain(|_, (rejected_at, _)| now.saturating_duration_since(*rejected_at) < ttl);
+			while self.recently_rejected.len() >= self.config.max_rejected_candidates {
+				let least_recent = self.recently_rejected
+					.iter()
+					.min_by_key(|(_, (rejected_at, _))| *rejected_at)
+					.map(|(key, _)| key.clone())
+					.expect("Bound is non zero, so there is an entry; qed");
+				self.recently_rejected.remove(&least_recent);
+			}
 		}
-		NetworkBridgeEvent::OurViewChange(view) => {
-			handle_our_view_change(ctx, keystore, state, view, metrics).await?;
+		self.recently_rejected.insert(key, (now, previous.saturating_add(1)));
+
+		previous
+	}
+
+	/// Hold back a chunk of a candidate which is not known yet, evicting the oldest chunks
+	/// beyond the configured bounds. Returns the number of evicted chunks.
+	fn defer_chunk(&mut self, origin: PeerId, message: AvailabilityGossipMessage, now: Instant) -> usize {
+		let mut evicted = self.evict_expired_pending_chunks(now);
+
+		self.pending_chunk_bytes += chunk_size(&message.erasure_chunk);
+		self.pending_chunks.push_back((now, origin, message));
+		while self.pending_chunks.len() > self.config.max_pending_chunks
+			|| self.pending_chunk_bytes > self.config.max_pending_chunk_bytes
+		{
+			match self.pending_chunks.pop_front() {
+				Some((_, _, message)) => self.pending_chunk_bytes -= chunk_size(&message.erasure_chunk),
+				None => break,
+			}
+			evicted += 1;
 		}
-		NetworkBridgeEvent::PeerMessage(remote, msg) => {
-			let gossiped_availability = match msg {
-				protocol_v1::AvailabilityDistributionMessage::Chunk(candidate_hash, chunk) => {
-					AvailabilityGossipMessage {
-						candidate_hash,
-						erasure_chunk: chunk,
-					}
-				}
-			};
 
-			let mut _span = jaeger::hash_span(&gossiped_availability.candidate_hash.0, "availability-message-received");
+		evicted
+	}
 
-			process_incoming_peer_message(ctx, state, remote, gossiped_availability, metrics)
-				.await?;
+	/// Drop all held back chunks which are older than the configured TTL as of `now`,
+	/// returning the number of dropped chunks.
+	fn evict_expired_pending_chunks(&mut self, now: Instant) -> usize {
+		let ttl = self.config.pending_chunk_ttl;
+		let expired = self.pending_chunks
+			.iter()
+			.take_while(|(received_at, _, _)| now.saturating_duration_since(*received_at) >= ttl)
+			.count();
+
+		for (_, _, message) in self.pending_chunks.drain(..expired) {
+			self.pending_chunk_bytes -= chunk_size(&message.erasure_chunk);
 		}
+		expired
 	}
-	Ok(())
-}
 
-/// Handle the changes necessary when our view changes.
-#[tracing::instrument(level = "trace", skip(ctx, keystore, metrics), fields(subsystem = LOG_TARGET))]
-async fn handle_our_view_change<Context>(
-	ctx: &mut Context,
-	keystore: &SyncCryptoStorePtr,
-	state: &mut ProtocolState,
-	view: View,
-	metrics: &Metrics,
-) -> Result<()>
-where
-	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
-{
-	let _timer = metrics.time_handle_our_view_change();
+	/// Take all held back chunks of candidates which became live, along with their origin.
+	fn take_pending_chunks_of_live_candidates(&mut self) -> Vec<(PeerId, AvailabilityGossipMessage)> {
+		let (live, pending): (VecDeque<_>, _) = std::mem::take(&mut self.pending_chunks)
+			.into_iter()
+			.partition(|(_, _, message)| self.is_candidate_live(&message.candidate_hash));
 
-	let old_view = std::mem::replace(&mut state.view, view);
+		self.pending_chunks = pending;
+		for (_, _, message) in live.iter() {
+			self.pending_chunk_bytes -= chunk_size(&message.erasure_chunk);
+		}
+		live.into_iter().map(|(_, origin, message)| (origin, message)).collect()
+	}
 
-	// needed due to borrow rules
-	let view = state.view.clone();
+	/// Note that a peer left a chunk request unanswered, depleting its retry budget.
+	fn note_unanswered_chunk_request(&mut self, peer: &PeerId, now: Instant) {
+		let budget = self.config.chunk_request_retry_budget;
+		let cooldown = self.config.chunk_source_cooldown;
+
+		let retry_budget = self.retry_budgets.entry(peer.clone()).or_default();
+		match retry_budget.exhausted_at {
+			Some(exhausted_at) if now.saturating_duration_since(exhausted_at) < cooldown => return,
+			// the peer is given a fresh budget after the cooldown
+			Some(_) => *retry_budget = RetryBudget::default(),
+			None => {}
+		}
 
-	// add all the relay parents and fill the cache
-	for added in view.difference(&old_view) {
-		let validators = query_validators(ctx, *added).await?;
-		let validator_index = obtain_our_validator_index(&validators, keystore.clone()).await;
-		let (candidates, ancestors)
-			= query_live_candidates(ctx, &mut state.live_under, *added).await?;
+		retry_budget.unanswered += 1;
+		if retry_budget.unanswered >= budget {
+			tracing::debug!(
+				target: LOG_TARGET,
+				peer = %peer,
+				unanswered = retry_budget.unanswered,
+				"Peer exhausted its chunk request retry budget",
+			);
+			retry_budget.exhausted_at = Some(now);
+		}
+	}
 
-		state.add_relay_parent(
-			*added,
-			validators,
-			validator_index,
-			candidates,
-			ancestors,
-		);
+	/// Note that a peer answered a chunk request, replenishing its retry budget.
+	fn note_answered_chunk_request(&mut self, peer: &PeerId) {
+		self.retry_budgets.remove(peer);
 	}
 
-	// handle all candidates
-	for candidate_hash in state.cached_live_candidates_unioned(view.difference(&old_view)) {
-		// If we are not a validator for this candidate, let's skip it.
-		if state.per_candidate.entry(candidate_hash).or_default().validator_index.is_none() {
-			continue
+	/// Returns `true` iff chunks may be requested from the peer, i.e. it did not exhaust
+	/// its retry budget within the cooldown.
+	fn is_chunk_source(&self, peer: &PeerId, now: Instant) -> bool {
+		match self.retry_budgets.get(peer).and_then(|retry_budget| retry_budget.exhausted_at) {
+			Some(exhausted_at) => now.saturating_duration_since(exhausted_at) >= self.config.chunk_source_cooldown,
+			None => true,
 		}
+	}
 
-		// check if the availability is present in the store exists
-		if !query_data_availability(ctx, candidate_hash).await? {
-			continue;
-		}
+	/// Obtain all connected peers chunks may be requested from.
+	fn chunk_sources(&self, now: Instant) -> Vec<PeerId> {
+		self.peer_views
+			.keys()
+			.filter(|peer| self.is_chunk_source(peer, now))
+			.cloned()
+			.collect()
+	}
 
-		// obtain interested peers in the candidate hash
-		let peers: Vec<PeerId> = state
-			.peer_views
-			.clone()
+	/// Note a request of our own chunk of a candidate from the next chunk source it was not
+	/// requested from yet, among the peers the candidate is live for. Returns that peer,
+	/// if any is left.
+	fn next_own_chunk_request(&mut self, candidate_hash: CandidateHash, now: Instant) -> Option<PeerId> {
+		let requested_from = self.own_chunk_requests
+			.get(&candidate_hash)
+			.map(|request| request.requested_from.clone())
+			.unwrap_or_default();
+		let peer = self.chunk_sources(now)
 			.into_iter()
-			.filter(|(_peer, view)| {
-				// collect all direct interests of a peer w/o ancestors
-				state
-					.cached_live_candidates_unioned(view.heads.iter())
-					.contains(&candidate_hash)
-			})
-			.map(|(peer, _view)| peer.clone())
-			.collect();
+			.filter(|peer| !requested_from.contains(peer))
+			.find(|peer| self.peer_views
+				.get(peer)
+				.map_or(false, |view| self.is_candidate_live_in(&candidate_hash, view.heads.iter())));
+
+		let request = self.own_chunk_requests.entry(candidate_hash).or_default();
+		request.peer = peer.clone();
+		request.requested_at = Some(now);
+		if let Some(peer) = &peer {
+			request.requested_from.insert(peer.clone());
+		}
+		peer
+	}
 
-		let per_candidate = state.per_candidate.entry(candidate_hash).or_default();
+	/// Obtain all chunks of a candidate we hold which may be served, ordered by their index.
+	///
+	/// This serves trusted in-process requesters and is thus not rate limited.
+	fn held_chunks(&self, candidate_hash: &CandidateHash) -> Vec<ErasureChunk> {
+		let mut chunks: Vec<_> = self.per_candidate
+			.get(candidate_hash)
+			.map(|per_candidate| per_candidate.message_vault
+				.keys()
+				.filter_map(|index| per_candidate.servable_chunk(index))
+				.map(|message| message.erasure_chunk.clone())
+				.collect())
+			.unwrap_or_default();
+		chunks.sort_by_key(|chunk| chunk.index);
+		chunks
+	}
 
-		let validator_count = per_candidate.validators.len();
+	/// Note a chunk request of a peer, returning how it relates to the number of requests
+	/// the peer may issue within the current window. Refused requests are not counted.
+	fn note_chunk_request(&mut self, peer: &PeerId, now: Instant) -> RequestRate {
+		let window = self.config.chunk_request_window;
+		let max_requests = self.config.max_chunk_requests_per_peer;
+		let max_burst = self.config.chunk_request_burst;
+
+		let request_window = self.request_windows
+			.entry(peer.clone())
+			.or_insert(RequestWindow { started_at: now, served: 0 });
+		if now.saturating_duration_since(request_window.started_at) >= window {
+			*request_window = RequestWindow { started_at: now, served: 0 };
+		}
 
-		// distribute all erasure messages to interested peers
-		for chunk_index in 0u32..(validator_count as u32) {
-			let message = if let Some(message) = per_candidate.message_vault.get(&chunk_index) {
-				tracing::trace!(
+		if request_window.served >= max_requests.saturating_add(max_burst) {
+			return RequestRate::Exceeded;
+		}
+		request_window.served += 1;
+		if request_window.served > max_requests {
+			RequestRate::Burst
+		} else {
+			RequestRate::Within
+		}
+	}
+
+	/// Serve a chunk requested by a peer over the network, unless we are not ready to serve
+	/// the chunk. Fails with the reputation change to apply if the peer exceeded its request
+	/// rate.
+	fn serve_chunk_request(
+		&mut self,
+		peer: &PeerId,
+		candidate_hash: &CandidateHash,
+		index: ValidatorIndex,
+		now: Instant,
+	) -> std::result::Result<Option<ErasureChunk>, Rep> {
+		match self.note_chunk_request(peer, now) {
+			RequestRate::Within => {}
+			RequestRate::Burst => {
+				tracing::debug!(
 					target: LOG_TARGET,
-					%chunk_index,
+					peer = %peer,
 					?candidate_hash,
-					"Retrieved chunk from message vault",
+					index,
+					"Peer exceeded its chunk request rate within the burst allowance",
 				);
-				message.clone()
-			} else if let Some(erasure_chunk) = query_chunk(ctx, candidate_hash, chunk_index as ValidatorIndex).await? {
-				tracing::trace!(
+			}
+			RequestRate::Exceeded => {
+				tracing::debug!(
 					target: LOG_TARGET,
-					%chunk_index,
+					peer = %peer,
 					?candidate_hash,
-					"Retrieved chunk from availability storage",
+					index,
+					"Peer exceeded its chunk request rate",
 				);
-
-				AvailabilityGossipMessage {
-					candidate_hash,
-					erasure_chunk,
+				return Err(self.config.reputation.cost_chunk_request_rate_exceeded.clone());
+			}
+		}
+
+		Ok(self.per_candidate
+			.get(candidate_hash)
+			.and_then(|per_candidate| per_candidate.servable_chunk(&index))
+			.map(|message| message.erasure_chunk.clone()))
+	}
+
+	// Removes all forgotten candidates whose grace period elapsed.
+	fn clean_up_recently_forgotten(&mut self) {
+		let grace = self.config.forgotten_candidate_grace;
+		self.recently_forgotten.retain(|_, forgotten_at| forgotten_at.elapsed() < grace);
+	}
+
+	// Removes all disconnected peers whose grace period elapsed.
+	fn clean_up_recently_disconnected(&mut self) {
+		self.recently_disconnected.retain(|_, disconnected_at| disconnected_at.elapsed() < DISCONNECTED_PEER_GRACE);
+	}
+
+	// Removes all validator sets which are not referenced by any candidate anymore.
+	fn clean_up_session_validators_cache(&mut self) {
+		self.session_validators.retain(|_, validators| Arc::strong_count(validators) > 1);
+	}
+
+	// Removes the block numbers of activated leaves which are not tracked as relay parents.
+	fn clean_up_block_numbers(&mut self) {
+		let per_relay_parent = &self.per_relay_parent;
+		self.block_numbers.retain(|relay_parent, _| per_relay_parent.contains_key(relay_parent));
+	}
+
+	/// Check the relations between relay parents and candidates, repairing inconsistencies
+	/// in place. Consistent states are left untouched, so this is safe to call at any time.
+	///
+	/// Returns what was repaired.
+	#[cfg(any(test, debug_assertions))]
+	fn validate_and_repair(&mut self) -> RepairReport {
+		let mut report = RepairReport::default();
+
+		// candidates can only be live in relay parents which are tracked
+		for (candidate_hash, per_candidate) in self.per_candidate.iter_mut() {
+			let relay_parents = &mut self.per_relay_parent;
+			per_candidate.live_in.retain(|relay_parent| match relay_parents.get_mut(relay_parent) {
+				Some(per_relay_parent) => {
+					if per_relay_parent.live_candidates.insert(*candidate_hash) {
+						report.restored_live_candidates.push((*relay_parent, *candidate_hash));
+					}
+					true
+				}
+				None => {
+					report.removed_live_in.push((*candidate_hash, *relay_parent));
+					false
+				}
+			});
+		}
+
+		// live candidates of a relay parent must be tracked and know they are live in it
+		for (relay_parent, per_relay_parent) in self.per_relay_parent.iter_mut() {
+			let candidates = &mut self.per_candidate;
+			per_relay_parent.live_candidates.retain(|candidate_hash| match candidates.get_mut(candidate_hash) {
+				Some(per_candidate) => {
+					if per_candidate.live_in.insert(*relay_parent) {
+						report.restored_live_in.push((*candidate_hash, *relay_parent));
+					}
+					true
 				}
+				None => {
+					report.removed_live_candidates.push((*relay_parent, *candidate_hash));
+					false
+				}
+			});
+		}
+
+		// candidates not live in any relay parent would never be removed
+		self.per_candidate.retain(|candidate_hash, per_candidate| {
+			if per_candidate.live_in.is_empty() {
+				report.removed_orphans.push(*candidate_hash);
+				false
+			} else {
+				true
+			}
+		});
+
+		if !report.is_empty() {
+			tracing::warn!(
+				target: LOG_TARGET,
+				?report,
+				"Repaired inconsistencies of the protocol state",
+			);
+		}
+
+		report
+	}
+}
+
+/// Inconsistencies of the protocol state repaired by `ProtocolState::validate_and_repair`.
+#[cfg(any(test, debug_assertions))]
+#[derive(Debug, Default, PartialEq, Eq)]
+struct RepairReport {
+	/// Candidates which were not live in any relay parent and thus removed.
+	removed_orphans: Vec<CandidateHash>,
+	/// Relay parents a candidate was live in, which were not tracked.
+	/// Holds (candidate hash, relay parent).
+	removed_live_in: Vec<(CandidateHash, Hash)>,
+	/// Candidates live in a relay parent, but missing from its live candidates.
+	/// Holds (relay parent, candidate hash).
+	restored_live_candidates: Vec<(Hash, CandidateHash)>,
+	/// Live candidates of a relay parent, which were not aware of being live in it.
+	/// Holds (candidate hash, relay parent).
+	restored_live_in: Vec<(CandidateHash, Hash)>,
+	/// Live candidates of a relay parent, which were not tracked.
+	/// Holds (relay parent, candidate hash).
+	removed_live_candidates: Vec<(Hash, CandidateHash)>,
+}
+
+#[cfg(any(test, debug_assertions))]
+impl RepairReport {
+	/// Returns `true` iff nothing was repaired.
+	fn is_empty(&self) -> bool {
+		self.removed_orphans.is_empty()
+			&& self.removed_live_in.is_empty()
+			&& self.restored_live_candidates.is_empty()
+			&& self.restored_live_in.is_empty()
+			&& self.removed_live_candidates.is_empty()
+	}
+}
+
+// Inspection of the protocol state, used for diagnostics rather than by the protocol itself.
+impl ProtocolState {
+	/// Estimate the memory used by the components of the protocol state.
+	fn memory_report(&self) -> MemoryReport {
+		let hash_size = mem::size_of::<Hash>();
+		let candidate_hash_size = mem::size_of::<CandidateHash>();
+
+		MemoryReport {
+			live_under: self.live_under
+				.values()
+				.map(|candidates| {
+					mem::size_of::<(Hash, HashSet<CandidateHash>)>() + candidates.len() * candidate_hash_size
+				})
+				.sum(),
+			per_relay_parent: self.per_relay_parent
+				.values()
+				.map(|per_relay_parent| {
+					mem::size_of::<(Hash, PerRelayParent)>()
+						+ per_relay_parent.ancestors.len() * hash_size
+						+ per_relay_parent.live_candidates.len() * candidate_hash_size
+				})
+				.sum(),
+			per_candidate: self.per_candidate.values().map(PerCandidate::bookkeeping_bytes).sum(),
+			message_vaults: self.per_candidate.values().map(PerCandidate::message_vault_bytes).sum(),
+		}
+	}
+
+	/// The bytes of chunk data held more than once across the message vaults of all candidates,
+	/// i.e. the memory a content-addressed chunk store would save. Identical chunk data is only
+	/// expected for distinct candidates sharing an erasure root.
+	fn duplicate_chunk_bytes(&self) -> usize {
+		let mut seen = HashSet::new();
+		self.per_candidate
+			.values()
+			.flat_map(|per_candidate| per_candidate.message_vault.values())
+			.map(|message| &message.erasure_chunk.chunk)
+			.filter(|chunk| !seen.insert(BlakeTwo256::hash(chunk)))
+			.map(|chunk| chunk.len())
+			.sum()
+	}
+
+	/// Summarize how long the tracked candidates have been live as of `now`, for spotting
+	/// candidates which are stuck. Returns the lower bound of each bucket along with the number
+	/// of candidates at least as old as it, but younger than the lower bound of the next bucket.
+	fn age_histogram(&self, now: Instant) -> Vec<(Duration, usize)> {
+		let mut histogram = CANDIDATE_AGE_BUCKETS
+			.iter()
+			.map(|secs| (Duration::from_secs(*secs), 0))
+			.collect::<Vec<_>>();
+
+		for first_seen in self.per_candidate.values().filter_map(|per_candidate| per_candidate.first_seen) {
+			let age = now.saturating_duration_since(first_seen);
+			if let Some((_, count)) = histogram.iter_mut().rev().find(|(lower_bound, _)| age >= *lower_bound) {
+				*count += 1;
+			}
+		}
+
+		histogram
+	}
+
+	/// The number of peers we know the view of.
+	fn peer_count(&self) -> usize {
+		self.peer_views.len()
+	}
+
+	/// The number of erasure chunks held in the message vaults of all candidates.
+	fn chunk_count(&self) -> usize {
+		self.per_candidate.values().map(|per_candidate| per_candidate.message_vault.len()).sum()
+	}
+
+	/// Obtain the counts of the chunks received from a peer since it connected.
+	fn on_peer_message_stats(&self, peer: &PeerId) -> Option<PeerMessageStats> {
+		self.peer_message_stats.0.get(peer).copied()
+	}
+
+	/// Obtain all tracked candidates which time out before the given block number,
+	/// which are thus at risk of not becoming available.
+	fn candidates_timing_out_before(&self, block_number: BlockNumber) -> Vec<CandidateHash> {
+		self.per_candidate
+			.iter()
+			.filter(|(_, per_candidate)| per_candidate.time_out_at.map_or(false, |t| t < block_number))
+			.map(|(candidate_hash, _)| *candidate_hash)
+			.collect()
+	}
+
+	/// Assert that all tracked candidates are live in at least one relay parent,
+	/// as candidates are removed along with the last relay parent they are live in.
+	#[cfg(any(test, debug_assertions))]
+	fn assert_no_orphans(&self) {
+		for (candidate_hash, per_candidate) in self.per_candidate.iter() {
+			assert!(
+				!per_candidate.live_in.is_empty(),
+				"Candidate {:?} is tracked, but not live in any relay parent",
+				candidate_hash,
+			);
+		}
+	}
+
+	/// Obtain all tracked candidates whose descriptor's relay parent is not tracked.
+	///
+	/// Candidates which are pending availability for longer than our view reaches back
+	/// appear here as well, so this hints at inconsistencies rather than proving them.
+	fn orphan_candidates(&self) -> Vec<CandidateHash> {
+		self.per_candidate
+			.iter()
+			.filter(|(_, per_candidate)| !self.per_relay_parent.contains_key(&per_candidate.descriptor.relay_parent))
+			.map(|(candidate_hash, _)| *candidate_hash)
+			.collect()
+	}
+}
+
+/// Reconstruct the `AvailableData` of a candidate from the erasure chunks in its message vault.
+///
+/// The size of the data implied by the chunks is checked against `max_pov_size` before
+/// reconstructing, so oversized chunks can not make us allocate arbitrary amounts of memory.
+fn reconstruct_available_data(per_candidate: &PerCandidate, max_pov_size: u32) -> Result<AvailableData> {
+	let n_validators = per_candidate.validators.len();
+	// the number of chunks the data is split into, all others are parity chunks
+	let data_shards = polkadot_erasure_coding::recovery_threshold(n_validators);
+
+	let chunk_len = per_candidate.message_vault
+		.values()
+		.map(|message| message.erasure_chunk.chunk.len())
+		.max()
+		.unwrap_or(0);
+
+	let implied_size = chunk_len.saturating_mul(data_shards);
+	let max_size = max_pov_size as usize + MAX_VALIDATION_DATA_OVERHEAD;
+	if implied_size > max_size {
+		return Err(Error::ReconstructionTooLarge { implied_size, max_size });
+	}
+
+	polkadot_erasure_coding::reconstruct_v1(
+		n_validators,
+		per_candidate.message_vault
+			.values()
+			.map(|message| (&message.erasure_chunk.chunk[..], message.erasure_chunk.index as usize)),
+	).map_err(|e| Error::Reconstruction(e))
+}
+
+/// Returns `true` iff a candidate timing out at `time_out_at` can no longer be included
+/// as of `block_number`.
+fn times_out_by(time_out_at: BlockNumber, block_number: BlockNumber) -> bool {
+	block_number >= time_out_at
+}
+
+/// The number of bytes an erasure chunk occupies, including its proof.
+fn chunk_size(erasure_chunk: &ErasureChunk) -> usize {
+	erasure_chunk.chunk.len() + erasure_chunk.proof.iter().map(|node| node.len()).sum::<usize>()
+}
+
+/// Returns `true` iff an erasure chunk of a candidate with `n_validators` validators could
+/// have been derived from a PoV of at most `max_pov_size` bytes, judging by its length and
+/// the shape of its proof. This is cheap compared to verifying the proof.
+fn chunk_within_limits(erasure_chunk: &ErasureChunk, n_validators: usize, max_pov_size: u32) -> bool {
+	let data_shards = polkadot_erasure_coding::recovery_threshold(n_validators);
+	let max_data_len = max_pov_size as usize + MAX_VALIDATION_DATA_OVERHEAD;
+	// shards are padded to an even length
+	let max_chunk_len = (max_data_len + data_shards - 1) / data_shards + 1;
+
+	// a trie proof holds at most one node per bit of the chunk index, besides the root and the leaf
+	let index_bits = std::mem::size_of::<usize>() * 8 - n_validators.leading_zeros() as usize;
+	let max_proof_nodes = index_bits + 2;
+
+	erasure_chunk.chunk.len() <= max_chunk_len
+		&& erasure_chunk.proof.len() <= max_proof_nodes
+		&& erasure_chunk.proof.iter().all(|node| node.len() <= MAX_PROOF_NODE_SIZE)
+}
+
+/// Reject a chunk received from `origin` which exceeds the size limits of its candidate,
+/// penalizing the peer. Chunks of candidates which are not known yet pass.
+///
+/// Returns `true` iff the chunk was rejected.
+async fn reject_oversized_chunk<Context>(
+	ctx: &mut Context,
+	state: &mut ProtocolState,
+	origin: &PeerId,
+	message: &AvailabilityGossipMessage,
+) -> bool
+where
+	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
+{
+	let n_validators = match state.per_candidate.get(&message.candidate_hash) {
+		Some(per_candidate) => per_candidate.validators.len(),
+		None => return false,
+	};
+	if chunk_within_limits(&message.erasure_chunk, n_validators, state.config.max_pov_size) {
+		return false;
+	}
+
+	tracing::debug!(
+		target: LOG_TARGET,
+		candidate_hash = ?message.candidate_hash,
+		peer = %origin,
+		"Chunk exceeds the size limits",
+	);
+	state.peer_message_stats.note_outcome(origin, MessageOutcome::Rejected);
+	modify_reputation(ctx, &state.peer_views, origin.clone(), state.config.reputation.cost_oversized_chunk.clone()).await;
+	true
+}
+
+/// Deal with network bridge updates and track what needs to be tracked
+/// which depends on the message type received.
+#[tracing::instrument(level = "trace", skip(ctx, keystore, metrics, deferred), fields(subsystem = LOG_TARGET))]
+async fn handle_network_msg<Context>(
+	ctx: &mut Context,
+	keystore: &SyncCryptoStorePtr,
+	state: &mut ProtocolState,
+	metrics: &Metrics,
+	deferred: &mut VecDeque<FromOverseer<AvailabilityDistributionMessage>>,
+	bridge_message: NetworkBridgeEvent<protocol_v1::AvailabilityDistributionMessage>,
+) -> Result<()>
+where
+	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
+{
+	match bridge_message {
+		NetworkBridgeEvent::PeerConnected(peerid, role) => {
+			// insert if none already present
+			state.recently_disconnected.remove(&peerid);
+			if role == ObservedRole::Authority {
+				state.authorities.insert(peerid.clone());
 			} else {
-				tracing::error!(
+				state.authorities.remove(&peerid);
+			}
+			state.peer_message_stats.note_connected(&peerid);
+			state.peer_views.entry(peerid).or_default();
+		}
+		NetworkBridgeEvent::PeerDisconnected(peerid) => {
+			// get rid of superfluous data
+			state.recently_disconnected.insert(peerid.clone(), Instant::now());
+			state.peer_views.remove(&peerid);
+			state.last_view_changes.remove(&peerid);
+			state.authorities.remove(&peerid);
+			state.recently_rejected.retain(|(peer, _), _| peer != &peerid);
+			state.retry_budgets.remove(&peerid);
+			state.request_windows.remove(&peerid);
+			if let Some(stats) = state.on_peer_message_stats(&peerid) {
+				tracing::debug!(
 					target: LOG_TARGET,
-					%chunk_index,
-					?candidate_hash,
-					"Availability store reported that we have the availability data, but we could not retrieve a chunk of it!",
+					peer = %peerid,
+					?stats,
+					"Chunks received from disconnected peer",
 				);
-				continue;
+			}
+			state.peer_message_stats.0.remove(&peerid);
+		}
+		NetworkBridgeEvent::PeerViewChange(peerid, view) => {
+			handle_peer_view_change(ctx, state, peerid, view, metrics).await;
+		}
+		NetworkBridgeEvent::OurViewChange(view) => {
+			handle_our_view_change(ctx, keystore, state, view, metrics, deferred).await?;
+		}
+		NetworkBridgeEvent::PeerMessage(remote, msg) => {
+			// messages of peers which never connected are dropped, as tracking such peers
+			// would leak state nothing cleans up. The peer is reported directly for the
+			// same reason, which `modify_reputation` skips for peers it does not know
+			if !state.peer_views.contains_key(&remote) && !state.recently_disconnected.contains_key(&remote) {
+				tracing::debug!(
+					target: LOG_TARGET,
+					peer = %remote,
+					"Ignoring a message of a peer which did not connect",
+				);
+				ctx.send_message(AllMessages::NetworkBridge(
+					NetworkBridgeMessage::ReportPeer(remote, state.config.reputation.cost_not_connected.clone()),
+				)).await;
+				return Ok(());
+			}
+
+			let chunks = match msg {
+				protocol_v1::AvailabilityDistributionMessage::Chunk(candidate_hash, chunk) => {
+					vec![(candidate_hash, chunk)]
+				}
+				protocol_v1::AvailabilityDistributionMessage::BatchChunks(chunks) => {
+					if chunks.len() > protocol_v1::MAX_BATCHED_CHUNKS {
+						tracing::debug!(
+							target: LOG_TARGET,
+							peer = %remote,
+							chunks = chunks.len(),
+							"Batch exceeds the number of chunks allowed",
+						);
+						state.peer_message_stats.note_outcome(&remote, MessageOutcome::Rejected);
+						modify_reputation(ctx, &state.peer_views, remote, state.config.reputation.cost_oversized_batch.clone()).await;
+						return Ok(());
+					}
+					chunks
+				}
+				protocol_v1::AvailabilityDistributionMessage::RequestChunk(candidate_hash, index) => {
+					handle_chunk_request(ctx, state, remote, candidate_hash, index).await;
+					return Ok(());
+				}
+			};
+
+			for (candidate_hash, chunk) in chunks {
+				let gossiped_availability = AvailabilityGossipMessage {
+					candidate_hash,
+					erasure_chunk: chunk,
+				};
+
+				let mut _span = jaeger::hash_span(&gossiped_availability.candidate_hash.0, "availability-message-received");
+				state.peer_message_stats.note_received(&remote);
+
+				// reject oversized chunks before spending any effort on them
+				if reject_oversized_chunk(ctx, state, &remote, &gossiped_availability).await {
+					continue;
+				}
+
+				process_incoming_peer_message(ctx, state, remote.clone(), gossiped_availability, metrics)
+					.await?;
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Answer a chunk request of a peer with the chunk, if we are ready to serve it.
+/// Peers exceeding their request rate are penalized instead.
+async fn handle_chunk_request<Context>(
+	ctx: &mut Context,
+	state: &mut ProtocolState,
+	peer: PeerId,
+	candidate_hash: CandidateHash,
+	index: ValidatorIndex,
+)
+where
+	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
+{
+	// requests of disconnected peers can not be answered anyway
+	if !state.peer_views.contains_key(&peer) {
+		return;
+	}
+
+	match state.serve_chunk_request(&peer, &candidate_hash, index, Instant::now()) {
+		Ok(Some(erasure_chunk)) => {
+			ctx.send_message(NetworkBridgeMessage::SendValidationMessage(
+				vec![peer],
+				protocol_v1::ValidationProtocol::AvailabilityDistribution(
+					protocol_v1::AvailabilityDistributionMessage::Chunk(candidate_hash, erasure_chunk),
+				),
+			).into()).await;
+		}
+		Ok(None) => {
+			tracing::trace!(
+				target: LOG_TARGET,
+				peer = %peer,
+				?candidate_hash,
+				index,
+				"Requested chunk is not ready to be served",
+			);
+		}
+		Err(rep) => modify_reputation(ctx, &state.peer_views, peer, rep).await,
+	}
+}
+
+/// Handle the changes necessary when our view changes.
+///
+/// Messages arriving while relay parents are fetched are pushed to `deferred`, so
+/// relay parents which left our view in the meantime are not tracked. Once a `Conclude`
+/// signal arrived, no further relay parents are fetched. Gossip among them
+/// is only processed once fetching completed, so chunks of candidates which are about
+/// to become known are not mistaken for chunks of candidates which are not live.
+#[tracing::instrument(level = "trace", skip(ctx, keystore, metrics, deferred), fields(subsystem = LOG_TARGET))]
+async fn handle_our_view_change<Context>(
+	ctx: &mut Context,
+	keystore: &SyncCryptoStorePtr,
+	state: &mut ProtocolState,
+	view: View,
+	metrics: &Metrics,
+	deferred: &mut VecDeque<FromOverseer<AvailabilityDistributionMessage>>,
+) -> Result<()>
+where
+	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
+{
+	let _timer = metrics.time_handle_our_view_change();
+
+	let old_view = std::mem::replace(&mut state.view, view);
+
+	// needed due to borrow rules
+	let view = state.view.clone();
+
+	// add all the relay parents and fill the cache
+	for added in view.difference(&old_view) {
+		// prefetched relay parents are tracked already
+		if state.prefetched.remove(added) {
+			continue;
+		}
+
+		// the relay parents fetched so far are kept, but the remaining ones are not worth
+		// fetching if we are about to conclude
+		if conclude_queued(deferred) {
+			tracing::debug!(
+				target: LOG_TARGET,
+				relay_parent = ?added,
+				"Concluding while fetching relay parents, skipping the remaining ones",
+			);
+			return Ok(());
+		}
+
+		let validators = query_validators(ctx, *added).await?;
+		let validator_index = obtain_our_validator_index(&validators, keystore.clone()).await;
+		let (our_group, group_assignment) = match validator_index {
+			Some(validator_index) if state.config.store_own_group_only => {
+				let (our_group, group_assignment) = query_our_group(ctx, *added, validator_index).await?;
+				(our_group, Some(group_assignment))
+			}
+			_ => (None, None),
+		};
+		let (candidates, ancestors, session_index)
+			= query_live_candidates(
+			ctx,
+			&mut state.live_under,
+			&mut state.ancestor_walk_requests,
+			*added,
+			metrics,
+		).await?;
+
+		if !relay_parent_still_in_view(ctx, state, metrics, deferred, added).await {
+			tracing::debug!(
+				target: LOG_TARGET,
+				relay_parent = ?added,
+				"Relay parent left our view while fetching it",
+			);
+			continue;
+		}
+
+		let validators = state.shared_validators(session_index, validators);
+		let timed_out = state.add_relay_parent(
+			*added,
+			validators,
+			validator_index,
+			candidates,
+			ancestors,
+			metrics,
+		);
+		metrics.on_timed_out_candidates_skipped(timed_out);
+		if let Some(per_relay_parent) = state.per_relay_parent.get_mut(added) {
+			per_relay_parent.our_group = our_group;
+			per_relay_parent.group_assignment = group_assignment;
+			per_relay_parent.session_index = session_index;
+		}
+	}
+
+	// requests issued before are retried first, such that those issued below are not
+	retry_own_chunk_requests(ctx, state, Instant::now()).await;
+
+	// handle all candidates
+	for candidate_hash in state.cached_live_candidates_unioned(view.difference(&old_view)) {
+		// If we are not a validator for this candidate, let's skip it.
+		if state.per_candidate.entry(candidate_hash).or_default().validator_index.is_none() {
+			continue
+		}
+
+		// Chunks of timed out candidates are not worth distributing.
+		if state.is_candidate_timed_out(&candidate_hash) {
+			continue
+		}
+
+		// check if the availability is present in the store exists, else we might still
+		// hold our own chunk
+		let own_chunk_only = if av_store::query_data_availability(ctx, candidate_hash).await? {
+			false
+		} else if state.config.gossip_stored_own_chunk
+			&& query_stored_own_chunk(ctx, state, candidate_hash).await?
+		{
+			true
+		} else {
+			if state.config.request_own_chunks {
+				request_own_chunk(ctx, state, candidate_hash, Instant::now()).await;
+			}
+			continue;
+		};
+
+		// obtain interested peers in the candidate hash
+		let peers: Vec<PeerId> = state
+			.peer_views
+			.clone()
+			.into_iter()
+			.filter(|(_peer, view)| {
+				// direct interests of a peer w/o ancestors
+				state.is_candidate_live_in(&candidate_hash, view.heads.iter())
+			})
+			.map(|(peer, _view)| peer.clone())
+			.collect();
+
+		let per_candidate = state.per_candidate.entry(candidate_hash).or_default();
+
+		let validator_count = per_candidate.validators.len();
+		let chunk_indices = match per_candidate.validator_index {
+			Some(validator_index) if own_chunk_only => validator_index..validator_index + 1,
+			_ => 0u32..(validator_count as u32),
+		};
+
+		// distribute all erasure messages to interested peers
+		for chunk_index in chunk_indices {
+			// failing to obtain a chunk must not keep us from distributing the others
+			let message = match obtain_own_chunk(ctx, per_candidate, candidate_hash, chunk_index).await {
+				Ok(message) => message,
+				Err(e) => {
+					tracing::error!(
+						target: LOG_TARGET,
+						err = ?e,
+						%chunk_index,
+						?candidate_hash,
+						"Failed to obtain a chunk of available data we hold",
+					);
+					metrics.on_own_chunk_generation_failure();
+					continue;
+				}
 			};
 
 			debug_assert_eq!(message.erasure_chunk.index, chunk_index);
@@ -410,51 +2557,628 @@ where
 				.filter(|peer| per_candidate.message_required_by_peer(peer, &chunk_index))
 				.cloned()
 				.collect::<Vec<_>>();
+			let last_view_changes = if state.config.prioritize_recently_interested_peers {
+				Some(&state.last_view_changes)
+			} else {
+				None
+			};
+			let authorities = if state.config.prioritize_authorities {
+				Some(&state.authorities)
+			} else {
+				None
+			};
+			let peers = state.peer_selection_rng.select(
+				peers,
+				state.config.max_gossip_fanout,
+				last_view_changes,
+				authorities,
+			);
+
+			let sent = send_tracked_gossip_messages_to_peers(
+				ctx,
+				per_candidate,
+				&state.config,
+				metrics,
+				peers,
+				iter::once(message),
+				MessagePriority::Normal,
+			).await;
+			metrics.on_chunks_originated(sent);
+		}
+	}
+
+	// cleanup the removed relay parents and their states,
+	// as well as prefetched ones which did not make it into our view
+	std::mem::take(&mut state.prefetched).iter().for_each(|r| state.remove_relay_parent(r, metrics));
+
+	// relay parents which return with a view change queued already are kept as if they were
+	// prefetched, such that their chunks survive
+	let upcoming_view = most_recent_queued_view(ctx, state, metrics, deferred).await;
+	for removed in old_view.difference(&view) {
+		if upcoming_view.as_ref().map_or(false, |upcoming_view| upcoming_view.contains(removed)) {
+			tracing::debug!(
+				target: LOG_TARGET,
+				relay_parent = ?removed,
+				"Keeping relay parent which returns to our view",
+			);
+			state.prefetched.insert(*removed);
+		} else {
+			state.remove_relay_parent(removed, metrics);
+		}
+	}
+	state.clean_up_live_under_cache_if_due(Instant::now());
+	state.clean_up_session_validators_cache();
+	state.clean_up_block_numbers();
+	state.clean_up_recently_forgotten();
+	state.clean_up_recently_disconnected();
+
+	metrics.on_pending_candidates_evicted(state.evict_expired_pending_chunks(Instant::now()));
+	for (origin, message) in state.take_pending_chunks_of_live_candidates() {
+		// the size limits are only known now that the candidate is
+		if reject_oversized_chunk(ctx, state, &origin, &message).await {
+			continue;
+		}
+		process_incoming_peer_message(ctx, state, origin, message, metrics).await?;
+	}
+
+	Ok(())
+}
+
+/// Request our own chunk of a candidate from the next peer to request it from, if any.
+async fn request_own_chunk<Context>(
+	ctx: &mut Context,
+	state: &mut ProtocolState,
+	candidate_hash: CandidateHash,
+	now: Instant,
+)
+where
+	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
+{
+	let validator_index = match state.per_candidate.get(&candidate_hash).and_then(|c| c.validator_index) {
+		Some(validator_index) => validator_index,
+		None => return,
+	};
+
+	match state.next_own_chunk_request(candidate_hash, now) {
+		Some(peer) => {
+			ctx.send_message(NetworkBridgeMessage::SendValidationMessage(
+				vec![peer],
+				protocol_v1::ValidationProtocol::AvailabilityDistribution(
+					protocol_v1::AvailabilityDistributionMessage::RequestChunk(candidate_hash, validator_index),
+				),
+			).into()).await;
+		}
+		None => {
+			tracing::trace!(
+				target: LOG_TARGET,
+				?candidate_hash,
+				"No peer left to request our own chunk from",
+			);
+		}
+	}
+}
+
+/// Request our own chunks anew from other peers if the requests timed out, noting them as
+/// unanswered by the peers they were sent to. Requests which found no peer are retried.
+async fn retry_own_chunk_requests<Context>(
+	ctx: &mut Context,
+	state: &mut ProtocolState,
+	now: Instant,
+)
+where
+	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
+{
+	// requests of candidates which are gone or whose chunk we hold meanwhile are done
+	let per_candidate = &state.per_candidate;
+	state.own_chunk_requests.retain(|candidate_hash, _| per_candidate
+		.get(candidate_hash)
+		.and_then(|per_candidate| per_candidate.validator_index
+			.map(|index| !per_candidate.message_vault.contains_key(&index)))
+		.unwrap_or(false));
+
+	let timeout = state.config.chunk_request_timeout;
+	let due: Vec<_> = state.own_chunk_requests
+		.iter()
+		.filter(|(_, request)| request.requested_at
+			.map_or(true, |requested_at| now.saturating_duration_since(requested_at) >= timeout))
+		.map(|(candidate_hash, request)| (*candidate_hash, request.peer.clone()))
+		.collect();
+
+	for (candidate_hash, peer) in due {
+		// disconnected peers are forgotten, so their budget is not tracked anymore
+		if let Some(peer) = peer.filter(|peer| state.peer_views.contains_key(peer)) {
+			state.note_unanswered_chunk_request(&peer, now);
+		}
+		request_own_chunk(ctx, state, candidate_hash, now).await;
+	}
+}
+
+/// Store our own chunks of candidates which became reconstructable. Many candidates may do so
+/// at once, so at most `max_outstanding_chunk_stores` stores are awaiting their result at a time.
+async fn store_backfilled_own_chunks<Context>(
+	ctx: &mut Context,
+	state: &mut ProtocolState,
+	own_chunks: Vec<(CandidateHash, Hash, ValidatorIndex, ErasureChunk)>,
+)
+where
+	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
+{
+	if own_chunks.is_empty() {
+		return;
+	}
+
+	let stores: Vec<_> = own_chunks
+		.iter()
+		.map(|(candidate_hash, _, index, _)| (*candidate_hash, *index))
+		.collect();
+	let results = match av_store::store_chunks(ctx, own_chunks, state.config.max_outstanding_chunk_stores).await {
+		Ok(results) => results,
+		Err(e) => {
+			tracing::warn!(
+				target: LOG_TARGET,
+				err = ?e,
+				"Failed to store backfilled erasure chunks to availability store",
+			);
+			vec![Err(()); stores.len()]
+		}
+	};
+
+	for ((candidate_hash, index), result) in stores.into_iter().zip(results) {
+		if result.is_ok() {
+			state.event_sink.emit(AvailabilityEvent::ChunkStored { candidate_hash, index });
+		} else {
+			tracing::warn!(
+				target: LOG_TARGET,
+				?candidate_hash,
+				"Failed to store backfilled erasure chunk to availability store",
+			);
+			// allow a later copy of the chunk to retry the store
+			if let Some(per_candidate) = state.per_candidate.get_mut(&candidate_hash) {
+				per_candidate.pending_stores.remove(&index);
+			}
+		}
+	}
+}
+
+/// Query the availability store for our own chunk of a candidate, holding it in the message
+/// vault if it verifies. Returns `true` iff our own chunk is held afterwards.
+async fn query_stored_own_chunk<Context>(
+	ctx: &mut Context,
+	state: &mut ProtocolState,
+	candidate_hash: CandidateHash,
+) -> Result<bool>
+where
+	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
+{
+	let validator_index = match state.per_candidate.get(&candidate_hash).and_then(|c| c.validator_index) {
+		Some(validator_index) => validator_index,
+		None => return Ok(false),
+	};
+
+	let erasure_chunk = match av_store::query_chunk(ctx, candidate_hash, validator_index).await? {
+		Some(erasure_chunk) => erasure_chunk,
+		None => return Ok(false),
+	};
+
+	let per_candidate = state.per_candidate
+		.get_mut(&candidate_hash)
+		.expect("Candidate was obtained from per_candidate; qed");
+	let erasure_root = per_candidate.descriptor.erasure_root;
+	if !per_candidate.verify_chunk_cached(&erasure_root, &erasure_chunk) {
+		tracing::warn!(
+			target: LOG_TARGET,
+			?candidate_hash,
+			%validator_index,
+			"Chunk held by the availability store does not match the erasure root",
+		);
+		return Ok(false);
+	}
+
+	tracing::trace!(
+		target: LOG_TARGET,
+		?candidate_hash,
+		%validator_index,
+		"Retrieved own chunk from availability storage",
+	);
+	per_candidate.insert_chunk(AvailabilityGossipMessage { candidate_hash, erasure_chunk }, true);
+	// the chunk is stored already, so copies received from peers need not be stored again
+	per_candidate.pending_stores.insert(validator_index);
+	Ok(true)
+}
+
+/// Obtain a chunk of a candidate whose available data we hold, from the message vault
+/// or else from the availability store.
+async fn obtain_own_chunk<Context>(
+	ctx: &mut Context,
+	per_candidate: &PerCandidate,
+	candidate_hash: CandidateHash,
+	chunk_index: ValidatorIndex,
+) -> Result<AvailabilityGossipMessage>
+where
+	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
+{
+	if let Some(message) = per_candidate.servable_chunk(&chunk_index) {
+		tracing::trace!(
+			target: LOG_TARGET,
+			%chunk_index,
+			?candidate_hash,
+			"Retrieved chunk from message vault",
+		);
+		return Ok(message.clone());
+	}
+
+	let erasure_chunk = av_store::query_chunk(ctx, candidate_hash, chunk_index)
+		.await?
+		.ok_or(Error::MissingChunk(candidate_hash, chunk_index))?;
+
+	tracing::trace!(
+		target: LOG_TARGET,
+		%chunk_index,
+		?candidate_hash,
+		"Retrieved chunk from availability storage",
+	);
+
+	Ok(AvailabilityGossipMessage {
+		candidate_hash,
+		erasure_chunk,
+	})
+}
+
+/// Gossip all chunks we hold anew to the interested peers which are not known to have them.
+///
+/// The fanout limit and the rebroadcast cooldown apply as when relaying chunks.
+#[tracing::instrument(level = "trace", skip(ctx, metrics), fields(subsystem = LOG_TARGET))]
+async fn handle_regossip_all<Context>(
+	ctx: &mut Context,
+	state: &mut ProtocolState,
+	metrics: &Metrics,
+)
+where
+	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
+{
+	let mut sent = 0;
+	let candidates = state.per_candidate.keys().cloned().collect::<Vec<_>>();
+	for candidate_hash in candidates {
+		// there is no point in gossiping chunks of a candidate that timed out
+		if state.is_candidate_timed_out(&candidate_hash) {
+			continue;
+		}
+
+		let peers = state
+			.peer_views
+			.iter()
+			.filter(|(_, view)| state.is_candidate_live_in(&candidate_hash, view.heads.iter()))
+			.map(|(peer, _)| peer.clone())
+			.collect::<Vec<_>>();
+		if peers.is_empty() {
+			continue;
+		}
+
+		let last_view_changes = if state.config.prioritize_recently_interested_peers {
+			Some(&state.last_view_changes)
+		} else {
+			None
+		};
+		let authorities = if state.config.prioritize_authorities {
+			Some(&state.authorities)
+		} else {
+			None
+		};
+		let per_candidate = state.per_candidate
+			.get_mut(&candidate_hash)
+			.expect("Candidate hashes were obtained from per_candidate; qed");
+
+		let mut indices = per_candidate.ready_to_serve.iter().cloned().collect::<Vec<_>>();
+		indices.sort();
+		for index in indices {
+			let message = match per_candidate.servable_chunk(&index) {
+				Some(message) => message.clone(),
+				None => continue,
+			};
+
+			let peers = peers
+				.iter()
+				.filter(|peer| per_candidate.message_required_by_peer(peer, &index))
+				.cloned()
+				.collect::<Vec<_>>();
+			let peers = state.peer_selection_rng.select(
+				peers,
+				state.config.max_gossip_fanout,
+				last_view_changes,
+				authorities,
+			);
+
+			sent += send_tracked_gossip_messages_to_peers(
+				ctx,
+				per_candidate,
+				&state.config,
+				metrics,
+				peers,
+				iter::once(message),
+				MessagePriority::Low,
+			).await;
+		}
+	}
+	metrics.on_chunks_relayed(sent);
+}
+
+/// Fetch the live candidates of a relay parent before it enters our view,
+/// such that their chunks can be distributed right away once it does.
+#[tracing::instrument(level = "trace", skip(ctx, keystore, metrics), fields(subsystem = LOG_TARGET))]
+async fn handle_prefetch<Context>(
+	ctx: &mut Context,
+	keystore: &SyncCryptoStorePtr,
+	state: &mut ProtocolState,
+	relay_parent: Hash,
+	metrics: &Metrics,
+) -> Result<()>
+where
+	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
+{
+	if state.per_relay_parent.contains_key(&relay_parent) {
+		return Ok(());
+	}
+
+	let validators = query_validators(ctx, relay_parent).await?;
+	let validator_index = obtain_our_validator_index(&validators, keystore.clone()).await;
+	let (our_group, group_assignment) = match validator_index {
+		Some(validator_index) if state.config.store_own_group_only => {
+			let (our_group, group_assignment) = query_our_group(ctx, relay_parent, validator_index).await?;
+			(our_group, Some(group_assignment))
+		}
+		_ => (None, None),
+	};
+	let (candidates, ancestors, session_index)
+		= query_live_candidates(
+		ctx,
+		&mut state.live_under,
+		&mut state.ancestor_walk_requests,
+		relay_parent,
+		metrics,
+	).await?;
+
+	let validators = state.shared_validators(session_index, validators);
+	let timed_out = state.add_relay_parent(
+		relay_parent,
+		validators,
+		validator_index,
+		candidates,
+		ancestors,
+		metrics,
+	);
+	metrics.on_timed_out_candidates_skipped(timed_out);
+	if let Some(per_relay_parent) = state.per_relay_parent.get_mut(&relay_parent) {
+		per_relay_parent.our_group = our_group;
+		per_relay_parent.group_assignment = group_assignment;
+		per_relay_parent.session_index = session_index;
+	}
+	state.prefetched.insert(relay_parent);
+
+	Ok(())
+}
 
-			send_tracked_gossip_messages_to_peers(ctx, per_candidate, metrics, peers, iter::once(message)).await;
+/// Collect all messages which arrived in the meantime into `deferred` and check whether
+/// the most recent view among them still contains the `relay_parent`.
+async fn relay_parent_still_in_view<Context>(
+	ctx: &mut Context,
+	state: &ProtocolState,
+	metrics: &Metrics,
+	deferred: &mut VecDeque<FromOverseer<AvailabilityDistributionMessage>>,
+	relay_parent: &Hash,
+) -> bool
+where
+	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
+{
+	most_recent_queued_view(ctx, state, metrics, deferred)
+		.await
+		.map_or(true, |view| view.contains(relay_parent))
+}
+
+/// Collect all messages which arrived in the meantime into `deferred` and return the most
+/// recent view among them, if any.
+///
+/// At most `max_deferred_messages` messages are collected per call. Messages of peers are
+/// dropped once `max_deferred_messages` are queued. The peers are not penalized for it, as the
+/// backlog is ours. A queued view of a peer is dropped once the peer changes its view again,
+/// penalizing the peer for flooding us with views.
+async fn most_recent_queued_view<Context>(
+	ctx: &mut Context,
+	state: &ProtocolState,
+	metrics: &Metrics,
+	deferred: &mut VecDeque<FromOverseer<AvailabilityDistributionMessage>>,
+) -> Option<View>
+where
+	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
+{
+	for _ in 0..state.config.max_deferred_messages.max(1) {
+		let message = match ctx.try_recv().await {
+			Ok(Some(message)) => message,
+			_ => break,
+		};
+		if deferred.len() >= state.config.max_deferred_messages {
+			if let FromOverseer::Communication {
+				msg: AvailabilityDistributionMessage::NetworkBridgeUpdateV1(
+					NetworkBridgeEvent::PeerMessage(peer, _),
+				),
+			} = message {
+				tracing::debug!(
+					target: LOG_TARGET,
+					peer = %peer,
+					queued = deferred.len(),
+					"Dropping message of peer as too many messages are queued",
+				);
+				metrics.on_message_shed();
+				continue;
+			}
+		}
+		// only the most recent view of a peer is of interest
+		if let FromOverseer::Communication {
+			msg: AvailabilityDistributionMessage::NetworkBridgeUpdateV1(
+				NetworkBridgeEvent::PeerViewChange(ref peer, _),
+			),
+		} = message {
+			let superseded = deferred.iter().position(|queued| matches!(
+				queued,
+				FromOverseer::Communication {
+					msg: AvailabilityDistributionMessage::NetworkBridgeUpdateV1(
+						NetworkBridgeEvent::PeerViewChange(queued_peer, _),
+					),
+				} if queued_peer == peer
+			));
+			if let Some(superseded) = superseded {
+				tracing::debug!(
+					target: LOG_TARGET,
+					peer = %peer,
+					"Dropping view of peer superseded by a more recent one",
+				);
+				deferred.remove(superseded);
+				metrics.on_message_shed();
+				let cost = state.config.reputation.cost_view_superseded.clone();
+				modify_reputation(ctx, &state.peer_views, peer.clone(), cost).await;
+			}
+		}
+		deferred.push_back(message);
+	}
+
+	deferred
+		.iter()
+		.rev()
+		.find_map(|message| match message {
+			FromOverseer::Communication {
+				msg: AvailabilityDistributionMessage::NetworkBridgeUpdateV1(
+					NetworkBridgeEvent::OurViewChange(view),
+				),
+			} => Some(view.clone()),
+			_ => None,
+		})
+}
+
+/// Returns `true` iff a `Conclude` signal is among the messages which arrived in the meantime.
+fn conclude_queued(deferred: &VecDeque<FromOverseer<AvailabilityDistributionMessage>>) -> bool {
+	deferred.iter().any(|message| matches!(message, FromOverseer::Signal(OverseerSignal::Conclude)))
+}
+
+/// Send messages to the peers which did not receive them yet, returning the number of
+/// messages which were sent.
+///
+/// Chunks originating from us should be sent with `MessagePriority::Normal`, while relayed
+/// chunks use `MessagePriority::Low` to not starve latency-critical protocols.
+#[tracing::instrument(level = "trace", skip(ctx, metrics, message_iter), fields(subsystem = LOG_TARGET))]
+async fn send_tracked_gossip_messages_to_peers<Context>(
+	ctx: &mut Context,
+	per_candidate: &mut PerCandidate,
+	config: &Config,
+	metrics: &Metrics,
+	peers: Vec<PeerId>,
+	message_iter: impl IntoIterator<Item = AvailabilityGossipMessage>,
+	priority: MessagePriority,
+) -> usize
+where
+	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
+{
+	let mut sent = 0;
+	for (peers, message) in track_gossip_messages(per_candidate, config, &peers, message_iter) {
+		if !peers.is_empty() {
+			ctx.send_message(NetworkBridgeMessage::SendPrioritizedValidationMessage(
+				peers,
+				protocol_v1::ValidationProtocol::AvailabilityDistribution(message.into()),
+				priority,
+			).into()).await;
+
+			metrics.on_chunk_distributed();
+			sent += 1;
 		}
 	}
+	sent
+}
+
+/// Send several messages to a single peer, batched into as few network messages as allowed
+/// if `batched`, one network message per chunk otherwise.
+///
+/// These are replayed to catch the peer up with its view, so they are sent with low priority.
+#[tracing::instrument(level = "trace", skip(ctx, metrics, messages), fields(subsystem = LOG_TARGET))]
+async fn send_batched_gossip_messages_to_peer<Context>(
+	ctx: &mut Context,
+	metrics: &Metrics,
+	peer: PeerId,
+	messages: Vec<AvailabilityGossipMessage>,
+	batched: bool,
+)
+where
+	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
+{
+	let batch_size = if batched { protocol_v1::MAX_BATCHED_CHUNKS } else { 1 };
+
+	for batch in messages.chunks(batch_size) {
+		for _ in batch {
+			metrics.on_chunk_distributed();
+		}
 
-	// cleanup the removed relay parents and their states
-	old_view.difference(&view).for_each(|r| state.remove_relay_parent(r));
-	state.clean_up_live_under_cache();
+		let message = if batch.len() == 1 {
+			batch[0].clone().into()
+		} else {
+			protocol_v1::AvailabilityDistributionMessage::BatchChunks(
+				batch
+					.iter()
+					.map(|message| (message.candidate_hash, message.erasure_chunk.clone()))
+					.collect()
+			)
+		};
 
-	Ok(())
+		ctx.send_message(NetworkBridgeMessage::SendPrioritizedValidationMessage(
+			vec![peer.clone()],
+			protocol_v1::ValidationProtocol::AvailabilityDistribution(message),
+			MessagePriority::Low,
+		).into()).await;
+	}
 }
 
-#[tracing::instrument(level = "trace", skip(ctx, metrics, message_iter), fields(subsystem = LOG_TARGET))]
-async fn send_tracked_gossip_messages_to_peers<Context>(
-	ctx: &mut Context,
+/// Track the given messages as sent to all of the `peers` which did not receive them
+/// just recently and put them into the message vault.
+///
+/// Returns each message along with the peers it needs to be sent to.
+fn track_gossip_messages(
 	per_candidate: &mut PerCandidate,
-	metrics: &Metrics,
-	peers: Vec<PeerId>,
+	config: &Config,
+	peers: &[PeerId],
 	message_iter: impl IntoIterator<Item = AvailabilityGossipMessage>,
-)
-where
-	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
-{
+) -> Vec<(Vec<PeerId>, AvailabilityGossipMessage)> {
+	let mut tracked = Vec::new();
 	for message in message_iter {
+		let now = Instant::now();
+		let erasure_chunk_index = message.erasure_chunk.index;
+
+		// skip peers we sent this chunk to just recently
+		let peers = peers
+			.iter()
+			.filter(|peer| {
+				per_candidate
+					.last_sent
+					.get(&((*peer).clone(), erasure_chunk_index))
+					.map_or(true, |sent| now.duration_since(*sent) >= config.rebroadcast_cooldown)
+			})
+			.cloned()
+			.collect::<Vec<_>>();
+
 		for peer in peers.iter() {
 			per_candidate
 				.sent_messages
 				.entry(peer.clone())
 				.or_default()
-				.insert(message.erasure_chunk.index);
+				.insert(erasure_chunk_index);
+			per_candidate.last_sent.insert((peer.clone(), erasure_chunk_index), now);
 		}
 
-		per_candidate
-			.message_vault
-			.insert(message.erasure_chunk.index, message.clone());
-
-		if !peers.is_empty() {
-			ctx.send_message(NetworkBridgeMessage::SendValidationMessage(
-				peers.clone(),
-				protocol_v1::ValidationProtocol::AvailabilityDistribution(message.into()),
-			).into()).await;
-
-			metrics.on_chunk_distributed();
+		// only chunks of the store or verified ones are gossiped
+		per_candidate.insert_chunk(message.clone(), true);
+		// chunks received from peers were noted on arrival, so any others are our own
+		if config.record_chunk_sources {
+			per_candidate.note_chunk_source(erasure_chunk_index, ChunkSource::Local);
 		}
+
+		tracked.push((peers, message));
 	}
+	tracked
 }
 
 // Send the difference between two views which were not sent
@@ -464,12 +3188,31 @@ async fn handle_peer_view_change<Context>(
 	ctx: &mut Context,
 	state: &mut ProtocolState,
 	origin: PeerId,
-	view: View,
+	mut view: View,
 	metrics: &Metrics,
 )
 where
 	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
 {
+	if view.heads.len() > state.config.max_peer_view_heads {
+		tracing::warn!(
+			target: LOG_TARGET,
+			peer = %origin,
+			heads = view.heads.len(),
+			"Peer announced an implausibly large view",
+		);
+		metrics.on_oversized_peer_view();
+
+		if state.config.cap_peer_views {
+			view.heads.truncate(state.config.max_peer_view_heads);
+		}
+	}
+
+	// only connected peers are tracked, as nothing would clean up after the others
+	if state.peer_views.contains_key(&origin) {
+		state.last_view_changes.insert(origin.clone(), Instant::now());
+	}
+
 	let current = state.peer_views.entry(origin.clone()).or_default();
 
 	let added: Vec<Hash> = view.difference(&*current).cloned().collect();
@@ -480,8 +3223,14 @@ where
 	// the union of all relay parent's candidates.
 	let added_candidates = state.cached_live_candidates_unioned(added.iter());
 
-	// Send all messages we've seen before and the peer is now interested in.
+	// All messages we've seen before and the peer is now interested in,
+	// which are sent together, batched if enabled.
+	let mut batch = Vec::new();
 	for candidate_hash in added_candidates {
+		if state.is_candidate_timed_out(&candidate_hash) {
+			continue
+		}
+
 		let per_candidate = state.per_candidate.entry(candidate_hash).or_default();
 
 		// obtain the relevant chunk indices not sent yet
@@ -491,35 +3240,88 @@ where
 				// try to pick up the message from the message vault
 				// so we send as much as we have
 				per_candidate
-					.message_vault
-					.get(&erasure_chunk_index)
+					.servable_chunk(&erasure_chunk_index)
 					.filter(|_| per_candidate.message_required_by_peer(&origin, &erasure_chunk_index))
 			})
 			.cloned()
 			.collect::<HashSet<_>>();
 
-		send_tracked_gossip_messages_to_peers(ctx, per_candidate, metrics, vec![origin.clone()], messages).await;
+		batch.extend(
+			track_gossip_messages(per_candidate, &state.config, &[origin.clone()], messages)
+				.into_iter()
+				.filter(|(peers, _)| !peers.is_empty())
+				.map(|(_, message)| message)
+		);
 	}
+
+	let batched = state.config.send_batched_chunks;
+	send_batched_gossip_messages_to_peer(ctx, metrics, origin, batch, batched).await;
 }
 
-/// Obtain the first key which has a signing key.
-/// Returns the index within the validator set as `ValidatorIndex`, if there exists one,
-/// otherwise, `None` is returned.
+/// Obtain our index within the validator set.
+///
+/// The keystore may hold several parachain keys, so its keys are intersected with `validators`
+/// rather than picking whichever key comes first. Should more than one key be part of the
+/// validator set, the lowest index wins. Returns `None` if none of our keys is a validator.
 async fn obtain_our_validator_index(
 	validators: &[ValidatorId],
 	keystore: SyncCryptoStorePtr,
 ) -> Option<ValidatorIndex> {
-	for (idx, validator) in validators.iter().enumerate() {
-		if CryptoStore::has_keys(
-			&*keystore,
-			&[(validator.to_raw_vec(), PARACHAIN_KEY_TYPE_ID)],
-		)
+	let our_keys: Vec<ValidatorId> = CryptoStore::sr25519_public_keys(&*keystore, PARACHAIN_KEY_TYPE_ID)
 		.await
-		{
-			return Some(idx as ValidatorIndex);
+		.into_iter()
+		.map(ValidatorId::from)
+		.collect();
+
+	let mut matching = validators
+		.iter()
+		.enumerate()
+		.filter(|(_, validator)| our_keys.contains(validator))
+		.map(|(idx, _)| idx as ValidatorIndex);
+
+	let our_index = matching.next();
+	match our_index {
+		Some(our_index) => {
+			let others: Vec<ValidatorIndex> = matching.collect();
+			if !others.is_empty() {
+				tracing::warn!(
+					target: LOG_TARGET,
+					our_index,
+					?others,
+					"Keystore holds keys of multiple validators, using the lowest index",
+				);
+			}
+		}
+		None if !our_keys.is_empty() => {
+			tracing::debug!(
+				target: LOG_TARGET,
+				keys = our_keys.len(),
+				"None of the parachain keys in our keystore are part of the validator set",
+			);
+		}
+		None => {}
+	}
+
+	our_index
+}
+
+/// Verify the merkle proof of an erasure chunk against the erasure root of its candidate.
+///
+/// The claimed index of the chunk is the key the branch is looked up by, so a chunk only
+/// verifies if the proof leads to it at exactly that position. A chunk and proof which are
+/// valid at another position fail, as does a proof lacking the path to the claimed index.
+fn verify_chunk(erasure_root: &Hash, erasure_chunk: &ErasureChunk) -> bool {
+	match branch_hash(erasure_root, &erasure_chunk.proof, erasure_chunk.index as usize) {
+		Ok(anticipated_hash) => anticipated_hash == BlakeTwo256::hash(&erasure_chunk.chunk),
+		Err(e) => {
+			tracing::trace!(
+				target: LOG_TARGET,
+				error = ?e,
+				"Failed to calculate chunk merkle proof",
+			);
+			false
 		}
 	}
-	None
 }
 
 /// Handle an incoming message from a peer.
@@ -536,11 +3338,8 @@ where
 {
 	let _timer = metrics.time_process_incoming_peer_message();
 
-	// obtain the set of candidates we are interested in based on our current view
-	let live_candidates = state.cached_live_candidates_unioned(state.view.heads.iter());
-
 	// check if the candidate is of interest
-	let descriptor = if live_candidates.contains(&message.candidate_hash) {
+	let mut descriptor = if state.is_candidate_live(&message.candidate_hash) {
 		state.per_candidate
 			.get(&message.candidate_hash)
 			.expect("All live candidates are contained in per_candidate; qed")
@@ -553,86 +3352,275 @@ where
 			peer = %origin,
 			"Peer send not live candidate",
 		);
-		modify_reputation(ctx, origin, COST_NOT_A_LIVE_CANDIDATE).await;
+		// the candidate might just not be known to us yet
+		if state.config.defer_unknown_candidates
+			&& !state.is_candidate_recently_forgotten(&message.candidate_hash)
+		{
+			let evicted = state.defer_chunk(origin, message, Instant::now());
+			metrics.on_pending_candidates_evicted(evicted);
+			return Ok(())
+		}
+
+		state.peer_message_stats.note_outcome(&origin, MessageOutcome::Rejected);
+
+		// a peer sending chunks of the same candidate over and over is penalized once more,
+		// after which its chunks of the candidate are dropped right away
+		let cost = match state.note_rejected_candidate(&origin, message.candidate_hash, Instant::now()) {
+			0 => None,
+			1 => Some(state.config.reputation.cost_repeated_not_a_live_candidate.clone()),
+			_ => {
+				tracing::trace!(
+					target: LOG_TARGET,
+					candidate_hash = ?message.candidate_hash,
+					peer = %origin,
+					"Dropping repeated chunk of a candidate which is not live",
+				);
+				return Ok(())
+			}
+		};
+
+		// the peer might just lag behind
+		let cost = cost.unwrap_or_else(|| if state.is_candidate_recently_forgotten(&message.candidate_hash) {
+			state.config.reputation.cost_forgotten_candidate.clone()
+		} else {
+			state.config.reputation.cost_not_a_live_candidate.clone()
+		});
+		modify_reputation(ctx, &state.peer_views, origin, cost).await;
 		return Ok(())
 	};
 
-	// check the merkle proof against the erasure root in the candidate descriptor.
-	let anticipated_hash = match branch_hash(
-		&descriptor.erasure_root,
-		&message.erasure_chunk.proof,
-		message.erasure_chunk.index as usize,
-	) {
-		Ok(hash) => hash,
-		Err(e) => {
-			tracing::trace!(
+	// check the merkle proof against the erasure root in the candidate descriptor,
+	// which might be stale, so it is fetched anew before blaming the peer.
+	let mut verified = state.per_candidate
+		.get_mut(&message.candidate_hash)
+		.expect("All live candidates are contained in per_candidate; qed")
+		.verify_chunk_cached(&descriptor.erasure_root, &message.erasure_chunk);
+
+	if !verified {
+		let fresh_descriptor = match query_fresh_descriptor(
+			ctx,
+			&state.live_under,
+			message.candidate_hash,
+			&descriptor,
+			metrics,
+		).await {
+			Ok(fresh_descriptor) => fresh_descriptor,
+			Err(e) => {
+				tracing::debug!(
+					target: LOG_TARGET,
+					err = ?e,
+					candidate_hash = ?message.candidate_hash,
+					"Failed to fetch the candidate descriptor anew",
+				);
+				None
+			}
+		};
+
+		if let Some(fresh_descriptor) = fresh_descriptor {
+			tracing::debug!(
 				target: LOG_TARGET,
 				candidate_hash = ?message.candidate_hash,
-				peer = %origin,
-				error = ?e,
-				"Failed to calculate chunk merkle proof",
+				"Replacing stale candidate descriptor",
 			);
-			modify_reputation(ctx, origin, COST_MERKLE_PROOF_INVALID).await;
-			return Ok(());
-		},
-	};
+			let per_candidate = state.per_candidate
+				.get_mut(&message.candidate_hash)
+				.expect("All live candidates are contained in per_candidate; qed");
+			per_candidate.replace_descriptor(fresh_descriptor.clone());
+			verified = per_candidate.verify_chunk_cached(&fresh_descriptor.erasure_root, &message.erasure_chunk);
+			descriptor = fresh_descriptor;
+		}
+	}
 
-	let erasure_chunk_hash = BlakeTwo256::hash(&message.erasure_chunk.chunk);
-	if anticipated_hash != erasure_chunk_hash {
+	if !verified {
 		tracing::trace!(
 			target: LOG_TARGET,
 			candidate_hash = ?message.candidate_hash,
 			peer = %origin,
 			"Peer send chunk with invalid merkle proof",
 		);
-		modify_reputation(ctx, origin, COST_MERKLE_PROOF_INVALID).await;
+		state.peer_message_stats.note_outcome(&origin, MessageOutcome::Rejected);
+		modify_reputation(ctx, &state.peer_views, origin, state.config.reputation.cost_merkle_proof_invalid.clone()).await;
+		return Ok(());
+	}
+
+	// candidates are live by occupying a core, so one of a para without a core is suspicious
+	if !state.is_para_known(&descriptor.para_id) {
+		tracing::debug!(
+			target: LOG_TARGET,
+			candidate_hash = ?message.candidate_hash,
+			para_id = ?descriptor.para_id,
+			peer = %origin,
+			"Peer sent chunk of a candidate of a para without a core",
+		);
+		state.peer_message_stats.note_outcome(&origin, MessageOutcome::Rejected);
+		modify_reputation(ctx, &state.peer_views, origin, state.config.reputation.cost_unknown_para.clone()).await;
 		return Ok(());
 	}
 
 	let erasure_chunk_index = &message.erasure_chunk.index;
+	let timed_out = state.is_candidate_timed_out(&message.candidate_hash);
+
+	// chunks of candidates of other groups are only relayed under this policy
+	let store_own_chunk = !state.config.store_own_group_only
+		|| state.is_backed_by_our_group(&message.candidate_hash);
+	let verify_chunk_stores = state.config.verify_chunk_stores;
 
 	{
+		let reputation = &state.config.reputation;
 		let per_candidate = state.per_candidate.entry(message.candidate_hash).or_default();
 
 		// check if this particular erasure chunk was already sent by that peer before
-		{
-			let received_set = per_candidate
-				.received_messages
-				.entry(origin.clone())
-				.or_default();
-			if !received_set.insert(*erasure_chunk_index) {
-				modify_reputation(ctx, origin, COST_PEER_DUPLICATE_MESSAGE).await;
-				return Ok(());
+		let known_to_peer = per_candidate
+			.received_messages
+			.get(&origin)
+			.map_or(false, |received| received.contains(erasure_chunk_index));
+		if known_to_peer {
+			state.peer_message_stats.note_outcome(&origin, MessageOutcome::Duplicate);
+			modify_reputation(ctx, &state.peer_views, origin, reputation.cost_peer_duplicate_message.clone()).await;
+			return Ok(());
+		}
+
+		// two different chunks of the same index can not both match the erasure root
+		if per_candidate.contradicts_verified_chunk(&message) {
+			tracing::warn!(
+				target: LOG_TARGET,
+				candidate_hash = ?message.candidate_hash,
+				peer = %origin,
+				index = %erasure_chunk_index,
+				"Peer sent a chunk contradicting a verified chunk of the same index",
+			);
+			state.peer_message_stats.note_outcome(&origin, MessageOutcome::Rejected);
+			modify_reputation(ctx, &state.peer_views, origin, reputation.cost_chunk_equivocation.clone()).await;
+			return Ok(());
+		}
+	}
+
+	if !state.make_room_for_chunk(&message, metrics) {
+		tracing::debug!(
+			target: LOG_TARGET,
+			candidate_hash = ?message.candidate_hash,
+			peer = %origin,
+			"Dropping chunk exceeding the buffer limit",
+		);
+		state.peer_message_stats.note_outcome(&origin, MessageOutcome::Rejected);
+		metrics.on_chunk_rejected();
+		return Ok(());
+	}
+
+	// our own chunk answers a request of it, replenishing the budget of the requested peer
+	let own_chunk = state.per_candidate
+		.get(&message.candidate_hash)
+		.map_or(false, |per_candidate| per_candidate.validator_index == Some(*erasure_chunk_index));
+	if own_chunk {
+		if let Some(request) = state.own_chunk_requests.remove(&message.candidate_hash) {
+			if request.peer.as_ref() == Some(&origin) {
+				state.note_answered_chunk_request(&origin);
 			}
 		}
+	}
 
-		// insert into known messages and change reputation
-		if per_candidate
-			.message_vault
-			.insert(*erasure_chunk_index, message.clone())
-			.is_some()
-		{
-			modify_reputation(ctx, origin, BENEFIT_VALID_MESSAGE).await;
+	{
+		let reputation = &state.config.reputation;
+		let per_candidate = state.per_candidate.entry(message.candidate_hash).or_default();
+
+		// only accepted chunks are considered held by the peer, others may still be sent to it
+		per_candidate
+			.received_messages
+			.entry(origin.clone())
+			.or_default()
+			.insert(*erasure_chunk_index);
+
+		if state.config.record_chunk_sources {
+			per_candidate.note_chunk_source(*erasure_chunk_index, ChunkSource::Peer(origin.clone()));
+		}
+
+		state.peer_message_stats.note_outcome(&origin, MessageOutcome::Valid);
+
+		// insert into known messages and change reputation, as long as the peer was not
+		// rewarded for too many chunks of the candidate already
+		let known = per_candidate.insert_chunk(message.clone(), true);
+		let rewarded = reputation.max_rewarded_chunks_per_candidate.map_or(true, |max_rewarded| {
+			per_candidate.received_messages.get(&origin).map_or(0, |received| received.len()) <= max_rewarded
+		});
+		if !rewarded {
+			tracing::trace!(
+				target: LOG_TARGET,
+				candidate_hash = ?message.candidate_hash,
+				peer = %origin,
+				"Peer was rewarded for enough chunks of the candidate",
+			);
+		} else if known {
+			modify_reputation(ctx, &state.peer_views, origin, reputation.benefit_valid_message.clone()).await;
 		} else {
-			modify_reputation(ctx, origin, BENEFIT_VALID_MESSAGE_FIRST).await;
+			modify_reputation(ctx, &state.peer_views, origin, reputation.benefit_valid_message_first.clone()).await;
+		}
 
-			// save the chunk for our index
-			if Some(*erasure_chunk_index) == per_candidate.validator_index {
-				if store_chunk(
+		// save the chunk for our index, unless a store for it was issued already
+		if Some(*erasure_chunk_index) == per_candidate.validator_index
+			&& store_own_chunk
+			&& per_candidate.pending_stores.insert(*erasure_chunk_index)
+		{
+			let mut stored = av_store::store_chunk(
+				ctx,
+				message.candidate_hash,
+				descriptor.relay_parent,
+				message.erasure_chunk.index,
+				message.erasure_chunk.clone(),
+			).await?;
+
+			if stored.is_ok()
+				&& verify_chunk_stores
+				&& av_store::query_chunk(ctx, message.candidate_hash, *erasure_chunk_index).await?.is_none()
+			{
+				tracing::warn!(
+					target: LOG_TARGET,
+					candidate_hash = ?message.candidate_hash,
+					"Availability store acknowledged an erasure chunk it does not hold, storing it again",
+				);
+				metrics.on_unpersisted_chunk_store();
+				stored = av_store::store_chunk(
 					ctx,
 					message.candidate_hash,
 					descriptor.relay_parent,
 					message.erasure_chunk.index,
 					message.erasure_chunk.clone(),
-				).await?.is_err() {
-					tracing::warn!(
-						target: LOG_TARGET,
-						"Failed to store erasure chunk to availability store"
-					);
-				}
+				).await?;
 			}
-		};
+
+			if stored.is_err() {
+				tracing::warn!(
+					target: LOG_TARGET,
+					"Failed to store erasure chunk to availability store"
+				);
+				// allow a later copy of the chunk to retry the store
+				per_candidate.pending_stores.remove(erasure_chunk_index);
+			} else {
+				state.event_sink.emit(AvailabilityEvent::ChunkStored {
+					candidate_hash: message.candidate_hash,
+					index: *erasure_chunk_index,
+				});
+			}
+		}
+	}
+	// there is no point in gossiping chunks of a candidate that timed out
+	if timed_out {
+		return Ok(());
+	}
+
+	// nor in gossiping chunks of a candidate which is estimated to be available everywhere
+	if state.config.suppress_gossip_once_available
+		&& state.per_candidate
+			.get(&message.candidate_hash)
+			.map_or(false, |per_candidate| per_candidate.is_estimated_available())
+	{
+		tracing::trace!(
+			target: LOG_TARGET,
+			candidate_hash = ?message.candidate_hash,
+			"Not relaying chunk of candidate estimated to be available",
+		);
+		return Ok(());
 	}
+
 	// condense the peers to the peers with interest on the candidate
 	let peers = state
 		.peer_views
@@ -640,9 +3628,7 @@ where
 		.into_iter()
 		.filter(|(_, view)| {
 			// peers view must contain the candidate hash too
-			state
-				.cached_live_candidates_unioned(view.heads.iter())
-				.contains(&message.candidate_hash)
+			state.is_candidate_live_in(&message.candidate_hash, view.heads.iter())
 		})
 		.map(|(peer, _)| -> PeerId { peer.clone() })
 		.collect::<Vec<_>>();
@@ -653,9 +3639,34 @@ where
 		.into_iter()
 		.filter(|peer| per_candidate.message_required_by_peer(peer, erasure_chunk_index))
 		.collect::<Vec<_>>();
+	let last_view_changes = if state.config.prioritize_recently_interested_peers {
+		Some(&state.last_view_changes)
+	} else {
+		None
+	};
+	let authorities = if state.config.prioritize_authorities {
+		Some(&state.authorities)
+	} else {
+		None
+	};
+	let peers = state.peer_selection_rng.select(
+		peers,
+		state.config.max_gossip_fanout,
+		last_view_changes,
+		authorities,
+	);
 
 	// gossip that message to interested peers
-	send_tracked_gossip_messages_to_peers(ctx, per_candidate, metrics, peers, iter::once(message)).await;
+	let sent = send_tracked_gossip_messages_to_peers(
+		ctx,
+		per_candidate,
+		&state.config,
+		metrics,
+		peers,
+		iter::once(message),
+		MessagePriority::Low,
+	).await;
+	metrics.on_chunks_relayed(sent);
 	Ok(())
 }
 
@@ -665,6 +3676,10 @@ pub struct AvailabilityDistributionSubsystem {
 	keystore: SyncCryptoStorePtr,
 	/// Prometheus metrics.
 	metrics: Metrics,
+	/// Configuration of the subsystem.
+	config: Config,
+	/// Sizes of the protocol state published for concurrent readers, if requested.
+	shared_counts: Option<SharedStateCounts>,
 }
 
 impl AvailabilityDistributionSubsystem {
@@ -673,7 +3688,18 @@ impl AvailabilityDistributionSubsystem {
 
 	/// Create a new instance of the availability distribution.
 	pub fn new(keystore: SyncCryptoStorePtr, metrics: Metrics) -> Self {
-		Self { keystore, metrics }
+		Self::with_config(keystore, metrics, Config::default())
+	}
+
+	/// Create a new instance of the availability distribution with the given configuration.
+	pub fn with_config(keystore: SyncCryptoStorePtr, metrics: Metrics, config: Config) -> Self {
+		Self { keystore, metrics, config, shared_counts: None }
+	}
+
+	/// Obtain a handle to the sizes of the protocol state, which are published
+	/// alongside the metrics once the subsystem runs.
+	pub fn shared_counts(&mut self) -> SharedStateCounts {
+		self.shared_counts.get_or_insert_with(Default::default).clone()
 	}
 
 	/// Start processing work as passed on from the Overseer.
@@ -681,7 +3707,7 @@ impl AvailabilityDistributionSubsystem {
 	where
 		Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
 	{
-		let mut state = ProtocolState::default();
+		let mut state = ProtocolState::with_config(self.config.clone());
 		self.run_inner(ctx, &mut state).await
 	}
 
@@ -691,12 +3717,18 @@ impl AvailabilityDistributionSubsystem {
 	where
 		Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
 	{
+		// messages which arrived while fetching relay parents
+		let mut deferred = VecDeque::new();
+
 		// work: process incoming messages from the overseer.
 		loop {
-			let message = ctx
-				.recv()
-				.await
-				.map_err(|e| Error::IncomingMessageChannel(e))?;
+			let message = match deferred.pop_front() {
+				Some(message) => message,
+				None => ctx
+					.recv()
+					.await
+					.map_err(|e| Error::IncomingMessageChannel(e))?,
+			};
 			match message {
 				FromOverseer::Communication {
 					msg: AvailabilityDistributionMessage::NetworkBridgeUpdateV1(event),
@@ -706,6 +3738,7 @@ impl AvailabilityDistributionSubsystem {
 						&self.keystore.clone(),
 						state,
 						&self.metrics,
+						&mut deferred,
 						event,
 					)
 					.await
@@ -717,11 +3750,127 @@ impl AvailabilityDistributionSubsystem {
 						);
 					}
 				}
+				FromOverseer::Communication {
+					msg: AvailabilityDistributionMessage::Prefetch(relay_parent),
+				} => {
+					if let Err(e) = handle_prefetch(
+						&mut ctx,
+						&self.keystore,
+						state,
+						relay_parent,
+						&self.metrics,
+					)
+					.await
+					{
+						tracing::warn!(
+							target: LOG_TARGET,
+							err = ?e,
+							"Failed to prefetch relay parent",
+						);
+					}
+				}
+				FromOverseer::Communication {
+					msg: AvailabilityDistributionMessage::FetchAvailableData { candidate_hash, tx },
+				} => {
+					let available_data = state.reconstructed_available_data(&candidate_hash);
+					if available_data.is_some() {
+						state.event_sink.emit(AvailabilityEvent::ReconstructionComplete { candidate_hash });
+					}
+					let _ = tx.send(available_data);
+				}
+				FromOverseer::Communication {
+					msg: AvailabilityDistributionMessage::FetchChunks { candidate_hash, tx },
+				} => {
+					let _ = tx.send(state.held_chunks(&candidate_hash));
+				}
+				FromOverseer::Communication {
+					msg: AvailabilityDistributionMessage::ReGossipAll,
+				} => {
+					handle_regossip_all(&mut ctx, state, &self.metrics).await;
+				}
+				FromOverseer::Communication {
+					msg: AvailabilityDistributionMessage::ClearMessageVaults,
+				} => {
+					state.clear_message_vaults(&self.metrics);
+				}
 				FromOverseer::Signal(OverseerSignal::ActiveLeaves(ActiveLeavesUpdate {
-					activated: _,
+					activated,
 					deactivated: _,
 				})) => {
-					// handled at view change
+					// track the block numbers to detect timed out candidates.
+					for leaf in activated.iter() {
+						match query_block_number(&mut ctx, *leaf).await {
+							Ok(Some(block_number)) => state.note_leaf(*leaf, block_number),
+							Ok(None) => {}
+							Err(e) => {
+								tracing::warn!(
+									target: LOG_TARGET,
+									err = ?e,
+									"Failed to obtain block number of activated leaf",
+								);
+							}
+						}
+					}
+
+					// relay parents are tracked at view change, but activated leaves may be
+					// prefetched together, such that their shared ancestors are fetched only once.
+					let prefetched = if state.config.prefetch_activated_leaves { activated } else { Default::default() };
+					for leaf in prefetched {
+						if let Err(e) = handle_prefetch(
+							&mut ctx,
+							&self.keystore,
+							state,
+							leaf,
+							&self.metrics,
+						)
+						.await
+						{
+							tracing::warn!(
+								target: LOG_TARGET,
+								err = ?e,
+								"Failed to prefetch activated leaf",
+							);
+						}
+					}
+
+					if state.config.backfill_reconstructable_candidates {
+						let mut own_chunks = Vec::new();
+						let backfilled = state.backfill_reconstructable_candidates(&self.metrics, &mut own_chunks);
+						self.metrics.on_chunks_backfilled(backfilled);
+						store_backfilled_own_chunks(&mut ctx, state, own_chunks).await;
+					}
+
+					// relay parents and candidates are kept consistent by construction, which is
+					// checked in debug builds only as it walks the entire state
+					#[cfg(debug_assertions)]
+					state.validate_and_repair();
+
+					let snapshot = state.export_metrics_snapshot();
+					self.metrics.on_snapshot(&snapshot);
+					if let Some(shared_counts) = &self.shared_counts {
+						shared_counts.publish(snapshot);
+					}
+					self.metrics.on_memory_report(&state.memory_report());
+					self.metrics.on_candidate_ages(&state.age_histogram(Instant::now()));
+					self.metrics.on_orphan_candidates(state.orphan_candidates().len());
+					if let Some(best_block_number) = state.best_block_number {
+						// candidates which have not timed out yet, but will with the next block
+						let at_risk = state.candidates_timing_out_before(best_block_number.saturating_add(2))
+							.into_iter()
+							.filter(|candidate_hash| !state.is_candidate_timed_out(candidate_hash))
+							.collect::<Vec<_>>();
+						if !at_risk.is_empty() {
+							tracing::debug!(
+								target: LOG_TARGET,
+								candidates = ?at_risk,
+								best_block_number,
+								"Candidates are at risk of timing out before becoming available",
+							);
+						}
+					}
+					if state.config.measure_duplicate_chunks {
+						self.metrics.on_duplicate_chunk_bytes(state.duplicate_chunk_bytes());
+					}
 				}
 				FromOverseer::Signal(OverseerSignal::BlockFinalized(..)) => {}
 				FromOverseer::Signal(OverseerSignal::Conclude) => {
@@ -757,7 +3906,12 @@ where
 #[derive(Debug)]
 enum FetchedLiveCandidate {
 	Cached,
-	Fresh(CandidateDescriptor),
+	Fresh {
+		descriptor: CandidateDescriptor,
+		time_out_at: BlockNumber,
+		group_responsible: GroupIndex,
+		core_index: CoreIndex,
+	},
 }
 
 /// Obtain all live candidates for all given `relay_blocks`.
@@ -767,11 +3921,12 @@ enum FetchedLiveCandidate {
 ///
 /// This also queries the provided `live_under` cache before reaching into the
 /// runtime and updates it with the information learned.
-#[tracing::instrument(level = "trace", skip(ctx, relay_blocks, live_under), fields(subsystem = LOG_TARGET))]
+#[tracing::instrument(level = "trace", skip(ctx, relay_blocks, live_under, metrics), fields(subsystem = LOG_TARGET))]
 async fn query_pending_availability_at<Context>(
 	ctx: &mut Context,
 	relay_blocks: impl IntoIterator<Item = Hash>,
 	live_under: &mut HashMap<Hash, HashSet<CandidateHash>>,
+	metrics: &Metrics,
 ) -> Result<HashMap<CandidateHash, FetchedLiveCandidate>>
 where
 	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
@@ -790,11 +3945,15 @@ where
 			e => e.or_default(),
 		};
 
-		for (receipt_hash, descriptor) in query_pending_availability(ctx, relay_parent).await? {
+		for (receipt_hash, descriptor, time_out_at, group_responsible, core_index)
+			in query_pending_availability(ctx, relay_parent, metrics).await?
+		{
 			// unfortunately we have no good way of telling the candidate was
 			// cached until now. But we don't clobber a `Cached` entry if there
 			// is one already.
-			live_candidates.entry(receipt_hash).or_insert(FetchedLiveCandidate::Fresh(descriptor));
+			live_candidates
+				.entry(receipt_hash)
+				.or_insert(FetchedLiveCandidate::Fresh { descriptor, time_out_at, group_responsible, core_index });
 			receipts_for.insert(receipt_hash);
 		}
 	}
@@ -809,21 +3968,25 @@ where
 /// from where it was fetched.
 ///
 /// This also updates all `live_under` cached by the protocol state and returns a list
-/// of up to `K` ancestors of the relay-parent.
-#[tracing::instrument(level = "trace", skip(ctx, live_under), fields(subsystem = LOG_TARGET))]
+/// of up to `K` ancestors of the relay-parent, as well as the session index of its child,
+/// if known.
+#[tracing::instrument(level = "trace", skip(ctx, live_under, limiter, metrics), fields(subsystem = LOG_TARGET))]
 async fn query_live_candidates<Context>(
 	ctx: &mut Context,
 	live_under: &mut HashMap<Hash, HashSet<CandidateHash>>,
+	limiter: &mut TokenBucket,
 	relay_parent: Hash,
-) -> Result<(HashMap<CandidateHash, FetchedLiveCandidate>, Vec<Hash>)>
+	metrics: &Metrics,
+) -> Result<(HashMap<CandidateHash, FetchedLiveCandidate>, Vec<Hash>, Option<SessionIndex>)>
 where
 	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
 {
 	// register one of relay parents (not the ancestors)
-	let ancestors = query_up_to_k_ancestors_in_same_session(
+	let (ancestors, session_index) = query_up_to_k_ancestors_in_same_session(
 		ctx,
 		relay_parent,
 		AvailabilityDistributionSubsystem::K,
+		limiter,
 	)
 	.await?;
 
@@ -833,15 +3996,23 @@ where
 		ctx,
 		ancestors.iter().cloned().chain(iter::once(relay_parent)),
 		live_under,
+		metrics,
 	).await?;
 
-	Ok((live_candidates, ancestors))
+	Ok((live_candidates, ancestors, session_index))
 }
 
-/// Query all hashes and descriptors of candidates pending availability at a particular block.
-#[tracing::instrument(level = "trace", skip(ctx), fields(subsystem = LOG_TARGET))]
-async fn query_pending_availability<Context>(ctx: &mut Context, relay_parent: Hash)
-	-> Result<Vec<(CandidateHash, CandidateDescriptor)>>
+/// Query all hashes, descriptors, time outs, responsible groups and occupied cores of candidates
+/// pending availability at a particular block.
+///
+/// At most one candidate per para can be pending availability. Should the runtime report
+/// several, the one with the lowest candidate hash is picked.
+#[tracing::instrument(level = "trace", skip(ctx, metrics), fields(subsystem = LOG_TARGET))]
+async fn query_pending_availability<Context>(
+	ctx: &mut Context,
+	relay_parent: Hash,
+	metrics: &Metrics,
+) -> Result<Vec<(CandidateHash, CandidateDescriptor, BlockNumber, GroupIndex, CoreIndex)>>
 where
 	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
 {
@@ -857,21 +4028,144 @@ where
 		.map_err(|e| Error::AvailabilityCoresResponseChannel(e))?
 		.map_err(|e| Error::AvailabilityCores(e))?;
 
-	Ok(cores.into_iter()
-		.filter_map(|core_state| if let CoreState::Occupied(occupied) = core_state {
-			Some((occupied.candidate_hash, occupied.candidate_descriptor))
-		} else {
-			None
-		})
-		.collect())
+	tracing::trace!(
+		target: LOG_TARGET,
+		?relay_parent,
+		occupied = ?occupied_paras(&cores),
+		"Obtained availability cores",
+	);
+
+	let mut pending = Vec::new();
+	let mut pending_index_of_para = HashMap::new();
+	let mut para_of_candidate = HashMap::new();
+	let indexed_cores = cores
+		.iter()
+		.enumerate()
+		.filter_map(|(core_index, core_state)| match core_state {
+			CoreState::Occupied(occupied) => Some((CoreIndex(core_index as u32), occupied)),
+			_ => None,
+		});
+	for (core_index, occupied) in indexed_cores {
+		let entry = (
+			occupied.candidate_hash,
+			occupied.candidate_descriptor.clone(),
+			occupied.time_out_at,
+			occupied.group_responsible,
+			core_index,
+		);
+
+		// the first candidate of a hash is kept, should the runtime report it for several paras
+		let para_id = *para_of_candidate.entry(entry.0).or_insert(entry.1.para_id);
+		if para_id != entry.1.para_id {
+			tracing::error!(
+				target: LOG_TARGET,
+				?relay_parent,
+				candidate_hash = ?entry.0,
+				para_a = ?para_id,
+				para_b = ?entry.1.para_id,
+				"Candidate hash is shared by candidates of different paras",
+			);
+			metrics.on_candidate_hash_collision();
+			continue;
+		}
+
+		match pending_index_of_para.entry(entry.1.para_id) {
+			Entry::Vacant(vac) => {
+				vac.insert(pending.len());
+				pending.push(entry);
+			}
+			Entry::Occupied(occ) => {
+				let known: &mut (CandidateHash, _, _, _, _) = &mut pending[*occ.get()];
+				tracing::warn!(
+					target: LOG_TARGET,
+					?relay_parent,
+					para_id = ?entry.1.para_id,
+					candidate_a = ?known.0,
+					candidate_b = ?entry.0,
+					"Multiple candidates pending availability for the same para",
+				);
+				metrics.on_pending_availability_conflict();
+
+				if entry.0 .0 < known.0 .0 {
+					*known = entry;
+				}
+			}
+		}
+	}
+
+	Ok(pending)
+}
+
+/// Obtain the cores occupied by candidates pending availability, skipping free and scheduled cores.
+fn occupied_cores(cores: &[CoreState]) -> impl Iterator<Item = &OccupiedCore> {
+	cores.iter().filter_map(|core_state| match core_state {
+		CoreState::Occupied(occupied) => Some(occupied),
+		_ => None,
+	})
+}
+
+/// Obtain the para, the responsible group and the time out of all occupied cores.
+fn occupied_paras(cores: &[CoreState]) -> Vec<(ParaId, GroupIndex, BlockNumber)> {
+	occupied_cores(cores)
+		.map(|occupied| (occupied.candidate_descriptor.para_id, occupied.group_responsible, occupied.time_out_at))
+		.collect()
+}
+
+/// Query the descriptor of a candidate from the runtime at the blocks it is known to be
+/// pending availability at, returning it iff it differs from the `known` one.
+#[tracing::instrument(level = "trace", skip(ctx, live_under, known, metrics), fields(subsystem = LOG_TARGET))]
+async fn query_fresh_descriptor<Context>(
+	ctx: &mut Context,
+	live_under: &HashMap<Hash, HashSet<CandidateHash>>,
+	candidate_hash: CandidateHash,
+	known: &CandidateDescriptor,
+	metrics: &Metrics,
+) -> Result<Option<CandidateDescriptor>>
+where
+	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
+{
+	let relay_blocks = live_under
+		.iter()
+		.filter(|(_, candidates)| candidates.contains(&candidate_hash))
+		.map(|(relay_block, _)| *relay_block)
+		.collect::<Vec<_>>();
+
+	for relay_block in relay_blocks {
+		let fresh = query_pending_availability(ctx, relay_block, metrics)
+			.await?
+			.into_iter()
+			.find(|(hash, descriptor, ..)| *hash == candidate_hash && descriptor != known);
+
+		if let Some((_, descriptor, ..)) = fresh {
+			return Ok(Some(descriptor));
+		}
+	}
+
+	Ok(None)
 }
 
 /// Modify the reputation of a peer based on its behavior.
-#[tracing::instrument(level = "trace", skip(ctx), fields(subsystem = LOG_TARGET))]
-async fn modify_reputation<Context>(ctx: &mut Context, peer: PeerId, rep: Rep)
+#[tracing::instrument(level = "trace", skip(ctx, peer_views), fields(subsystem = LOG_TARGET))]
+async fn modify_reputation<Context>(
+	ctx: &mut Context,
+	peer_views: &HashMap<PeerId, View>,
+	peer: PeerId,
+	rep: Rep,
+)
 where
 	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
 {
+	// the peer may have disconnected since sending the message, reporting it is wasted then
+	if !peer_views.contains_key(&peer) {
+		tracing::trace!(
+			target: LOG_TARGET,
+			rep = ?rep,
+			peer_id = ?peer,
+			"Skipping reputation change for disconnected peer",
+		);
+		return;
+	}
+
 	tracing::trace!(
 		target: LOG_TARGET,
 		rep = ?rep,
@@ -883,82 +4177,74 @@ where
 	)).await;
 }
 
-/// Query the proof of validity for a particular candidate hash.
+/// Query the validator set.
 #[tracing::instrument(level = "trace", skip(ctx), fields(subsystem = LOG_TARGET))]
-async fn query_data_availability<Context>(ctx: &mut Context, candidate_hash: CandidateHash) -> Result<bool>
+async fn query_validators<Context>(
+	ctx: &mut Context,
+	relay_parent: Hash,
+) -> Result<Vec<ValidatorId>>
 where
 	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
 {
 	let (tx, rx) = oneshot::channel();
-	ctx.send_message(AllMessages::AvailabilityStore(
-		AvailabilityStoreMessage::QueryDataAvailability(candidate_hash, tx),
-	)).await;
+	let query_validators = AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+		relay_parent,
+		RuntimeApiRequest::Validators(tx),
+	));
 
-	rx.await.map_err(|e| Error::QueryAvailabilityResponseChannel(e))
+	ctx.send_message(query_validators)
+		.await;
+	rx.await
+		.map_err(|e| Error::QueryValidatorsResponseChannel(e))?
+		.map_err(|e| Error::QueryValidators(e))
 }
 
+/// Query the validator groups at a relay parent and return the group containing our validator,
+/// along with the assignment of groups to cores.
 #[tracing::instrument(level = "trace", skip(ctx), fields(subsystem = LOG_TARGET))]
-async fn query_chunk<Context>(
+async fn query_our_group<Context>(
 	ctx: &mut Context,
-	candidate_hash: CandidateHash,
+	relay_parent: Hash,
 	validator_index: ValidatorIndex,
-) -> Result<Option<ErasureChunk>>
+) -> Result<(Option<GroupIndex>, GroupAssignment)>
 where
 	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
 {
 	let (tx, rx) = oneshot::channel();
-	ctx.send_message(AllMessages::AvailabilityStore(
-		AvailabilityStoreMessage::QueryChunk(candidate_hash, validator_index, tx),
-	)).await;
+	ctx.send_message(AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+		relay_parent,
+		RuntimeApiRequest::ValidatorGroups(tx),
+	)))
+	.await;
 
-	rx.await.map_err(|e| Error::QueryChunkResponseChannel(e))
-}
+	let (groups, rotation) = rx
+		.await
+		.map_err(|e| Error::ValidatorGroupsResponseChannel(e))?
+		.map_err(|e| Error::ValidatorGroups(e))?;
 
-#[tracing::instrument(level = "trace", skip(ctx, erasure_chunk), fields(subsystem = LOG_TARGET))]
-async fn store_chunk<Context>(
-	ctx: &mut Context,
-	candidate_hash: CandidateHash,
-	relay_parent: Hash,
-	validator_index: ValidatorIndex,
-	erasure_chunk: ErasureChunk,
-) -> Result<std::result::Result<(), ()>>
-where
-	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
-{
-	let (tx, rx) = oneshot::channel();
-	ctx.send_message(AllMessages::AvailabilityStore(
-		AvailabilityStoreMessage::StoreChunk {
-			candidate_hash,
-			relay_parent,
-			validator_index,
-			chunk: erasure_chunk,
-			tx,
-		}
-	)).await;
+	let our_group = groups
+		.iter()
+		.position(|group| group.contains(&validator_index))
+		.map(|group| GroupIndex(group as u32));
 
-	rx.await.map_err(|e| Error::StoreChunkResponseChannel(e))
+	Ok((our_group, GroupAssignment { rotation, n_groups: groups.len() }))
 }
 
-/// Query the validator set.
+/// Query the block number of a relay chain block.
 #[tracing::instrument(level = "trace", skip(ctx), fields(subsystem = LOG_TARGET))]
-async fn query_validators<Context>(
+async fn query_block_number<Context>(
 	ctx: &mut Context,
 	relay_parent: Hash,
-) -> Result<Vec<ValidatorId>>
+) -> Result<Option<BlockNumber>>
 where
 	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
 {
 	let (tx, rx) = oneshot::channel();
-	let query_validators = AllMessages::RuntimeApi(RuntimeApiMessage::Request(
-		relay_parent,
-		RuntimeApiRequest::Validators(tx),
-	));
-
-	ctx.send_message(query_validators)
+	ctx.send_message(AllMessages::ChainApi(ChainApiMessage::BlockNumber(relay_parent, tx)))
 		.await;
 	rx.await
-		.map_err(|e| Error::QueryValidatorsResponseChannel(e))?
-		.map_err(|e| Error::QueryValidators(e))
+		.map_err(|e| Error::QueryBlockNumberResponseChannel(e))?
+		.map_err(|e| Error::QueryBlockNumber(e))
 }
 
 /// Query the hash of the `K` ancestors
@@ -1007,20 +4293,61 @@ where
 		.map_err(|e| Error::QuerySession(e))
 }
 
-/// Queries up to k ancestors with the constraints of equiv session
-#[tracing::instrument(level = "trace", skip(ctx), fields(subsystem = LOG_TARGET))]
+/// Queries up to k ancestors with the constraints of equiv session, along with the session
+/// index of the child of `relay_parent`, which is unknown iff the ancestors could not be queried.
+/// The walk over the ancestors stops at the first one whose session index can not be queried.
+///
+/// The session index requests are paced by `limiter`.
+#[tracing::instrument(level = "trace", skip(ctx, limiter), fields(subsystem = LOG_TARGET))]
 async fn query_up_to_k_ancestors_in_same_session<Context>(
 	ctx: &mut Context,
 	relay_parent: Hash,
 	k: usize,
-) -> Result<Vec<Hash>>
+	limiter: &mut TokenBucket,
+) -> Result<(Vec<Hash>, Option<SessionIndex>)>
+where
+	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
+{
+	query_up_to_k_ancestors_in_same_session_with(ctx, relay_parent, k, None, limiter).await
+}
+
+/// Queries up to k ancestors with the constraints of equiv session.
+///
+/// Session indices are resolved by `session_index_for_child` if given, which allows tests
+/// to define session topologies. Otherwise they are queried from the runtime, paced by `limiter`.
+#[tracing::instrument(level = "trace", skip(ctx, session_index_for_child, limiter), fields(subsystem = LOG_TARGET))]
+async fn query_up_to_k_ancestors_in_same_session_with<Context>(
+	ctx: &mut Context,
+	relay_parent: Hash,
+	k: usize,
+	session_index_for_child: Option<&(dyn Fn(Hash) -> SessionIndex + Sync)>,
+	limiter: &mut TokenBucket,
+) -> Result<(Vec<Hash>, Option<SessionIndex>)>
 where
 	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
 {
 	// k + 1 since we always query the child's session index
 	// ordering is [parent, grandparent, greatgrandparent, greatgreatgrandparent, ...]
-	let ancestors = query_k_ancestors(ctx, relay_parent, k + 1).await?;
-	let desired_session = query_session_index_for_child(ctx, relay_parent).await?;
+	let ancestors = match query_k_ancestors(ctx, relay_parent, k + 1).await {
+		Ok(ancestors) => ancestors,
+		Err(e) => {
+			// the relay parent itself is still worth tracking
+			tracing::warn!(
+				target: LOG_TARGET,
+				err = ?e,
+				?relay_parent,
+				"Failed to query ancestors, continuing without them",
+			);
+			return Ok((Vec::new(), None));
+		}
+	};
+	let desired_session = match session_index_for_child {
+		Some(resolve) => resolve(relay_parent),
+		None => {
+			limiter.pace().await;
+			query_session_index_for_child(ctx, relay_parent).await?
+		}
+	};
 	// we would only need `ancestors.len() - 1`, but the one extra could avoid a re-alloc
 	// if the consumer wants to push the `relay_parent` onto it too and does not hurt otherwise
 	let mut acc = Vec::with_capacity(ancestors.len());
@@ -1029,21 +4356,64 @@ where
 	let mut iter = ancestors.into_iter().peekable();
 
 	while let Some((ancestor, ancestor_parent)) = iter.next().and_then(|a| iter.peek().map(|ap| (a, ap))) {
-		if query_session_index_for_child(ctx, *ancestor_parent).await? != desired_session {
+		let session = match session_index_for_child {
+			Some(resolve) => resolve(*ancestor_parent),
+			None => {
+				limiter.pace().await;
+				match query_session_index_for_child(ctx, *ancestor_parent).await {
+					Ok(session) => session,
+					Err(e) => {
+						// the ancestors resolved so far are still worth tracking
+						tracing::warn!(
+							target: LOG_TARGET,
+							err = ?e,
+							?ancestor,
+							"Failed to query session index, stopping the walk over ancestors",
+						);
+						break;
+					}
+				}
+			}
+		};
+		if session != desired_session {
 			break;
 		}
 		acc.push(ancestor);
 	}
 
 	debug_assert!(acc.len() <= k);
-	Ok(acc)
+	Ok((acc, Some(desired_session)))
 }
 
 #[derive(Clone)]
 struct MetricsInner {
 	gossipped_availability_chunks: prometheus::Counter<prometheus::U64>,
+	rejected_availability_chunks: prometheus::Counter<prometheus::U64>,
+	chunks_originated: prometheus::Counter<prometheus::U64>,
+	chunks_relayed: prometheus::Counter<prometheus::U64>,
+	oversized_peer_views: prometheus::Counter<prometheus::U64>,
+	pending_availability_conflicts: prometheus::Counter<prometheus::U64>,
+	pending_candidates_evicted: prometheus::Counter<prometheus::U64>,
+	timed_out_candidates_skipped: prometheus::Counter<prometheus::U64>,
+	own_chunk_generation_failures: prometheus::Counter<prometheus::U64>,
+	erasure_root_mismatches: prometheus::Counter<prometheus::U64>,
+	unpersisted_chunk_stores: prometheus::Counter<prometheus::U64>,
+	messages_shed: prometheus::Counter<prometheus::U64>,
+	chunks_backfilled: prometheus::Counter<prometheus::U64>,
+	candidate_hash_collisions: prometheus::Counter<prometheus::U64>,
+	malformed_candidates: prometheus::Counter<prometheus::U64>,
+	chunk_buffered_duration: prometheus::Histogram,
 	handle_our_view_change: prometheus::Histogram,
 	process_incoming_peer_message: prometheus::Histogram,
+	buffered_chunks: prometheus::Gauge<prometheus::U64>,
+	connected_peers: prometheus::Gauge<prometheus::U64>,
+	tracked_candidates: prometheus::Gauge<prometheus::U64>,
+	tracked_relay_parents: prometheus::Gauge<prometheus::U64>,
+	buffered_bytes: prometheus::Gauge<prometheus::U64>,
+	memory_usage: prometheus::GaugeVec<prometheus::U64>,
+	candidate_ages: prometheus::GaugeVec<prometheus::U64>,
+	orphan_candidates: prometheus::Gauge<prometheus::U64>,
+	duplicate_chunk_bytes: prometheus::Gauge<prometheus::U64>,
 }
 
 /// Availability Distribution metrics.
@@ -1057,6 +4427,158 @@ impl Metrics {
 		}
 	}
 
+	fn on_chunk_rejected(&self) {
+		if let Some(metrics) = &self.0 {
+			metrics.rejected_availability_chunks.inc();
+		}
+	}
+
+	/// Account chunks sent of candidates whose available data we hold.
+	fn on_chunks_originated(&self, count: usize) {
+		if let Some(metrics) = &self.0 {
+			metrics.chunks_originated.inc_by(count as u64);
+		}
+	}
+
+	/// Account chunks sent which were received from other peers.
+	fn on_chunks_relayed(&self, count: usize) {
+		if let Some(metrics) = &self.0 {
+			metrics.chunks_relayed.inc_by(count as u64);
+		}
+	}
+
+	fn on_oversized_peer_view(&self) {
+		if let Some(metrics) = &self.0 {
+			metrics.oversized_peer_views.inc();
+		}
+	}
+
+	fn on_pending_availability_conflict(&self) {
+		if let Some(metrics) = &self.0 {
+			metrics.pending_availability_conflicts.inc();
+		}
+	}
+
+	/// Account chunks of unknown candidates dropped due to their age or the buffer being full.
+	fn on_pending_candidates_evicted(&self, count: usize) {
+		if let Some(metrics) = &self.0 {
+			metrics.pending_candidates_evicted.inc_by(count as u64);
+		}
+	}
+
+	fn on_own_chunk_generation_failure(&self) {
+		if let Some(metrics) = &self.0 {
+			metrics.own_chunk_generation_failures.inc();
+		}
+	}
+
+	/// Account a candidate fetched with an erasure root differing from the one already tracked.
+	fn on_erasure_root_mismatch(&self) {
+		if let Some(metrics) = &self.0 {
+			metrics.erasure_root_mismatches.inc();
+		}
+	}
+
+	/// Account a chunk store which was acknowledged, but not persisted by the availability store.
+	fn on_unpersisted_chunk_store(&self) {
+		if let Some(metrics) = &self.0 {
+			metrics.unpersisted_chunk_stores.inc();
+		}
+	}
+
+	/// Account a message of a peer which was dropped as too many messages were queued,
+	/// or as it was superseded by a more recent one.
+	fn on_message_shed(&self) {
+		if let Some(metrics) = &self.0 {
+			metrics.messages_shed.inc();
+		}
+	}
+
+	/// Account chunks derived from reconstructed available data.
+	fn on_chunks_backfilled(&self, count: usize) {
+		if let Some(metrics) = &self.0 {
+			metrics.chunks_backfilled.inc_by(count as u64);
+		}
+	}
+
+	/// Account a candidate hash shared by candidates of different paras.
+	fn on_candidate_hash_collision(&self) {
+		if let Some(metrics) = &self.0 {
+			metrics.candidate_hash_collisions.inc();
+		}
+	}
+
+	/// Account a malformed candidate, which was not tracked.
+	fn on_malformed_candidate(&self) {
+		if let Some(metrics) = &self.0 {
+			metrics.malformed_candidates.inc();
+		}
+	}
+
+	/// Account candidates which were not tracked as they timed out before becoming live.
+	fn on_timed_out_candidates_skipped(&self, count: usize) {
+		if let Some(metrics) = &self.0 {
+			metrics.timed_out_candidates_skipped.inc_by(count as u64);
+		}
+	}
+
+	/// Observe for how long a chunk was held in the message vault before it was dropped.
+	fn on_chunk_evicted(&self, buffered_for: Duration) {
+		if let Some(metrics) = &self.0 {
+			metrics.chunk_buffered_duration.observe(buffered_for.as_secs_f64());
+		}
+	}
+
+	/// Update all gauges from a single snapshot of the protocol state.
+	fn on_snapshot(&self, snapshot: &MetricsSnapshot) {
+		if let Some(metrics) = &self.0 {
+			metrics.buffered_chunks.set(snapshot.buffered_chunks as u64);
+			metrics.connected_peers.set(snapshot.connected_peers as u64);
+			metrics.tracked_candidates.set(snapshot.tracked_candidates as u64);
+			metrics.tracked_relay_parents.set(snapshot.tracked_relay_parents as u64);
+			metrics.buffered_bytes.set(snapshot.buffered_bytes as u64);
+		}
+	}
+
+	/// Update the estimated memory used by the components of the protocol state.
+	fn on_memory_report(&self, report: &MemoryReport) {
+		if let Some(metrics) = &self.0 {
+			for (component, bytes) in &[
+				("live_under", report.live_under),
+				("per_relay_parent", report.per_relay_parent),
+				("per_candidate", report.per_candidate),
+				("message_vaults", report.message_vaults),
+			] {
+				metrics.memory_usage.with_label_values(&[component]).set(*bytes as u64);
+			}
+		}
+	}
+
+	/// Update the number of tracked candidates per age bucket.
+	fn on_candidate_ages(&self, histogram: &[(Duration, usize)]) {
+		if let Some(metrics) = &self.0 {
+			for (lower_bound, count) in histogram {
+				metrics.candidate_ages
+					.with_label_values(&[&lower_bound.as_secs().to_string()])
+					.set(*count as u64);
+			}
+		}
+	}
+
+	/// Update the number of tracked candidates whose relay parent is not tracked.
+	fn on_orphan_candidates(&self, count: usize) {
+		if let Some(metrics) = &self.0 {
+			metrics.orphan_candidates.set(count as u64);
+		}
+	}
+
+	/// Update the size of chunk data held more than once across the message vaults.
+	fn on_duplicate_chunk_bytes(&self, bytes: usize) {
+		if let Some(metrics) = &self.0 {
+			metrics.duplicate_chunk_bytes.set(bytes as u64);
+		}
+	}
+
 	/// Provide a timer for `handle_our_view_change` which observes on drop.
 	fn time_handle_our_view_change(&self) -> Option<metrics::prometheus::prometheus::HistogramTimer> {
 		self.0.as_ref().map(|metrics| metrics.handle_our_view_change.start_timer())
@@ -1080,6 +4602,113 @@ impl metrics::Metrics for Metrics {
 				)?,
 				registry,
 			)?,
+			rejected_availability_chunks: prometheus::register(
+				prometheus::Counter::new(
+					"parachain_availability_distribution_rejected_chunks_total",
+					"Number of availability chunks dropped as the message vaults were full.",
+				)?,
+				registry,
+			)?,
+			chunks_originated: prometheus::register(
+				prometheus::Counter::new(
+					"parachain_availability_distribution_chunks_originated_total",
+					"Number of availability chunks sent of candidates whose available data we hold.",
+				)?,
+				registry,
+			)?,
+			chunks_relayed: prometheus::register(
+				prometheus::Counter::new(
+					"parachain_availability_distribution_chunks_relayed_total",
+					"Number of availability chunks sent which were received from other peers.",
+				)?,
+				registry,
+			)?,
+			oversized_peer_views: prometheus::register(
+				prometheus::Counter::new(
+					"parachain_availability_distribution_oversized_peer_views_total",
+					"Number of peer views with an implausibly large number of heads.",
+				)?,
+				registry,
+			)?,
+			pending_availability_conflicts: prometheus::register(
+				prometheus::Counter::new(
+					"parachain_availability_distribution_pending_availability_conflicts_total",
+					"Number of candidates pending availability for a para which already had one pending.",
+				)?,
+				registry,
+			)?,
+			pending_candidates_evicted: prometheus::register(
+				prometheus::Counter::new(
+					"parachain_availability_distribution_pending_candidates_evicted_total",
+					"Number of chunks of unknown candidates dropped due to their age or the buffer being full.",
+				)?,
+				registry,
+			)?,
+			timed_out_candidates_skipped: prometheus::register(
+				prometheus::Counter::new(
+					"parachain_availability_distribution_timed_out_candidates_skipped_total",
+					"Number of candidates not tracked as they timed out before the relay parent they were live in.",
+				)?,
+				registry,
+			)?,
+			own_chunk_generation_failures: prometheus::register(
+				prometheus::Counter::new(
+					"parachain_availability_distribution_own_chunk_generation_failures_total",
+					"Number of chunks of available data we hold which could not be obtained.",
+				)?,
+				registry,
+			)?,
+			erasure_root_mismatches: prometheus::register(
+				prometheus::Counter::new(
+					"parachain_availability_distribution_erasure_root_mismatches_total",
+					"Number of candidates fetched with an erasure root diverging from the tracked one.",
+				)?,
+				registry,
+			)?,
+			unpersisted_chunk_stores: prometheus::register(
+				prometheus::Counter::new(
+					"parachain_availability_distribution_unpersisted_chunk_stores_total",
+					"Number of chunk stores acknowledged by the availability store without persisting the chunk.",
+				)?,
+				registry,
+			)?,
+			messages_shed: prometheus::register(
+				prometheus::Counter::new(
+					"parachain_availability_distribution_messages_shed_total",
+					"Number of messages of peers dropped as too many messages were queued or they were superseded.",
+				)?,
+				registry,
+			)?,
+			chunks_backfilled: prometheus::register(
+				prometheus::Counter::new(
+					"parachain_availability_distribution_chunks_backfilled_total",
+					"Number of chunks derived from available data reconstructed from other chunks.",
+				)?,
+				registry,
+			)?,
+			candidate_hash_collisions: prometheus::register(
+				prometheus::Counter::new(
+					"parachain_availability_distribution_candidate_hash_collisions_total",
+					"Number of candidate hashes reported for candidates of different paras.",
+				)?,
+				registry,
+			)?,
+			malformed_candidates: prometheus::register(
+				prometheus::Counter::new(
+					"parachain_availability_distribution_malformed_candidates_total",
+					"Number of candidates which were not tracked as they are malformed.",
+				)?,
+				registry,
+			)?,
+			chunk_buffered_duration: prometheus::register(
+				prometheus::Histogram::with_opts(
+					prometheus::HistogramOpts::new(
+						"parachain_availability_distribution_chunk_buffered_duration",
+						"Time in seconds availability chunks were held in the message vaults before being dropped.",
+					).buckets(vec![0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0])
+				)?,
+				registry,
+			)?,
 			handle_our_view_change: prometheus::register(
 				prometheus::Histogram::with_opts(
 					prometheus::HistogramOpts::new(
@@ -1098,6 +4727,75 @@ impl metrics::Metrics for Metrics {
 				)?,
 				registry,
 			)?,
+			buffered_chunks: prometheus::register(
+				prometheus::Gauge::new(
+					"parachain_availability_distribution_buffered_chunks",
+					"Number of availability chunks held in the message vaults.",
+				)?,
+				registry,
+			)?,
+			connected_peers: prometheus::register(
+				prometheus::Gauge::new(
+					"parachain_availability_distribution_connected_peers",
+					"Number of peers connected to the availability distribution.",
+				)?,
+				registry,
+			)?,
+			tracked_candidates: prometheus::register(
+				prometheus::Gauge::new(
+					"parachain_availability_distribution_tracked_candidates",
+					"Number of candidates tracked by the availability distribution.",
+				)?,
+				registry,
+			)?,
+			tracked_relay_parents: prometheus::register(
+				prometheus::Gauge::new(
+					"parachain_availability_distribution_tracked_relay_parents",
+					"Number of relay parents tracked by the availability distribution.",
+				)?,
+				registry,
+			)?,
+			buffered_bytes: prometheus::register(
+				prometheus::Gauge::new(
+					"parachain_availability_distribution_buffered_bytes",
+					"Size in bytes of all availability chunks held in the message vaults.",
+				)?,
+				registry,
+			)?,
+			memory_usage: prometheus::register(
+				prometheus::GaugeVec::new(
+					prometheus::Opts::new(
+						"parachain_availability_distribution_memory_usage_bytes",
+						"Estimated memory in bytes used by the components of the protocol state.",
+					),
+					&["component"],
+				)?,
+				registry,
+			)?,
+			candidate_ages: prometheus::register(
+				prometheus::GaugeVec::new(
+					prometheus::Opts::new(
+						"parachain_availability_distribution_candidates_by_age",
+						"Number of tracked candidates per lower bound of the time in seconds they have been live.",
+					),
+					&["age_seconds"],
+				)?,
+				registry,
+			)?,
+			orphan_candidates: prometheus::register(
+				prometheus::Gauge::new(
+					"parachain_availability_distribution_orphan_candidates",
+					"Number of tracked candidates whose relay parent is not tracked.",
+				)?,
+				registry,
+			)?,
+			duplicate_chunk_bytes: prometheus::register(
+				prometheus::Gauge::new(
+					"parachain_availability_distribution_duplicate_chunk_bytes",
+					"Size in bytes of chunk data held more than once across the message vaults.",
+				)?,
+				registry,
+			)?,
 		};
 		Ok(Metrics(Some(metrics)))
 	}
@@ -0,0 +1,433 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Utilities for driving the availability distribution subsystem in tests, and for
+//! constructing consistent availability scenarios.
+
+use std::collections::HashMap;
+use std::iter;
+use std::sync::Arc;
+use std::time::Duration;
+
+use polkadot_erasure_coding::{branches, obtain_chunks_v1 as obtain_chunks};
+use polkadot_node_network_protocol::{NetworkBridgeEvent, PeerId, ReputationChange as Rep, View};
+use polkadot_node_subsystem_util::TimeoutExt;
+use polkadot_primitives::v1::{
+	AvailableData, BlockData, CandidateCommitments, CandidateDescriptor, CandidateHash,
+	CommittedCandidateReceipt, CoreState, ErasureChunk, GroupIndex, GroupRotationInfo, Hash,
+	HeadData, Id as ParaId, OccupiedCore, PersistedValidationData, PoV, ScheduledCore,
+	SessionIndex, ValidatorId, ValidatorIndex,
+};
+use polkadot_subsystem::messages::{
+	AllMessages, AvailabilityDistributionMessage, AvailabilityStoreMessage, ChainApiMessage,
+	NetworkBridgeMessage, RuntimeApiMessage, RuntimeApiRequest,
+};
+use polkadot_subsystem::FromOverseer;
+use polkadot_subsystem_testhelpers::TestSubsystemContextHandle;
+use sc_keystore::LocalKeystore;
+use sp_application_crypto::AppKey;
+use sp_keyring::Sr25519Keyring;
+use sp_keystore::{SyncCryptoStore, SyncCryptoStorePtr};
+
+use crate::AvailabilityGossipMessage;
+
+/// Time without any message from the subsystem after which it is considered idle.
+const IDLE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The answers to the queries the subsystem issues while changing its view.
+#[derive(Debug, Clone, Default)]
+pub struct ViewChangeScript {
+	/// The validators at every relay parent.
+	pub validators: Vec<ValidatorId>,
+	/// The ancestors of every relay parent, youngest first.
+	pub ancestors: Vec<Hash>,
+	/// The session index of the child of a relay chain block, `0` if not given.
+	pub session_per_relay_parent: HashMap<Hash, SessionIndex>,
+	/// The availability cores at a relay chain block, none if not given.
+	pub availability_cores_per_relay_parent: HashMap<Hash, Vec<CoreState>>,
+	/// Whether the available data of a candidate is stored, `false` if not given.
+	pub data_availability: HashMap<CandidateHash, bool>,
+	/// The erasure chunks of candidates whose available data is stored.
+	pub chunks_per_candidate: HashMap<CandidateHash, Vec<ErasureChunk>>,
+}
+
+/// Change our view to `view` and answer all queries of the subsystem according to `script`,
+/// until the subsystem is idle.
+///
+/// Panics on any other message, such as chunks being gossiped, so no peer should be
+/// interested in the candidates of the view yet.
+pub async fn drive_view_change(
+	virtual_overseer: &mut TestSubsystemContextHandle<AvailabilityDistributionMessage>,
+	view: View,
+	script: &ViewChangeScript,
+) {
+	virtual_overseer.send(FromOverseer::Communication {
+		msg: AvailabilityDistributionMessage::NetworkBridgeUpdateV1(
+			NetworkBridgeEvent::OurViewChange(view),
+		),
+	}).await;
+
+	while let Some(message) = virtual_overseer.recv().timeout(IDLE_TIMEOUT).await {
+		match message {
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				_,
+				RuntimeApiRequest::Validators(tx),
+			)) => {
+				let _ = tx.send(Ok(script.validators.clone()));
+			}
+			AllMessages::ChainApi(ChainApiMessage::Ancestors { k, response_channel, .. }) => {
+				let _ = response_channel.send(Ok(script.ancestors.iter().take(k).cloned().collect()));
+			}
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				relay_parent,
+				RuntimeApiRequest::SessionIndexForChild(tx),
+			)) => {
+				let session_index = script.session_per_relay_parent.get(&relay_parent).cloned();
+				let _ = tx.send(Ok(session_index.unwrap_or_default()));
+			}
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				relay_parent,
+				RuntimeApiRequest::AvailabilityCores(tx),
+			)) => {
+				let cores = script.availability_cores_per_relay_parent.get(&relay_parent).cloned();
+				let _ = tx.send(Ok(cores.unwrap_or_default()));
+			}
+			AllMessages::AvailabilityStore(
+				AvailabilityStoreMessage::QueryDataAvailability(candidate_hash, tx),
+			) => {
+				let available = script.data_availability.get(&candidate_hash).cloned();
+				let _ = tx.send(available.unwrap_or_default());
+			}
+			AllMessages::AvailabilityStore(
+				AvailabilityStoreMessage::QueryChunk(candidate_hash, index, tx),
+			) => {
+				let chunk = script.chunks_per_candidate
+					.get(&candidate_hash)
+					.and_then(|chunks| chunks.get(index as usize))
+					.cloned();
+				let _ = tx.send(chunk);
+			}
+			message => panic!("Unexpected message while changing our view: {:?}", message),
+		}
+	}
+}
+
+/// Expect the next message of the subsystem to report `peer` with `rep`.
+///
+/// Panics if the subsystem is idle or sends any other message.
+pub async fn expect_report(
+	virtual_overseer: &mut TestSubsystemContextHandle<AvailabilityDistributionMessage>,
+	peer: &PeerId,
+	rep: Rep,
+) {
+	match virtual_overseer.recv().timeout(IDLE_TIMEOUT).await {
+		Some(AllMessages::NetworkBridge(NetworkBridgeMessage::ReportPeer(reported_peer, reported_rep))) => {
+			assert_eq!(
+				(&reported_peer, &reported_rep),
+				(peer, &rep),
+				"Peer {} was expected to be reported with {:?}",
+				peer,
+				rep,
+			);
+		}
+		Some(message) => panic!("Expected peer {} to be reported with {:?}, got: {:?}", peer, rep, message),
+		None => panic!("Expected peer {} to be reported with {:?}, but the subsystem is idle", peer, rep),
+	}
+}
+
+/// A relay parent with two candidates pending availability, assessed by five validators
+/// of which this node is the first.
+#[derive(Clone)]
+pub struct TestState {
+	/// The paras of the candidates.
+	pub chain_ids: Vec<ParaId>,
+	/// The keys of the validators, this node being the first.
+	pub validators: Vec<Sr25519Keyring>,
+	/// The public keys of the validators.
+	pub validator_public: Vec<ValidatorId>,
+	/// The validator groups and their rotation.
+	pub validator_groups: (Vec<Vec<ValidatorIndex>>, GroupRotationInfo),
+	/// The head data per para.
+	pub head_data: HashMap<ParaId, HeadData>,
+	/// A keystore holding the key of the first validator.
+	pub keystore: SyncCryptoStorePtr,
+	/// The relay parent of the candidates.
+	pub relay_parent: Hash,
+	/// The ancestors of the relay parent, youngest first.
+	pub ancestors: Vec<Hash>,
+	/// The availability cores, scheduled for the paras.
+	pub availability_cores: Vec<CoreState>,
+	/// The validation data shared by all candidates.
+	pub persisted_validation_data: PersistedValidationData,
+	/// The candidates, one per para.
+	pub candidates: Vec<CommittedCandidateReceipt>,
+	/// The PoVs of the candidates.
+	pub pov_blocks: Vec<PoV>,
+}
+
+/// Obtain the public keys of the given validators.
+pub fn validator_pubkeys(val_ids: &[Sr25519Keyring]) -> Vec<ValidatorId> {
+	val_ids.iter().map(|v| v.public().into()).collect()
+}
+
+impl Default for TestState {
+	fn default() -> Self {
+		let chain_a = ParaId::from(1);
+		let chain_b = ParaId::from(2);
+
+		let chain_ids = vec![chain_a, chain_b];
+
+		let validators = vec![
+			Sr25519Keyring::Ferdie, // <- this node, role: validator
+			Sr25519Keyring::Alice,
+			Sr25519Keyring::Bob,
+			Sr25519Keyring::Charlie,
+			Sr25519Keyring::Dave,
+		];
+
+		let keystore: SyncCryptoStorePtr = Arc::new(LocalKeystore::in_memory());
+
+		SyncCryptoStore::sr25519_generate_new(
+			&*keystore,
+			ValidatorId::ID,
+			Some(&validators[0].to_seed()),
+		)
+		.expect("Insert key into keystore");
+
+		let validator_public = validator_pubkeys(&validators);
+
+		let validator_groups = vec![vec![2, 0, 4], vec![1], vec![3]];
+		let group_rotation_info = GroupRotationInfo {
+			session_start_block: 0,
+			group_rotation_frequency: 100,
+			now: 1,
+		};
+		let validator_groups = (validator_groups, group_rotation_info);
+
+		let availability_cores = vec![
+			CoreState::Scheduled(ScheduledCore {
+				para_id: chain_ids[0],
+				collator: None,
+			}),
+			CoreState::Scheduled(ScheduledCore {
+				para_id: chain_ids[1],
+				collator: None,
+			}),
+		];
+
+		let mut head_data = HashMap::new();
+		head_data.insert(chain_a, HeadData(vec![4, 5, 6]));
+		head_data.insert(chain_b, HeadData(vec![7, 8, 9]));
+
+		let ancestors = vec![
+			Hash::repeat_byte(0x44),
+			Hash::repeat_byte(0x33),
+			Hash::repeat_byte(0x22),
+		];
+		let relay_parent = Hash::repeat_byte(0x05);
+
+		let persisted_validation_data = PersistedValidationData {
+			parent_head: HeadData(vec![7, 8, 9]),
+			block_number: Default::default(),
+			hrmp_mqc_heads: Vec::new(),
+			dmq_mqc_head: Default::default(),
+			max_pov_size: 1024,
+		};
+
+		let pov_block_a = PoV {
+			block_data: BlockData(vec![42, 43, 44]),
+		};
+
+		let pov_block_b = PoV {
+			block_data: BlockData(vec![45, 46, 47]),
+		};
+
+		let candidates = vec![
+			TestCandidateBuilder {
+				para_id: chain_ids[0],
+				relay_parent: relay_parent,
+				pov_hash: pov_block_a.hash(),
+				erasure_root: make_erasure_root(persisted_validation_data.clone(), validators.len(), pov_block_a.clone()),
+				head_data: head_data.get(&chain_ids[0]).unwrap().clone(),
+				..Default::default()
+			}
+			.build(),
+			TestCandidateBuilder {
+				para_id: chain_ids[1],
+				relay_parent: relay_parent,
+				pov_hash: pov_block_b.hash(),
+				erasure_root: make_erasure_root(persisted_validation_data.clone(), validators.len(), pov_block_b.clone()),
+				head_data: head_data.get(&chain_ids[1]).unwrap().clone(),
+				..Default::default()
+			}
+			.build(),
+		];
+
+		let pov_blocks = vec![pov_block_a, pov_block_b];
+
+		Self {
+			chain_ids,
+			keystore,
+			validators,
+			validator_public,
+			validator_groups,
+			availability_cores,
+			head_data,
+			persisted_validation_data,
+			relay_parent,
+			ancestors,
+			candidates,
+			pov_blocks,
+		}
+	}
+}
+
+impl TestState {
+	/// Script a view change in which all candidates are pending availability at the relay parent,
+	/// which is in the same session as its ancestors.
+	pub fn view_change_script(&self) -> ViewChangeScript {
+		ViewChangeScript {
+			validators: self.validator_public.clone(),
+			ancestors: self.ancestors.clone(),
+			session_per_relay_parent: self.ancestors
+				.iter()
+				.chain(iter::once(&self.relay_parent))
+				.map(|hash| (*hash, 1))
+				.collect(),
+			availability_cores_per_relay_parent: iter::once((
+				self.relay_parent,
+				self.candidates.iter().map(occupied_core_from_candidate).collect(),
+			)).collect(),
+			..Default::default()
+		}
+	}
+}
+
+/// Obtain a core occupied by the given candidate.
+pub fn occupied_core_from_candidate(receipt: &CommittedCandidateReceipt) -> CoreState {
+	CoreState::Occupied(OccupiedCore {
+		next_up_on_available: None,
+		occupied_since: 0,
+		time_out_at: 5,
+		next_up_on_time_out: None,
+		availability: Default::default(),
+		group_responsible: GroupIndex::from(0),
+		candidate_hash: receipt.hash(),
+		candidate_descriptor: receipt.descriptor().clone(),
+	})
+}
+
+/// Obtain the available data made up of the given validation data and PoV.
+pub fn make_available_data(validation_data: PersistedValidationData, pov: PoV) -> AvailableData {
+	AvailableData {
+		validation_data,
+		pov: Arc::new(pov),
+	}
+}
+
+/// Obtain the erasure root of the available data made up of the given validation data and PoV.
+pub fn make_erasure_root(peristed: PersistedValidationData, validator_count: usize, pov: PoV) -> Hash {
+	let available_data = make_available_data(peristed, pov);
+
+	let chunks = obtain_chunks(validator_count, &available_data).unwrap();
+	branches(&chunks).root()
+}
+
+/// Obtain the erasure chunks of the available data made up of the given validation data and PoV.
+pub fn make_erasure_chunks(peristed: PersistedValidationData, validator_count: usize, pov: PoV) -> Vec<ErasureChunk> {
+	let available_data = make_available_data(peristed, pov);
+
+	derive_erasure_chunks_with_proofs(validator_count, &available_data)
+}
+
+/// Obtain a gossip message carrying a valid erasure chunk of a candidate of the `test` state.
+pub fn make_valid_availability_gossip(
+	test: &TestState,
+	candidate: usize,
+	erasure_chunk_index: u32,
+) -> AvailabilityGossipMessage {
+	let erasure_chunks = make_erasure_chunks(
+		test.persisted_validation_data.clone(),
+		test.validator_public.len(),
+		test.pov_blocks[candidate].clone(),
+	);
+
+	let erasure_chunk: ErasureChunk = erasure_chunks
+		.get(erasure_chunk_index as usize)
+		.expect("Must be valid or input is oob")
+		.clone();
+
+	AvailabilityGossipMessage {
+		candidate_hash: test.candidates[candidate].hash(),
+		erasure_chunk,
+	}
+}
+
+/// Builds a candidate receipt from the fields relevant to availability distribution.
+#[derive(Default)]
+pub struct TestCandidateBuilder {
+	/// The para of the candidate.
+	pub para_id: ParaId,
+	/// The head data the candidate commits to.
+	pub head_data: HeadData,
+	/// The hash of the PoV of the candidate.
+	pub pov_hash: Hash,
+	/// The relay parent of the candidate.
+	pub relay_parent: Hash,
+	/// The erasure root of the available data of the candidate.
+	pub erasure_root: Hash,
+}
+
+impl TestCandidateBuilder {
+	/// Build the candidate receipt.
+	pub fn build(self) -> CommittedCandidateReceipt {
+		CommittedCandidateReceipt {
+			descriptor: CandidateDescriptor {
+				para_id: self.para_id,
+				pov_hash: self.pov_hash,
+				relay_parent: self.relay_parent,
+				erasure_root: self.erasure_root,
+				..Default::default()
+			},
+			commitments: CandidateCommitments {
+				head_data: self.head_data,
+				..Default::default()
+			},
+		}
+	}
+}
+
+/// Obtain the erasure chunks of the available data along with their merkle proofs.
+pub fn derive_erasure_chunks_with_proofs(
+	n_validators: usize,
+	available_data: &AvailableData,
+) -> Vec<ErasureChunk> {
+	let chunks: Vec<Vec<u8>> = obtain_chunks(n_validators, available_data).unwrap();
+
+	// create proofs for each erasure chunk
+	let branches = branches(chunks.as_ref());
+
+	let erasure_chunks = branches
+		.enumerate()
+		.map(|(index, (proof, chunk))| ErasureChunk {
+			chunk: chunk.to_vec(),
+			index: index as _,
+			proof,
+		})
+		.collect::<Vec<ErasureChunk>>();
+
+	erasure_chunks
+}
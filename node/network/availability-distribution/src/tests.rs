@@ -20,17 +20,18 @@ use polkadot_erasure_coding::{branches, obtain_chunks_v1 as obtain_chunks};
 use polkadot_node_network_protocol::{view, ObservedRole};
 use polkadot_node_subsystem_util::TimeoutExt;
 use polkadot_primitives::v1::{
-	AvailableData, BlockData, CandidateCommitments, CandidateDescriptor, GroupIndex,
-	GroupRotationInfo, HeadData, OccupiedCore, PersistedValidationData, PoV, ScheduledCore, Id as ParaId,
-	CommittedCandidateReceipt,
+	AvailableData, BlockData, CandidateDescriptor, GroupIndex, OccupiedCore, PersistedValidationData, PoV,
+	Id as ParaId, CommittedCandidateReceipt,
 };
+use polkadot_subsystem::messages::AvailabilityStoreMessage;
 use polkadot_subsystem_testhelpers as test_helpers;
+use super::testing::{
+	expect_report, make_available_data, make_erasure_chunks, make_valid_availability_gossip,
+	occupied_core_from_candidate, TestState,
+};
 
 use futures::{executor, future, Future};
-use sc_keystore::LocalKeystore;
-use sp_application_crypto::AppKey;
-use sp_keystore::{SyncCryptoStore, SyncCryptoStorePtr};
-use sp_keyring::Sr25519Keyring;
+use sp_keystore::SyncCryptoStorePtr;
 use std::{sync::Arc, time::Duration};
 use maplit::hashmap;
 
@@ -50,6 +51,12 @@ fn chunk_protocol_message(
 	)
 }
 
+/// A descriptor which is tracked by `add_relay_parent`, as candidates without an erasure root
+/// are skipped as malformed.
+fn well_formed_descriptor() -> CandidateDescriptor {
+	CandidateDescriptor { erasure_root: Hash::repeat_byte(0xE0), ..Default::default() }
+}
+
 struct TestHarness {
 	virtual_overseer: test_helpers::TestSubsystemContextHandle<AvailabilityDistributionMessage>,
 }
@@ -57,14 +64,31 @@ struct TestHarness {
 fn test_harness<T: Future<Output = ()>>(
 	keystore: SyncCryptoStorePtr,
 	test_fx: impl FnOnce(TestHarness) -> T,
+) -> ProtocolState {
+	test_harness_with_config(keystore, Config::default(), test_fx)
+}
+
+fn test_harness_with_config<T: Future<Output = ()>>(
+	keystore: SyncCryptoStorePtr,
+	config: Config,
+	test_fx: impl FnOnce(TestHarness) -> T,
+) -> ProtocolState {
+	test_harness_with_metrics(keystore, config, Default::default(), test_fx)
+}
+
+fn test_harness_with_metrics<T: Future<Output = ()>>(
+	keystore: SyncCryptoStorePtr,
+	config: Config,
+	metrics: Metrics,
+	test_fx: impl FnOnce(TestHarness) -> T,
 ) -> ProtocolState {
 	sp_tracing::try_init_simple();
 
 	let pool = sp_core::testing::TaskExecutor::new();
 	let (context, virtual_overseer) = test_helpers::make_subsystem_context(pool.clone());
 
-	let subsystem = AvailabilityDistributionSubsystem::new(keystore, Default::default());
-	let mut state = ProtocolState::default();
+	let subsystem = AvailabilityDistributionSubsystem::with_config(keystore, metrics, config.clone());
+	let mut state = ProtocolState::with_config(config);
 	{
 		let subsystem = subsystem.run_inner(context, &mut state);
 
@@ -97,220 +121,6 @@ async fn overseer_recv(
 	msg
 }
 
-fn occupied_core_from_candidate(receipt: &CommittedCandidateReceipt) -> CoreState {
-	CoreState::Occupied(OccupiedCore {
-		next_up_on_available: None,
-		occupied_since: 0,
-		time_out_at: 5,
-		next_up_on_time_out: None,
-		availability: Default::default(),
-		group_responsible: GroupIndex::from(0),
-		candidate_hash: receipt.hash(),
-		candidate_descriptor: receipt.descriptor().clone(),
-	})
-}
-
-#[derive(Clone)]
-struct TestState {
-	chain_ids: Vec<ParaId>,
-	validators: Vec<Sr25519Keyring>,
-	validator_public: Vec<ValidatorId>,
-	validator_groups: (Vec<Vec<ValidatorIndex>>, GroupRotationInfo),
-	head_data: HashMap<ParaId, HeadData>,
-	keystore: SyncCryptoStorePtr,
-	relay_parent: Hash,
-	ancestors: Vec<Hash>,
-	availability_cores: Vec<CoreState>,
-	persisted_validation_data: PersistedValidationData,
-	candidates: Vec<CommittedCandidateReceipt>,
-	pov_blocks: Vec<PoV>,
-}
-
-fn validator_pubkeys(val_ids: &[Sr25519Keyring]) -> Vec<ValidatorId> {
-	val_ids.iter().map(|v| v.public().into()).collect()
-}
-
-impl Default for TestState {
-	fn default() -> Self {
-		let chain_a = ParaId::from(1);
-		let chain_b = ParaId::from(2);
-
-		let chain_ids = vec![chain_a, chain_b];
-
-		let validators = vec![
-			Sr25519Keyring::Ferdie, // <- this node, role: validator
-			Sr25519Keyring::Alice,
-			Sr25519Keyring::Bob,
-			Sr25519Keyring::Charlie,
-			Sr25519Keyring::Dave,
-		];
-
-		let keystore: SyncCryptoStorePtr = Arc::new(LocalKeystore::in_memory());
-
-		SyncCryptoStore::sr25519_generate_new(
-			&*keystore,
-			ValidatorId::ID,
-			Some(&validators[0].to_seed()),
-		)
-		.expect("Insert key into keystore");
-
-		let validator_public = validator_pubkeys(&validators);
-
-		let validator_groups = vec![vec![2, 0, 4], vec![1], vec![3]];
-		let group_rotation_info = GroupRotationInfo {
-			session_start_block: 0,
-			group_rotation_frequency: 100,
-			now: 1,
-		};
-		let validator_groups = (validator_groups, group_rotation_info);
-
-		let availability_cores = vec![
-			CoreState::Scheduled(ScheduledCore {
-				para_id: chain_ids[0],
-				collator: None,
-			}),
-			CoreState::Scheduled(ScheduledCore {
-				para_id: chain_ids[1],
-				collator: None,
-			}),
-		];
-
-		let mut head_data = HashMap::new();
-		head_data.insert(chain_a, HeadData(vec![4, 5, 6]));
-		head_data.insert(chain_b, HeadData(vec![7, 8, 9]));
-
-		let ancestors = vec![
-			Hash::repeat_byte(0x44),
-			Hash::repeat_byte(0x33),
-			Hash::repeat_byte(0x22),
-		];
-		let relay_parent = Hash::repeat_byte(0x05);
-
-		let persisted_validation_data = PersistedValidationData {
-			parent_head: HeadData(vec![7, 8, 9]),
-			block_number: Default::default(),
-			hrmp_mqc_heads: Vec::new(),
-			dmq_mqc_head: Default::default(),
-			max_pov_size: 1024,
-		};
-
-		let pov_block_a = PoV {
-			block_data: BlockData(vec![42, 43, 44]),
-		};
-
-		let pov_block_b = PoV {
-			block_data: BlockData(vec![45, 46, 47]),
-		};
-
-		let candidates = vec![
-			TestCandidateBuilder {
-				para_id: chain_ids[0],
-				relay_parent: relay_parent,
-				pov_hash: pov_block_a.hash(),
-				erasure_root: make_erasure_root(persisted_validation_data.clone(), validators.len(), pov_block_a.clone()),
-				head_data: head_data.get(&chain_ids[0]).unwrap().clone(),
-				..Default::default()
-			}
-			.build(),
-			TestCandidateBuilder {
-				para_id: chain_ids[1],
-				relay_parent: relay_parent,
-				pov_hash: pov_block_b.hash(),
-				erasure_root: make_erasure_root(persisted_validation_data.clone(), validators.len(), pov_block_b.clone()),
-				head_data: head_data.get(&chain_ids[1]).unwrap().clone(),
-				..Default::default()
-			}
-			.build(),
-		];
-
-		let pov_blocks = vec![pov_block_a, pov_block_b];
-
-		Self {
-			chain_ids,
-			keystore,
-			validators,
-			validator_public,
-			validator_groups,
-			availability_cores,
-			head_data,
-			persisted_validation_data,
-			relay_parent,
-			ancestors,
-			candidates,
-			pov_blocks,
-		}
-	}
-}
-
-fn make_available_data(validation_data: PersistedValidationData, pov: PoV) -> AvailableData {
-	AvailableData {
-		validation_data,
-		pov: Arc::new(pov),
-	}
-}
-
-fn make_erasure_root(peristed: PersistedValidationData, validator_count: usize, pov: PoV) -> Hash {
-	let available_data = make_available_data(peristed, pov);
-
-	let chunks = obtain_chunks(validator_count, &available_data).unwrap();
-	branches(&chunks).root()
-}
-
-fn make_erasure_chunks(peristed: PersistedValidationData, validator_count: usize, pov: PoV) -> Vec<ErasureChunk> {
-	let available_data = make_available_data(peristed, pov);
-
-	derive_erasure_chunks_with_proofs(validator_count, &available_data)
-}
-
-fn make_valid_availability_gossip(
-	test: &TestState,
-	candidate: usize,
-	erasure_chunk_index: u32,
-) -> AvailabilityGossipMessage {
-	let erasure_chunks = make_erasure_chunks(
-		test.persisted_validation_data.clone(),
-		test.validator_public.len(),
-		test.pov_blocks[candidate].clone(),
-	);
-
-	let erasure_chunk: ErasureChunk = erasure_chunks
-		.get(erasure_chunk_index as usize)
-		.expect("Must be valid or input is oob")
-		.clone();
-
-	AvailabilityGossipMessage {
-		candidate_hash: test.candidates[candidate].hash(),
-		erasure_chunk,
-	}
-}
-
-#[derive(Default)]
-struct TestCandidateBuilder {
-	para_id: ParaId,
-	head_data: HeadData,
-	pov_hash: Hash,
-	relay_parent: Hash,
-	erasure_root: Hash,
-}
-
-impl TestCandidateBuilder {
-	fn build(self) -> CommittedCandidateReceipt {
-		CommittedCandidateReceipt {
-			descriptor: CandidateDescriptor {
-				para_id: self.para_id,
-				pov_hash: self.pov_hash,
-				relay_parent: self.relay_parent,
-				erasure_root: self.erasure_root,
-				..Default::default()
-			},
-			commitments: CandidateCommitments {
-				head_data: self.head_data,
-				..Default::default()
-			},
-		}
-	}
-}
-
 #[test]
 fn helper_integrity() {
 	let test_state = TestState::default();
@@ -335,44 +145,26 @@ fn helper_integrity() {
 	);
 }
 
-fn derive_erasure_chunks_with_proofs(
-	n_validators: usize,
-	available_data: &AvailableData,
-) -> Vec<ErasureChunk> {
-	let chunks: Vec<Vec<u8>> = obtain_chunks(n_validators, available_data).unwrap();
-
-	// create proofs for each erasure chunk
-	let branches = branches(chunks.as_ref());
-
-	let erasure_chunks = branches
-		.enumerate()
-		.map(|(index, (proof, chunk))| ErasureChunk {
-			chunk: chunk.to_vec(),
-			index: index as _,
-			proof,
-		})
-		.collect::<Vec<ErasureChunk>>();
-
-	erasure_chunks
-}
-
 async fn expect_chunks_network_message(
 	virtual_overseer: &mut test_helpers::TestSubsystemContextHandle<AvailabilityDistributionMessage>,
 	peers: &[PeerId],
 	candidates: &[CandidateHash],
 	chunks: &[ErasureChunk],
+	priority: MessagePriority,
 ) {
 	for _ in 0..chunks.len() {
 		assert_matches!(
 			overseer_recv(virtual_overseer).await,
 			AllMessages::NetworkBridge(
-				NetworkBridgeMessage::SendValidationMessage(
+				NetworkBridgeMessage::SendPrioritizedValidationMessage(
 					send_peers,
 					protocol_v1::ValidationProtocol::AvailabilityDistribution(
 						protocol_v1::AvailabilityDistributionMessage::Chunk(send_candidate, send_chunk),
 					),
+					send_priority,
 				)
 			) => {
+				assert_eq!(send_priority, priority);
 				assert!(candidates.contains(&send_candidate), format!("Could not find candidate: {:?}", send_candidate));
 				assert!(chunks.iter().any(|c| c == &send_chunk), format!("Could not find chunk: {:?}", send_chunk));
 				assert_eq!(peers.len(), send_peers.len());
@@ -382,6 +174,41 @@ async fn expect_chunks_network_message(
 	}
 }
 
+/// Expect the `chunks` to be replayed to the `peer`, in as many messages as it takes.
+async fn expect_batched_chunks_network_message(
+	virtual_overseer: &mut test_helpers::TestSubsystemContextHandle<AvailabilityDistributionMessage>,
+	peer: &PeerId,
+	candidates: &[CandidateHash],
+	chunks: &[ErasureChunk],
+) {
+	let mut batch = Vec::new();
+	while batch.len() < chunks.len() {
+		batch.extend(assert_matches!(
+			overseer_recv(virtual_overseer).await,
+			AllMessages::NetworkBridge(
+				NetworkBridgeMessage::SendPrioritizedValidationMessage(
+					send_peers,
+					protocol_v1::ValidationProtocol::AvailabilityDistribution(message),
+					MessagePriority::Low,
+				)
+			) => {
+				assert_eq!(send_peers, vec![peer.clone()]);
+				match message {
+					protocol_v1::AvailabilityDistributionMessage::Chunk(candidate, chunk) => vec![(candidate, chunk)],
+					protocol_v1::AvailabilityDistributionMessage::BatchChunks(batch) => batch,
+					message => panic!("Unexpected message {:?}", message),
+				}
+			}
+		));
+	}
+
+	assert_eq!(batch.len(), chunks.len());
+	for (send_candidate, send_chunk) in batch {
+		assert!(candidates.contains(&send_candidate), format!("Could not find candidate: {:?}", send_candidate));
+		assert!(chunks.iter().any(|c| c == &send_chunk), format!("Could not find chunk: {:?}", send_chunk));
+	}
+}
+
 async fn change_our_view(
 	virtual_overseer: &mut test_helpers::TestSubsystemContextHandle<AvailabilityDistributionMessage>,
 	view: View,
@@ -492,13 +319,73 @@ async fn change_our_view(
 				);
 
 				if let Some(peers) = send_chunks_to.get(&candidate_hash) {
-					expect_chunks_network_message(virtual_overseer, &peers, &[candidate_hash], &[chunk]).await;
+					expect_chunks_network_message(
+						virtual_overseer,
+						&peers,
+						&[candidate_hash],
+						&[chunk],
+						MessagePriority::Normal,
+					).await;
 				}
 			}
 		}
 	}
 }
 
+/// Answer the queries issued to track `relay_parent`, which are expected to ask for the
+/// availability cores of exactly the blocks in `cores_per_block`.
+async fn expect_relay_parent_fetch(
+	virtual_overseer: &mut test_helpers::TestSubsystemContextHandle<AvailabilityDistributionMessage>,
+	relay_parent: Hash,
+	validator_public: &[ValidatorId],
+	ancestors: Vec<Hash>,
+	session_index: SessionIndex,
+	mut cores_per_block: HashMap<Hash, Vec<CoreState>>,
+) {
+	assert_matches!(
+		overseer_recv(virtual_overseer).await,
+		AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+			hash,
+			RuntimeApiRequest::Validators(tx),
+		)) => {
+			assert_eq!(hash, relay_parent);
+			tx.send(Ok(validator_public.to_vec())).unwrap();
+		}
+	);
+
+	assert_matches!(
+		overseer_recv(virtual_overseer).await,
+		AllMessages::ChainApi(ChainApiMessage::Ancestors {
+			hash,
+			response_channel: tx,
+			..
+		}) => {
+			assert_eq!(hash, relay_parent);
+			tx.send(Ok(ancestors)).unwrap();
+		}
+	);
+
+	while !cores_per_block.is_empty() {
+		match overseer_recv(virtual_overseer).await {
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				_,
+				RuntimeApiRequest::SessionIndexForChild(tx),
+			)) => {
+				tx.send(Ok(session_index)).unwrap();
+			}
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				hash,
+				RuntimeApiRequest::AvailabilityCores(tx),
+			)) => {
+				let cores = cores_per_block.remove(&hash)
+					.expect(&format!("Unexpected query of availability cores at {:?}", hash));
+				tx.send(Ok(cores)).unwrap();
+			}
+			msg => panic!("Unexpected message while fetching relay parent: {:?}", msg),
+		}
+	}
+}
+
 async fn setup_peer_with_view(
 	virtual_overseer: &mut test_helpers::TestSubsystemContextHandle<AvailabilityDistributionMessage>,
 	peer: PeerId,
@@ -509,26 +396,17 @@ async fn setup_peer_with_view(
 	overseer_send(virtual_overseer, NetworkBridgeEvent::PeerViewChange(peer, view)).await;
 }
 
+/// Send a message from `peer`, which is connected first unless it is already.
 async fn peer_send_message(
 	virtual_overseer: &mut test_helpers::TestSubsystemContextHandle<AvailabilityDistributionMessage>,
 	peer: PeerId,
 	message: AvailabilityGossipMessage,
 	expected_reputation_change: Rep,
 ) {
+	overseer_send(virtual_overseer, NetworkBridgeEvent::PeerConnected(peer.clone(), ObservedRole::Full)).await;
 	overseer_send(virtual_overseer, NetworkBridgeEvent::PeerMessage(peer.clone(), chunk_protocol_message(message))).await;
 
-	assert_matches!(
-		overseer_recv(virtual_overseer).await,
-		AllMessages::NetworkBridge(
-			NetworkBridgeMessage::ReportPeer(
-				rep_peer,
-				rep,
-			)
-		) => {
-			assert_eq!(peer, rep_peer);
-			assert_eq!(expected_reputation_change, rep);
-		}
-	);
+	expect_report(virtual_overseer, &peer, expected_reputation_change).await;
 }
 
 #[test]
@@ -632,6 +510,43 @@ fn check_views() {
 	};
 }
 
+#[test]
+fn drive_view_change_reaches_check_views_state() {
+	let test_state = TestState::default();
+
+	let peer_a = PeerId::random();
+	let peer_a_2 = peer_a.clone();
+	let peer_b = PeerId::random();
+	let peer_b_2 = peer_b.clone();
+
+	let keystore = test_state.keystore.clone();
+	let current = test_state.relay_parent;
+	let ancestors = test_state.ancestors.clone();
+	let candidates = test_state.candidates.clone();
+	let script = test_state.view_change_script();
+
+	let state = test_harness(keystore, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		testing::drive_view_change(&mut virtual_overseer, view![current], &script).await;
+
+		setup_peer_with_view(&mut virtual_overseer, peer_a.clone(), view![current]).await;
+		setup_peer_with_view(&mut virtual_overseer, peer_b.clone(), view![ancestors[0]]).await;
+	});
+
+	assert_eq!(
+		state.peer_views,
+		hashmap! {
+			peer_a_2 => view![current],
+			peer_b_2 => view![test_state.ancestors[0]],
+		},
+	);
+	assert_eq!(state.view, view![current]);
+	for candidate in candidates.iter() {
+		assert!(state.is_candidate_live(&candidate.hash()));
+	}
+}
+
 #[test]
 fn reputation_verification() {
 	let test_state = TestState::default();
@@ -711,11 +626,11 @@ fn reputation_verification() {
 			// Both peers send us this chunk already
 			chunks.remove(2);
 
-			expect_chunks_network_message(&mut virtual_overseer, &[peer_a.clone()], &[candidates[0].hash()], &chunks).await;
+			expect_batched_chunks_network_message(&mut virtual_overseer, &peer_a, &[candidates[0].hash()], &chunks).await;
 
 			overseer_send(&mut virtual_overseer, NetworkBridgeEvent::PeerViewChange(peer_b.clone(), view![current])).await;
 
-			expect_chunks_network_message(&mut virtual_overseer, &[peer_b.clone()], &[candidates[0].hash()], &chunks).await;
+			expect_batched_chunks_network_message(&mut virtual_overseer, &peer_b, &[candidates[0].hash()], &chunks).await;
 
 			peer_send_message(&mut virtual_overseer, peer_a.clone(), valid.clone(), BENEFIT_VALID_MESSAGE_FIRST).await;
 
@@ -724,6 +639,7 @@ fn reputation_verification() {
 				&[peer_b.clone()],
 				&[candidates[1].hash()],
 				&[valid.erasure_chunk.clone()],
+				MessagePriority::Low,
 			).await;
 
 			// Let B send the same message
@@ -733,22 +649,54 @@ fn reputation_verification() {
 }
 
 #[test]
-fn not_a_live_candidate_is_detected() {
+fn reports_are_matched_by_expect_report() {
 	let test_state = TestState::default();
 
 	let peer_a = PeerId::random();
+	let peer_b = PeerId::random();
 
 	let keystore = test_state.keystore.clone();
 
 	test_harness(keystore, move |test_harness| async move {
 		let mut virtual_overseer = test_harness.virtual_overseer;
 
+		let script = test_state.view_change_script();
+		testing::drive_view_change(&mut virtual_overseer, view![test_state.relay_parent], &script).await;
+
+		let valid = make_valid_availability_gossip(&test_state, 0, 2);
+		for (peer, rep) in vec![
+			(&peer_b, BENEFIT_VALID_MESSAGE_FIRST),
+			(&peer_b, COST_PEER_DUPLICATE_MESSAGE),
+			(&peer_a, BENEFIT_VALID_MESSAGE),
+			(&peer_a, COST_PEER_DUPLICATE_MESSAGE),
+		] {
+			overseer_send(&mut virtual_overseer, NetworkBridgeEvent::PeerConnected(peer.clone(), ObservedRole::Full)).await;
+			overseer_send(
+				&mut virtual_overseer,
+				NetworkBridgeEvent::PeerMessage(peer.clone(), chunk_protocol_message(valid.clone())),
+			).await;
+			expect_report(&mut virtual_overseer, peer, rep).await;
+		}
+	});
+}
+
+#[test]
+fn peer_and_chunk_counts_reflect_the_state() {
+	let test_state = TestState::default();
+
+	let peer_a = PeerId::random();
+	let peer_b = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+
+	let state = test_harness(keystore, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
 		let TestState {
 			relay_parent: current,
 			validator_public,
 			ancestors,
 			candidates,
-			pov_blocks,
 			..
 		} = test_state.clone();
 
@@ -761,32 +709,54 @@ fn not_a_live_candidate_is_detected() {
 			hashmap! {
 				current => vec![
 					occupied_core_from_candidate(&candidates[0]),
+					occupied_core_from_candidate(&candidates[1]),
 				],
 			},
-			hashmap! { candidates[0].hash() => true },
-			hashmap! { candidates[0].hash() => (pov_blocks[0].clone(), test_state.persisted_validation_data.clone())},
+			hashmap! { candidates[0].hash() => false, candidates[1].hash() => false },
+			hashmap! {},
 			hashmap! {},
 		).await;
 
-		let valid = make_valid_availability_gossip(
-			&test_state,
-			1,
-			1,
-		);
+		setup_peer_with_view(&mut virtual_overseer, peer_a.clone(), view![current]).await;
+		setup_peer_with_view(&mut virtual_overseer, peer_b.clone(), view![current]).await;
 
-		peer_send_message(&mut virtual_overseer, peer_a.clone(), valid.clone(), COST_NOT_A_LIVE_CANDIDATE).await;
+		// both peers send us the same chunk
+		let valid = make_valid_availability_gossip(&test_state, 1, 2);
+		peer_send_message(&mut virtual_overseer, peer_a.clone(), valid.clone(), BENEFIT_VALID_MESSAGE_FIRST).await;
+		expect_chunks_network_message(
+			&mut virtual_overseer,
+			&[peer_b.clone()],
+			&[candidates[1].hash()],
+			&[valid.erasure_chunk.clone()],
+			MessagePriority::Low,
+		).await;
+		peer_send_message(&mut virtual_overseer, peer_b.clone(), valid.clone(), BENEFIT_VALID_MESSAGE).await;
 	});
+
+	assert_eq!(state.peer_count(), 2);
+	assert_eq!(state.chunk_count(), 1);
+	assert_eq!(
+		state.chunk_count(),
+		state.per_candidate.values().map(|per_candidate| per_candidate.message_vault.len()).sum::<usize>(),
+	);
 }
 
 #[test]
-fn peer_change_view_before_us() {
+fn chunk_sources_are_recorded() {
 	let test_state = TestState::default();
 
 	let peer_a = PeerId::random();
 
 	let keystore = test_state.keystore.clone();
 
-	test_harness(keystore, move |test_harness| async move {
+	let config = Config {
+		record_chunk_sources: true,
+		..Default::default()
+	};
+
+	let peer_a_2 = peer_a.clone();
+	let candidates = test_state.candidates.clone();
+	let state = test_harness_with_config(keystore, config, move |test_harness| async move {
 		let mut virtual_overseer = test_harness.virtual_overseer;
 
 		let TestState {
@@ -798,8 +768,6 @@ fn peer_change_view_before_us() {
 			..
 		} = test_state.clone();
 
-		setup_peer_with_view(&mut virtual_overseer, peer_a.clone(), view![current]).await;
-
 		change_our_view(
 			&mut virtual_overseer,
 			view![current],
@@ -809,26 +777,31 @@ fn peer_change_view_before_us() {
 			hashmap! {
 				current => vec![
 					occupied_core_from_candidate(&candidates[0]),
+					occupied_core_from_candidate(&candidates[1]),
 				],
 			},
-			hashmap! { candidates[0].hash() => true },
-			hashmap! { candidates[0].hash() => (pov_blocks[0].clone(), test_state.persisted_validation_data.clone())},
-			hashmap! { candidates[0].hash() => vec![peer_a.clone()] },
+			hashmap! { candidates[0].hash() => true, candidates[1].hash() => false },
+			hashmap! {
+				candidates[0].hash() => (pov_blocks[0].clone(), test_state.persisted_validation_data.clone()),
+			},
+			hashmap! {},
 		).await;
 
-		let valid = make_valid_availability_gossip(
-			&test_state,
-			0,
-			0,
-		);
-
-		// We send peer a all the chunks of candidate0, so we just benefit him for sending a valid message
-		peer_send_message(&mut virtual_overseer, peer_a.clone(), valid.clone(), BENEFIT_VALID_MESSAGE).await;
+		let valid = make_valid_availability_gossip(&test_state, 1, 2);
+		peer_send_message(&mut virtual_overseer, peer_a_2, valid, BENEFIT_VALID_MESSAGE_FIRST).await;
 	});
+
+	let own_candidate = &state.per_candidate[&candidates[0].hash()];
+	assert_eq!(own_candidate.chunk_source(0), Some(ChunkSource::Local));
+	assert_eq!(own_candidate.chunk_source(2), Some(ChunkSource::Local));
+
+	let received_candidate = &state.per_candidate[&candidates[1].hash()];
+	assert_eq!(received_candidate.chunk_source(2), Some(ChunkSource::Peer(peer_a)));
+	assert_eq!(received_candidate.chunk_source(3), None);
 }
 
 #[test]
-fn candidate_chunks_are_put_into_message_vault_when_candidate_is_first_seen() {
+fn not_a_live_candidate_is_detected() {
 	let test_state = TestState::default();
 
 	let peer_a = PeerId::random();
@@ -847,22 +820,298 @@ fn candidate_chunks_are_put_into_message_vault_when_candidate_is_first_seen() {
 			..
 		} = test_state.clone();
 
-		change_our_view(
-			&mut virtual_overseer,
-			view![ancestors[0]],
-			&validator_public,
-			vec![ancestors[1]],
-			hashmap! { ancestors[0] => 1 },
-			hashmap! {
-				ancestors[0] => vec![
-					occupied_core_from_candidate(&candidates[0]),
-				],
-			},
-			hashmap! { candidates[0].hash() => true },
-			hashmap! { candidates[0].hash() => (pov_blocks[0].clone(), test_state.persisted_validation_data.clone())},
-			hashmap! {},
-		).await;
-
+		change_our_view(
+			&mut virtual_overseer,
+			view![current],
+			&validator_public,
+			vec![ancestors[0]],
+			hashmap! { current => 1 },
+			hashmap! {
+				current => vec![
+					occupied_core_from_candidate(&candidates[0]),
+				],
+			},
+			hashmap! { candidates[0].hash() => true },
+			hashmap! { candidates[0].hash() => (pov_blocks[0].clone(), test_state.persisted_validation_data.clone())},
+			hashmap! {},
+		).await;
+
+		let valid = make_valid_availability_gossip(
+			&test_state,
+			1,
+			1,
+		);
+
+		peer_send_message(&mut virtual_overseer, peer_a.clone(), valid.clone(), COST_NOT_A_LIVE_CANDIDATE).await;
+	});
+}
+
+#[test]
+fn chunks_of_recently_forgotten_candidates_are_penalized_less() {
+	let test_state = TestState::default();
+
+	let peer = PeerId::random();
+	let forgotten = make_valid_availability_gossip(&test_state, 0, 2);
+	let unknown = make_valid_availability_gossip(&test_state, 1, 2);
+
+	let mut state = ProtocolState::default();
+	state.add_relay_parent(
+		test_state.relay_parent,
+		Arc::new(test_state.validator_public.clone()),
+		None,
+		vec![(forgotten.candidate_hash, FetchedLiveCandidate::Fresh {
+			descriptor: test_state.candidates[0].descriptor.clone(),
+			time_out_at: 10,
+			group_responsible: Default::default(),
+			core_index: Default::default(),
+		})].into_iter().collect(),
+		vec![],
+		&Default::default(),
+	);
+	state.peer_views.insert(peer.clone(), view![test_state.relay_parent]);
+
+	// the relay parent left our view, so its candidate is pruned
+	state.remove_relay_parent(&test_state.relay_parent, &Default::default());
+	assert!(!state.is_candidate_live(&forgotten.candidate_hash));
+
+	let pool = sp_core::testing::TaskExecutor::new();
+	let (mut ctx, mut virtual_overseer) =
+		test_helpers::make_subsystem_context::<AvailabilityDistributionMessage, _>(pool);
+
+	for (message, expected) in vec![(forgotten.clone(), COST_FORGOTTEN_CANDIDATE), (unknown, COST_NOT_A_LIVE_CANDIDATE)] {
+		executor::block_on(
+			process_incoming_peer_message(&mut ctx, &mut state, peer.clone(), message, &Default::default())
+		).unwrap();
+
+		assert_matches!(
+			executor::block_on(overseer_recv(&mut virtual_overseer)),
+			AllMessages::NetworkBridge(NetworkBridgeMessage::ReportPeer(rep_peer, rep)) => {
+				assert_eq!(rep_peer, peer);
+				assert_eq!(rep, expected);
+			}
+		);
+	}
+
+	// the candidate is not tracked anew
+	assert!(!state.per_candidate.contains_key(&forgotten.candidate_hash));
+}
+
+#[test]
+fn chunks_of_candidates_of_paras_without_a_core_are_penalized() {
+	let mut test_state = TestState::default();
+
+	// a candidate of a para which occupies none of the cores
+	let unknown_para = ParaId::from(99);
+	assert!(!test_state.chain_ids.contains(&unknown_para));
+	test_state.candidates[1].descriptor.para_id = unknown_para;
+
+	let peer = PeerId::random();
+	let relay_parent = test_state.relay_parent;
+	let message = make_valid_availability_gossip(&test_state, 1, 2);
+
+	let mut state = ProtocolState::default();
+	state.add_relay_parent(
+		relay_parent,
+		Arc::new(test_state.validator_public.clone()),
+		None,
+		hashmap! {
+			test_state.candidates[0].hash() => FetchedLiveCandidate::Fresh {
+				descriptor: test_state.candidates[0].descriptor.clone(),
+				time_out_at: 10,
+				group_responsible: Default::default(),
+				core_index: Default::default(),
+			},
+		},
+		vec![],
+		&Default::default(),
+	);
+	state.per_relay_parent.get_mut(&relay_parent).unwrap().live_candidates.insert(message.candidate_hash);
+	let per_candidate = state.per_candidate.entry(message.candidate_hash).or_default();
+	per_candidate.descriptor = test_state.candidates[1].descriptor.clone();
+	per_candidate.live_in.insert(relay_parent);
+	state.peer_views.insert(peer.clone(), view![relay_parent]);
+
+	let pool = sp_core::testing::TaskExecutor::new();
+	let (mut ctx, mut virtual_overseer) =
+		test_helpers::make_subsystem_context::<AvailabilityDistributionMessage, _>(pool);
+
+	executor::block_on(
+		process_incoming_peer_message(&mut ctx, &mut state, peer.clone(), message.clone(), &Default::default())
+	).unwrap();
+
+	assert_matches!(
+		executor::block_on(overseer_recv(&mut virtual_overseer)),
+		AllMessages::NetworkBridge(NetworkBridgeMessage::ReportPeer(rep_peer, rep)) => {
+			assert_eq!(rep_peer, peer);
+			assert_eq!(rep, COST_UNKNOWN_PARA);
+		}
+	);
+	assert!(state.per_candidate[&message.candidate_hash].message_vault.is_empty());
+}
+
+#[test]
+fn deferred_chunks_of_unknown_candidates_are_bounded() {
+	let test_state = TestState::default();
+
+	let peer = PeerId::random();
+
+	let config = Config {
+		defer_unknown_candidates: true,
+		max_pending_chunks: 2,
+		..Default::default()
+	};
+	let ttl = config.pending_chunk_ttl;
+	let mut state = ProtocolState::with_config(config);
+	state.peer_views.insert(peer.clone(), view![test_state.relay_parent]);
+
+	let registry = prometheus::Registry::new();
+	let metrics = <Metrics as metrics::Metrics>::try_register(&registry).unwrap();
+	let inner = metrics.0.clone().unwrap();
+
+	let pool = sp_core::testing::TaskExecutor::new();
+	let (mut ctx, mut virtual_overseer) =
+		test_helpers::make_subsystem_context::<AvailabilityDistributionMessage, _>(pool);
+
+	// none of the candidates is known, so all chunks are held back
+	for index in 0..3 {
+		let message = make_valid_availability_gossip(&test_state, 0, index);
+		executor::block_on(
+			process_incoming_peer_message(&mut ctx, &mut state, peer.clone(), message, &metrics)
+		).unwrap();
+		assert!(state.pending_chunks.len() <= 2);
+	}
+
+	assert_eq!(state.pending_chunks.len(), 2);
+	assert_eq!(inner.pending_candidates_evicted.get(), 1);
+
+	// the peer is not penalized for sending them
+	assert!(
+		executor::block_on(overseer_recv(&mut virtual_overseer).timeout(Duration::from_millis(100))).is_none()
+	);
+
+	// all chunks expire eventually
+	assert_eq!(state.evict_expired_pending_chunks(Instant::now() + ttl), 2);
+	assert!(state.pending_chunks.is_empty());
+}
+
+#[test]
+fn deferred_chunks_of_unknown_candidates_are_bounded_by_size() {
+	let test_state = TestState::default();
+
+	let messages: Vec<_> = (0..3).map(|index| make_valid_availability_gossip(&test_state, 0, index)).collect();
+	let size = |message: &AvailabilityGossipMessage| chunk_size(&message.erasure_chunk);
+
+	let config = Config {
+		defer_unknown_candidates: true,
+		max_pending_chunk_bytes: size(&messages[1]) + size(&messages[2]),
+		..Default::default()
+	};
+	let ttl = config.pending_chunk_ttl;
+	let mut state = ProtocolState::with_config(config);
+
+	let now = Instant::now();
+	let evicted: usize = messages
+		.iter()
+		.map(|message| state.defer_chunk(PeerId::random(), message.clone(), now))
+		.sum();
+
+	// the oldest chunk made room for the last one
+	assert_eq!(evicted, 1);
+	assert_eq!(
+		state.pending_chunks.iter().map(|(_, _, message)| message.clone()).collect::<Vec<_>>(),
+		messages[1..].to_vec(),
+	);
+	assert_eq!(state.pending_chunk_bytes, size(&messages[1]) + size(&messages[2]));
+
+	assert_eq!(state.evict_expired_pending_chunks(now + ttl), 2);
+	assert_eq!(state.pending_chunk_bytes, 0);
+}
+
+#[test]
+fn peer_change_view_before_us() {
+	let test_state = TestState::default();
+
+	let peer_a = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+
+	test_harness(keystore, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			relay_parent: current,
+			validator_public,
+			ancestors,
+			candidates,
+			pov_blocks,
+			..
+		} = test_state.clone();
+
+		setup_peer_with_view(&mut virtual_overseer, peer_a.clone(), view![current]).await;
+
+		change_our_view(
+			&mut virtual_overseer,
+			view![current],
+			&validator_public,
+			vec![ancestors[0]],
+			hashmap! { current => 1 },
+			hashmap! {
+				current => vec![
+					occupied_core_from_candidate(&candidates[0]),
+				],
+			},
+			hashmap! { candidates[0].hash() => true },
+			hashmap! { candidates[0].hash() => (pov_blocks[0].clone(), test_state.persisted_validation_data.clone())},
+			hashmap! { candidates[0].hash() => vec![peer_a.clone()] },
+		).await;
+
+		let valid = make_valid_availability_gossip(
+			&test_state,
+			0,
+			0,
+		);
+
+		// We send peer a all the chunks of candidate0, so we just benefit him for sending a valid message
+		peer_send_message(&mut virtual_overseer, peer_a.clone(), valid.clone(), BENEFIT_VALID_MESSAGE).await;
+	});
+}
+
+#[test]
+fn candidate_chunks_are_put_into_message_vault_when_candidate_is_first_seen() {
+	let test_state = TestState::default();
+
+	let peer_a = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+
+	test_harness(keystore, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			relay_parent: current,
+			validator_public,
+			ancestors,
+			candidates,
+			pov_blocks,
+			..
+		} = test_state.clone();
+
+		change_our_view(
+			&mut virtual_overseer,
+			view![ancestors[0]],
+			&validator_public,
+			vec![ancestors[1]],
+			hashmap! { ancestors[0] => 1 },
+			hashmap! {
+				ancestors[0] => vec![
+					occupied_core_from_candidate(&candidates[0]),
+				],
+			},
+			hashmap! { candidates[0].hash() => true },
+			hashmap! { candidates[0].hash() => (pov_blocks[0].clone(), test_state.persisted_validation_data.clone())},
+			hashmap! {},
+		).await;
+
 		change_our_view(
 			&mut virtual_overseer,
 			view![current],
@@ -887,9 +1136,9 @@ fn candidate_chunks_are_put_into_message_vault_when_candidate_is_first_seen() {
 			validator_public.len(),
 			pov_blocks[0].clone(),
 		);
-		expect_chunks_network_message(
+		expect_batched_chunks_network_message(
 			&mut virtual_overseer,
-			&[peer_a],
+			&peer_a,
 			&[candidates[0].hash()],
 			&chunks,
 		).await;
@@ -962,10 +1211,16 @@ fn k_ancestors_in_session() {
 	};
 
 	let sut = async move {
-		let ancestors = query_up_to_k_ancestors_in_same_session(&mut ctx, DATA[0].0, K)
+		let (ancestors, session_index) = query_up_to_k_ancestors_in_same_session(
+			&mut ctx,
+			DATA[0].0,
+			K,
+			&mut Default::default(),
+		)
 			.await
 			.unwrap();
 		assert_eq!(ancestors, EXPECTED.to_vec());
+		assert_eq!(session_index, Some(DATA[0].1));
 	};
 
 	futures::pin_mut!(test_fut);
@@ -975,34 +1230,283 @@ fn k_ancestors_in_session() {
 }
 
 #[test]
-fn clean_up_receipts_cache_unions_ancestors_and_view() {
-	let mut state = ProtocolState::default();
+fn k_ancestors_in_session_stops_at_failing_session_query() {
+	let pool = sp_core::testing::TaskExecutor::new();
+	let (mut ctx, mut virtual_overseer) =
+		test_helpers::make_subsystem_context::<AvailabilityDistributionMessage, _>(pool);
 
-	let hash_a = [0u8; 32].into();
-	let hash_b = [1u8; 32].into();
-	let hash_c = [2u8; 32].into();
-	let hash_d = [3u8; 32].into();
+	const DATA: &[(Hash, SessionIndex)] = &[
+		(Hash::repeat_byte(0x32), 3), // relay parent
+		(Hash::repeat_byte(0x31), 3), // grand parent
+		(Hash::repeat_byte(0x30), 3), // great ...
+		(Hash::repeat_byte(0x29), 3),
+		(Hash::repeat_byte(0x28), 3),
+		(Hash::repeat_byte(0x27), 3),
+		(Hash::repeat_byte(0x26), 3),
+	];
+	const K: usize = 5;
 
-	state.live_under.insert(hash_a, HashSet::new());
-	state.live_under.insert(hash_b, HashSet::new());
-	state.live_under.insert(hash_c, HashSet::new());
-	state.live_under.insert(hash_d, HashSet::new());
+	// the session index of the child of `DATA[4]` can not be obtained
+	const EXPECTED: &[Hash] = &[DATA[1].0, DATA[2].0];
 
-	state.per_relay_parent.insert(hash_a, PerRelayParent {
-		ancestors: vec![hash_b],
-		live_candidates: HashSet::new(),
-	});
+	let test_fut = async move {
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::ChainApi(ChainApiMessage::Ancestors {
+				hash: relay_parent,
+				k,
+				response_channel: tx,
+			}) => {
+				assert_eq!(k, K+1);
+				assert_eq!(relay_parent, DATA[0].0);
+				tx.send(Ok(DATA[1..=k].into_iter().map(|x| x.0).collect::<Vec<_>>())).unwrap();
+			}
+		);
 
-	state.per_relay_parent.insert(hash_c, PerRelayParent::default());
+		// query the desired session index of the relay parent
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				relay_parent,
+				RuntimeApiRequest::SessionIndexForChild(tx),
+			)) => {
+				assert_eq!(relay_parent, DATA[0].0);
+				tx.send(Ok(DATA[0].1)).unwrap();
+			}
+		);
 
-	state.clean_up_live_under_cache();
+		// the third query of the walk fails
+		for i in 2usize..=4 {
+			assert_matches!(
+				overseer_recv(&mut virtual_overseer).await,
+				AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+					relay_parent,
+					RuntimeApiRequest::SessionIndexForChild(tx),
+				)) => {
+					assert_eq!(relay_parent, DATA[i].0);
+					if i < 4 {
+						tx.send(Ok(DATA[i - 1].1)).unwrap();
+					} else {
+						tx.send(Err(RuntimeApiError::from("Session index not available".to_owned()))).unwrap();
+					}
+				}
+			);
+		}
 
-	assert_eq!(state.live_under.len(), 3);
-	assert!(state.live_under.contains_key(&hash_a));
-	assert!(state.live_under.contains_key(&hash_b));
-	assert!(state.live_under.contains_key(&hash_c));
-	assert!(!state.live_under.contains_key(&hash_d));
-}
+		// the walk is not continued
+		assert!(
+			overseer_recv(&mut virtual_overseer)
+				.timeout(Duration::from_millis(100))
+				.await
+				.is_none()
+		);
+	};
+
+	let sut = async move {
+		let (ancestors, session_index) = query_up_to_k_ancestors_in_same_session(
+			&mut ctx,
+			DATA[0].0,
+			K,
+			&mut Default::default(),
+		)
+			.await
+			.unwrap();
+		assert_eq!(ancestors, EXPECTED.to_vec());
+		assert_eq!(session_index, Some(DATA[0].1));
+	};
+
+	futures::pin_mut!(test_fut);
+	futures::pin_mut!(sut);
+
+	executor::block_on(future::join(test_fut, sut).timeout(Duration::from_millis(1000)));
+}
+
+#[test]
+fn k_ancestors_in_session_with_injected_resolver() {
+	let pool = sp_core::testing::TaskExecutor::new();
+	let (mut ctx, mut virtual_overseer) =
+		test_helpers::make_subsystem_context::<AvailabilityDistributionMessage, _>(pool);
+
+	const DATA: &[(Hash, SessionIndex)] = &[
+		(Hash::repeat_byte(0x32), 3), // relay parent
+		(Hash::repeat_byte(0x31), 3), // grand parent
+		(Hash::repeat_byte(0x30), 3), // great ...
+		(Hash::repeat_byte(0x20), 2),
+		(Hash::repeat_byte(0x12), 1),
+		(Hash::repeat_byte(0x11), 1),
+		(Hash::repeat_byte(0x10), 1),
+	];
+	const K: usize = 5;
+
+	const EXPECTED: &[Hash] = &[DATA[1].0, DATA[2].0];
+
+	// the session index for the child of `DATA[i]` is the one of `DATA[i - 1]`
+	let session_index_for_child = |hash: Hash| -> SessionIndex {
+		let i = DATA.iter().position(|(h, _)| *h == hash).expect("Only known blocks are queried");
+		DATA[i.saturating_sub(1)].1
+	};
+
+	let test_fut = async move {
+		// only the ancestors are queried, session indices are not
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::ChainApi(ChainApiMessage::Ancestors {
+				hash: relay_parent,
+				k,
+				response_channel: tx,
+			}) => {
+				assert_eq!(k, K+1);
+				assert_eq!(relay_parent, DATA[0].0);
+				tx.send(Ok(DATA[1..=k].into_iter().map(|x| x.0).collect::<Vec<_>>())).unwrap();
+			}
+		);
+	};
+
+	let sut = async move {
+		let (ancestors, session_index) = query_up_to_k_ancestors_in_same_session_with(
+			&mut ctx,
+			DATA[0].0,
+			K,
+			Some(&session_index_for_child),
+			&mut Default::default(),
+		)
+			.await
+			.unwrap();
+		assert_eq!(ancestors, EXPECTED.to_vec());
+		assert_eq!(session_index, Some(DATA[0].1));
+	};
+
+	futures::pin_mut!(test_fut);
+	futures::pin_mut!(sut);
+
+	executor::block_on(future::join(test_fut, sut).timeout(Duration::from_millis(1000)));
+}
+
+#[test]
+fn ancestor_walk_requests_are_paced_by_the_token_bucket() {
+	let now = Instant::now();
+	let zero = Duration::from_secs(0);
+
+	let mut unbounded = TokenBucket::new(None);
+	for _ in 0..10 {
+		assert_eq!(unbounded.take(now), zero);
+	}
+
+	let mut bucket = TokenBucket::new(Some(2));
+
+	// a full bucket admits a burst of `rate` requests
+	assert_eq!(bucket.take(now), zero);
+	assert_eq!(bucket.take(now), zero);
+
+	// further requests queue up behind each other
+	assert_eq!(bucket.take(now), Duration::from_millis(500));
+	assert_eq!(bucket.take(now), Duration::from_secs(1));
+
+	// the debt is paid off over time
+	assert_eq!(bucket.take(now + Duration::from_secs(1)), Duration::from_millis(500));
+
+	// and the bucket refills up to its capacity only
+	let later = now + Duration::from_secs(10);
+	assert_eq!(bucket.take(later), zero);
+	assert_eq!(bucket.take(later), zero);
+	assert_eq!(bucket.take(later), Duration::from_millis(500));
+}
+
+#[test]
+fn clean_up_receipts_cache_unions_ancestors_and_view() {
+	let mut state = ProtocolState::default();
+
+	let hash_a = [0u8; 32].into();
+	let hash_b = [1u8; 32].into();
+	let hash_c = [2u8; 32].into();
+	let hash_d = [3u8; 32].into();
+
+	state.live_under.insert(hash_a, HashSet::new());
+	state.live_under.insert(hash_b, HashSet::new());
+	state.live_under.insert(hash_c, HashSet::new());
+	state.live_under.insert(hash_d, HashSet::new());
+
+	state.per_relay_parent.insert(hash_a, PerRelayParent {
+		ancestors: vec![hash_b],
+		live_candidates: HashSet::new(),
+		our_group: None,
+		group_assignment: None,
+		session_index: None,
+	});
+
+	state.per_relay_parent.insert(hash_c, PerRelayParent::default());
+
+	state.clean_up_live_under_cache();
+
+	assert_eq!(state.live_under.len(), 3);
+	assert!(state.live_under.contains_key(&hash_a));
+	assert!(state.live_under.contains_key(&hash_b));
+	assert!(state.live_under.contains_key(&hash_c));
+	assert!(!state.live_under.contains_key(&hash_d));
+}
+
+#[test]
+fn clean_ups_of_receipts_cache_are_rate_limited() {
+	let interval = Duration::from_secs(10);
+	let mut state = ProtocolState::with_config(Config { live_under_clean_up_interval: interval, ..Default::default() });
+
+	let live = [0u8; 32].into();
+	let stale = [1u8; 32].into();
+	state.per_relay_parent.insert(live, PerRelayParent::default());
+	state.live_under.insert(live, HashSet::new());
+	state.live_under.insert(stale, HashSet::new());
+
+	let start = Instant::now();
+	assert!(state.clean_up_live_under_cache_if_due(start));
+	assert!(!state.live_under.contains_key(&stale));
+
+	// rapid view changes within the interval leave the cache untouched
+	state.live_under.insert(stale, HashSet::new());
+	for secs in 1..10 {
+		assert!(!state.clean_up_live_under_cache_if_due(start + Duration::from_secs(secs)));
+	}
+	assert!(state.live_under.contains_key(&stale));
+
+	assert!(state.clean_up_live_under_cache_if_due(start + interval));
+	assert!(!state.live_under.contains_key(&stale));
+	assert!(state.live_under.contains_key(&live));
+
+	// by default, the cache is cleaned up on every view change
+	let mut state = ProtocolState::default();
+	assert!(state.clean_up_live_under_cache_if_due(start));
+	assert!(state.clean_up_live_under_cache_if_due(start));
+}
+
+#[test]
+fn with_capacity_does_not_reallocate_within_capacity() {
+	const RELAY_PARENTS: usize = 100;
+	const CANDIDATES: usize = 5000;
+
+	let mut state = ProtocolState::with_capacity(RELAY_PARENTS, CANDIDATES);
+
+	let relay_parent_capacity = state.per_relay_parent.capacity();
+	let candidate_capacity = state.per_candidate.capacity();
+	assert!(relay_parent_capacity >= RELAY_PARENTS);
+	assert!(candidate_capacity >= CANDIDATES);
+
+	for i in 0..RELAY_PARENTS {
+		let relay_parent = Hash::from_low_u64_be(i as u64);
+		state.per_relay_parent.insert(relay_parent, PerRelayParent::default());
+		state.live_under.insert(relay_parent, HashSet::new());
+	}
+	for i in 0..CANDIDATES {
+		state.per_candidate.insert(CandidateHash(Hash::from_low_u64_be(i as u64)), PerCandidate::default());
+	}
+
+	assert_eq!(state.per_relay_parent.capacity(), relay_parent_capacity);
+	assert_eq!(state.per_candidate.capacity(), candidate_capacity);
+
+	// the configured capacity is used by the subsystem
+	let config = Config { expected_relay_parents: RELAY_PARENTS, expected_candidates: CANDIDATES, ..Default::default() };
+	let state = ProtocolState::with_config(config);
+	assert!(state.live_under.capacity() >= RELAY_PARENTS);
+	assert!(state.per_candidate.capacity() >= CANDIDATES);
+}
 
 #[test]
 fn remove_relay_parent_only_removes_per_candidate_if_final() {
@@ -1016,11 +1520,17 @@ fn remove_relay_parent_only_removes_per_candidate_if_final() {
 	state.per_relay_parent.insert(hash_a, PerRelayParent {
 		ancestors: vec![],
 		live_candidates: std::iter::once(candidate_hash_a).collect(),
+		our_group: None,
+		group_assignment: None,
+		session_index: None,
 	});
 
 	state.per_relay_parent.insert(hash_b, PerRelayParent {
 		ancestors: vec![],
 		live_candidates: std::iter::once(candidate_hash_a).collect(),
+		our_group: None,
+		group_assignment: None,
+		session_index: None,
 	});
 
 	state.per_candidate.insert(candidate_hash_a, PerCandidate {
@@ -1028,18 +1538,86 @@ fn remove_relay_parent_only_removes_per_candidate_if_final() {
 		..Default::default()
 	});
 
-	state.remove_relay_parent(&hash_a);
+	state.remove_relay_parent(&hash_a, &Default::default());
 
 	assert!(!state.per_relay_parent.contains_key(&hash_a));
 	assert!(!state.per_candidate.get(&candidate_hash_a).unwrap().live_in.contains(&hash_a));
 	assert!(state.per_candidate.get(&candidate_hash_a).unwrap().live_in.contains(&hash_b));
 
-	state.remove_relay_parent(&hash_b);
+	state.remove_relay_parent(&hash_b, &Default::default());
 
 	assert!(!state.per_relay_parent.contains_key(&hash_b));
 	assert!(!state.per_candidate.contains_key(&candidate_hash_a));
 }
 
+#[test]
+fn buffered_duration_is_observed_on_eviction() {
+	let test_state = TestState::default();
+	let mut state = ProtocolState::default();
+
+	let registry = prometheus::Registry::new();
+	let metrics = <Metrics as metrics::Metrics>::try_register(&registry).unwrap();
+	let inner = metrics.0.clone().unwrap();
+
+	let relay_parent = test_state.relay_parent;
+	let candidate_hash = test_state.candidates[0].hash();
+
+	state.add_relay_parent(
+		relay_parent,
+		Default::default(),
+		None,
+		hashmap! {
+			candidate_hash => FetchedLiveCandidate::Fresh {
+				descriptor: test_state.candidates[0].descriptor.clone(),
+				time_out_at: 0,
+				group_responsible: Default::default(),
+				core_index: Default::default(),
+			},
+		},
+		vec![],
+		&Default::default(),
+	);
+	state.per_candidate
+		.get_mut(&candidate_hash)
+		.unwrap()
+		.insert_chunk(make_valid_availability_gossip(&test_state, 0, 1), true);
+
+	let buffered_for = Duration::from_millis(50);
+	std::thread::sleep(buffered_for);
+
+	state.remove_relay_parent(&relay_parent, &metrics);
+
+	assert!(!state.per_candidate.contains_key(&candidate_hash));
+	assert_eq!(inner.chunk_buffered_duration.get_sample_count(), 1);
+	assert!(inner.chunk_buffered_duration.get_sample_sum() >= buffered_for.as_secs_f64());
+}
+
+#[test]
+#[should_panic(expected = "not live in any relay parent")]
+fn assert_no_orphans_detects_candidates_without_relay_parent() {
+	let mut state = ProtocolState::default();
+
+	let relay_parent = [0u8; 32].into();
+	let candidate_hash = CandidateHash([46u8; 32].into());
+
+	state.per_relay_parent.insert(relay_parent, PerRelayParent {
+		ancestors: vec![],
+		live_candidates: std::iter::once(candidate_hash).collect(),
+		our_group: None,
+		group_assignment: None,
+		session_index: None,
+	});
+	state.per_candidate.insert(candidate_hash, PerCandidate {
+		live_in: std::iter::once(relay_parent).collect(),
+		..Default::default()
+	});
+	state.assert_no_orphans();
+
+	// a candidate which was never live
+	state.per_candidate.insert(CandidateHash([47u8; 32].into()), PerCandidate::default());
+	state.assert_no_orphans();
+}
+
 #[test]
 fn add_relay_parent_includes_all_live_candidates() {
 	let relay_parent = [0u8; 32].into();
@@ -1052,16 +1630,22 @@ fn add_relay_parent_includes_all_live_candidates() {
 	let candidate_hash_b = CandidateHash([11u8; 32].into());
 
 	let candidates = vec![
-		(candidate_hash_a, FetchedLiveCandidate::Fresh(Default::default())),
+		(candidate_hash_a, FetchedLiveCandidate::Fresh {
+			descriptor: well_formed_descriptor(),
+			time_out_at: 0,
+			group_responsible: Default::default(),
+			core_index: Default::default(),
+		}),
 		(candidate_hash_b, FetchedLiveCandidate::Cached),
 	].into_iter().collect();
 
 	state.add_relay_parent(
 		relay_parent,
-		Vec::new(),
+		Default::default(),
 		None,
 		candidates,
 		vec![ancestor_a],
+		&Default::default(),
 	);
 
 	assert!(
@@ -1073,166 +1657,5119 @@ fn add_relay_parent_includes_all_live_candidates() {
 
 	let per_relay_parent = state.per_relay_parent.get(&relay_parent).unwrap();
 
-	assert!(per_relay_parent.live_candidates.contains(&candidate_hash_a));
-	assert!(per_relay_parent.live_candidates.contains(&candidate_hash_b));
+	assert!(per_relay_parent.contains_candidate(&candidate_hash_a));
+	assert!(per_relay_parent.contains_candidate(&candidate_hash_b));
 }
 
 #[test]
-fn query_pending_availability_at_pulls_from_and_updates_receipts() {
-	let hash_a = [0u8; 32].into();
-	let hash_b = [1u8; 32].into();
-
-	let para_a = ParaId::from(1);
-	let para_b = ParaId::from(2);
-	let para_c = ParaId::from(3);
+fn candidates_of_paras_not_allowed_are_not_tracked() {
+	let test_state = TestState::default();
 
-	let make_candidate = |para_id| {
-		let mut candidate = CommittedCandidateReceipt::default();
-		candidate.descriptor.para_id = para_id;
-		candidate.descriptor.relay_parent = [69u8; 32].into();
-		candidate
+	let relay_parent = test_state.relay_parent;
+	let config = Config {
+		para_allowlist: Some(std::iter::once(test_state.chain_ids[0]).collect()),
+		..Default::default()
 	};
+	let mut state = ProtocolState::with_config(config);
+
+	let candidates = test_state.candidates
+		.iter()
+		.map(|candidate| (candidate.hash(), FetchedLiveCandidate::Fresh {
+			descriptor: candidate.descriptor.clone(),
+			time_out_at: 5,
+			group_responsible: Default::default(),
+			core_index: Default::default(),
+		}))
+		.collect();
 
-	let candidate_a = make_candidate(para_a);
-	let candidate_b = make_candidate(para_b);
-	let candidate_c = make_candidate(para_c);
+	state.add_relay_parent(
+		relay_parent,
+		Default::default(),
+		Some(0),
+		candidates,
+		vec![],
+		&Default::default(),
+	);
 
-	let candidate_hash_a = candidate_a.hash();
-	let candidate_hash_b = candidate_b.hash();
-	let candidate_hash_c = candidate_c.hash();
+	let allowed = test_state.candidates[0].hash();
+	let filtered = test_state.candidates[1].hash();
 
-	// receipts has an initial entry for hash_a but not hash_b.
-	let mut receipts = HashMap::new();
-	receipts.insert(hash_a, vec![candidate_hash_a, candidate_hash_b].into_iter().collect());
+	assert!(state.is_candidate_live(&allowed));
+	assert!(!state.is_candidate_live(&filtered));
+	assert!(!state.per_candidate.contains_key(&filtered));
+	assert_eq!(
+		state.per_relay_parent[&relay_parent].live_candidates,
+		std::iter::once(allowed).collect(),
+	);
 
-	let pool = sp_core::testing::TaskExecutor::new();
+	// the candidate stays filtered when it is live under another relay parent
+	state.add_relay_parent(
+		test_state.ancestors[0],
+		Default::default(),
+		Some(0),
+		vec![(allowed, FetchedLiveCandidate::Cached), (filtered, FetchedLiveCandidate::Cached)].into_iter().collect(),
+		vec![],
+		&Default::default(),
+	);
 
-	let (mut ctx, mut virtual_overseer) =
-		test_helpers::make_subsystem_context::<AvailabilityDistributionMessage, _>(pool);
+	assert!(state.per_candidate[&allowed].live_in.contains(&test_state.ancestors[0]));
+	assert!(!state.per_candidate.contains_key(&filtered));
+}
 
-	let test_fut = async move {
-		let live_candidates = query_pending_availability_at(
-			&mut ctx,
-			vec![hash_a, hash_b],
-			&mut receipts,
-		).await.unwrap();
+#[test]
+fn relay_parent_contains_only_its_live_candidates() {
+	let live = CandidateHash([10u8; 32].into());
+	let other = CandidateHash([11u8; 32].into());
 
-		// although 'b' is cached from the perspective of hash_a, it gets overwritten when we query what's happening in
-		//
-		assert_eq!(live_candidates.len(), 3);
-		assert_matches!(live_candidates.get(&candidate_hash_a).unwrap(), FetchedLiveCandidate::Cached);
-		assert_matches!(live_candidates.get(&candidate_hash_b).unwrap(), FetchedLiveCandidate::Cached);
-		assert_matches!(live_candidates.get(&candidate_hash_c).unwrap(), FetchedLiveCandidate::Fresh(_));
+	let per_relay_parent = PerRelayParent {
+		live_candidates: std::iter::once(live).collect(),
+		..Default::default()
+	};
 
-		assert!(receipts.get(&hash_b).unwrap().contains(&candidate_hash_b));
-		assert!(receipts.get(&hash_b).unwrap().contains(&candidate_hash_c));
+	assert!(per_relay_parent.contains_candidate(&live));
+	assert!(!per_relay_parent.contains_candidate(&other));
+	assert!(!PerRelayParent::default().contains_candidate(&live));
+}
+
+#[test]
+fn live_candidates_stay_consistent_through_adds_and_removes() {
+	let mut state = ProtocolState::default();
+
+	let relay_parents: Vec<Hash> = (0u8..3).map(|i| [i; 32].into()).collect();
+	let candidate_hashes: Vec<CandidateHash> = (10u8..14).map(|i| CandidateHash([i; 32].into())).collect();
+
+	let fresh = || FetchedLiveCandidate::Fresh {
+		descriptor: well_formed_descriptor(),
+		time_out_at: 0,
+		group_responsible: Default::default(),
+		core_index: Default::default(),
+	};
+	let add = |state: &mut ProtocolState, relay_parent: Hash, candidates: &[CandidateHash]| {
+		state.add_relay_parent(
+			relay_parent,
+			Default::default(),
+			None,
+			candidates.iter().map(|c| (*c, fresh())).collect(),
+			vec![],
+			&Default::default(),
+		);
 	};
 
-	let answer = async move {
-		// hash_a should be answered out of cache, so we should just have
-		// queried for hash_b.
-		assert_matches!(
-			overseer_recv(&mut virtual_overseer).await,
-			AllMessages::RuntimeApi(
-				RuntimeApiMessage::Request(
-					r,
-					RuntimeApiRequest::AvailabilityCores(tx),
-				)
-			) if r == hash_b => {
-				let _ = tx.send(Ok(vec![
-					CoreState::Occupied(OccupiedCore {
-						next_up_on_available: None,
-						occupied_since: 0,
-						time_out_at: 0,
-						next_up_on_time_out: None,
-						availability: Default::default(),
-						group_responsible: GroupIndex::from(0),
-						candidate_hash: candidate_hash_b,
-						candidate_descriptor: candidate_b.descriptor.clone(),
-					}),
-					CoreState::Occupied(OccupiedCore {
-						next_up_on_available: None,
-						occupied_since: 0,
-						time_out_at: 0,
-						next_up_on_time_out: None,
-						availability: Default::default(),
-						group_responsible: GroupIndex::from(0),
-						candidate_hash: candidate_hash_c,
-						candidate_descriptor: candidate_c.descriptor.clone(),
-					}),
-				]));
+	let assert_consistent = |state: &ProtocolState| {
+		for relay_parent in relay_parents.iter() {
+			for candidate_hash in candidate_hashes.iter() {
+				let live_in = state.per_candidate
+					.get(candidate_hash)
+					.map_or(false, |per_candidate| per_candidate.live_in.contains(relay_parent));
+				let live_candidate = state.per_relay_parent
+					.get(relay_parent)
+					.map_or(false, |per_relay_parent| per_relay_parent.live_candidates.contains(candidate_hash));
+
+				assert_eq!(live_in, live_candidate);
+				assert_eq!(live_candidate, state.is_candidate_live_in(candidate_hash, iter::once(relay_parent)));
 			}
-		);
+		}
 	};
 
-	futures::pin_mut!(test_fut);
-	futures::pin_mut!(answer);
+	add(&mut state, relay_parents[0], &candidate_hashes[0..2]);
+	add(&mut state, relay_parents[1], &candidate_hashes[1..3]);
+	assert_consistent(&state);
 
-	executor::block_on(future::join(test_fut, answer));
+	state.remove_relay_parent(&relay_parents[0], &Default::default());
+	add(&mut state, relay_parents[2], &candidate_hashes[2..4]);
+	assert_consistent(&state);
+
+	assert!(state.is_candidate_live_in(&candidate_hashes[1], relay_parents.iter()));
+	assert!(!state.is_candidate_live_in(&candidate_hashes[0], relay_parents.iter()));
+
+	state.remove_relay_parent(&relay_parents[1], &Default::default());
+	state.remove_relay_parent(&relay_parents[2], &Default::default());
+	assert_consistent(&state);
+	assert!(state.per_candidate.is_empty());
 }
 
 #[test]
-fn new_peer_gets_all_chunks_send() {
+fn adding_a_relay_parent_again_leaves_its_candidates_unchanged() {
 	let test_state = TestState::default();
 
-	let peer_a = PeerId::random();
-	let peer_b = PeerId::random();
-	assert_ne!(&peer_a, &peer_b);
-
-	let keystore = test_state.keystore.clone();
+	let relay_parent = test_state.relay_parent;
+	let candidates = || test_state.candidates
+		.iter()
+		.map(|candidate| (candidate.hash(), FetchedLiveCandidate::Fresh {
+			descriptor: candidate.descriptor.clone(),
+			time_out_at: 5,
+			group_responsible: Default::default(),
+			core_index: Default::default(),
+		}))
+		.collect::<HashMap<_, _>>();
 
-	test_harness(keystore, move |test_harness| async move {
-		let mut virtual_overseer = test_harness.virtual_overseer;
+	let mut state = ProtocolState::default();
+	state.add_relay_parent(
+		relay_parent,
+		Default::default(),
+		Some(0),
+		candidates(),
+		vec![],
+		&Default::default(),
+	);
+	let expected = state.clone();
+
+	// the candidates were learned at the relay parent with another validator index
+	state.add_relay_parent(
+		relay_parent,
+		Default::default(),
+		Some(1),
+		candidates(),
+		vec![],
+		&Default::default(),
+	);
+
+	assert_eq!(state, expected);
+	assert_eq!(
+		state.per_relay_parent[&relay_parent].live_candidates,
+		test_state.candidates.iter().map(|candidate| candidate.hash()).collect(),
+	);
+	for candidate in test_state.candidates.iter() {
+		let per_candidate = &state.per_candidate[&candidate.hash()];
+		assert_eq!(per_candidate.live_in, std::iter::once(relay_parent).collect());
+		assert_eq!(per_candidate.validator_index, Some(0));
+	}
+}
+
+#[test]
+fn candidates_timed_out_before_the_relay_parent_are_not_tracked() {
+	let mut state = ProtocolState::default();
+
+	let relay_parent = Hash::repeat_byte(0x01);
+	let timed_out = CandidateHash(Hash::repeat_byte(0x0A));
+	let pending = CandidateHash(Hash::repeat_byte(0x0B));
+
+	state.note_leaf(relay_parent, 10);
+
+	let fresh = |time_out_at| FetchedLiveCandidate::Fresh {
+		descriptor: well_formed_descriptor(),
+		time_out_at,
+		group_responsible: Default::default(),
+		core_index: Default::default(),
+	};
+	let skipped = state.add_relay_parent(
+		relay_parent,
+		Default::default(),
+		None,
+		hashmap! { timed_out => fresh(9), pending => fresh(11) },
+		vec![],
+		&Default::default(),
+	);
+
+	assert_eq!(skipped, 1);
+	assert!(!state.per_candidate.contains_key(&timed_out));
+	assert!(state.per_candidate.contains_key(&pending));
+	assert_eq!(
+		state.per_relay_parent[&relay_parent].live_candidates,
+		std::iter::once(pending).collect(),
+	);
+}
+
+#[test]
+fn candidates_timing_out_at_the_relay_parent_are_not_tracked() {
+	let mut state = ProtocolState::default();
+
+	let relay_parent = Hash::repeat_byte(0x01);
+	let candidate_hash = CandidateHash(Hash::repeat_byte(0x0A));
+
+	state.note_leaf(relay_parent, 10);
+
+	let skipped = state.add_relay_parent(
+		relay_parent,
+		Default::default(),
+		None,
+		hashmap! {
+			candidate_hash => FetchedLiveCandidate::Fresh {
+				descriptor: well_formed_descriptor(),
+				time_out_at: 10,
+				group_responsible: Default::default(),
+				core_index: Default::default(),
+			},
+		},
+		vec![],
+		&Default::default(),
+	);
+
+	// the candidate is skipped exactly when it counts as timed out once tracked
+	assert_eq!(skipped, 1);
+	assert!(!state.per_candidate.contains_key(&candidate_hash));
+	assert!(state.per_relay_parent[&relay_parent].live_candidates.is_empty());
+}
+
+#[test]
+fn diverging_erasure_roots_of_a_candidate_are_detected() {
+	let test_state = TestState::default();
+
+	let registry = prometheus::Registry::new();
+	let metrics = <Metrics as metrics::Metrics>::try_register(&registry).unwrap();
+	let inner = metrics.0.clone().unwrap();
+
+	let relay_parent = test_state.relay_parent;
+	let ancestor = test_state.ancestors[0];
+	let candidate = &test_state.candidates[0];
+	let candidate_hash = candidate.hash();
+
+	let fresh = |erasure_root| FetchedLiveCandidate::Fresh {
+		descriptor: CandidateDescriptor { erasure_root, ..candidate.descriptor.clone() },
+		time_out_at: 10,
+		group_responsible: Default::default(),
+		core_index: Default::default(),
+	};
+
+	let mut state = ProtocolState::default();
+	state.add_relay_parent(
+		relay_parent,
+		Default::default(),
+		None,
+		hashmap! { candidate_hash => fresh(candidate.descriptor.erasure_root) },
+		vec![],
+		&metrics,
+	);
+	state.add_relay_parent(
+		ancestor,
+		Default::default(),
+		None,
+		hashmap! { candidate_hash => fresh(Hash::repeat_byte(0xEE)) },
+		vec![],
+		&metrics,
+	);
+
+	assert_eq!(inner.erasure_root_mismatches.get(), 1);
+	let per_candidate = &state.per_candidate[&candidate_hash];
+	assert_eq!(per_candidate.descriptor.erasure_root, candidate.descriptor.erasure_root);
+	assert_eq!(per_candidate.live_in, vec![relay_parent, ancestor].into_iter().collect());
+
+	// the same root at yet another relay parent is fine
+	state.add_relay_parent(
+		Hash::repeat_byte(0xDD),
+		Default::default(),
+		None,
+		hashmap! { candidate_hash => fresh(candidate.descriptor.erasure_root) },
+		vec![],
+		&metrics,
+	);
+	assert_eq!(inner.erasure_root_mismatches.get(), 1);
+}
+
+#[test]
+fn independently_built_states_compare_equal() {
+	let test_state = TestState::default();
+
+	let relay_parent = test_state.relay_parent;
+	let ancestor = test_state.ancestors[0];
+
+	let build = |relay_parents: &[Hash], chunk_indices: &[u32], config: Config| {
+		let mut state = ProtocolState::with_config(config);
+		state.view = view![relay_parent];
+		for relay_parent in relay_parents {
+			state.add_relay_parent(
+				*relay_parent,
+				Default::default(),
+				Some(0),
+				test_state.candidates
+					.iter()
+					.map(|candidate| (candidate.hash(), FetchedLiveCandidate::Fresh {
+						descriptor: candidate.descriptor.clone(),
+						time_out_at: 10,
+						group_responsible: Default::default(),
+						core_index: Default::default(),
+					}))
+					.collect(),
+				vec![],
+				&Default::default(),
+			);
+		}
+		for index in chunk_indices {
+			let per_candidate = state.per_candidate.get_mut(&test_state.candidates[0].hash()).unwrap();
+			per_candidate.insert_chunk(make_valid_availability_gossip(&test_state, 0, *index), true);
+		}
+		state
+	};
+
+	let state_a = build(&[relay_parent, ancestor], &[1, 2], Config::default());
+	let mut state_b = build(
+		&[ancestor, relay_parent],
+		&[2, 1],
+		Config { max_gossip_fanout: Some(2), ..Default::default() },
+	);
+	state_b.peer_views.insert(PeerId::random(), view![relay_parent]);
+
+	assert_eq!(state_a, state_b);
+
+	let state_c = build(&[relay_parent, ancestor], &[1], Config::default());
+	assert_ne!(state_a, state_c);
+}
+
+#[test]
+fn unresponsive_peers_are_skipped_as_chunk_sources_until_cooldown() {
+	let config = Config {
+		chunk_request_retry_budget: 2,
+		chunk_source_cooldown: Duration::from_secs(10),
+		..Default::default()
+	};
+	let mut state = ProtocolState::with_config(config);
+
+	let unresponsive = PeerId::random();
+	let responsive = PeerId::random();
+	state.peer_views.insert(unresponsive.clone(), View::default());
+	state.peer_views.insert(responsive.clone(), View::default());
+
+	let sources = |state: &ProtocolState, now| state.chunk_sources(now).into_iter().collect::<HashSet<_>>();
+	let all: HashSet<_> = vec![unresponsive.clone(), responsive.clone()].into_iter().collect();
+
+	let now = Instant::now();
+	state.note_unanswered_chunk_request(&unresponsive, now);
+	state.note_unanswered_chunk_request(&responsive, now);
+	state.note_answered_chunk_request(&responsive);
+	state.note_unanswered_chunk_request(&responsive, now);
+	assert_eq!(sources(&state, now), all);
+
+	// the budget is exhausted
+	state.note_unanswered_chunk_request(&unresponsive, now);
+	assert_eq!(sources(&state, now), std::iter::once(responsive.clone()).collect());
+
+	// failures during the cooldown do not extend it
+	let later = now + Duration::from_secs(5);
+	state.note_unanswered_chunk_request(&unresponsive, later);
+	assert_eq!(sources(&state, later), std::iter::once(responsive.clone()).collect());
+
+	let after_cooldown = now + Duration::from_secs(10);
+	assert_eq!(sources(&state, after_cooldown), all);
+
+	// a fresh budget is granted after the cooldown
+	state.note_unanswered_chunk_request(&unresponsive, after_cooldown);
+	assert_eq!(sources(&state, after_cooldown), all);
+}
+
+#[test]
+fn own_chunk_is_requested_anew_from_other_peers_if_unanswered() {
+	async fn expect_own_chunk_request(
+		virtual_overseer: &mut test_helpers::TestSubsystemContextHandle<AvailabilityDistributionMessage>,
+		peer: &PeerId,
+		candidate_hash: CandidateHash,
+	) {
+		assert_matches!(
+			overseer_recv(virtual_overseer).await,
+			AllMessages::NetworkBridge(NetworkBridgeMessage::SendValidationMessage(
+				peers,
+				protocol_v1::ValidationProtocol::AvailabilityDistribution(
+					protocol_v1::AvailabilityDistributionMessage::RequestChunk(hash, index),
+				),
+			)) => {
+				assert_eq!(&peers, &[peer.clone()]);
+				assert_eq!(hash, candidate_hash);
+				assert_eq!(index, 0);
+			}
+		);
+	}
+
+	let test_state = TestState::default();
+
+	let peer_a = PeerId::random();
+	let peer_a_2 = peer_a.clone();
+	let peer_b = PeerId::random();
+	let peer_b_2 = peer_b.clone();
+	let candidate_hash = test_state.candidates[0].hash();
+
+	let keystore = test_state.keystore.clone();
+
+	let config = Config {
+		request_own_chunks: true,
+		chunk_request_timeout: Duration::from_secs(0),
+		chunk_request_retry_budget: 1,
+		..Default::default()
+	};
+
+	let state = test_harness_with_config(keystore, config, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			relay_parent: current,
+			validator_public,
+			ancestors,
+			candidates,
+			..
+		} = test_state.clone();
+
+		setup_peer_with_view(&mut virtual_overseer, peer_a.clone(), view![current]).await;
+
+		// the store lacks our chunk, so it is requested
+		change_our_view(
+			&mut virtual_overseer,
+			view![current],
+			&validator_public,
+			vec![ancestors[0]],
+			hashmap! { current => 1 },
+			hashmap! { current => vec![occupied_core_from_candidate(&candidates[0])] },
+			hashmap! { candidate_hash => false },
+			hashmap! {},
+			hashmap! {},
+		).await;
+		expect_own_chunk_request(&mut virtual_overseer, &peer_a, candidate_hash).await;
+
+		// peer a exhausted its budget by not answering, and no other peer is left
+		overseer_send(&mut virtual_overseer, NetworkBridgeEvent::OurViewChange(view![current])).await;
+		assert!(
+			overseer_recv(&mut virtual_overseer)
+				.timeout(Duration::from_millis(100))
+				.await
+				.is_none()
+		);
+
+		// until peer b connects
+		setup_peer_with_view(&mut virtual_overseer, peer_b.clone(), view![current]).await;
+		overseer_send(&mut virtual_overseer, NetworkBridgeEvent::OurViewChange(view![current])).await;
+		expect_own_chunk_request(&mut virtual_overseer, &peer_b, candidate_hash).await;
+
+		let own_chunk = make_valid_availability_gossip(&test_state, 0, 0);
+		peer_send_message(&mut virtual_overseer, peer_b.clone(), own_chunk, BENEFIT_VALID_MESSAGE_FIRST).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::AvailabilityStore(AvailabilityStoreMessage::StoreChunk { candidate_hash: hash, validator_index, tx, .. }) => {
+				assert_eq!(hash, candidate_hash);
+				assert_eq!(validator_index, 0);
+				tx.send(Ok(())).unwrap();
+			}
+		);
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::NetworkBridge(NetworkBridgeMessage::SendPrioritizedValidationMessage(peers, _, _)) => {
+				assert_eq!(peers, vec![peer_a.clone()]);
+			}
+		);
+	});
+
+	let now = Instant::now();
+	assert!(state.own_chunk_requests.is_empty());
+	assert!(state.is_chunk_source(&peer_b_2, now));
+	assert!(!state.is_chunk_source(&peer_a_2, now));
+	assert!(state.is_chunk_source(&peer_a_2, now + state.config.chunk_source_cooldown));
+}
+
+#[test]
+fn clearing_message_vaults_preserves_bookkeeping() {
+	let test_state = TestState::default();
+	let mut state = ProtocolState::default();
+
+	let relay_parent = test_state.relay_parent;
+	let peer = PeerId::random();
+
+	state.add_relay_parent(
+		relay_parent,
+		Default::default(),
+		Some(0),
+		test_state.candidates
+			.iter()
+			.map(|candidate| (candidate.hash(), FetchedLiveCandidate::Fresh {
+				descriptor: candidate.descriptor.clone(),
+				time_out_at: 0,
+				group_responsible: Default::default(),
+				core_index: Default::default(),
+			}))
+			.collect(),
+		vec![],
+		&Default::default(),
+	);
+
+	for (candidate_idx, candidate) in test_state.candidates.iter().enumerate() {
+		let per_candidate = state.per_candidate.get_mut(&candidate.hash()).unwrap();
+		per_candidate.insert_chunk(make_valid_availability_gossip(&test_state, candidate_idx, 1), true);
+		per_candidate.insert_chunk(make_valid_availability_gossip(&test_state, candidate_idx, 2), false);
+		per_candidate.received_messages.entry(peer.clone()).or_default().insert(1);
+	}
+	assert_eq!(state.chunk_count(), 2 * test_state.candidates.len());
+
+	let live_candidates = state.per_relay_parent[&relay_parent].live_candidates.clone();
+	let candidate_hashes: HashSet<_> = state.per_candidate.keys().cloned().collect();
+
+	state.clear_message_vaults(&Default::default());
+
+	assert_eq!(state.chunk_count(), 0);
+	assert_eq!(state.per_candidate.keys().cloned().collect::<HashSet<_>>(), candidate_hashes);
+	assert_eq!(state.per_relay_parent[&relay_parent].live_candidates, live_candidates);
+	for candidate in test_state.candidates.iter() {
+		let per_candidate = &state.per_candidate[&candidate.hash()];
+		assert!(per_candidate.message_vault.is_empty());
+		assert!(per_candidate.ready_to_serve.is_empty());
+		assert_eq!(per_candidate.descriptor, candidate.descriptor);
+		assert_eq!(per_candidate.live_in, vec![relay_parent].into_iter().collect());
+		assert_eq!(per_candidate.validator_index, Some(0));
+		assert!(!per_candidate.message_required_by_peer(&peer, &1));
+	}
+}
+
+#[test]
+fn validate_and_repair_restores_a_consistent_state() {
+	let relay_parent_a = Hash::repeat_byte(0x0A);
+	let relay_parent_b = Hash::repeat_byte(0x0B);
+	let untracked = Hash::repeat_byte(0x0F);
+	let candidate = |i: u8| CandidateHash(Hash::repeat_byte(i));
+
+	let build = || {
+		let mut state = ProtocolState::default();
+		let fresh = || FetchedLiveCandidate::Fresh {
+			descriptor: well_formed_descriptor(),
+			time_out_at: 0,
+			group_responsible: Default::default(),
+			core_index: Default::default(),
+		};
+		state.add_relay_parent(
+			relay_parent_a,
+			Default::default(),
+			None,
+			hashmap! { candidate(1) => fresh(), candidate(2) => fresh() },
+			vec![],
+			&Default::default(),
+		);
+		state.add_relay_parent(
+			relay_parent_b,
+			Default::default(),
+			None,
+			hashmap! { candidate(2) => fresh(), candidate(3) => fresh() },
+			vec![],
+			&Default::default(),
+		);
+		state
+	};
+
+	let expected = build();
+	let mut state = build();
+	assert_eq!(state.validate_and_repair(), RepairReport::default());
+
+	state.per_candidate.get_mut(&candidate(1)).unwrap().live_in.insert(untracked);
+	state.per_relay_parent.get_mut(&relay_parent_a).unwrap().live_candidates.remove(&candidate(2));
+	state.per_candidate.get_mut(&candidate(3)).unwrap().live_in.remove(&relay_parent_b);
+	state.per_relay_parent.get_mut(&relay_parent_b).unwrap().live_candidates.insert(candidate(9));
+	state.per_candidate.insert(candidate(8), PerCandidate::default());
+	state.per_candidate.insert(candidate(7), PerCandidate {
+		live_in: std::iter::once(untracked).collect(),
+		..Default::default()
+	});
+
+	let report = state.validate_and_repair();
+
+	let set = |items: Vec<_>| items.into_iter().collect::<HashSet<_>>();
+	assert_eq!(set(report.removed_orphans), set(vec![candidate(7), candidate(8)]));
+	assert_eq!(
+		set(report.removed_live_in),
+		set(vec![(candidate(1), untracked), (candidate(7), untracked)]),
+	);
+	assert_eq!(report.restored_live_candidates, vec![(relay_parent_a, candidate(2))]);
+	assert_eq!(report.restored_live_in, vec![(candidate(3), relay_parent_b)]);
+	assert_eq!(report.removed_live_candidates, vec![(relay_parent_b, candidate(9))]);
+
+	assert_eq!(state, expected);
+	assert_eq!(state.validate_and_repair(), RepairReport::default());
+}
+
+#[test]
+fn query_pending_availability_at_pulls_from_and_updates_receipts() {
+	let hash_a = [0u8; 32].into();
+	let hash_b = [1u8; 32].into();
+
+	let para_a = ParaId::from(1);
+	let para_b = ParaId::from(2);
+	let para_c = ParaId::from(3);
+
+	let make_candidate = |para_id| {
+		let mut candidate = CommittedCandidateReceipt::default();
+		candidate.descriptor.para_id = para_id;
+		candidate.descriptor.relay_parent = [69u8; 32].into();
+		candidate
+	};
+
+	let candidate_a = make_candidate(para_a);
+	let candidate_b = make_candidate(para_b);
+	let candidate_c = make_candidate(para_c);
+
+	let candidate_hash_a = candidate_a.hash();
+	let candidate_hash_b = candidate_b.hash();
+	let candidate_hash_c = candidate_c.hash();
+
+	// receipts has an initial entry for hash_a but not hash_b.
+	let mut receipts = HashMap::new();
+	receipts.insert(hash_a, vec![candidate_hash_a, candidate_hash_b].into_iter().collect());
+
+	let pool = sp_core::testing::TaskExecutor::new();
+
+	let (mut ctx, mut virtual_overseer) =
+		test_helpers::make_subsystem_context::<AvailabilityDistributionMessage, _>(pool);
+
+	let test_fut = async move {
+		let live_candidates = query_pending_availability_at(
+			&mut ctx,
+			vec![hash_a, hash_b],
+			&mut receipts,
+			&Default::default(),
+		).await.unwrap();
+
+		// although 'b' is cached from the perspective of hash_a, it gets overwritten when we query what's happening in
+		//
+		assert_eq!(live_candidates.len(), 3);
+		assert_matches!(live_candidates.get(&candidate_hash_a).unwrap(), FetchedLiveCandidate::Cached);
+		assert_matches!(live_candidates.get(&candidate_hash_b).unwrap(), FetchedLiveCandidate::Cached);
+		assert_matches!(live_candidates.get(&candidate_hash_c).unwrap(), FetchedLiveCandidate::Fresh { .. });
+
+		assert!(receipts.get(&hash_b).unwrap().contains(&candidate_hash_b));
+		assert!(receipts.get(&hash_b).unwrap().contains(&candidate_hash_c));
+	};
+
+	let answer = async move {
+		// hash_a should be answered out of cache, so we should just have
+		// queried for hash_b.
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(
+				RuntimeApiMessage::Request(
+					r,
+					RuntimeApiRequest::AvailabilityCores(tx),
+				)
+			) if r == hash_b => {
+				let _ = tx.send(Ok(vec![
+					CoreState::Occupied(OccupiedCore {
+						next_up_on_available: None,
+						occupied_since: 0,
+						time_out_at: 0,
+						next_up_on_time_out: None,
+						availability: Default::default(),
+						group_responsible: GroupIndex::from(0),
+						candidate_hash: candidate_hash_b,
+						candidate_descriptor: candidate_b.descriptor.clone(),
+					}),
+					CoreState::Occupied(OccupiedCore {
+						next_up_on_available: None,
+						occupied_since: 0,
+						time_out_at: 0,
+						next_up_on_time_out: None,
+						availability: Default::default(),
+						group_responsible: GroupIndex::from(0),
+						candidate_hash: candidate_hash_c,
+						candidate_descriptor: candidate_c.descriptor.clone(),
+					}),
+				]));
+			}
+		);
+	};
+
+	futures::pin_mut!(test_fut);
+	futures::pin_mut!(answer);
+
+	executor::block_on(future::join(test_fut, answer));
+}
+
+#[test]
+fn occupied_paras_skips_free_and_scheduled_cores() {
+	let test_state = TestState::default();
+
+	let occupied = |candidate: &CommittedCandidateReceipt, group: u32, time_out_at: BlockNumber| {
+		CoreState::Occupied(OccupiedCore {
+			next_up_on_available: None,
+			occupied_since: 0,
+			time_out_at,
+			next_up_on_time_out: None,
+			availability: Default::default(),
+			group_responsible: GroupIndex::from(group),
+			candidate_hash: candidate.hash(),
+			candidate_descriptor: candidate.descriptor.clone(),
+		})
+	};
+
+	let cores = vec![
+		CoreState::Free,
+		occupied(&test_state.candidates[0], 1, 10),
+		test_state.availability_cores[0].clone(),
+		CoreState::Free,
+		occupied(&test_state.candidates[1], 2, 20),
+		test_state.availability_cores[1].clone(),
+	];
+
+	let paras: Vec<_> = occupied_paras(&cores)
+		.into_iter()
+		.map(|(para_id, group, time_out_at)| (para_id, group.0, time_out_at))
+		.collect();
+	assert_eq!(
+		paras,
+		vec![(test_state.chain_ids[0], 1, 10), (test_state.chain_ids[1], 2, 20)],
+	);
+}
+
+#[test]
+fn candidates_without_an_erasure_root_are_not_tracked() {
+	let test_state = TestState::default();
+
+	let relay_parent = test_state.relay_parent;
+	let candidate_hash = test_state.candidates[0].hash();
+
+	let mut malformed = test_state.candidates[1].descriptor.clone();
+	malformed.erasure_root = Hash::default();
+	let malformed_hash = test_state.candidates[1].hash();
+
+	let registry = prometheus::Registry::new();
+	let metrics = <Metrics as metrics::Metrics>::try_register(&registry).unwrap();
+	let inner = metrics.0.clone().unwrap();
+
+	let fresh = |descriptor| FetchedLiveCandidate::Fresh {
+		descriptor,
+		time_out_at: 10,
+		group_responsible: Default::default(),
+		core_index: Default::default(),
+	};
+
+	let mut state = ProtocolState::default();
+	state.live_under.insert(relay_parent, vec![candidate_hash, malformed_hash].into_iter().collect());
+	state.add_relay_parent(
+		relay_parent,
+		Default::default(),
+		None,
+		hashmap! {
+			candidate_hash => fresh(test_state.candidates[0].descriptor.clone()),
+			malformed_hash => fresh(malformed),
+		},
+		vec![],
+		&metrics,
+	);
+
+	assert!(state.per_relay_parent[&relay_parent].contains_candidate(&candidate_hash));
+	assert!(!state.per_relay_parent[&relay_parent].contains_candidate(&malformed_hash));
+	assert!(!state.per_candidate.contains_key(&malformed_hash));
+	assert_eq!(inner.malformed_candidates.get(), 1);
+
+	// nor can they be revived from the cache
+	assert!(!state.live_under[&relay_parent].contains(&malformed_hash));
+	assert!(state.live_under[&relay_parent].contains(&candidate_hash));
+}
+
+#[test]
+fn candidates_of_different_paras_sharing_a_hash_keep_the_first() {
+	let test_state = TestState::default();
+
+	let relay_parent = test_state.relay_parent;
+	let ancestor = test_state.ancestors[0];
+	let candidate = &test_state.candidates[0];
+	let candidate_hash = candidate.hash();
+
+	// a candidate of another para claiming the same hash
+	let mut impostor = candidate.descriptor.clone();
+	impostor.para_id = test_state.chain_ids[1];
+
+	let registry = prometheus::Registry::new();
+	let metrics = <Metrics as metrics::Metrics>::try_register(&registry).unwrap();
+	let inner = metrics.0.clone().unwrap();
+
+	let pool = sp_core::testing::TaskExecutor::new();
+	let (mut ctx, mut virtual_overseer) =
+		test_helpers::make_subsystem_context::<AvailabilityDistributionMessage, _>(pool);
+
+	let impostor_core = match occupied_core_from_candidate(candidate) {
+		CoreState::Occupied(core) => CoreState::Occupied(OccupiedCore { candidate_descriptor: impostor.clone(), ..core }),
+		_ => unreachable!("Cores of candidates are occupied; qed"),
+	};
+	let cores = vec![occupied_core_from_candidate(candidate), impostor_core];
+
+	let test_fut = query_pending_availability(&mut ctx, relay_parent, &metrics);
+	let answer = async move {
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(
+				RuntimeApiMessage::Request(r, RuntimeApiRequest::AvailabilityCores(tx))
+			) if r == relay_parent => {
+				tx.send(Ok(cores)).unwrap();
+			}
+		);
+	};
+
+	let (pending, _) = executor::block_on(future::join(test_fut, answer));
+	let pending: Vec<_> = pending.unwrap().into_iter().map(|(hash, descriptor, ..)| (hash, descriptor)).collect();
+	assert_eq!(pending, vec![(candidate_hash, candidate.descriptor.clone())]);
+	assert_eq!(inner.candidate_hash_collisions.get(), 1);
+
+	// the same holds across relay parents
+	let fresh = |descriptor| FetchedLiveCandidate::Fresh {
+		descriptor,
+		time_out_at: 10,
+		group_responsible: Default::default(),
+		core_index: Default::default(),
+	};
+
+	let mut state = ProtocolState::default();
+	state.add_relay_parent(
+		relay_parent,
+		Default::default(),
+		None,
+		hashmap! { candidate_hash => fresh(candidate.descriptor.clone()) },
+		vec![],
+		&metrics,
+	);
+	state.add_relay_parent(
+		ancestor,
+		Default::default(),
+		None,
+		hashmap! { candidate_hash => fresh(impostor) },
+		vec![],
+		&metrics,
+	);
+
+	assert_eq!(inner.candidate_hash_collisions.get(), 2);
+	assert_eq!(inner.erasure_root_mismatches.get(), 0);
+	let per_candidate = &state.per_candidate[&candidate_hash];
+	assert_eq!(per_candidate.descriptor, candidate.descriptor);
+	assert_eq!(per_candidate.live_in, vec![relay_parent, ancestor].into_iter().collect());
+}
+
+#[test]
+fn conflicting_pending_candidates_of_a_para_are_resolved_deterministically() {
+	let relay_parent = [0u8; 32].into();
+
+	let make_candidate = |para_id, pov_hash: Hash| {
+		let mut candidate = CommittedCandidateReceipt::default();
+		candidate.descriptor.para_id = ParaId::from(para_id);
+		candidate.descriptor.pov_hash = pov_hash;
+		candidate
+	};
+
+	let conflicting = vec![make_candidate(1, [1u8; 32].into()), make_candidate(1, [2u8; 32].into())];
+	let other = make_candidate(2, Default::default());
+	let lowest = conflicting.iter().map(|candidate| candidate.hash()).min_by_key(|hash| hash.0).unwrap();
+
+	let registry = prometheus::Registry::new();
+	let metrics = <Metrics as metrics::Metrics>::try_register(&registry).unwrap();
+	let inner = metrics.0.clone().unwrap();
+
+	// the choice must not depend on the order of the cores
+	for order in vec![[0, 1], [1, 0]] {
+		let pool = sp_core::testing::TaskExecutor::new();
+		let (mut ctx, mut virtual_overseer) =
+			test_helpers::make_subsystem_context::<AvailabilityDistributionMessage, _>(pool);
+
+		let cores = vec![
+			occupied_core_from_candidate(&conflicting[order[0]]),
+			occupied_core_from_candidate(&other),
+			occupied_core_from_candidate(&conflicting[order[1]]),
+		];
+
+		let test_fut = query_pending_availability(&mut ctx, relay_parent, &metrics);
+		let answer = async move {
+			assert_matches!(
+				overseer_recv(&mut virtual_overseer).await,
+				AllMessages::RuntimeApi(
+					RuntimeApiMessage::Request(r, RuntimeApiRequest::AvailabilityCores(tx))
+				) if r == relay_parent => {
+					tx.send(Ok(cores)).unwrap();
+				}
+			);
+		};
+
+		let (pending, _) = executor::block_on(future::join(test_fut, answer));
+		let pending: Vec<_> = pending.unwrap().into_iter().map(|(hash, _, _, _, _)| hash).collect();
+		assert_eq!(pending, vec![lowest, other.hash()]);
+	}
+
+	assert_eq!(inner.pending_availability_conflicts.get(), 2);
+}
+
+#[test]
+fn new_peer_gets_all_chunks_send() {
+	let test_state = TestState::default();
+
+	let peer_a = PeerId::random();
+	let peer_b = PeerId::random();
+	assert_ne!(&peer_a, &peer_b);
+
+	let keystore = test_state.keystore.clone();
+
+	test_harness(keystore, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			relay_parent: current,
+			validator_public,
+			ancestors,
+			candidates,
+			pov_blocks,
+			..
+		} = test_state.clone();
+
+		let valid = make_valid_availability_gossip(
+			&test_state,
+			1,
+			2,
+		);
+
+		change_our_view(
+			&mut virtual_overseer,
+			view![current],
+			&validator_public,
+			vec![ancestors[0]],
+			hashmap! { current => 1 },
+			hashmap! {
+				current => vec![
+					occupied_core_from_candidate(&candidates[0]),
+					occupied_core_from_candidate(&candidates[1])
+				],
+			},
+			hashmap! { candidates[0].hash() => true, candidates[1].hash() => false },
+			hashmap! { candidates[0].hash() => (pov_blocks[0].clone(), test_state.persisted_validation_data.clone())},
+			hashmap! {},
+		).await;
+
+		peer_send_message(&mut virtual_overseer, peer_b.clone(), valid.clone(), BENEFIT_VALID_MESSAGE_FIRST).await;
+
+		setup_peer_with_view(&mut virtual_overseer, peer_a.clone(), view![current]).await;
+
+		let mut chunks = make_erasure_chunks(
+			test_state.persisted_validation_data.clone(),
+			validator_public.len(),
+			pov_blocks[0].clone(),
+		);
+
+		chunks.push(valid.erasure_chunk);
+
+		expect_batched_chunks_network_message(
+			&mut virtual_overseer,
+			&peer_a,
+			&[candidates[0].hash(), candidates[1].hash()],
+			&chunks,
+		).await;
+	});
+}
+
+#[test]
+fn own_chunk_is_stored_only_once() {
+	let test_state = TestState::default();
+
+	let peer_a = PeerId::random();
+	let peer_b = PeerId::random();
+	assert_ne!(&peer_a, &peer_b);
+
+	let keystore = test_state.keystore.clone();
+
+	test_harness(keystore, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			relay_parent: current,
+			validator_public,
+			ancestors,
+			candidates,
+			pov_blocks,
+			..
+		} = test_state.clone();
+
+		change_our_view(
+			&mut virtual_overseer,
+			view![current],
+			&validator_public,
+			vec![ancestors[0]],
+			hashmap! { current => 1 },
+			hashmap! {
+				current => vec![
+					occupied_core_from_candidate(&candidates[0]),
+					occupied_core_from_candidate(&candidates[1]),
+				],
+			},
+			hashmap! { candidates[0].hash() => true, candidates[1].hash() => false },
+			hashmap! { candidates[0].hash() => (pov_blocks[0].clone(), test_state.persisted_validation_data.clone())},
+			hashmap! {},
+		).await;
+
+		// chunk `0` is the chunk of this node.
+		let own = make_valid_availability_gossip(&test_state, 1, 0);
+
+		overseer_send(&mut virtual_overseer, NetworkBridgeEvent::PeerConnected(peer_a.clone(), ObservedRole::Full)).await;
+		overseer_send(&mut virtual_overseer, NetworkBridgeEvent::PeerConnected(peer_b.clone(), ObservedRole::Full)).await;
+
+		// both peers send our chunk before the store replied
+		overseer_send(
+			&mut virtual_overseer,
+			NetworkBridgeEvent::PeerMessage(peer_a.clone(), chunk_protocol_message(own.clone())),
+		).await;
+		overseer_send(
+			&mut virtual_overseer,
+			NetworkBridgeEvent::PeerMessage(peer_b.clone(), chunk_protocol_message(own.clone())),
+		).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::NetworkBridge(
+				NetworkBridgeMessage::ReportPeer(peer, rep)
+			) => {
+				assert_eq!(peer, peer_a);
+				assert_eq!(rep, BENEFIT_VALID_MESSAGE_FIRST);
+			}
+		);
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::AvailabilityStore(
+				AvailabilityStoreMessage::StoreChunk {
+					candidate_hash,
+					validator_index,
+					tx,
+					..
+				}
+			) => {
+				assert_eq!(candidate_hash, candidates[1].hash());
+				assert_eq!(validator_index, 0);
+				tx.send(Ok(())).unwrap();
+			}
+		);
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::NetworkBridge(
+				NetworkBridgeMessage::ReportPeer(peer, rep)
+			) => {
+				assert_eq!(peer, peer_b);
+				assert_eq!(rep, BENEFIT_VALID_MESSAGE);
+			}
+		);
+
+		// no second `StoreChunk` is issued
+		assert!(
+			overseer_recv(&mut virtual_overseer)
+				.timeout(Duration::from_millis(100))
+				.await
+				.is_none()
+		);
+	});
+}
+
+#[test]
+fn own_chunk_dropped_by_the_store_is_stored_again() {
+	let test_state = TestState::default();
+
+	let peer = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+	let config = Config { verify_chunk_stores: true, ..Default::default() };
+
+	test_harness_with_config(keystore, config, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let script = test_state.view_change_script();
+		testing::drive_view_change(&mut virtual_overseer, view![test_state.relay_parent], &script).await;
+
+		// chunk `0` is the chunk of this node.
+		let own = make_valid_availability_gossip(&test_state, 0, 0);
+		let candidate_hash = test_state.candidates[0].hash();
+
+		overseer_send(&mut virtual_overseer, NetworkBridgeEvent::PeerConnected(peer.clone(), ObservedRole::Full)).await;
+		overseer_send(
+			&mut virtual_overseer,
+			NetworkBridgeEvent::PeerMessage(peer.clone(), chunk_protocol_message(own.clone())),
+		).await;
+
+		expect_report(&mut virtual_overseer, &peer, BENEFIT_VALID_MESSAGE_FIRST).await;
+
+		// the store acknowledges the chunk, but drops it
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::AvailabilityStore(
+				AvailabilityStoreMessage::StoreChunk { candidate_hash: hash, validator_index: 0, tx, .. }
+			) => {
+				assert_eq!(hash, candidate_hash);
+				tx.send(Ok(())).unwrap();
+			}
+		);
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::AvailabilityStore(
+				AvailabilityStoreMessage::QueryChunk(hash, 0, tx)
+			) => {
+				assert_eq!(hash, candidate_hash);
+				tx.send(None).unwrap();
+			}
+		);
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::AvailabilityStore(
+				AvailabilityStoreMessage::StoreChunk { candidate_hash: hash, validator_index: 0, chunk, tx, .. }
+			) => {
+				assert_eq!(hash, candidate_hash);
+				assert_eq!(chunk, own.erasure_chunk);
+				tx.send(Ok(())).unwrap();
+			}
+		);
+
+		assert!(
+			overseer_recv(&mut virtual_overseer)
+				.timeout(Duration::from_millis(100))
+				.await
+				.is_none()
+		);
+	});
+}
+
+#[test]
+fn own_chunk_received_from_peer_is_not_queried_from_store() {
+	let test_state = TestState::default();
+
+	let peer = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+
+	test_harness(keystore, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			relay_parent: current,
+			validator_public,
+			ancestors,
+			candidates,
+			..
+		} = test_state.clone();
+
+		change_our_view(
+			&mut virtual_overseer,
+			view![current],
+			&validator_public,
+			vec![ancestors[0]],
+			hashmap! { current => 1 },
+			hashmap! { current => vec![occupied_core_from_candidate(&candidates[0])] },
+			hashmap! { candidates[0].hash() => false },
+			hashmap! {},
+			hashmap! {},
+		).await;
+
+		// chunk `0` is the chunk of this node, the peer supplies it before our store has it
+		let own = make_valid_availability_gossip(&test_state, 0, 0);
+		overseer_send(&mut virtual_overseer, NetworkBridgeEvent::PeerConnected(peer.clone(), ObservedRole::Full)).await;
+		overseer_send(
+			&mut virtual_overseer,
+			NetworkBridgeEvent::PeerMessage(peer.clone(), chunk_protocol_message(own.clone())),
+		).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::NetworkBridge(
+				NetworkBridgeMessage::ReportPeer(p, rep)
+			) => {
+				assert_eq!(p, peer);
+				assert_eq!(rep, BENEFIT_VALID_MESSAGE_FIRST);
+			}
+		);
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::AvailabilityStore(
+				AvailabilityStoreMessage::StoreChunk {
+					candidate_hash,
+					validator_index,
+					chunk,
+					tx,
+					..
+				}
+			) => {
+				assert_eq!(candidate_hash, candidates[0].hash());
+				assert_eq!(validator_index, 0);
+				assert_eq!(chunk, own.erasure_chunk);
+				tx.send(Ok(())).unwrap();
+			}
+		);
+
+		// once the candidate is available, only the chunks we did not receive are queried
+		let next = Hash::repeat_byte(0x77);
+		overseer_send(&mut virtual_overseer, NetworkBridgeEvent::OurViewChange(view![current, next])).await;
+
+		expect_relay_parent_fetch(
+			&mut virtual_overseer,
+			next,
+			&validator_public,
+			vec![current],
+			1,
+			hashmap! { next => vec![occupied_core_from_candidate(&candidates[0])] },
+		).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::AvailabilityStore(
+				AvailabilityStoreMessage::QueryDataAvailability(candidate_hash, tx)
+			) => {
+				assert_eq!(candidate_hash, candidates[0].hash());
+				tx.send(true).unwrap();
+			}
+		);
+
+		for _ in 1..validator_public.len() {
+			assert_matches!(
+				overseer_recv(&mut virtual_overseer).await,
+				AllMessages::AvailabilityStore(
+					AvailabilityStoreMessage::QueryChunk(candidate_hash, index, tx)
+				) => {
+					assert_eq!(candidate_hash, candidates[0].hash());
+					assert_ne!(index, 0);
+					tx.send(None).unwrap();
+				}
+			);
+		}
+
+		assert!(
+			overseer_recv(&mut virtual_overseer)
+				.timeout(Duration::from_millis(100))
+				.await
+				.is_none()
+		);
+	});
+}
+
+#[test]
+fn failing_to_obtain_own_chunks_does_not_stop_distribution() {
+	let test_state = TestState::default();
+
+	let peer_a = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+
+	let registry = prometheus::Registry::new();
+	let metrics = <Metrics as metrics::Metrics>::try_register(&registry).unwrap();
+	let inner = metrics.0.clone().unwrap();
+
+	test_harness_with_metrics(keystore, Config::default(), metrics, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			relay_parent: current,
+			validator_public,
+			ancestors,
+			candidates,
+			pov_blocks,
+			persisted_validation_data,
+			..
+		} = test_state.clone();
+
+		setup_peer_with_view(&mut virtual_overseer, peer_a.clone(), view![current]).await;
+
+		overseer_send(&mut virtual_overseer, NetworkBridgeEvent::OurViewChange(view![current])).await;
+
+		expect_relay_parent_fetch(
+			&mut virtual_overseer,
+			current,
+			&validator_public,
+			vec![ancestors[0]],
+			1,
+			hashmap! { current => vec![occupied_core_from_candidate(&candidates[0])] },
+		).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::AvailabilityStore(
+				AvailabilityStoreMessage::QueryDataAvailability(candidate_hash, tx)
+			) => {
+				assert_eq!(candidate_hash, candidates[0].hash());
+				tx.send(true).unwrap();
+			}
+		);
+
+		let chunks = make_erasure_chunks(persisted_validation_data, validator_public.len(), pov_blocks[0].clone());
+		for chunk in chunks.iter() {
+			assert_matches!(
+				overseer_recv(&mut virtual_overseer).await,
+				AllMessages::AvailabilityStore(
+					AvailabilityStoreMessage::QueryChunk(candidate_hash, index, tx)
+				) => {
+					assert_eq!(candidate_hash, candidates[0].hash());
+					assert_eq!(index, chunk.index);
+					match index {
+						// the store lost a chunk of the data it holds
+						2 => tx.send(None).unwrap(),
+						// the store failed to answer
+						3 => drop(tx),
+						_ => tx.send(Some(chunk.clone())).unwrap(),
+					}
+				}
+			);
+
+			if chunk.index != 2 && chunk.index != 3 {
+				expect_chunks_network_message(
+					&mut virtual_overseer,
+					&[peer_a.clone()],
+					&[candidates[0].hash()],
+					&[chunk.clone()],
+					MessagePriority::Normal,
+				).await;
+			}
+		}
+
+		// the subsystem keeps processing messages
+		let valid = make_valid_availability_gossip(&test_state, 0, 2);
+		peer_send_message(&mut virtual_overseer, peer_a.clone(), valid, BENEFIT_VALID_MESSAGE_FIRST).await;
+	});
+
+	assert_eq!(inner.own_chunk_generation_failures.get(), 2);
+}
+
+#[test]
+fn is_candidate_live_requires_non_empty_live_in() {
+	let mut state = ProtocolState::default();
+
+	let relay_parent = [0u8; 32].into();
+
+	let live = CandidateHash([10u8; 32].into());
+	let unknown = CandidateHash([11u8; 32].into());
+	let not_live = CandidateHash([12u8; 32].into());
+
+	state.per_candidate.insert(live, PerCandidate {
+		live_in: std::iter::once(relay_parent).collect(),
+		..Default::default()
+	});
+	state.per_candidate.insert(not_live, PerCandidate::default());
+
+	assert!(state.is_candidate_live(&live));
+	assert!(!state.is_candidate_live(&unknown));
+	assert!(!state.is_candidate_live(&not_live));
+}
+
+#[test]
+fn timed_out_candidates_are_not_gossiped() {
+	let test_state = TestState::default();
+
+	let peer_a = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+	let config = Config { prefetch_activated_leaves: true, ..Default::default() };
+
+	test_harness_with_config(keystore, config, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			relay_parent: current,
+			validator_public,
+			ancestors,
+			candidates,
+			pov_blocks,
+			..
+		} = test_state.clone();
+
+		// candidate `0` times out at block `5`
+		change_our_view(
+			&mut virtual_overseer,
+			view![current],
+			&validator_public,
+			vec![ancestors[0]],
+			hashmap! { current => 1 },
+			hashmap! {
+				current => vec![
+					occupied_core_from_candidate(&candidates[0]),
+				],
+			},
+			hashmap! { candidates[0].hash() => true },
+			hashmap! { candidates[0].hash() => (pov_blocks[0].clone(), test_state.persisted_validation_data.clone())},
+			hashmap! {},
+		).await;
+
+		let leaf = Hash::repeat_byte(0x06);
+		virtual_overseer.send(FromOverseer::Signal(
+			OverseerSignal::ActiveLeaves(ActiveLeavesUpdate::start_work(leaf)),
+		)).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::ChainApi(ChainApiMessage::BlockNumber(hash, tx)) => {
+				assert_eq!(hash, leaf);
+				tx.send(Ok(Some(6))).unwrap();
+			}
+		);
+
+		expect_relay_parent_fetch(
+			&mut virtual_overseer,
+			leaf,
+			&validator_public,
+			vec![current],
+			1,
+			hashmap! { leaf => vec![] },
+		).await;
+
+		// peer a becomes interested, but the candidate timed out already
+		setup_peer_with_view(&mut virtual_overseer, peer_a.clone(), view![current]).await;
+
+		assert!(
+			overseer_recv(&mut virtual_overseer)
+				.timeout(Duration::from_millis(100))
+				.await
+				.is_none()
+		);
+	});
+}
+
+#[test]
+fn chunks_of_reconstructable_candidates_are_backfilled_on_active_leaves() {
+	let test_state = TestState::default();
+
+	let peer_a = PeerId::random();
+	let peer_b = PeerId::random();
+
+	let candidate_hash_a = test_state.candidates[0].hash();
+	let candidate_hash_b = test_state.candidates[1].hash();
+	let expected = make_erasure_chunks(
+		test_state.persisted_validation_data.clone(),
+		test_state.validator_public.len(),
+		test_state.pov_blocks[0].clone(),
+	);
+	let own_chunk = expected[0].clone();
+
+	let keystore = test_state.keystore.clone();
+	let config = Config {
+		backfill_reconstructable_candidates: true,
+		prefetch_activated_leaves: true,
+		..Default::default()
+	};
+
+	let state = test_harness_with_config(keystore, config, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let current = test_state.relay_parent;
+		let script = test_state.view_change_script();
+		testing::drive_view_change(&mut virtual_overseer, view![current], &script).await;
+
+		// two chunks suffice to reconstruct the data of five validators
+		for (peer, index) in vec![(peer_a.clone(), 1), (peer_b.clone(), 2)] {
+			let message = make_valid_availability_gossip(&test_state, 0, index);
+			peer_send_message(&mut virtual_overseer, peer, message, BENEFIT_VALID_MESSAGE_FIRST).await;
+		}
+
+		let leaf = Hash::repeat_byte(0x06);
+		virtual_overseer.send(FromOverseer::Signal(
+			OverseerSignal::ActiveLeaves(ActiveLeavesUpdate::start_work(leaf)),
+		)).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::ChainApi(ChainApiMessage::BlockNumber(hash, tx)) => {
+				assert_eq!(hash, leaf);
+				tx.send(Ok(Some(2))).unwrap();
+			}
+		);
+
+		expect_relay_parent_fetch(
+			&mut virtual_overseer,
+			leaf,
+			&test_state.validator_public,
+			vec![current],
+			1,
+			hashmap! { leaf => vec![] },
+		).await;
+
+		// our own chunk is derived as well, so it is stored
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::AvailabilityStore(AvailabilityStoreMessage::StoreChunk {
+				candidate_hash,
+				validator_index,
+				chunk,
+				tx,
+				..
+			}) => {
+				assert_eq!(candidate_hash, candidate_hash_a);
+				assert_eq!(validator_index, 0);
+				assert_eq!(chunk, own_chunk);
+				tx.send(Ok(())).unwrap();
+			}
+		);
+	});
+
+	let per_candidate = &state.per_candidate[&candidate_hash_a];
+	assert_eq!(per_candidate.verified_chunk_count(), expected.len());
+	for chunk in expected {
+		assert_eq!(per_candidate.servable_chunk(&chunk.index).map(|message| &message.erasure_chunk), Some(&chunk));
+	}
+
+	assert!(per_candidate.pending_stores.contains(&0));
+
+	// chunks of the other candidate are too few to reconstruct its data
+	assert!(state.per_candidate[&candidate_hash_b].message_vault.is_empty());
+}
+
+#[test]
+fn metrics_snapshot_matches_state() {
+	let test_state = TestState::default();
+	let mut state = ProtocolState::default();
+
+	let relay_parent = test_state.relay_parent;
+	let candidate_hash_a = test_state.candidates[0].hash();
+	let candidate_hash_b = test_state.candidates[1].hash();
+
+	state.peer_views.insert(PeerId::random(), view![relay_parent]);
+	state.peer_views.insert(PeerId::random(), view![]);
+
+	state.per_relay_parent.insert(relay_parent, PerRelayParent {
+		ancestors: vec![],
+		live_candidates: vec![candidate_hash_a, candidate_hash_b].into_iter().collect(),
+		our_group: None,
+		group_assignment: None,
+		session_index: None,
+	});
+
+	let messages = vec![
+		make_valid_availability_gossip(&test_state, 0, 0),
+		make_valid_availability_gossip(&test_state, 0, 1),
+		make_valid_availability_gossip(&test_state, 1, 2),
+	];
+
+	for message in messages.iter() {
+		state.per_candidate
+			.entry(message.candidate_hash)
+			.or_default()
+			.message_vault
+			.insert(message.erasure_chunk.index, message.clone());
+	}
+
+	let buffered_bytes = messages.iter()
+		.map(|m| m.erasure_chunk.chunk.len() + m.erasure_chunk.proof.iter().map(|p| p.len()).sum::<usize>())
+		.sum::<usize>();
+
+	let snapshot = state.export_metrics_snapshot();
+
+	assert_eq!(snapshot, MetricsSnapshot {
+		buffered_chunks: 3,
+		connected_peers: 2,
+		tracked_candidates: 2,
+		tracked_relay_parents: 1,
+		buffered_bytes,
+	});
+}
+
+#[test]
+fn duplicate_chunk_bytes_are_those_of_candidates_sharing_chunk_data() {
+	let test_state = TestState::default();
+
+	let chunk = make_valid_availability_gossip(&test_state, 0, 1);
+	let other_chunk = make_valid_availability_gossip(&test_state, 0, 2);
+
+	// a distinct candidate with the same erasure root and thus the same chunk data
+	let twin_hash = CandidateHash(Hash::repeat_byte(0x7A));
+	let twin_chunk = AvailabilityGossipMessage { candidate_hash: twin_hash, ..chunk.clone() };
+
+	let mut state = ProtocolState::default();
+	let per_candidate = state.per_candidate.entry(chunk.candidate_hash).or_default();
+	per_candidate.insert_chunk(chunk.clone(), true);
+	per_candidate.insert_chunk(other_chunk, true);
+	assert_eq!(state.duplicate_chunk_bytes(), 0);
+
+	state.per_candidate.entry(twin_hash).or_default().insert_chunk(twin_chunk, true);
+	assert_eq!(state.duplicate_chunk_bytes(), chunk.erasure_chunk.chunk.len());
+}
+
+#[test]
+fn candidates_fall_into_the_buckets_of_their_age() {
+	let test_state = TestState::default();
+
+	let mut state = ProtocolState::default();
+	let start = Instant::now();
+	let now = start + Duration::from_secs(1000);
+
+	let ages = vec![0, 5, 6, 29, 45, 119, 300, 600, 1000];
+	for (i, age) in ages.iter().enumerate() {
+		let per_candidate = state.per_candidate.entry(CandidateHash(Hash::from_low_u64_be(i as u64))).or_default();
+		per_candidate.first_seen = Some(now - Duration::from_secs(*age));
+	}
+	// candidates which never became live are not accounted
+	state.per_candidate.insert(CandidateHash(Hash::repeat_byte(0xFF)), PerCandidate::default());
+
+	let secs = |secs| Duration::from_secs(secs);
+	assert_eq!(
+		state.age_histogram(now),
+		vec![(secs(0), 2), (secs(6), 2), (secs(30), 1), (secs(60), 1), (secs(120), 1), (secs(600), 2)],
+	);
+
+	// candidates are seen once they become live
+	let candidate_hash = test_state.candidates[0].hash();
+	let mut state = ProtocolState::default();
+	state.add_relay_parent(
+		test_state.relay_parent,
+		Default::default(),
+		None,
+		hashmap! { candidate_hash => FetchedLiveCandidate::Fresh {
+			descriptor: test_state.candidates[0].descriptor.clone(),
+			time_out_at: 10,
+			group_responsible: Default::default(),
+			core_index: Default::default(),
+		}},
+		vec![],
+		&Default::default(),
+	);
+	let first_seen = state.per_candidate[&candidate_hash].first_seen.expect("Candidate is live");
+	assert!(first_seen >= start);
+	assert_eq!(state.age_histogram(first_seen)[0], (Duration::from_secs(0), 1));
+}
+
+#[test]
+fn memory_report_is_dominated_by_buffered_chunks() {
+	let mut test_state = TestState::default();
+	test_state.pov_blocks[0] = PoV { block_data: BlockData(vec![0xAB; 64 * 1024]) };
+
+	let relay_parent = test_state.relay_parent;
+	let candidate_hash = test_state.candidates[0].hash();
+
+	let mut state = ProtocolState::default();
+	state.add_relay_parent(
+		relay_parent,
+		Arc::new(test_state.validator_public.clone()),
+		Some(0),
+		test_state.candidates
+			.iter()
+			.map(|candidate| (candidate.hash(), FetchedLiveCandidate::Fresh {
+				descriptor: candidate.descriptor.clone(),
+				time_out_at: 5,
+				group_responsible: Default::default(),
+				core_index: Default::default(),
+			}))
+			.collect(),
+		test_state.ancestors.clone(),
+		&Default::default(),
+	);
+	state.live_under.insert(relay_parent, state.per_relay_parent[&relay_parent].live_candidates.clone());
+
+	let empty = state.memory_report();
+	assert_eq!(empty.message_vaults, 0);
+	assert!(empty.live_under > 0);
+	assert!(empty.per_relay_parent > 0);
+	assert!(empty.per_candidate > 0);
+
+	let per_candidate = state.per_candidate.get_mut(&candidate_hash).unwrap();
+	for index in 0..3 {
+		per_candidate.insert_chunk(make_valid_availability_gossip(&test_state, 0, index), true);
+	}
+
+	let report = state.memory_report();
+	assert_eq!(report.live_under, empty.live_under);
+	assert_eq!(report.per_relay_parent, empty.per_relay_parent);
+	// the chunks are buffered along with the time they were buffered at
+	assert!(report.per_candidate > empty.per_candidate);
+	assert!(report.message_vaults >= state.total_buffered_bytes());
+	assert!(report.message_vaults > report.total() / 2);
+	assert_eq!(
+		report.total(),
+		report.live_under + report.per_relay_parent + report.per_candidate + report.message_vaults,
+	);
+}
+
+#[test]
+fn shared_counts_are_read_consistently_during_mutation() {
+	let mut subsystem = AvailabilityDistributionSubsystem::new(
+		TestState::default().keystore,
+		Default::default(),
+	);
+	let shared_counts = subsystem.shared_counts();
+	let reader_counts = subsystem.shared_counts();
+
+	let uniform = |n: usize| MetricsSnapshot {
+		buffered_chunks: n,
+		connected_peers: n,
+		tracked_candidates: n,
+		tracked_relay_parents: n,
+		buffered_bytes: n,
+	};
+
+	const ROUNDS: usize = 10_000;
+
+	let reader = std::thread::spawn(move || {
+		let mut last = 0;
+		loop {
+			let counts = reader_counts.snapshot_counts();
+			// all fields are published at once, a torn read would mix them up
+			assert_eq!(counts, uniform(counts.buffered_chunks));
+			assert!(counts.buffered_chunks >= last);
+			last = counts.buffered_chunks;
+			if last == ROUNDS {
+				break;
+			}
+		}
+	});
+
+	for n in 1..=ROUNDS {
+		shared_counts.publish(uniform(n));
+	}
+
+	reader.join().expect("Reader observed consistent counts");
+	assert_eq!(subsystem.shared_counts().snapshot_counts(), uniform(ROUNDS));
+}
+
+#[test]
+fn chunks_are_not_resent_within_cooldown() {
+	let test_state = TestState::default();
+
+	let peer_a = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+
+	let config = Config {
+		rebroadcast_cooldown: Duration::from_secs(60),
+		..Default::default()
+	};
+
+	test_harness_with_config(keystore, config, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			relay_parent: current,
+			validator_public,
+			ancestors,
+			candidates,
+			pov_blocks,
+			..
+		} = test_state.clone();
+
+		change_our_view(
+			&mut virtual_overseer,
+			view![current],
+			&validator_public,
+			vec![ancestors[0]],
+			hashmap! { current => 1 },
+			hashmap! {
+				current => vec![
+					occupied_core_from_candidate(&candidates[0]),
+				],
+			},
+			hashmap! { candidates[0].hash() => true },
+			hashmap! { candidates[0].hash() => (pov_blocks[0].clone(), test_state.persisted_validation_data.clone())},
+			hashmap! {},
+		).await;
+
+		let chunks = make_erasure_chunks(
+			test_state.persisted_validation_data.clone(),
+			validator_public.len(),
+			pov_blocks[0].clone(),
+		);
+
+		setup_peer_with_view(&mut virtual_overseer, peer_a.clone(), view![current]).await;
+
+		expect_batched_chunks_network_message(
+			&mut virtual_overseer,
+			&peer_a,
+			&[candidates[0].hash()],
+			&chunks,
+		).await;
+
+		// the peer toggles its interest in `current` a few times
+		for _ in 0..3 {
+			overseer_send(&mut virtual_overseer, NetworkBridgeEvent::PeerViewChange(peer_a.clone(), view![])).await;
+			overseer_send(&mut virtual_overseer, NetworkBridgeEvent::PeerViewChange(peer_a.clone(), view![current])).await;
+		}
+
+		assert!(
+			overseer_recv(&mut virtual_overseer)
+				.timeout(Duration::from_millis(100))
+				.await
+				.is_none()
+		);
+	});
+}
+
+#[test]
+fn reconstruction_is_bounded_by_max_pov_size() {
+	let test_state = TestState::default();
+
+	let max_pov_size = test_state.persisted_validation_data.max_pov_size;
+	let candidate_hash = test_state.candidates[0].hash();
+
+	let mut per_candidate = PerCandidate {
+		validators: Arc::new(test_state.validator_public.clone()),
+		..Default::default()
+	};
+
+	// a threshold of honest chunks reconstructs the data
+	for index in 0..2 {
+		let message = make_valid_availability_gossip(&test_state, 0, index);
+		per_candidate.message_vault.insert(index, message);
+	}
+
+	let available_data = reconstruct_available_data(&per_candidate, max_pov_size)
+		.expect("Threshold of honest chunks must be reconstructable");
+	assert_eq!(
+		available_data,
+		make_available_data(test_state.persisted_validation_data.clone(), test_state.pov_blocks[0].clone()),
+	);
+
+	// chunks implying more data than a PoV can have are refused
+	for index in 0..2 {
+		per_candidate.message_vault.insert(index, AvailabilityGossipMessage {
+			candidate_hash,
+			erasure_chunk: ErasureChunk {
+				chunk: vec![0u8; 1024 * 1024],
+				index,
+				proof: vec![],
+			},
+		});
+	}
+
+	assert_matches!(
+		reconstruct_available_data(&per_candidate, max_pov_size),
+		Err(Error::ReconstructionTooLarge { implied_size, max_size }) => {
+			assert_eq!(implied_size, 2 * 1024 * 1024);
+			assert_eq!(max_size, max_pov_size as usize + MAX_VALIDATION_DATA_OVERHEAD);
+		}
+	);
+}
+
+#[test]
+fn only_verified_chunks_are_servable() {
+	let test_state = TestState::default();
+
+	let mut per_candidate = PerCandidate {
+		descriptor: test_state.candidates[0].descriptor.clone(),
+		..Default::default()
+	};
+
+	let valid = make_valid_availability_gossip(&test_state, 0, 1);
+
+	let mut invalid = make_valid_availability_gossip(&test_state, 0, 2);
+	invalid.erasure_chunk.chunk[0] ^= 0xff;
+
+	for message in vec![valid.clone(), invalid] {
+		let verified = verify_chunk(&per_candidate.descriptor.erasure_root, &message.erasure_chunk);
+		per_candidate.insert_chunk(message, verified);
+	}
+
+	assert_eq!(per_candidate.servable_chunk(&1), Some(&valid));
+	assert_eq!(per_candidate.servable_chunk(&2), None);
+
+	// an unverified chunk never replaces a verified one
+	let mut tampered = valid.clone();
+	tampered.erasure_chunk.chunk[0] ^= 0xff;
+	per_candidate.insert_chunk(tampered, false);
+	assert_eq!(per_candidate.servable_chunk(&1), Some(&valid));
+}
+
+#[test]
+fn chunks_not_matching_a_replaced_erasure_root_are_not_served() {
+	let test_state = TestState::default();
+
+	let mut per_candidate = PerCandidate {
+		descriptor: test_state.candidates[0].descriptor.clone(),
+		..Default::default()
+	};
+
+	let stale = make_valid_availability_gossip(&test_state, 0, 1);
+	per_candidate.insert_chunk(stale.clone(), true);
+
+	// the same root keeps the chunk servable
+	per_candidate.replace_descriptor(test_state.candidates[0].descriptor.clone());
+	assert_eq!(per_candidate.servable_chunk(&1), Some(&stale));
+
+	// the chunk was verified against a root the candidate does not commit to anymore
+	per_candidate.replace_descriptor(test_state.candidates[1].descriptor.clone());
+	assert_eq!(per_candidate.servable_chunk(&1), None);
+	assert_eq!(per_candidate.chunk(1), Some(&stale));
+	assert_eq!(per_candidate.verified_chunk_count(), 0);
+
+	// until a chunk matching the new root is obtained
+	let fresh = make_valid_availability_gossip(&test_state, 1, 1);
+	assert!(verify_chunk(&per_candidate.descriptor.erasure_root, &fresh.erasure_chunk));
+	per_candidate.insert_chunk(fresh.clone(), true);
+	assert_eq!(per_candidate.servable_chunk(&1), Some(&fresh));
+}
+
+#[test]
+fn only_verified_chunks_are_handed_out_in_bulk() {
+	let test_state = TestState::default();
+
+	let candidate_hash = test_state.candidates[0].hash();
+	let verified = make_valid_availability_gossip(&test_state, 0, 1);
+	let unverified = make_valid_availability_gossip(&test_state, 0, 3);
+
+	let mut state = ProtocolState::default();
+	let per_candidate = state.per_candidate.entry(candidate_hash).or_default();
+	per_candidate.insert_chunk(verified.clone(), true);
+	per_candidate.insert_chunk(unverified, false);
+
+	assert_eq!(state.held_chunks(&candidate_hash), vec![verified.erasure_chunk]);
+	assert!(state.held_chunks(&test_state.candidates[1].hash()).is_empty());
+}
+
+#[test]
+fn verified_chunk_count_ignores_unverified_chunks() {
+	let test_state = TestState::default();
+
+	let mut per_candidate = PerCandidate::default();
+
+	per_candidate.insert_chunk(make_valid_availability_gossip(&test_state, 0, 1), true);
+	per_candidate.insert_chunk(make_valid_availability_gossip(&test_state, 0, 2), true);
+	per_candidate.insert_chunk(make_valid_availability_gossip(&test_state, 0, 3), false);
+
+	assert_eq!(per_candidate.message_vault.len(), 3);
+	assert_eq!(per_candidate.verified_chunk_count(), 2);
+}
+
+#[test]
+fn chunk_is_obtained_by_reference() {
+	let test_state = TestState::default();
+
+	let mut per_candidate = PerCandidate::default();
+
+	let verified = make_valid_availability_gossip(&test_state, 0, 1);
+	let unverified = make_valid_availability_gossip(&test_state, 0, 3);
+	per_candidate.insert_chunk(verified.clone(), true);
+	per_candidate.insert_chunk(unverified.clone(), false);
+
+	let chunk = per_candidate.chunk(1).unwrap();
+	assert_eq!(chunk, &verified);
+	assert!(std::ptr::eq(chunk, &per_candidate.message_vault[&1]));
+	assert_eq!(per_candidate.chunk(3), Some(&unverified));
+	assert_eq!(per_candidate.chunk(2), None);
+}
+
+#[test]
+fn relay_parent_leaving_view_mid_fetch_is_not_tracked() {
+	let test_state = TestState::default();
+
+	let keystore = test_state.keystore.clone();
+	let current = test_state.relay_parent;
+
+	let state = test_harness(keystore, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			relay_parent: current,
+			validator_public,
+			ancestors,
+			candidates,
+			..
+		} = test_state.clone();
+
+		overseer_send(&mut virtual_overseer, NetworkBridgeEvent::OurViewChange(view![current])).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				relay_parent,
+				RuntimeApiRequest::Validators(tx),
+			)) => {
+				assert_eq!(relay_parent, current);
+				tx.send(Ok(validator_public.clone())).unwrap();
+			}
+		);
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::ChainApi(ChainApiMessage::Ancestors {
+				hash: relay_parent,
+				response_channel: tx,
+				..
+			}) => {
+				assert_eq!(relay_parent, current);
+				tx.send(Ok(vec![ancestors[0]])).unwrap();
+			}
+		);
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				relay_parent,
+				RuntimeApiRequest::SessionIndexForChild(tx),
+			)) => {
+				assert_eq!(relay_parent, current);
+				tx.send(Ok(1)).unwrap();
+			}
+		);
+
+		let cores_tx = assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				relay_parent,
+				RuntimeApiRequest::AvailabilityCores(tx),
+			)) => {
+				assert_eq!(relay_parent, current);
+				tx
+			}
+		);
+
+		// Our view changes before the fetch completed. The message is queued
+		// without waiting for the subsystem to read it.
+		{
+			let send = overseer_send(&mut virtual_overseer, NetworkBridgeEvent::OurViewChange(view![]));
+			futures::pin_mut!(send);
+			assert!(futures::poll!(send).is_pending());
+		}
+
+		cores_tx.send(Ok(vec![occupied_core_from_candidate(&candidates[0])])).unwrap();
+
+		// the candidate is not considered for distribution
+		assert!(
+			overseer_recv(&mut virtual_overseer)
+				.timeout(Duration::from_millis(100))
+				.await
+				.is_none()
+		);
+	});
+
+	assert!(!state.per_relay_parent.contains_key(&current));
+	assert!(state.per_candidate.is_empty());
+	assert_eq!(state.view, view![]);
+}
+
+#[test]
+fn chunk_arriving_mid_fetch_is_processed_once_fetch_completed() {
+	let test_state = TestState::default();
+
+	let peer = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+	let candidate_hash = test_state.candidates[0].hash();
+
+	let state = test_harness(keystore, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			relay_parent: current,
+			validator_public,
+			ancestors,
+			candidates,
+			..
+		} = test_state.clone();
+
+		overseer_send(&mut virtual_overseer, NetworkBridgeEvent::PeerConnected(peer.clone(), ObservedRole::Full)).await;
+
+		overseer_send(&mut virtual_overseer, NetworkBridgeEvent::OurViewChange(view![current])).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				relay_parent,
+				RuntimeApiRequest::Validators(tx),
+			)) => {
+				assert_eq!(relay_parent, current);
+				tx.send(Ok(validator_public.clone())).unwrap();
+			}
+		);
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::ChainApi(ChainApiMessage::Ancestors {
+				hash: relay_parent,
+				response_channel: tx,
+				..
+			}) => {
+				assert_eq!(relay_parent, current);
+				tx.send(Ok(vec![ancestors[0]])).unwrap();
+			}
+		);
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				relay_parent,
+				RuntimeApiRequest::SessionIndexForChild(tx),
+			)) => {
+				assert_eq!(relay_parent, current);
+				tx.send(Ok(1)).unwrap();
+			}
+		);
+
+		let cores_tx = assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				relay_parent,
+				RuntimeApiRequest::AvailabilityCores(tx),
+			)) => {
+				assert_eq!(relay_parent, current);
+				tx
+			}
+		);
+
+		// A chunk of a candidate which is not known yet arrives before the fetch completed.
+		// The message is queued without waiting for the subsystem to read it.
+		{
+			let valid = make_valid_availability_gossip(&test_state, 0, 2);
+			let send = overseer_send(
+				&mut virtual_overseer,
+				NetworkBridgeEvent::PeerMessage(peer.clone(), chunk_protocol_message(valid)),
+			);
+			futures::pin_mut!(send);
+			assert!(futures::poll!(send).is_pending());
+		}
+
+		cores_tx.send(Ok(vec![occupied_core_from_candidate(&candidates[0])])).unwrap();
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::AvailabilityStore(
+				AvailabilityStoreMessage::QueryDataAvailability(candidate_hash, tx)
+			) => {
+				assert_eq!(candidate_hash, candidates[0].hash());
+				tx.send(false).unwrap();
+			}
+		);
+
+		// the chunk is only processed afterwards and hence not penalized
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::NetworkBridge(
+				NetworkBridgeMessage::ReportPeer(p, rep)
+			) => {
+				assert_eq!(p, peer);
+				assert_eq!(rep, BENEFIT_VALID_MESSAGE_FIRST);
+			}
+		);
+
+		assert!(
+			overseer_recv(&mut virtual_overseer)
+				.timeout(Duration::from_millis(100))
+				.await
+				.is_none()
+		);
+	});
+
+	assert!(state.per_candidate[&candidate_hash].message_vault.contains_key(&2));
+}
+
+#[test]
+fn session_of_relay_parent_is_stored() {
+	let test_state = TestState::default();
+
+	let keystore = test_state.keystore.clone();
+	let current = test_state.relay_parent;
+	let ancestor = test_state.ancestors[0];
+
+	let state = test_harness(keystore, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			validator_public,
+			ancestors,
+			candidates,
+			..
+		} = test_state.clone();
+
+		change_our_view(
+			&mut virtual_overseer,
+			view![current],
+			&validator_public,
+			vec![ancestors[0]],
+			hashmap! { current => 7 },
+			hashmap! { current => vec![occupied_core_from_candidate(&candidates[0])] },
+			hashmap! { candidates[0].hash() => false },
+			hashmap! {},
+			hashmap! {},
+		).await;
+	});
+
+	assert_eq!(state.per_relay_parent[&current].session_index, Some(7));
+	assert_eq!(state.per_relay_parent.get(&ancestor).and_then(|relay_parent| relay_parent.session_index), None);
+}
+
+#[test]
+fn candidates_timing_out_before_block_number() {
+	let mut state = ProtocolState::default();
+
+	let early = CandidateHash([10u8; 32].into());
+	let late = CandidateHash([11u8; 32].into());
+
+	state.per_candidate.insert(early, PerCandidate { time_out_at: Some(5), ..Default::default() });
+	state.per_candidate.insert(late, PerCandidate { time_out_at: Some(10), ..Default::default() });
+
+	assert_eq!(state.candidates_timing_out_before(7), vec![early]);
+	assert!(state.candidates_timing_out_before(5).is_empty());
+	assert_eq!(state.candidates_timing_out_before(11).len(), 2);
+}
+
+#[test]
+fn candidates_of_untracked_relay_parents_are_orphans() {
+	let mut state = ProtocolState::default();
+
+	let tracked = Hash::repeat_byte(0x01);
+	let untracked = Hash::repeat_byte(0x02);
+
+	let candidate_of = |relay_parent: Hash| FetchedLiveCandidate::Fresh {
+		descriptor: CandidateDescriptor { relay_parent, ..well_formed_descriptor() },
+		time_out_at: 0,
+		group_responsible: Default::default(),
+		core_index: Default::default(),
+	};
+
+	let healthy = CandidateHash([10u8; 32].into());
+	let orphan = CandidateHash([11u8; 32].into());
+
+	state.add_relay_parent(
+		tracked,
+		Default::default(),
+		None,
+		vec![(healthy, candidate_of(tracked)), (orphan, candidate_of(untracked))].into_iter().collect(),
+		vec![],
+		&Default::default(),
+	);
+
+	assert_eq!(state.orphan_candidates(), vec![orphan]);
+}
+
+#[test]
+fn buffered_chunks_are_replayed_in_a_single_batch() {
+	let test_state = TestState::default();
+
+	let peer_a = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+	let config = Config { send_batched_chunks: true, ..Default::default() };
+
+	test_harness_with_config(keystore, config, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			relay_parent: current,
+			validator_public,
+			ancestors,
+			candidates,
+			pov_blocks,
+			..
+		} = test_state.clone();
+
+		change_our_view(
+			&mut virtual_overseer,
+			view![current],
+			&validator_public,
+			vec![ancestors[0]],
+			hashmap! { current => 1 },
+			hashmap! {
+				current => vec![
+					occupied_core_from_candidate(&candidates[0]),
+				],
+			},
+			hashmap! { candidates[0].hash() => true },
+			hashmap! { candidates[0].hash() => (pov_blocks[0].clone(), test_state.persisted_validation_data.clone())},
+			hashmap! {},
+		).await;
+
+		setup_peer_with_view(&mut virtual_overseer, peer_a.clone(), view![current]).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::NetworkBridge(
+				NetworkBridgeMessage::SendPrioritizedValidationMessage(
+					peers,
+					protocol_v1::ValidationProtocol::AvailabilityDistribution(
+						protocol_v1::AvailabilityDistributionMessage::BatchChunks(batch),
+					),
+					MessagePriority::Low,
+				)
+			) => {
+				assert_eq!(peers, vec![peer_a.clone()]);
+				assert_eq!(batch.len(), validator_public.len());
+				assert!(batch.iter().all(|(hash, _)| hash == &candidates[0].hash()));
+			}
+		);
+
+		assert!(
+			overseer_recv(&mut virtual_overseer)
+				.timeout(Duration::from_millis(100))
+				.await
+				.is_none()
+		);
+	});
+}
+
+#[test]
+fn buffered_chunks_are_replayed_one_by_one_unless_batching_is_enabled() {
+	let test_state = TestState::default();
+
+	let peer_a = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+
+	test_harness(keystore, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			relay_parent: current,
+			validator_public,
+			ancestors,
+			candidates,
+			pov_blocks,
+			..
+		} = test_state.clone();
+		let candidate_hash = candidates[0].hash();
+
+		change_our_view(
+			&mut virtual_overseer,
+			view![current],
+			&validator_public,
+			vec![ancestors[0]],
+			hashmap! { current => 1 },
+			hashmap! {
+				current => vec![
+					occupied_core_from_candidate(&candidates[0]),
+				],
+			},
+			hashmap! { candidate_hash => true },
+			hashmap! { candidate_hash => (pov_blocks[0].clone(), test_state.persisted_validation_data.clone())},
+			hashmap! {},
+		).await;
+
+		setup_peer_with_view(&mut virtual_overseer, peer_a.clone(), view![current]).await;
+
+		for _ in 0..validator_public.len() {
+			assert_matches!(
+				overseer_recv(&mut virtual_overseer).await,
+				AllMessages::NetworkBridge(
+					NetworkBridgeMessage::SendPrioritizedValidationMessage(
+						peers,
+						protocol_v1::ValidationProtocol::AvailabilityDistribution(
+							protocol_v1::AvailabilityDistributionMessage::Chunk(hash, _),
+						),
+						MessagePriority::Low,
+					)
+				) => {
+					assert_eq!(peers, vec![peer_a.clone()]);
+					assert_eq!(hash, candidate_hash);
+				}
+			);
+		}
+
+		assert!(
+			overseer_recv(&mut virtual_overseer)
+				.timeout(Duration::from_millis(100))
+				.await
+				.is_none()
+		);
+	});
+}
+
+#[test]
+fn batches_are_bounded() {
+	let test_state = TestState::default();
+
+	let peer = PeerId::random();
+	let message = make_valid_availability_gossip(&test_state, 0, 0);
+	let messages = vec![message.clone(); protocol_v1::MAX_BATCHED_CHUNKS + 1];
+
+	let pool = sp_core::testing::TaskExecutor::new();
+	let (mut ctx, mut virtual_overseer) =
+		test_helpers::make_subsystem_context::<AvailabilityDistributionMessage, _>(pool);
+
+	executor::block_on(async {
+		// batches sent by us are split at the bound
+		send_batched_gossip_messages_to_peer(&mut ctx, &Default::default(), peer.clone(), messages.clone(), true).await;
+
+		for expected in vec![protocol_v1::MAX_BATCHED_CHUNKS, 1] {
+			let sent = assert_matches!(
+				overseer_recv(&mut virtual_overseer).await,
+				AllMessages::NetworkBridge(NetworkBridgeMessage::SendPrioritizedValidationMessage(
+					_,
+					protocol_v1::ValidationProtocol::AvailabilityDistribution(message),
+					_,
+				)) => message
+			);
+			match sent {
+				protocol_v1::AvailabilityDistributionMessage::BatchChunks(batch) => assert_eq!(batch.len(), expected),
+				protocol_v1::AvailabilityDistributionMessage::Chunk(..) => assert_eq!(expected, 1),
+				message => panic!("Unexpected message {:?}", message),
+			}
+		}
+
+		// and batches of peers exceeding it are rejected as a whole
+		let mut state = ProtocolState::default();
+		state.peer_views.insert(peer.clone(), View::default());
+		let batch = protocol_v1::AvailabilityDistributionMessage::BatchChunks(
+			messages.into_iter().map(|message| (message.candidate_hash, message.erasure_chunk)).collect(),
+		);
+		handle_network_msg(
+			&mut ctx,
+			&test_state.keystore,
+			&mut state,
+			&Default::default(),
+			&mut VecDeque::new(),
+			NetworkBridgeEvent::PeerMessage(peer.clone(), batch),
+		).await.unwrap();
+
+		expect_report(&mut virtual_overseer, &peer, COST_OVERSIZED_BATCH).await;
+		assert!(state.pending_chunks.is_empty());
+	});
+}
+
+#[test]
+fn original_chunks_have_normal_and_replayed_chunks_low_priority() {
+	let test_state = TestState::default();
+
+	let peer_a = PeerId::random();
+	let peer_b = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+
+	test_harness(keystore, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			relay_parent: current,
+			validator_public,
+			ancestors,
+			candidates,
+			pov_blocks,
+			..
+		} = test_state.clone();
+
+		setup_peer_with_view(&mut virtual_overseer, peer_a.clone(), view![current]).await;
+
+		// the chunks we originate are sent with normal priority
+		change_our_view(
+			&mut virtual_overseer,
+			view![current],
+			&validator_public,
+			vec![ancestors[0]],
+			hashmap! { current => 1 },
+			hashmap! {
+				current => vec![
+					occupied_core_from_candidate(&candidates[0]),
+				],
+			},
+			hashmap! { candidates[0].hash() => true },
+			hashmap! { candidates[0].hash() => (pov_blocks[0].clone(), test_state.persisted_validation_data.clone())},
+			hashmap! { candidates[0].hash() => vec![peer_a.clone()] },
+		).await;
+
+		// while replaying them to a peer catching up is done with low priority
+		setup_peer_with_view(&mut virtual_overseer, peer_b.clone(), view![current]).await;
+
+		let chunks = make_erasure_chunks(
+			test_state.persisted_validation_data.clone(),
+			validator_public.len(),
+			pov_blocks[0].clone(),
+		);
+		expect_batched_chunks_network_message(&mut virtual_overseer, &peer_b, &[candidates[0].hash()], &chunks).await;
+	});
+}
+
+#[test]
+fn configured_reputation_changes_are_applied() {
+	let test_state = TestState::default();
+
+	let peer_a = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+
+	let custom_duplicate_cost = Rep::new(-7, "Custom duplicate cost");
+	let config = Config {
+		reputation: ReputationConfig {
+			cost_peer_duplicate_message: custom_duplicate_cost.clone(),
+			..Default::default()
+		},
+		..Default::default()
+	};
+
+	test_harness_with_config(keystore, config, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			relay_parent: current,
+			validator_public,
+			ancestors,
+			candidates,
+			pov_blocks,
+			..
+		} = test_state.clone();
+
+		let valid = make_valid_availability_gossip(
+			&test_state,
+			0,
+			2,
+		);
+
+		change_our_view(
+			&mut virtual_overseer,
+			view![current],
+			&validator_public,
+			vec![ancestors[0]],
+			hashmap! { current => 1 },
+			hashmap! {
+				current => vec![
+					occupied_core_from_candidate(&candidates[0]),
+				],
+			},
+			hashmap! { candidates[0].hash() => true },
+			hashmap! { candidates[0].hash() => (pov_blocks[0].clone(), test_state.persisted_validation_data.clone())},
+			hashmap! {},
+		).await;
+
+		// values which were not overridden keep their defaults
+		peer_send_message(&mut virtual_overseer, peer_a.clone(), valid.clone(), BENEFIT_VALID_MESSAGE).await;
+
+		peer_send_message(&mut virtual_overseer, peer_a.clone(), valid.clone(), custom_duplicate_cost).await;
+	});
+}
+
+#[test]
+fn rewards_for_chunks_of_a_candidate_are_capped() {
+	let test_state = TestState::default();
+
+	let peer_a = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+
+	let config = Config {
+		reputation: ReputationConfig {
+			max_rewarded_chunks_per_candidate: Some(2),
+			..Default::default()
+		},
+		..Default::default()
+	};
+
+	let candidate_hash = test_state.candidates[0].hash();
+	let state = test_harness_with_config(keystore, config, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			relay_parent: current,
+			validator_public,
+			ancestors,
+			candidates,
+			..
+		} = test_state.clone();
+
+		change_our_view(
+			&mut virtual_overseer,
+			view![current],
+			&validator_public,
+			vec![ancestors[0]],
+			hashmap! { current => 1 },
+			hashmap! { current => vec![occupied_core_from_candidate(&candidates[0])] },
+			hashmap! { candidates[0].hash() => false },
+			hashmap! {},
+			hashmap! {},
+		).await;
+
+		for index in 1..3 {
+			let valid = make_valid_availability_gossip(&test_state, 0, index);
+			peer_send_message(&mut virtual_overseer, peer_a.clone(), valid, BENEFIT_VALID_MESSAGE_FIRST).await;
+		}
+
+		// further valid chunks of the same candidate are accepted, but not rewarded
+		for index in 3..5 {
+			let valid = make_valid_availability_gossip(&test_state, 0, index);
+			overseer_send(
+				&mut virtual_overseer,
+				NetworkBridgeEvent::PeerMessage(peer_a.clone(), chunk_protocol_message(valid)),
+			).await;
+		}
+
+		assert!(
+			overseer_recv(&mut virtual_overseer)
+				.timeout(Duration::from_millis(100))
+				.await
+				.is_none()
+		);
+
+		// duplicates are still penalized
+		let valid = make_valid_availability_gossip(&test_state, 0, 4);
+		peer_send_message(&mut virtual_overseer, peer_a.clone(), valid, COST_PEER_DUPLICATE_MESSAGE).await;
+	});
+
+	assert_eq!(state.per_candidate[&candidate_hash].message_vault.len(), 4);
+}
+
+#[test]
+fn equivocating_chunks_are_penalized() {
+	let test_state = TestState::default();
+
+	let pool = sp_core::testing::TaskExecutor::new();
+	let (mut ctx, mut virtual_overseer) =
+		test_helpers::make_subsystem_context::<AvailabilityDistributionMessage, _>(pool);
+
+	let peer_a = PeerId::random();
+	let peer_a_2 = peer_a.clone();
+	let peer_a_3 = peer_a.clone();
+
+	let valid = make_valid_availability_gossip(&test_state, 0, 2);
+
+	// a chunk of the same index with a different payload, which we consider verified already
+	let mut conflicting = valid.clone();
+	conflicting.erasure_chunk.chunk[0] ^= 0xff;
+
+	let mut state = ProtocolState::default();
+	let per_candidate = state.per_candidate.entry(valid.candidate_hash).or_default();
+	per_candidate.descriptor = test_state.candidates[0].descriptor.clone();
+	per_candidate.live_in.insert(test_state.relay_parent);
+	per_candidate.insert_chunk(conflicting.clone(), true);
+	state.peer_views.insert(peer_a.clone(), View::default());
+
+	let test_fut = async move {
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::NetworkBridge(
+				NetworkBridgeMessage::ReportPeer(peer, rep)
+			) => {
+				assert_eq!(peer, peer_a_2);
+				assert_eq!(rep, COST_CHUNK_EQUIVOCATION);
+			}
+		);
+	};
+
+	let sut = async move {
+		process_incoming_peer_message(&mut ctx, &mut state, peer_a, valid, &Default::default())
+			.await
+			.unwrap();
+
+		// the verified chunk is kept
+		let per_candidate = &state.per_candidate[&conflicting.candidate_hash];
+		assert_eq!(per_candidate.servable_chunk(&2), Some(&conflicting));
+		// and may still be sent to the peer
+		assert!(per_candidate.message_required_by_peer(&peer_a_3, &2));
+	};
+
+	futures::pin_mut!(test_fut);
+	futures::pin_mut!(sut);
+
+	executor::block_on(future::join(test_fut, sut).timeout(Duration::from_millis(1000)));
+}
+
+#[test]
+fn prefetched_relay_parent_is_not_fetched_again() {
+	let test_state = TestState::default();
+
+	let keystore = test_state.keystore.clone();
+
+	let current = test_state.relay_parent;
+	let candidate_hash = test_state.candidates[0].hash();
+
+	let state = test_harness(keystore, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			validator_public,
+			ancestors,
+			candidates,
+			..
+		} = test_state.clone();
+
+		overseer_send(&mut virtual_overseer, AvailabilityDistributionMessage::Prefetch(current)).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				relay_parent,
+				RuntimeApiRequest::Validators(tx),
+			)) => {
+				assert_eq!(relay_parent, current);
+				tx.send(Ok(validator_public.clone())).unwrap();
+			}
+		);
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::ChainApi(ChainApiMessage::Ancestors {
+				hash: relay_parent,
+				response_channel: tx,
+				..
+			}) => {
+				assert_eq!(relay_parent, current);
+				tx.send(Ok(vec![ancestors[0]])).unwrap();
+			}
+		);
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				relay_parent,
+				RuntimeApiRequest::SessionIndexForChild(tx),
+			)) => {
+				assert_eq!(relay_parent, current);
+				tx.send(Ok(1)).unwrap();
+			}
+		);
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				relay_parent,
+				RuntimeApiRequest::AvailabilityCores(tx),
+			)) => {
+				assert_eq!(relay_parent, current);
+				tx.send(Ok(vec![occupied_core_from_candidate(&candidates[0])])).unwrap();
+			}
+		);
+
+		virtual_overseer.send(FromOverseer::Signal(
+			OverseerSignal::ActiveLeaves(ActiveLeavesUpdate::start_work(current)),
+		)).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::ChainApi(ChainApiMessage::BlockNumber(hash, tx)) => {
+				assert_eq!(hash, current);
+				tx.send(Ok(Some(1))).unwrap();
+			}
+		);
+
+		overseer_send(&mut virtual_overseer, NetworkBridgeEvent::OurViewChange(view![current])).await;
+
+		// the candidates are known already, so we go straight to distributing them
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::AvailabilityStore(
+				AvailabilityStoreMessage::QueryDataAvailability(candidate_hash, tx)
+			) => {
+				assert_eq!(candidate_hash, candidates[0].hash());
+				tx.send(false).unwrap();
+			}
+		);
+
+		assert!(
+			overseer_recv(&mut virtual_overseer)
+				.timeout(Duration::from_millis(100))
+				.await
+				.is_none()
+		);
+	});
+
+	assert!(state.prefetched.is_empty());
+	assert!(state.per_relay_parent.contains_key(&current));
+	assert!(state.is_candidate_live(&candidate_hash));
+}
+
+#[test]
+fn stale_descriptor_is_fetched_anew_before_penalizing() {
+	let test_state = TestState::default();
+
+	let peer_a = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+
+	let current = test_state.relay_parent;
+	let candidate_hash = test_state.candidates[0].hash();
+	let fresh_descriptor = test_state.candidates[0].descriptor.clone();
+
+	let state = test_harness(keystore, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			validator_public,
+			ancestors,
+			candidates,
+			pov_blocks,
+			..
+		} = test_state.clone();
+
+		// the candidate is known with an outdated descriptor
+		let stale_core = match occupied_core_from_candidate(&candidates[0]) {
+			CoreState::Occupied(mut occupied) => {
+				occupied.candidate_descriptor.erasure_root = Hash::repeat_byte(0xAB);
+				CoreState::Occupied(occupied)
+			}
+			_ => unreachable!(),
+		};
+
+		change_our_view(
+			&mut virtual_overseer,
+			view![current],
+			&validator_public,
+			vec![ancestors[0]],
+			hashmap! { current => 1 },
+			hashmap! { current => vec![stale_core] },
+			hashmap! { candidates[0].hash() => false },
+			hashmap! { candidates[0].hash() => (pov_blocks[0].clone(), test_state.persisted_validation_data.clone())},
+			hashmap! {},
+		).await;
+
+		let valid = make_valid_availability_gossip(&test_state, 0, 2);
+
+		overseer_send(&mut virtual_overseer, NetworkBridgeEvent::PeerConnected(peer_a.clone(), ObservedRole::Full)).await;
+		overseer_send(
+			&mut virtual_overseer,
+			NetworkBridgeEvent::PeerMessage(peer_a.clone(), chunk_protocol_message(valid)),
+		).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				relay_parent,
+				RuntimeApiRequest::AvailabilityCores(tx),
+			)) => {
+				assert_eq!(relay_parent, current);
+				tx.send(Ok(vec![occupied_core_from_candidate(&candidates[0])])).unwrap();
+			}
+		);
+
+		// the chunk verifies against the fresh descriptor
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::NetworkBridge(
+				NetworkBridgeMessage::ReportPeer(peer, rep)
+			) => {
+				assert_eq!(peer, peer_a);
+				assert_eq!(rep, BENEFIT_VALID_MESSAGE_FIRST);
+			}
+		);
+	});
+
+	assert_eq!(state.per_candidate[&candidate_hash].descriptor, fresh_descriptor);
+}
+
+#[test]
+fn failing_ancestors_query_still_tracks_relay_parent() {
+	let test_state = TestState::default();
+
+	let keystore = test_state.keystore.clone();
+
+	let current = test_state.relay_parent;
+	let candidate_hash = test_state.candidates[0].hash();
+
+	let state = test_harness(keystore, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			validator_public,
+			candidates,
+			..
+		} = test_state.clone();
+
+		overseer_send(&mut virtual_overseer, NetworkBridgeEvent::OurViewChange(view![current])).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				relay_parent,
+				RuntimeApiRequest::Validators(tx),
+			)) => {
+				assert_eq!(relay_parent, current);
+				tx.send(Ok(validator_public.clone())).unwrap();
+			}
+		);
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::ChainApi(ChainApiMessage::Ancestors {
+				hash: relay_parent,
+				response_channel: tx,
+				..
+			}) => {
+				assert_eq!(relay_parent, current);
+				tx.send(Err(ChainApiError::from("Ancestors not available"))).unwrap();
+			}
+		);
+
+		// no session index is queried, the candidates of the relay parent are fetched right away
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				relay_parent,
+				RuntimeApiRequest::AvailabilityCores(tx),
+			)) => {
+				assert_eq!(relay_parent, current);
+				tx.send(Ok(vec![occupied_core_from_candidate(&candidates[0])])).unwrap();
+			}
+		);
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::AvailabilityStore(
+				AvailabilityStoreMessage::QueryDataAvailability(candidate_hash, tx)
+			) => {
+				assert_eq!(candidate_hash, candidates[0].hash());
+				tx.send(false).unwrap();
+			}
+		);
+	});
+
+	assert_matches!(
+		state.per_relay_parent.get(&current),
+		Some(per_relay_parent) => {
+			assert!(per_relay_parent.ancestors.is_empty());
+			assert!(per_relay_parent.live_candidates.contains(&candidate_hash));
+		}
+	);
+	assert!(state.is_candidate_live(&candidate_hash));
+}
+
+#[test]
+fn av_store_helpers_answer_from_store() {
+	let test_state = TestState::default();
+
+	let pool = sp_core::testing::TaskExecutor::new();
+	let (mut ctx, mut virtual_overseer) =
+		test_helpers::make_subsystem_context::<AvailabilityDistributionMessage, _>(pool);
+
+	let relay_parent = test_state.relay_parent;
+	let candidate_hash = test_state.candidates[0].hash();
+	let chunk = make_valid_availability_gossip(&test_state, 0, 1).erasure_chunk;
+	let stored_chunk = chunk.clone();
+
+	let test_fut = async move {
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::AvailabilityStore(
+				AvailabilityStoreMessage::QueryDataAvailability(hash, tx)
+			) => {
+				assert_eq!(hash, candidate_hash);
+				tx.send(true).unwrap();
+			}
+		);
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::AvailabilityStore(
+				AvailabilityStoreMessage::QueryChunk(hash, index, tx)
+			) => {
+				assert_eq!(hash, candidate_hash);
+				assert_eq!(index, 1);
+				tx.send(Some(stored_chunk.clone())).unwrap();
+			}
+		);
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::AvailabilityStore(
+				AvailabilityStoreMessage::StoreChunk { candidate_hash: hash, relay_parent: parent, validator_index, chunk, tx }
+			) => {
+				assert_eq!(hash, candidate_hash);
+				assert_eq!(parent, relay_parent);
+				assert_eq!(validator_index, 1);
+				assert_eq!(chunk, stored_chunk);
+				tx.send(Err(())).unwrap();
+			}
+		);
+
+		// the store going away is reported as an error
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::AvailabilityStore(
+				AvailabilityStoreMessage::QueryChunk(_, _, tx)
+			) => {
+				drop(tx);
+			}
+		);
+	};
+
+	let sut = async move {
+		assert!(av_store::query_data_availability(&mut ctx, candidate_hash).await.unwrap());
+
+		assert_eq!(
+			av_store::query_chunk(&mut ctx, candidate_hash, 1).await.unwrap(),
+			Some(chunk.clone()),
+		);
+
+		assert_eq!(
+			av_store::store_chunk(&mut ctx, candidate_hash, relay_parent, 1, chunk).await.unwrap(),
+			Err(()),
+		);
+
+		assert_matches!(
+			av_store::query_chunk(&mut ctx, candidate_hash, 1).await,
+			Err(Error::QueryChunkResponseChannel(_))
+		);
+	};
+
+	futures::pin_mut!(test_fut);
+	futures::pin_mut!(sut);
+
+	executor::block_on(future::join(test_fut, sut).timeout(Duration::from_millis(1000)));
+}
+
+#[test]
+fn outstanding_chunk_stores_are_bounded() {
+	let test_state = TestState::default();
+
+	let pool = sp_core::testing::TaskExecutor::new();
+	let (mut ctx, mut virtual_overseer) =
+		test_helpers::make_subsystem_context::<AvailabilityDistributionMessage, _>(pool);
+
+	let max_outstanding = Config::default().max_outstanding_chunk_stores;
+	assert_eq!(max_outstanding, 16);
+
+	let relay_parent = test_state.relay_parent;
+	let chunk = make_valid_availability_gossip(&test_state, 0, 1).erasure_chunk;
+	let chunks: Vec<_> = (0u8..30)
+		.map(|i| (CandidateHash(Hash::repeat_byte(i)), relay_parent, 1, chunk.clone()))
+		.collect();
+
+	let test_fut = async move {
+		let mut stored = 0;
+		let mut most_outstanding = 0;
+		while stored < 30 {
+			// collect all stores issued without any of them completing
+			let mut outstanding = Vec::new();
+			while let Some(message) = virtual_overseer.recv().timeout(Duration::from_millis(100)).await {
+				assert_matches!(
+					message,
+					AllMessages::AvailabilityStore(AvailabilityStoreMessage::StoreChunk { tx, .. }) => {
+						outstanding.push(tx);
+					}
+				);
+			}
+
+			assert!(!outstanding.is_empty());
+			most_outstanding = most_outstanding.max(outstanding.len());
+			stored += outstanding.len();
+			for tx in outstanding {
+				tx.send(Ok(())).unwrap();
+			}
+		}
+
+		assert_eq!(stored, 30);
+		assert_eq!(most_outstanding, max_outstanding);
+	};
+
+	let sut = async move {
+		let results = av_store::store_chunks(&mut ctx, chunks, max_outstanding).await.unwrap();
+		assert_eq!(results, vec![Ok(()); 30]);
+	};
+
+	futures::pin_mut!(test_fut);
+	futures::pin_mut!(sut);
+
+	executor::block_on(future::join(test_fut, sut).timeout(Duration::from_secs(5)))
+		.expect("All chunks were stored");
+}
+
+#[test]
+fn candidates_of_a_session_share_validators() {
+	let test_state = TestState::default();
+	let mut state = ProtocolState::default();
+
+	let relay_parent_a = Hash::repeat_byte(0xAA);
+	let relay_parent_b = Hash::repeat_byte(0xBB);
+
+	let candidate_hash_a = CandidateHash(Hash::repeat_byte(0x0A));
+	let candidate_hash_b = CandidateHash(Hash::repeat_byte(0x0B));
+
+	for (relay_parent, candidate_hash) in vec![
+		(relay_parent_a, candidate_hash_a),
+		(relay_parent_b, candidate_hash_b),
+	] {
+		let validators = state.shared_validators(Some(1), test_state.validator_public.clone());
+		let candidates = vec![
+			(candidate_hash, FetchedLiveCandidate::Fresh {
+				descriptor: well_formed_descriptor(),
+				time_out_at: 0,
+				group_responsible: Default::default(),
+				core_index: Default::default(),
+			}),
+		].into_iter().collect();
+
+		state.add_relay_parent(relay_parent, validators, None, candidates, Vec::new(), &Default::default());
+	}
+
+	assert!(Arc::ptr_eq(
+		&state.per_candidate[&candidate_hash_a].validators,
+		&state.per_candidate[&candidate_hash_b].validators,
+	));
+	assert_eq!(*state.per_candidate[&candidate_hash_a].validators, test_state.validator_public);
+
+	// other sessions and unknown sessions get their own validator sets
+	let other_session = state.shared_validators(Some(2), test_state.validator_public.clone());
+	assert!(!Arc::ptr_eq(&other_session, &state.per_candidate[&candidate_hash_a].validators));
+	let unknown_session = state.shared_validators(None, test_state.validator_public.clone());
+	assert!(!Arc::ptr_eq(&unknown_session, &state.per_candidate[&candidate_hash_a].validators));
+	assert_eq!(state.session_validators.len(), 2);
+	drop(other_session);
+
+	// validator sets are dropped with the last candidate referencing them
+	state.remove_relay_parent(&relay_parent_a, &Default::default());
+	state.clean_up_session_validators_cache();
+	assert!(state.session_validators.contains_key(&1));
+	assert!(!state.session_validators.contains_key(&2));
+
+	state.remove_relay_parent(&relay_parent_b, &Default::default());
+	state.clean_up_session_validators_cache();
+	assert!(state.session_validators.is_empty());
+}
+
+#[test]
+fn activated_leaves_are_prefetched_together() {
+	let test_state = TestState::default();
+
+	let keystore = test_state.keystore.clone();
+
+	let leaves = vec![
+		Hash::repeat_byte(0x41),
+		Hash::repeat_byte(0x42),
+		Hash::repeat_byte(0x43),
+	];
+	let parent = Hash::repeat_byte(0x40);
+	let grand_parent = Hash::repeat_byte(0x30);
+
+	let candidate_hash = test_state.candidates[0].hash();
+	let expected_leaves = leaves.clone();
+
+	let config = Config { prefetch_activated_leaves: true, ..Default::default() };
+
+	let state = test_harness_with_config(keystore, config, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			validator_public,
+			candidates,
+			..
+		} = test_state.clone();
+
+		virtual_overseer.send(FromOverseer::Signal(
+			OverseerSignal::ActiveLeaves(ActiveLeavesUpdate {
+				activated: leaves.iter().cloned().collect(),
+				deactivated: Default::default(),
+			}),
+		)).await;
+
+		for (number, leaf) in leaves.iter().enumerate() {
+			assert_matches!(
+				overseer_recv(&mut virtual_overseer).await,
+				AllMessages::ChainApi(ChainApiMessage::BlockNumber(hash, tx)) => {
+					assert_eq!(hash, *leaf);
+					tx.send(Ok(Some(number as BlockNumber))).unwrap();
+				}
+			);
+		}
+
+		// the candidate pending availability at the shared parent is fetched with the first leaf only
+		for (i, leaf) in leaves.iter().enumerate() {
+			let mut cores_per_block = hashmap! { *leaf => vec![] };
+			if i == 0 {
+				cores_per_block.insert(parent, vec![occupied_core_from_candidate(&candidates[0])]);
+			}
+
+			expect_relay_parent_fetch(
+				&mut virtual_overseer,
+				*leaf,
+				&validator_public,
+				vec![parent, grand_parent],
+				1,
+				cores_per_block,
+			).await;
+		}
+
+		assert!(
+			overseer_recv(&mut virtual_overseer)
+				.timeout(Duration::from_millis(100))
+				.await
+				.is_none()
+		);
+	});
+
+	for leaf in expected_leaves.iter() {
+		assert!(state.per_relay_parent.contains_key(leaf));
+		assert!(state.per_candidate[&candidate_hash].live_in.contains(leaf));
+	}
+	assert_eq!(state.prefetched, expected_leaves.into_iter().collect());
+	assert_eq!(state.best_block_number, Some(2));
+}
+
+#[test]
+fn activated_leaves_are_not_prefetched_by_default() {
+	let test_state = TestState::default();
+
+	let keystore = test_state.keystore.clone();
+	let leaf = Hash::repeat_byte(0x41);
+
+	let state = test_harness(keystore, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		virtual_overseer.send(FromOverseer::Signal(
+			OverseerSignal::ActiveLeaves(ActiveLeavesUpdate::start_work(leaf)),
+		)).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::ChainApi(ChainApiMessage::BlockNumber(hash, tx)) => {
+				assert_eq!(hash, leaf);
+				tx.send(Ok(Some(1))).unwrap();
+			}
+		);
+
+		// the relay parent is fetched once it enters our view only
+		assert!(
+			overseer_recv(&mut virtual_overseer)
+				.timeout(Duration::from_millis(100))
+				.await
+				.is_none()
+		);
+	});
+
+	assert!(state.prefetched.is_empty());
+	assert!(!state.per_relay_parent.contains_key(&leaf));
+}
+
+#[test]
+fn cached_chunk_verification_matches_uncached() {
+	let test_state = TestState::default();
+
+	let mut per_candidate = PerCandidate {
+		descriptor: test_state.candidates[0].descriptor.clone(),
+		..Default::default()
+	};
+	let erasure_root = per_candidate.descriptor.erasure_root;
+
+	let mut chunks = make_erasure_chunks(
+		test_state.persisted_validation_data.clone(),
+		test_state.validator_public.len(),
+		test_state.pov_blocks[0].clone(),
+	);
+
+	// a chunk with a bad payload and a chunk with a proof for the wrong index
+	let mut tampered_chunk = chunks[0].clone();
+	tampered_chunk.chunk[0] ^= 0xff;
+	let mut tampered_proof = chunks[1].clone();
+	tampered_proof.proof = chunks[2].proof.clone();
+	chunks.push(tampered_chunk);
+	chunks.push(tampered_proof);
+
+	// every chunk is received from many peers
+	for _ in 0..100 {
+		for chunk in chunks.iter() {
+			assert_eq!(
+				per_candidate.verify_chunk_cached(&erasure_root, chunk),
+				verify_chunk(&erasure_root, chunk),
+			);
+		}
+	}
+
+	// only verified chunks are cached
+	assert_eq!(per_candidate.verified_branches.len(), test_state.validator_public.len());
+
+	// the cache does not apply to other erasure roots
+	let other_root = Hash::repeat_byte(0xAB);
+	assert!(!per_candidate.verify_chunk_cached(&other_root, &chunks[0]));
+}
+
+#[test]
+fn chunks_claiming_another_index_than_their_proof_are_penalized() {
+	let test_state = TestState::default();
+
+	let peer = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+	let candidate_hash = test_state.candidates[0].hash();
+	let erasure_root = test_state.candidates[0].descriptor.erasure_root;
+
+	// data and proof of chunk `2` verify, but only at position `2`
+	let valid = make_valid_availability_gossip(&test_state, 0, 2);
+	let mut misplaced = valid.clone();
+	misplaced.erasure_chunk.index = 3;
+
+	assert!(verify_chunk(&erasure_root, &valid.erasure_chunk));
+	assert!(!verify_chunk(&erasure_root, &misplaced.erasure_chunk));
+
+	// a cached verification of the chunk at its position does not carry over
+	let mut per_candidate = PerCandidate::default();
+	assert!(per_candidate.verify_chunk_cached(&erasure_root, &valid.erasure_chunk));
+	assert!(!per_candidate.verify_chunk_cached(&erasure_root, &misplaced.erasure_chunk));
+
+	let state = test_harness(keystore, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let script = test_state.view_change_script();
+		testing::drive_view_change(&mut virtual_overseer, view![test_state.relay_parent], &script).await;
+
+		overseer_send(
+			&mut virtual_overseer,
+			NetworkBridgeEvent::PeerMessage(peer.clone(), chunk_protocol_message(misplaced)),
+		).await;
+
+		// the descriptor is fetched anew before blaming the peer
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				relay_parent,
+				RuntimeApiRequest::AvailabilityCores(tx),
+			)) => {
+				assert_eq!(relay_parent, test_state.relay_parent);
+				let cores = script.availability_cores_per_relay_parent[&relay_parent].clone();
+				tx.send(Ok(cores)).unwrap();
+			}
+		);
+
+		expect_report(&mut virtual_overseer, &peer, COST_MERKLE_PROOF_INVALID).await;
+	});
+
+	assert!(state.per_candidate[&candidate_hash].servable_chunk(&3).is_none());
+}
+
+/// Receive chunks `1` and `2` of candidate `0` from peers, with a limit on the size of
+/// buffered chunks exceeding the size of both chunks by `excess` bytes.
+/// Returns the indices of the buffered chunks.
+fn receive_chunks_with_buffer_limit(excess: isize, memory_policy: MemoryPolicy) -> Vec<ValidatorIndex> {
+	let test_state = TestState::default();
+
+	let messages = vec![
+		make_valid_availability_gossip(&test_state, 0, 1),
+		make_valid_availability_gossip(&test_state, 0, 2),
+	];
+	let candidate_hash = test_state.candidates[0].hash();
+
+	let size = messages.iter().map(|m| chunk_size(&m.erasure_chunk)).sum::<usize>();
+	let config = Config {
+		max_buffered_bytes: Some((size as isize + excess) as usize),
+		memory_policy,
+		..Default::default()
+	};
+
+	let mut state = ProtocolState::with_config(config);
+	let per_candidate = state.per_candidate.entry(candidate_hash).or_default();
+	per_candidate.descriptor = test_state.candidates[0].descriptor.clone();
+	per_candidate.live_in.insert(test_state.relay_parent);
+
+	let pool = sp_core::testing::TaskExecutor::new();
+	let (mut ctx, _virtual_overseer) =
+		test_helpers::make_subsystem_context::<AvailabilityDistributionMessage, _>(pool);
+
+	executor::block_on(async {
+		for message in messages {
+			process_incoming_peer_message(&mut ctx, &mut state, PeerId::random(), message, &Default::default())
+				.await
+				.unwrap();
+		}
+	});
+
+	let mut buffered = state.per_candidate[&candidate_hash].message_vault.keys().cloned().collect::<Vec<_>>();
+	buffered.sort();
+	buffered
+}
+
+#[test]
+fn reject_new_memory_policy_drops_chunks_beyond_limit() {
+	assert_eq!(receive_chunks_with_buffer_limit(0, MemoryPolicy::RejectNew), vec![1, 2]);
+	assert_eq!(receive_chunks_with_buffer_limit(-1, MemoryPolicy::RejectNew), vec![1]);
+}
+
+#[test]
+fn evict_oldest_memory_policy_evicts_chunks_beyond_limit() {
+	assert_eq!(receive_chunks_with_buffer_limit(0, MemoryPolicy::EvictOldest), vec![1, 2]);
+	assert_eq!(receive_chunks_with_buffer_limit(-1, MemoryPolicy::EvictOldest), vec![2]);
+}
+
+#[test]
+fn buffered_bytes_follow_insertions_and_removals() {
+	let test_state = TestState::default();
+	let candidate_hash = test_state.candidates[0].hash();
+
+	let mut state = ProtocolState::default();
+	let vault_size = |state: &ProtocolState| state.per_candidate[&candidate_hash]
+		.message_vault
+		.values()
+		.map(|message| chunk_size(&message.erasure_chunk))
+		.sum::<usize>();
+
+	let per_candidate = state.per_candidate.entry(candidate_hash).or_default();
+	for index in 0..3 {
+		per_candidate.insert_chunk(make_valid_availability_gossip(&test_state, 0, index), true);
+	}
+	// replacing a chunk does not count it twice
+	per_candidate.insert_chunk(make_valid_availability_gossip(&test_state, 0, 1), false);
+	per_candidate.insert_chunk(
+		AvailabilityGossipMessage {
+			candidate_hash,
+			erasure_chunk: ErasureChunk { chunk: vec![0u8; 16], index: 3, proof: vec![] },
+		},
+		false,
+	);
+	per_candidate.insert_chunk(make_valid_availability_gossip(&test_state, 0, 3), true);
+	assert_eq!(state.total_buffered_bytes(), vault_size(&state));
+
+	let evicted = state.evict_oldest_chunk(&Default::default()).unwrap();
+	assert!(evicted > 0);
+	assert_eq!(state.per_candidate[&candidate_hash].message_vault.len(), 3);
+	assert_eq!(state.total_buffered_bytes(), vault_size(&state));
+
+	state.clear_message_vaults(&Default::default());
+	assert_eq!(state.total_buffered_bytes(), 0);
+}
+
+#[test]
+fn originated_and_relayed_chunks_are_accounted() {
+	let test_state = TestState::default();
+
+	let peer_a = PeerId::random();
+	let peer_b = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+
+	let registry = prometheus::Registry::new();
+	let metrics = <Metrics as metrics::Metrics>::try_register(&registry).unwrap();
+	let inner = metrics.0.clone().unwrap();
+
+	test_harness_with_metrics(keystore, Default::default(), metrics, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			relay_parent: current,
+			validator_public,
+			ancestors,
+			candidates,
+			pov_blocks,
+			..
+		} = test_state.clone();
+
+		setup_peer_with_view(&mut virtual_overseer, peer_a.clone(), view![current]).await;
+
+		// we hold the available data of candidate `0` and send all of its chunks
+		change_our_view(
+			&mut virtual_overseer,
+			view![current],
+			&validator_public,
+			vec![ancestors[0]],
+			hashmap! { current => 1 },
+			hashmap! {
+				current => vec![
+					occupied_core_from_candidate(&candidates[0]),
+					occupied_core_from_candidate(&candidates[1]),
+				],
+			},
+			hashmap! { candidates[0].hash() => true, candidates[1].hash() => false },
+			hashmap! { candidates[0].hash() => (pov_blocks[0].clone(), test_state.persisted_validation_data.clone())},
+			hashmap! { candidates[0].hash() => vec![peer_a.clone()] },
+		).await;
+
+		// a chunk of candidate `1` is relayed
+		let valid = make_valid_availability_gossip(&test_state, 1, 2);
+		peer_send_message(&mut virtual_overseer, peer_b.clone(), valid.clone(), BENEFIT_VALID_MESSAGE_FIRST).await;
+		expect_chunks_network_message(
+			&mut virtual_overseer,
+			&[peer_a.clone()],
+			&[candidates[1].hash()],
+			&[valid.erasure_chunk],
+			MessagePriority::Low,
+		).await;
+	});
+
+	assert_eq!(inner.chunks_originated.get(), test_state.validator_public.len() as u64);
+	assert_eq!(inner.chunks_relayed.get(), 1);
+	assert_eq!(
+		inner.gossipped_availability_chunks.get(),
+		test_state.validator_public.len() as u64 + 1,
+	);
+}
+
+#[test]
+fn oversized_chunks_are_rejected_before_verification() {
+	let test_state = TestState::default();
+
+	let peer = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+
+	test_harness(keystore, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			relay_parent: current,
+			validator_public,
+			ancestors,
+			candidates,
+			..
+		} = test_state.clone();
+
+		change_our_view(
+			&mut virtual_overseer,
+			view![current],
+			&validator_public,
+			vec![ancestors[0]],
+			hashmap! { current => 1 },
+			hashmap! { current => vec![occupied_core_from_candidate(&candidates[0])] },
+			hashmap! { candidates[0].hash() => false },
+			hashmap! {},
+			hashmap! {},
+		).await;
+
+		let mut oversized = make_valid_availability_gossip(&test_state, 0, 2);
+		oversized.erasure_chunk.proof = vec![vec![0u8; 32]; 10_000];
+
+		// the oversized proof is not even verified
+		peer_send_message(&mut virtual_overseer, peer.clone(), oversized, COST_OVERSIZED_CHUNK).await;
+
+		let valid = make_valid_availability_gossip(&test_state, 0, 2);
+		assert!(chunk_within_limits(&valid.erasure_chunk, validator_public.len(), Config::default().max_pov_size));
+		peer_send_message(&mut virtual_overseer, peer.clone(), valid, BENEFIT_VALID_MESSAGE_FIRST).await;
+	});
+}
+
+#[test]
+fn oversized_chunks_held_back_are_rejected_once_their_candidate_is_known() {
+	let test_state = TestState::default();
+
+	let peer = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+	let config = Config { defer_unknown_candidates: true, ..Default::default() };
+
+	test_harness_with_config(keystore, config, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			relay_parent: current,
+			validator_public,
+			ancestors,
+			candidates,
+			..
+		} = test_state.clone();
+
+		// the candidate is not known yet, so neither are its size limits
+		let mut oversized = make_valid_availability_gossip(&test_state, 0, 2);
+		oversized.erasure_chunk.proof = vec![vec![0u8; 32]; 10_000];
+		overseer_send(&mut virtual_overseer, NetworkBridgeEvent::PeerConnected(peer.clone(), ObservedRole::Full)).await;
+		overseer_send(
+			&mut virtual_overseer,
+			NetworkBridgeEvent::PeerMessage(peer.clone(), chunk_protocol_message(oversized)),
+		).await;
+
+		change_our_view(
+			&mut virtual_overseer,
+			view![current],
+			&validator_public,
+			vec![ancestors[0]],
+			hashmap! { current => 1 },
+			hashmap! { current => vec![occupied_core_from_candidate(&candidates[0])] },
+			hashmap! { candidates[0].hash() => false },
+			hashmap! {},
+			hashmap! {},
+		).await;
+
+		expect_report(&mut virtual_overseer, &peer, COST_OVERSIZED_CHUNK).await;
+	});
+}
+
+#[test]
+fn chunk_limits_scale_with_validators_and_pov_size() {
+	let chunk = |len: usize, proof_nodes: usize| ErasureChunk {
+		chunk: vec![0u8; len],
+		index: 0,
+		proof: vec![vec![0u8; 32]; proof_nodes],
+	};
+
+	let max_pov_size = 1024 * 1024;
+	let max_data_len = max_pov_size as usize + MAX_VALIDATION_DATA_OVERHEAD;
+
+	// with 10 validators the data is split into 4 shards
+	assert!(chunk_within_limits(&chunk(max_data_len / 4, 6), 10, max_pov_size));
+	assert!(!chunk_within_limits(&chunk(max_data_len / 2, 6), 10, max_pov_size));
+	assert!(!chunk_within_limits(&chunk(max_data_len / 4, 7), 10, max_pov_size));
+
+	// the same chunk is fine for a single validator
+	assert!(chunk_within_limits(&chunk(max_data_len / 2, 2), 1, max_pov_size));
+
+	// proof nodes are bounded in size
+	let mut huge_node = chunk(1, 1);
+	huge_node.proof[0] = vec![0u8; MAX_PROOF_NODE_SIZE + 1];
+	assert!(!chunk_within_limits(&huge_node, 10, max_pov_size));
+}
+
+/// Relay chunk `2` of candidate `0` to the given interested `peers`, with a fanout of `fanout`
+/// and the peer selection seeded with `seed`. Returns the peers the chunk was sent to.
+fn relay_chunk_with_fanout(peers: &[PeerId], fanout: usize, seed: u64) -> HashSet<PeerId> {
+	let test_state = TestState::default();
+
+	let message = make_valid_availability_gossip(&test_state, 0, 2);
+	let candidate_hash = test_state.candidates[0].hash();
+
+	let config = Config {
+		max_gossip_fanout: Some(fanout),
+		peer_selection_seed: Some(seed),
+		..Default::default()
+	};
+
+	let mut state = ProtocolState::with_config(config);
+	state.per_relay_parent.insert(test_state.relay_parent, PerRelayParent {
+		ancestors: vec![],
+		live_candidates: std::iter::once(candidate_hash).collect(),
+		our_group: None,
+		group_assignment: None,
+		session_index: None,
+	});
+	let per_candidate = state.per_candidate.entry(candidate_hash).or_default();
+	per_candidate.descriptor = test_state.candidates[0].descriptor.clone();
+	per_candidate.live_in.insert(test_state.relay_parent);
+	for peer in peers {
+		state.peer_views.insert(peer.clone(), view![test_state.relay_parent]);
+	}
+
+	let pool = sp_core::testing::TaskExecutor::new();
+	let (mut ctx, _virtual_overseer) =
+		test_helpers::make_subsystem_context::<AvailabilityDistributionMessage, _>(pool);
+
+	executor::block_on(
+		process_incoming_peer_message(&mut ctx, &mut state, PeerId::random(), message, &Default::default())
+	).unwrap();
+
+	state.per_candidate[&candidate_hash].sent_messages.keys().cloned().collect()
+}
+
+#[test]
+fn seeded_peer_selection_is_reproducible() {
+	let peers: Vec<PeerId> = (0..6).map(|_| PeerId::random()).collect();
+
+	let chosen = relay_chunk_with_fanout(&peers, 2, 42);
+	assert_eq!(chosen.len(), 2);
+	assert!(chosen.iter().all(|peer| peers.contains(peer)));
+
+	for _ in 0..5 {
+		assert_eq!(relay_chunk_with_fanout(&peers, 2, 42), chosen);
+	}
+
+	// all peers are chosen if there are not more of them than the fanout allows
+	assert_eq!(relay_chunk_with_fanout(&peers, 6, 42).len(), 6);
+}
+
+/// Expect `message` to be sent to a single one of the `expected` peers, which is returned.
+async fn expect_sent_to_one_of(
+	virtual_overseer: &mut test_helpers::TestSubsystemContextHandle<AvailabilityDistributionMessage>,
+	message: &AvailabilityGossipMessage,
+	expected: &[PeerId],
+) -> PeerId {
+	assert_matches!(
+		overseer_recv(virtual_overseer).await,
+		AllMessages::NetworkBridge(
+			NetworkBridgeMessage::SendPrioritizedValidationMessage(
+				peers,
+				protocol_v1::ValidationProtocol::AvailabilityDistribution(
+					protocol_v1::AvailabilityDistributionMessage::Chunk(candidate_hash, chunk),
+				),
+				MessagePriority::Low,
+			)
+		) => {
+			assert_eq!(candidate_hash, message.candidate_hash);
+			assert_eq!(chunk, message.erasure_chunk);
+			assert_eq!(peers.len(), 1);
+			assert!(expected.contains(&peers[0]));
+			peers[0].clone()
+		}
+	)
+}
+
+#[test]
+fn regossip_all_sends_chunks_to_interested_peers_lacking_them() {
+	let test_state = TestState::default();
+
+	let peer_a = PeerId::random();
+	let peer_b = PeerId::random();
+	let peer_c = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+	let config = Config { max_gossip_fanout: Some(1), ..Default::default() };
+
+	test_harness_with_config(keystore, config, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let current = test_state.relay_parent;
+		let script = test_state.view_change_script();
+		testing::drive_view_change(&mut virtual_overseer, view![current], &script).await;
+
+		setup_peer_with_view(&mut virtual_overseer, peer_b.clone(), view![current]).await;
+		setup_peer_with_view(&mut virtual_overseer, peer_c.clone(), view![current]).await;
+
+		let valid = make_valid_availability_gossip(&test_state, 0, 2);
+		peer_send_message(&mut virtual_overseer, peer_a.clone(), valid.clone(), BENEFIT_VALID_MESSAGE_FIRST).await;
+
+		// the fanout limits relaying to one of the interested peers
+		let relayed_to = expect_sent_to_one_of(&mut virtual_overseer, &valid, &[peer_b.clone(), peer_c.clone()]).await;
+		let missing = if relayed_to == peer_b { peer_c.clone() } else { peer_b.clone() };
+
+		overseer_send(&mut virtual_overseer, AvailabilityDistributionMessage::ReGossipAll).await;
+		expect_sent_to_one_of(&mut virtual_overseer, &valid, &[missing]).await;
+
+		// all interested peers have the chunk now
+		overseer_send(&mut virtual_overseer, AvailabilityDistributionMessage::ReGossipAll).await;
+		assert!(
+			overseer_recv(&mut virtual_overseer)
+				.timeout(Duration::from_millis(100))
+				.await
+				.is_none()
+		);
+	});
+}
+
+#[test]
+fn message_vaults_are_cleared_on_request() {
+	let test_state = TestState::default();
+
+	let peer_a = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+
+	test_harness_with_config(keystore, Default::default(), move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let current = test_state.relay_parent;
+		let script = test_state.view_change_script();
+		testing::drive_view_change(&mut virtual_overseer, view![current], &script).await;
+
+		let valid = make_valid_availability_gossip(&test_state, 0, 2);
+		peer_send_message(&mut virtual_overseer, peer_a.clone(), valid.clone(), BENEFIT_VALID_MESSAGE_FIRST).await;
+
+		assert_eq!(
+			fetch_chunks(&mut virtual_overseer, valid.candidate_hash).await,
+			vec![valid.erasure_chunk.clone()],
+		);
+
+		overseer_send(&mut virtual_overseer, AvailabilityDistributionMessage::ClearMessageVaults).await;
+
+		assert!(fetch_chunks(&mut virtual_overseer, valid.candidate_hash).await.is_empty());
+	});
+}
+
+#[test]
+fn authorities_are_prioritized_under_the_fanout() {
+	let test_state = TestState::default();
+
+	let peer_a = PeerId::random();
+	let full = PeerId::random();
+	let authority = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+	let config = Config { max_gossip_fanout: Some(1), prioritize_authorities: true, ..Default::default() };
+
+	test_harness_with_config(keystore, config, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let current = test_state.relay_parent;
+		let script = test_state.view_change_script();
+		testing::drive_view_change(&mut virtual_overseer, view![current], &script).await;
+
+		setup_peer_with_view(&mut virtual_overseer, full.clone(), view![current]).await;
+		overseer_send(
+			&mut virtual_overseer,
+			NetworkBridgeEvent::PeerConnected(authority.clone(), ObservedRole::Authority),
+		).await;
+		overseer_send(&mut virtual_overseer, NetworkBridgeEvent::PeerViewChange(authority.clone(), view![current])).await;
+
+		// regardless of the peer selection, the authority is served first
+		for index in 1..4 {
+			let valid = make_valid_availability_gossip(&test_state, 0, index);
+			peer_send_message(&mut virtual_overseer, peer_a.clone(), valid.clone(), BENEFIT_VALID_MESSAGE_FIRST).await;
+			expect_sent_to_one_of(&mut virtual_overseer, &valid, &[authority.clone()]).await;
+		}
+	});
+}
+
+#[test]
+fn recently_interested_peers_are_prioritized_under_the_fanout() {
+	let test_state = TestState::default();
+
+	let message = make_valid_availability_gossip(&test_state, 0, 2);
+	let candidate_hash = test_state.candidates[0].hash();
+	let relay_parent = test_state.relay_parent;
+
+	let config = Config {
+		max_gossip_fanout: Some(1),
+		prioritize_recently_interested_peers: true,
+		..Default::default()
+	};
+
+	let mut state = ProtocolState::with_config(config);
+	state.per_relay_parent.insert(relay_parent, PerRelayParent {
+		ancestors: vec![],
+		live_candidates: std::iter::once(candidate_hash).collect(),
+		our_group: None,
+		group_assignment: None,
+		session_index: None,
+	});
+	let per_candidate = state.per_candidate.entry(candidate_hash).or_default();
+	per_candidate.descriptor = test_state.candidates[0].descriptor.clone();
+	per_candidate.live_in.insert(relay_parent);
+
+	let now = Instant::now();
+	let peers: Vec<PeerId> = (0..3).map(|_| PeerId::random()).collect();
+	for (peer, secs_ago) in peers.iter().zip(vec![3, 1, 2]) {
+		state.peer_views.insert(peer.clone(), view![relay_parent]);
+		state.last_view_changes.insert(peer.clone(), now - Duration::from_secs(secs_ago));
+	}
+
+	let pool = sp_core::testing::TaskExecutor::new();
+	let (mut ctx, _virtual_overseer) =
+		test_helpers::make_subsystem_context::<AvailabilityDistributionMessage, _>(pool);
+
+	executor::block_on(
+		process_incoming_peer_message(&mut ctx, &mut state, PeerId::random(), message, &Default::default())
+	).unwrap();
+
+	let chosen: Vec<PeerId> = state.per_candidate[&candidate_hash].sent_messages.keys().cloned().collect();
+	assert_eq!(chosen, vec![peers[1].clone()]);
+}
+
+#[test]
+fn chunks_of_candidates_estimated_to_be_available_are_not_relayed() {
+	let test_state = TestState::default();
+
+	let candidate_hash = test_state.candidates[0].hash();
+	let relay_parent = test_state.relay_parent;
+	let peer_a = PeerId::random();
+	let peer_b = PeerId::random();
+
+	let config = Config { suppress_gossip_once_available: true, ..Default::default() };
+	let mut state = ProtocolState::with_config(config);
+	state.per_relay_parent.insert(relay_parent, PerRelayParent {
+		ancestors: vec![],
+		live_candidates: std::iter::once(candidate_hash).collect(),
+		our_group: None,
+		group_assignment: None,
+		session_index: None,
+	});
+	let per_candidate = state.per_candidate.entry(candidate_hash).or_default();
+	per_candidate.descriptor = test_state.candidates[0].descriptor.clone();
+	per_candidate.validators = Arc::new(test_state.validator_public.clone());
+	per_candidate.live_in.insert(relay_parent);
+	state.peer_views.insert(peer_a.clone(), View::default());
+	state.peer_views.insert(peer_b.clone(), view![relay_parent]);
+
+	let pool = sp_core::testing::TaskExecutor::new();
+	let (mut ctx, mut virtual_overseer) =
+		test_helpers::make_subsystem_context::<AvailabilityDistributionMessage, _>(pool);
+
+	let mut process = |state: &mut ProtocolState, origin: PeerId, index| {
+		let message = make_valid_availability_gossip(&test_state, 0, index);
+		executor::block_on(async {
+			process_incoming_peer_message(&mut ctx, state, origin, message, &Default::default()).await.unwrap();
+
+			let mut relayed_to = Vec::new();
+			while let Some(message) = virtual_overseer.recv().timeout(Duration::from_millis(50)).await {
+				match message {
+					AllMessages::NetworkBridge(NetworkBridgeMessage::ReportPeer(..)) => {}
+					AllMessages::NetworkBridge(
+						NetworkBridgeMessage::SendPrioritizedValidationMessage(peers, _, _)
+					) => relayed_to.extend(peers),
+					message => panic!("Unexpected message {:?}", message),
+				}
+			}
+			relayed_to
+		})
+	};
+
+	for index in 0..4 {
+		assert_eq!(process(&mut state, peer_a.clone(), index), vec![peer_b.clone()]);
+	}
+	assert!(!state.per_candidate[&candidate_hash].is_estimated_available());
+
+	// the last index completes the set
+	assert!(process(&mut state, peer_a.clone(), 4).is_empty());
+	assert!(state.per_candidate[&candidate_hash].is_estimated_available());
+
+	// a newly connected peer would be interested, but further chunks are accepted without relaying them
+	let peer_c = PeerId::random();
+	let peer_d = PeerId::random();
+	state.peer_views.insert(peer_c.clone(), view![relay_parent]);
+	state.peer_views.insert(peer_d.clone(), View::default());
+	assert!(process(&mut state, peer_d.clone(), 2).is_empty());
+	assert!(state.per_candidate[&candidate_hash].received_messages[&peer_d].contains(&2));
+}
+
+#[test]
+fn group_responsible_follows_group_rotations() {
+	let test_state = TestState::default();
+
+	let (groups, rotation) = test_state.validator_groups.clone();
+	assert_eq!(rotation.group_rotation_frequency, 100);
+
+	let candidate_hash = test_state.candidates[0].hash();
+	let before_rotation = test_state.relay_parent;
+	let after_rotation = test_state.ancestors[0];
+	let assignment_at = |now| GroupAssignment {
+		rotation: GroupRotationInfo { now, ..rotation.clone() },
+		n_groups: groups.len(),
+	};
+
+	let mut state = ProtocolState::default();
+	for (relay_parent, now) in vec![(before_rotation, 1), (after_rotation, 101)] {
+		state.per_relay_parent.insert(relay_parent, PerRelayParent {
+			ancestors: vec![],
+			live_candidates: std::iter::once(candidate_hash).collect(),
+			our_group: Some(GroupIndex::from(1)),
+			group_assignment: Some(assignment_at(now)),
+			session_index: None,
+		});
+	}
+	let per_candidate = state.per_candidate.entry(candidate_hash).or_default();
+	per_candidate.group_responsible = Some(GroupIndex::from(0));
+	per_candidate.core_index = Some(CoreIndex(0));
+	per_candidate.live_in.insert(before_rotation);
+
+	let per_candidate = &state.per_candidate[&candidate_hash];
+	assert_eq!(
+		per_candidate.group_responsible_at(&state.per_relay_parent[&before_rotation]),
+		Some(GroupIndex::from(0)),
+	);
+	assert_eq!(
+		per_candidate.group_responsible_at(&state.per_relay_parent[&after_rotation]),
+		Some(GroupIndex::from(1)),
+	);
+
+	// we are a member of group `1`, which takes over the core only after the rotation
+	assert!(!state.is_backed_by_our_group(&candidate_hash));
+	state.per_candidate.get_mut(&candidate_hash).unwrap().live_in.insert(after_rotation);
+	assert!(state.is_backed_by_our_group(&candidate_hash));
+
+	// without knowing the rotation, the group responsible when fetching the candidate is used
+	state.per_relay_parent.get_mut(&after_rotation).unwrap().group_assignment = None;
+	assert_eq!(
+		state.per_candidate[&candidate_hash].group_responsible_at(&state.per_relay_parent[&after_rotation]),
+		Some(GroupIndex::from(0)),
+	);
+	assert!(!state.is_backed_by_our_group(&candidate_hash));
+}
+
+#[test]
+fn own_chunks_of_other_groups_are_relayed_but_not_stored() {
+	let test_state = TestState::default();
+
+	// we are validator `0`, which is a member of group `0`
+	let our_group = test_state.validator_groups.0.iter().position(|group| group.contains(&0)).unwrap();
+	assert_eq!(our_group, 0);
+
+	let message = make_valid_availability_gossip(&test_state, 0, 0);
+	let candidate_hash = test_state.candidates[0].hash();
+	let peer = PeerId::random();
+
+	let config = Config { store_own_group_only: true, ..Default::default() };
+	let mut state = ProtocolState::with_config(config);
+	state.per_relay_parent.insert(test_state.relay_parent, PerRelayParent {
+		ancestors: vec![],
+		live_candidates: std::iter::once(candidate_hash).collect(),
+		our_group: Some(GroupIndex::from(our_group as u32)),
+		group_assignment: None,
+		session_index: None,
+	});
+	let per_candidate = state.per_candidate.entry(candidate_hash).or_default();
+	per_candidate.descriptor = test_state.candidates[0].descriptor.clone();
+	per_candidate.validators = Arc::new(test_state.validator_public.clone());
+	per_candidate.validator_index = Some(0);
+	per_candidate.group_responsible = Some(GroupIndex::from(1));
+	per_candidate.live_in.insert(test_state.relay_parent);
+	state.peer_views.insert(peer.clone(), view![test_state.relay_parent]);
+
+	let pool = sp_core::testing::TaskExecutor::new();
+	let (mut ctx, mut virtual_overseer) =
+		test_helpers::make_subsystem_context::<AvailabilityDistributionMessage, _>(pool);
+
+	executor::block_on(
+		process_incoming_peer_message(&mut ctx, &mut state, PeerId::random(), message, &Default::default())
+	).unwrap();
+
+	// the chunk is relayed to the interested peer, without being stored
+	executor::block_on(async {
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::NetworkBridge(
+				NetworkBridgeMessage::SendPrioritizedValidationMessage(peers, _, _)
+			) => {
+				assert_eq!(peers, vec![peer.clone()]);
+			}
+		);
+
+		assert!(
+			overseer_recv(&mut virtual_overseer)
+				.timeout(Duration::from_millis(100))
+				.await
+				.is_none()
+		);
+	});
+
+	assert!(state.per_candidate[&candidate_hash].pending_stores.is_empty());
+}
+
+#[test]
+fn peer_messages_beyond_the_queue_bound_are_shed() {
+	let test_state = TestState::default();
+	let peer = PeerId::random();
+
+	let registry = prometheus::Registry::new();
+	let metrics = <Metrics as metrics::Metrics>::try_register(&registry).unwrap();
+	let inner = metrics.0.clone().unwrap();
+
+	let config = Config { max_deferred_messages: 2, ..Default::default() };
+	let mut state = ProtocolState::with_config(config);
+	state.peer_views.insert(peer.clone(), View::default());
+
+	let pool = sp_core::testing::TaskExecutor::new();
+	let (mut ctx, mut virtual_overseer) =
+		test_helpers::make_subsystem_context::<AvailabilityDistributionMessage, _>(pool);
+
+	let chunk = |index| NetworkBridgeEvent::PeerMessage(
+		peer.clone(),
+		chunk_protocol_message(make_valid_availability_gossip(&test_state, 0, index)),
+	);
+	let mut deferred: VecDeque<_> = vec![chunk(0), chunk(1)]
+		.into_iter()
+		.map(|event| FromOverseer::Communication { msg: event.into() })
+		.collect();
+
+	executor::block_on(async {
+		// the queue is full, so another message of the peer is dropped
+		{
+			let queued = overseer_send(&mut virtual_overseer, chunk(2));
+			futures::pin_mut!(queued);
+			assert!(futures::poll!(queued.as_mut()).is_pending());
+
+			assert_eq!(most_recent_queued_view(&mut ctx, &state, &metrics, &mut deferred).await, None);
+		}
+		// without penalizing the peer, as the backlog is ours
+		assert!(virtual_overseer.recv().timeout(Duration::from_millis(50)).await.is_none());
+		assert_eq!(deferred.len(), 2);
+		assert_eq!(inner.messages_shed.get(), 1);
+
+		// changes of our view are queued regardless
+		let view = view![test_state.relay_parent];
+		{
+			let queued = overseer_send(&mut virtual_overseer, NetworkBridgeEvent::OurViewChange(view.clone()));
+			futures::pin_mut!(queued);
+			assert!(futures::poll!(queued.as_mut()).is_pending());
+
+			assert_eq!(most_recent_queued_view(&mut ctx, &state, &metrics, &mut deferred).await, Some(view));
+		}
+		assert_eq!(deferred.len(), 3);
+		assert_eq!(inner.messages_shed.get(), 1);
+	});
+}
+
+#[test]
+fn view_changes_of_peers_which_never_connected_are_not_timed() {
+	let test_state = TestState::default();
+	let connected = PeerId::random();
+	let unknown = PeerId::random();
+
+	let mut state = ProtocolState::default();
+	state.peer_views.insert(connected.clone(), View::default());
+
+	let pool = sp_core::testing::TaskExecutor::new();
+	let (mut ctx, _virtual_overseer) =
+		test_helpers::make_subsystem_context::<AvailabilityDistributionMessage, _>(pool);
+
+	executor::block_on(async {
+		for peer in vec![connected.clone(), unknown.clone()] {
+			let view = view![test_state.relay_parent];
+			handle_peer_view_change(&mut ctx, &mut state, peer, view, &Default::default()).await;
+		}
+	});
+
+	assert!(state.last_view_changes.contains_key(&connected));
+	assert!(!state.last_view_changes.contains_key(&unknown));
+}
+
+#[test]
+fn superseded_peer_views_are_shed_with_a_penalty() {
+	let test_state = TestState::default();
+	let peer_a = PeerId::random();
+	let peer_b = PeerId::random();
+
+	let registry = prometheus::Registry::new();
+	let metrics = <Metrics as metrics::Metrics>::try_register(&registry).unwrap();
+	let inner = metrics.0.clone().unwrap();
+
+	let mut state = ProtocolState::default();
+	state.peer_views.insert(peer_a.clone(), View::default());
+	state.peer_views.insert(peer_b.clone(), View::default());
+
+	let pool = sp_core::testing::TaskExecutor::new();
+	let (mut ctx, mut virtual_overseer) =
+		test_helpers::make_subsystem_context::<AvailabilityDistributionMessage, _>(pool);
+
+	let current = test_state.relay_parent;
+	let ancestor = test_state.ancestors[0];
+	let queued_views = vec![
+		NetworkBridgeEvent::PeerViewChange(peer_a.clone(), view![ancestor]),
+		NetworkBridgeEvent::PeerViewChange(peer_b.clone(), view![ancestor]),
+	];
+	let mut deferred: VecDeque<_> = queued_views
+		.into_iter()
+		.map(|event| FromOverseer::Communication { msg: event.into() })
+		.collect();
+
+	executor::block_on(async {
+		{
+			let queued = overseer_send(
+				&mut virtual_overseer,
+				NetworkBridgeEvent::PeerViewChange(peer_a.clone(), view![current]),
+			);
+			futures::pin_mut!(queued);
+			assert!(futures::poll!(queued.as_mut()).is_pending());
+
+			assert_eq!(most_recent_queued_view(&mut ctx, &state, &metrics, &mut deferred).await, None);
+		}
+		expect_report(&mut virtual_overseer, &peer_a, COST_VIEW_SUPERSEDED).await;
+		assert_eq!(inner.messages_shed.get(), 1);
+	});
+
+	// only the most recent view of each peer remains queued, views of other peers are kept
+	let queued_views = deferred
+		.iter()
+		.map(|message| match message {
+			FromOverseer::Communication {
+				msg: AvailabilityDistributionMessage::NetworkBridgeUpdateV1(
+					NetworkBridgeEvent::PeerViewChange(peer, view),
+				),
+			} => (peer.clone(), view.clone()),
+			_ => panic!("Only views of peers were queued"),
+		})
+		.collect::<Vec<_>>();
+	assert_eq!(queued_views, vec![(peer_b, view![ancestor]), (peer_a, view![current])]);
+}
+
+#[test]
+fn relay_parents_returning_to_our_view_keep_their_chunks() {
+	let test_state = TestState::default();
+
+	let current = test_state.relay_parent;
+	let candidate_hash = test_state.candidates[0].hash();
+	let message = make_valid_availability_gossip(&test_state, 0, 2);
+
+	let mut state = ProtocolState::default();
+	state.view = view![current];
+	state.per_relay_parent.insert(current, PerRelayParent {
+		ancestors: vec![],
+		live_candidates: std::iter::once(candidate_hash).collect(),
+		our_group: None,
+		group_assignment: None,
+		session_index: None,
+	});
+	let per_candidate = state.per_candidate.entry(candidate_hash).or_default();
+	per_candidate.descriptor = test_state.candidates[0].descriptor.clone();
+	per_candidate.validators = Arc::new(test_state.validator_public.clone());
+	per_candidate.live_in.insert(current);
+	per_candidate.insert_chunk(message.clone(), true);
+
+	let pool = sp_core::testing::TaskExecutor::new();
+	let (mut ctx, mut virtual_overseer) =
+		test_helpers::make_subsystem_context::<AvailabilityDistributionMessage, _>(pool);
+	let mut deferred = VecDeque::new();
+
+	// the relay parent returns with the view change queued right after the one removing it
+	let returning_view = view![current];
+	let queued = overseer_send(&mut virtual_overseer, NetworkBridgeEvent::OurViewChange(returning_view.clone()));
+	futures::pin_mut!(queued);
+	executor::block_on(async { assert!(futures::poll!(queued.as_mut()).is_pending()) });
+
+	executor::block_on(handle_our_view_change(
+		&mut ctx,
+		&test_state.keystore,
+		&mut state,
+		View::default(),
+		&Default::default(),
+		&mut deferred,
+	)).unwrap();
+
+	assert_eq!(deferred.len(), 1);
+	assert!(state.prefetched.contains(&current));
+	assert_eq!(state.per_candidate[&candidate_hash].chunk(2), Some(&message));
+
+	executor::block_on(handle_our_view_change(
+		&mut ctx,
+		&test_state.keystore,
+		&mut state,
+		returning_view,
+		&Default::default(),
+		&mut deferred,
+	)).unwrap();
+
+	assert!(state.prefetched.is_empty());
+	assert!(state.is_candidate_live(&candidate_hash));
+	assert_eq!(state.per_candidate[&candidate_hash].chunk(2), Some(&message));
+}
+
+/// An executor which can not run any task.
+#[derive(Clone)]
+struct RejectingSpawner;
+
+impl sp_core::traits::SpawnNamed for RejectingSpawner {
+	fn spawn_blocking(&self, name: &'static str, _: futures::future::BoxFuture<'static, ()>) {
+		panic!("Spawning blocking task {} was rejected", name);
+	}
+
+	fn spawn(&self, name: &'static str, _: futures::future::BoxFuture<'static, ()>) {
+		panic!("Spawning task {} was rejected", name);
+	}
+}
+
+#[test]
+fn own_chunk_is_stored_without_spawning_tasks() {
+	let test_state = TestState::default();
+
+	let message = make_valid_availability_gossip(&test_state, 0, 0);
+	let candidate_hash = test_state.candidates[0].hash();
+	let relay_parent = test_state.relay_parent;
+
+	let mut state = ProtocolState::default();
+	state.per_relay_parent.insert(relay_parent, PerRelayParent {
+		ancestors: vec![],
+		live_candidates: std::iter::once(candidate_hash).collect(),
+		our_group: None,
+		group_assignment: None,
+		session_index: None,
+	});
+	let per_candidate = state.per_candidate.entry(candidate_hash).or_default();
+	per_candidate.descriptor = test_state.candidates[0].descriptor.clone();
+	per_candidate.validators = Arc::new(test_state.validator_public.clone());
+	per_candidate.validator_index = Some(0);
+	per_candidate.live_in.insert(relay_parent);
+
+	// the store of our own chunk is critical, thus done on the main task
+	let (mut ctx, mut virtual_overseer) =
+		test_helpers::make_subsystem_context::<AvailabilityDistributionMessage, _>(RejectingSpawner);
+
+	let stored_chunk = message.erasure_chunk.clone();
+	let test_fut = async move {
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::AvailabilityStore(
+				AvailabilityStoreMessage::StoreChunk { candidate_hash: hash, validator_index, chunk, tx, .. }
+			) => {
+				assert_eq!(hash, candidate_hash);
+				assert_eq!(validator_index, 0);
+				assert_eq!(chunk, stored_chunk);
+				tx.send(Ok(())).unwrap();
+			}
+		);
+	};
+
+	{
+		let sut = process_incoming_peer_message(&mut ctx, &mut state, PeerId::random(), message, &Default::default());
+
+		futures::pin_mut!(test_fut);
+		futures::pin_mut!(sut);
+
+		let (_, result) = executor::block_on(future::join(test_fut, sut));
+		result.unwrap();
+	}
+
+	assert!(state.per_candidate[&candidate_hash].pending_stores.contains(&0));
+}
+
+#[test]
+fn chunks_of_disconnected_peers_are_buffered_without_reporting() {
+	let test_state = TestState::default();
+
+	let peer = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+	let candidate_hash = test_state.candidates[0].hash();
+
+	let state = test_harness(keystore, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			relay_parent: current,
+			validator_public,
+			ancestors,
+			candidates,
+			..
+		} = test_state.clone();
+
+		change_our_view(
+			&mut virtual_overseer,
+			view![current],
+			&validator_public,
+			vec![ancestors[0]],
+			hashmap! { current => 1 },
+			hashmap! { current => vec![occupied_core_from_candidate(&candidates[0])] },
+			hashmap! { candidates[0].hash() => false },
+			hashmap! {},
+			hashmap! {},
+		).await;
+
+		// the message was queued before the peer disconnected
+		overseer_send(&mut virtual_overseer, NetworkBridgeEvent::PeerConnected(peer.clone(), ObservedRole::Full)).await;
+		overseer_send(&mut virtual_overseer, NetworkBridgeEvent::PeerDisconnected(peer.clone())).await;
+
+		let valid = make_valid_availability_gossip(&test_state, 0, 2);
+		overseer_send(
+			&mut virtual_overseer,
+			NetworkBridgeEvent::PeerMessage(peer.clone(), chunk_protocol_message(valid)),
+		).await;
+
+		assert!(
+			overseer_recv(&mut virtual_overseer)
+				.timeout(Duration::from_millis(100))
+				.await
+				.is_none()
+		);
+	});
+
+	assert!(state.per_candidate[&candidate_hash].servable_chunk(&2).is_some());
+}
+
+async fn fetch_available_data(
+	virtual_overseer: &mut test_helpers::TestSubsystemContextHandle<AvailabilityDistributionMessage>,
+	candidate_hash: CandidateHash,
+) -> Option<AvailableData> {
+	let (tx, rx) = oneshot::channel();
+	overseer_send(virtual_overseer, AvailabilityDistributionMessage::FetchAvailableData { candidate_hash, tx }).await;
+	rx.await.unwrap()
+}
+
+#[test]
+fn chunks_of_peers_which_never_connected_are_ignored_and_reported() {
+	let test_state = TestState::default();
+
+	let peer = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+	let candidate_hash = test_state.candidates[0].hash();
+
+	let peer_2 = peer.clone();
+	let state = test_harness(keystore, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			relay_parent: current,
+			validator_public,
+			ancestors,
+			candidates,
+			..
+		} = test_state.clone();
+
+		change_our_view(
+			&mut virtual_overseer,
+			view![current],
+			&validator_public,
+			vec![ancestors[0]],
+			hashmap! { current => 1 },
+			hashmap! { current => vec![occupied_core_from_candidate(&candidates[0])] },
+			hashmap! { candidates[0].hash() => false },
+			hashmap! {},
+			hashmap! {},
+		).await;
+
+		// no `PeerConnected` was received for the peer
+		for candidate in 0..2 {
+			let message = make_valid_availability_gossip(&test_state, candidate, 2);
+			overseer_send(
+				&mut virtual_overseer,
+				NetworkBridgeEvent::PeerMessage(peer_2.clone(), chunk_protocol_message(message)),
+			).await;
+		}
+
+		// the peer is reported for each message, without being rewarded
+		for _ in 0..2 {
+			expect_report(&mut virtual_overseer, &peer_2, COST_NOT_CONNECTED).await;
+		}
+		assert!(overseer_recv(&mut virtual_overseer).timeout(Duration::from_millis(100)).await.is_none());
+	});
+
+	// but not tracked
+	assert!(!state.peer_views.contains_key(&peer));
+	assert_eq!(state.peer_count(), 0);
+	assert!(!state.per_candidate[&candidate_hash].message_vault.contains_key(&2));
+}
+
+#[test]
+fn available_data_is_served_once_enough_chunks_are_held() {
+	let test_state = TestState::default();
+
+	let peer = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+
+	test_harness(keystore, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			relay_parent: current,
+			validator_public,
+			ancestors,
+			candidates,
+			..
+		} = test_state.clone();
+
+		change_our_view(
+			&mut virtual_overseer,
+			view![current],
+			&validator_public,
+			vec![ancestors[0]],
+			hashmap! { current => 1 },
+			hashmap! { current => vec![occupied_core_from_candidate(&candidates[0])] },
+			hashmap! { candidates[0].hash() => false },
+			hashmap! {},
+			hashmap! {},
+		).await;
+
+		// a single chunk is not enough
+		let valid = make_valid_availability_gossip(&test_state, 0, 1);
+		peer_send_message(&mut virtual_overseer, peer.clone(), valid, BENEFIT_VALID_MESSAGE_FIRST).await;
+		assert!(fetch_available_data(&mut virtual_overseer, candidates[0].hash()).await.is_none());
+
+		// two out of five chunks are
+		let valid = make_valid_availability_gossip(&test_state, 0, 2);
+		peer_send_message(&mut virtual_overseer, peer.clone(), valid, BENEFIT_VALID_MESSAGE_FIRST).await;
+		let available_data = fetch_available_data(&mut virtual_overseer, candidates[0].hash()).await
+			.expect("Enough chunks to reconstruct the data");
+
+		let chunks = obtain_chunks(validator_public.len(), &available_data).unwrap();
+		assert_eq!(branches(&chunks).root(), candidates[0].descriptor.erasure_root);
+	});
+}
+
+#[test]
+fn available_data_of_badly_encoded_candidates_is_not_served() {
+	let test_state = TestState::default();
+
+	let candidate_hash = test_state.candidates[0].hash();
+	let n_validators = test_state.validator_public.len();
+	let available_data = |pov: usize| make_available_data(
+		test_state.persisted_validation_data.clone(),
+		test_state.pov_blocks[pov].clone(),
+	);
+
+	// a candidate committing to chunks of different data, each of them with a valid proof
+	let chunks = obtain_chunks(n_validators, &available_data(0)).unwrap();
+	let mut mixed = chunks.clone();
+	mixed[4] = obtain_chunks(n_validators, &available_data(1)).unwrap().swap_remove(4);
+	let mixed_branches = branches(&mixed);
+
+	let mut state = ProtocolState::default();
+	let per_candidate = state.per_candidate.entry(candidate_hash).or_default();
+	per_candidate.validators = Arc::new(test_state.validator_public.clone());
+	per_candidate.descriptor = CandidateDescriptor {
+		erasure_root: mixed_branches.root(),
+		..test_state.candidates[0].descriptor.clone()
+	};
+	for (index, (proof, chunk)) in mixed_branches.enumerate().filter(|(index, _)| [0, 2].contains(index)) {
+		per_candidate.insert_chunk(AvailabilityGossipMessage {
+			candidate_hash,
+			erasure_chunk: ErasureChunk { chunk: chunk.to_vec(), index: index as u32, proof },
+		}, true);
+	}
+
+	// the chunks decode to data which is not what the candidate committed to
+	assert!(state.reconstructed_available_data(&candidate_hash).is_none());
+
+	// while the same chunks are fine for a properly encoded candidate
+	state.per_candidate.get_mut(&candidate_hash).unwrap().descriptor.erasure_root = branches(&chunks).root();
+	assert_eq!(state.reconstructed_available_data(&candidate_hash), Some(available_data(0)));
+}
+
+async fn fetch_chunks(
+	virtual_overseer: &mut test_helpers::TestSubsystemContextHandle<AvailabilityDistributionMessage>,
+	candidate_hash: CandidateHash,
+) -> Vec<ErasureChunk> {
+	let (tx, rx) = oneshot::channel();
+	overseer_send(virtual_overseer, AvailabilityDistributionMessage::FetchChunks { candidate_hash, tx }).await;
+	rx.await.unwrap()
+}
+
+#[test]
+fn only_network_chunk_requests_are_rate_limited() {
+	let test_state = TestState::default();
+
+	let peer = PeerId::random();
+	let requester = PeerId::random();
+	let candidate_hash = test_state.candidates[0].hash();
+
+	let keystore = test_state.keystore.clone();
+
+	let config = Config {
+		max_chunk_requests_per_peer: 2,
+		..Default::default()
+	};
+
+	let mut state = test_harness_with_config(keystore, config, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			relay_parent: current,
+			validator_public,
+			ancestors,
+			candidates,
+			..
+		} = test_state.clone();
+
+		change_our_view(
+			&mut virtual_overseer,
+			view![current],
+			&validator_public,
+			vec![ancestors[0]],
+			hashmap! { current => 1 },
+			hashmap! { current => vec![occupied_core_from_candidate(&candidates[0])] },
+			hashmap! { candidates[0].hash() => false },
+			hashmap! {},
+			hashmap! {},
+		).await;
+
+		for index in 1..4 {
+			let valid = make_valid_availability_gossip(&test_state, 0, index);
+			peer_send_message(&mut virtual_overseer, peer.clone(), valid, BENEFIT_VALID_MESSAGE_FIRST).await;
+		}
+
+		// in-process requesters are trusted
+		for _ in 0..5 {
+			let chunks = fetch_chunks(&mut virtual_overseer, candidate_hash).await;
+			assert_eq!(chunks.iter().map(|chunk| chunk.index).collect::<Vec<_>>(), vec![1, 2, 3]);
+		}
+	});
+
+	assert!(state.request_windows.is_empty());
+
+	let now = Instant::now();
+	assert!(state.serve_chunk_request(&requester, &candidate_hash, 1, now).unwrap().is_some());
+	assert!(state.serve_chunk_request(&requester, &candidate_hash, 2, now).unwrap().is_some());
+	assert_eq!(
+		state.serve_chunk_request(&requester, &candidate_hash, 3, now),
+		Err(COST_CHUNK_REQUEST_RATE_EXCEEDED),
+	);
+
+	// other peers are limited independently
+	assert!(state.serve_chunk_request(&PeerId::random(), &candidate_hash, 3, now).unwrap().is_some());
+
+	// and the limit is lifted once the window passed
+	let later = now + state.config.chunk_request_window;
+	assert!(state.serve_chunk_request(&requester, &candidate_hash, 3, later).unwrap().is_some());
+
+	// bursts beyond the limit are served up to the allowance, and penalized beyond it
+	state.config.chunk_request_burst = 2;
+	let requester = PeerId::random();
+	for index in 1..5 {
+		assert!(state.serve_chunk_request(&requester, &candidate_hash, index % 3 + 1, now).unwrap().is_some());
+	}
+	assert_eq!(
+		state.serve_chunk_request(&requester, &candidate_hash, 1, now),
+		Err(COST_CHUNK_REQUEST_RATE_EXCEEDED),
+	);
+	assert_eq!(state.request_windows[&requester].served, 4);
+}
+
+#[test]
+fn chunk_requests_of_peers_are_answered_within_their_rate() {
+	let test_state = TestState::default();
+
+	let peer = PeerId::random();
+	let requester = PeerId::random();
+	let candidate_hash = test_state.candidates[0].hash();
+
+	let keystore = test_state.keystore.clone();
+
+	let config = Config {
+		max_chunk_requests_per_peer: 2,
+		..Default::default()
+	};
+
+	test_harness_with_config(keystore, config, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			relay_parent: current,
+			validator_public,
+			ancestors,
+			candidates,
+			..
+		} = test_state.clone();
+
+		change_our_view(
+			&mut virtual_overseer,
+			view![current],
+			&validator_public,
+			vec![ancestors[0]],
+			hashmap! { current => 1 },
+			hashmap! { current => vec![occupied_core_from_candidate(&candidates[0])] },
+			hashmap! { candidates[0].hash() => false },
+			hashmap! {},
+			hashmap! {},
+		).await;
+
+		for index in 1..4 {
+			let valid = make_valid_availability_gossip(&test_state, 0, index);
+			peer_send_message(&mut virtual_overseer, peer.clone(), valid, BENEFIT_VALID_MESSAGE_FIRST).await;
+		}
+
+		overseer_send(
+			&mut virtual_overseer,
+			NetworkBridgeEvent::PeerConnected(requester.clone(), ObservedRole::Full),
+		).await;
+
+		let request = |index| NetworkBridgeEvent::PeerMessage(
+			requester.clone(),
+			protocol_v1::AvailabilityDistributionMessage::RequestChunk(candidate_hash, index),
+		);
+
+		for index in 1..3 {
+			overseer_send(&mut virtual_overseer, request(index)).await;
+			assert_matches!(
+				overseer_recv(&mut virtual_overseer).await,
+				AllMessages::NetworkBridge(NetworkBridgeMessage::SendValidationMessage(
+					peers,
+					protocol_v1::ValidationProtocol::AvailabilityDistribution(
+						protocol_v1::AvailabilityDistributionMessage::Chunk(hash, chunk),
+					),
+				)) => {
+					assert_eq!(peers, vec![requester.clone()]);
+					assert_eq!(hash, candidate_hash);
+					assert_eq!(chunk, make_valid_availability_gossip(&test_state, 0, index).erasure_chunk);
+				}
+			);
+		}
+
+		// the rate of the requester is exceeded
+		overseer_send(&mut virtual_overseer, request(3)).await;
+		expect_report(&mut virtual_overseer, &requester, COST_CHUNK_REQUEST_RATE_EXCEEDED).await;
+
+		// chunks we do not hold are not answered
+		overseer_send(
+			&mut virtual_overseer,
+			NetworkBridgeEvent::PeerMessage(
+				peer.clone(),
+				protocol_v1::AvailabilityDistributionMessage::RequestChunk(candidate_hash, 0),
+			),
+		).await;
+		assert!(
+			overseer_recv(&mut virtual_overseer)
+				.timeout(Duration::from_millis(100))
+				.await
+				.is_none()
+		);
+	});
+}
+
+#[test]
+fn chunk_requests_of_peers_within_the_burst_allowance_are_not_penalized() {
+	let test_state = TestState::default();
+
+	let peer = PeerId::random();
+	let requester = PeerId::random();
+	let candidate_hash = test_state.candidates[0].hash();
+
+	let keystore = test_state.keystore.clone();
+
+	let config = Config {
+		max_chunk_requests_per_peer: 1,
+		chunk_request_burst: 2,
+		..Default::default()
+	};
+
+	test_harness_with_config(keystore, config, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
 
 		let TestState {
 			relay_parent: current,
 			validator_public,
 			ancestors,
 			candidates,
-			pov_blocks,
 			..
 		} = test_state.clone();
 
-		let valid = make_valid_availability_gossip(
-			&test_state,
-			1,
-			2,
+		change_our_view(
+			&mut virtual_overseer,
+			view![current],
+			&validator_public,
+			vec![ancestors[0]],
+			hashmap! { current => 1 },
+			hashmap! { current => vec![occupied_core_from_candidate(&candidates[0])] },
+			hashmap! { candidates[0].hash() => false },
+			hashmap! {},
+			hashmap! {},
+		).await;
+
+		let valid = make_valid_availability_gossip(&test_state, 0, 1);
+		peer_send_message(&mut virtual_overseer, peer.clone(), valid, BENEFIT_VALID_MESSAGE_FIRST).await;
+
+		overseer_send(
+			&mut virtual_overseer,
+			NetworkBridgeEvent::PeerConnected(requester.clone(), ObservedRole::Full),
+		).await;
+
+		let request = || NetworkBridgeEvent::PeerMessage(
+			requester.clone(),
+			protocol_v1::AvailabilityDistributionMessage::RequestChunk(candidate_hash, 1),
 		);
 
+		// the request within the limit and those within the burst allowance are served
+		for _ in 0..3 {
+			overseer_send(&mut virtual_overseer, request()).await;
+			assert_matches!(
+				overseer_recv(&mut virtual_overseer).await,
+				AllMessages::NetworkBridge(NetworkBridgeMessage::SendValidationMessage(
+					peers,
+					protocol_v1::ValidationProtocol::AvailabilityDistribution(
+						protocol_v1::AvailabilityDistributionMessage::Chunk(hash, _),
+					),
+				)) => {
+					assert_eq!(peers, vec![requester.clone()]);
+					assert_eq!(hash, candidate_hash);
+				}
+			);
+		}
+
+		// while further ones are penalized
+		overseer_send(&mut virtual_overseer, request()).await;
+		expect_report(&mut virtual_overseer, &requester, COST_CHUNK_REQUEST_RATE_EXCEEDED).await;
+	});
+}
+
+#[test]
+fn message_stats_are_tracked_per_peer() {
+	let test_state = TestState::default();
+
+	let peer_a = PeerId::random();
+	let peer_b = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+
+	let (peer_a_2, peer_b_2) = (peer_a.clone(), peer_b.clone());
+	let state = test_harness(keystore, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let TestState {
+			relay_parent: current,
+			validator_public,
+			ancestors,
+			candidates,
+			..
+		} = test_state.clone();
+
 		change_our_view(
 			&mut virtual_overseer,
 			view![current],
 			&validator_public,
 			vec![ancestors[0]],
 			hashmap! { current => 1 },
-			hashmap! {
-				current => vec![
-					occupied_core_from_candidate(&candidates[0]),
-					occupied_core_from_candidate(&candidates[1])
-				],
-			},
-			hashmap! { candidates[0].hash() => true, candidates[1].hash() => false },
-			hashmap! { candidates[0].hash() => (pov_blocks[0].clone(), test_state.persisted_validation_data.clone())},
+			hashmap! { current => vec![occupied_core_from_candidate(&candidates[0])] },
+			hashmap! { candidates[0].hash() => false },
+			hashmap! {},
 			hashmap! {},
 		).await;
 
-		peer_send_message(&mut virtual_overseer, peer_b.clone(), valid.clone(), BENEFIT_VALID_MESSAGE_FIRST).await;
+		let valid = make_valid_availability_gossip(&test_state, 0, 1);
+		peer_send_message(&mut virtual_overseer, peer_a_2.clone(), valid.clone(), BENEFIT_VALID_MESSAGE_FIRST).await;
+		peer_send_message(&mut virtual_overseer, peer_a_2.clone(), valid, COST_PEER_DUPLICATE_MESSAGE).await;
 
-		setup_peer_with_view(&mut virtual_overseer, peer_a.clone(), view![current]).await;
+		let valid = make_valid_availability_gossip(&test_state, 0, 2);
+		peer_send_message(&mut virtual_overseer, peer_a_2.clone(), valid.clone(), BENEFIT_VALID_MESSAGE_FIRST).await;
+		peer_send_message(&mut virtual_overseer, peer_a_2, valid, COST_PEER_DUPLICATE_MESSAGE).await;
 
-		let mut chunks = make_erasure_chunks(
-			test_state.persisted_validation_data.clone(),
-			validator_public.len(),
-			pov_blocks[0].clone(),
+		// stats are reset once a peer disconnects
+		let valid = make_valid_availability_gossip(&test_state, 0, 3);
+		peer_send_message(&mut virtual_overseer, peer_b_2.clone(), valid, BENEFIT_VALID_MESSAGE_FIRST).await;
+		overseer_send(&mut virtual_overseer, NetworkBridgeEvent::PeerDisconnected(peer_b_2.clone())).await;
+
+		// chunks still in flight from a disconnected peer do not bring its stats back
+		let valid = make_valid_availability_gossip(&test_state, 0, 4);
+		overseer_send(
+			&mut virtual_overseer,
+			NetworkBridgeEvent::PeerMessage(peer_b_2, chunk_protocol_message(valid)),
+		).await;
+	});
+
+	assert_eq!(
+		state.on_peer_message_stats(&peer_a),
+		Some(PeerMessageStats { received: 4, valid: 2, duplicate: 2, rejected: 0 }),
+	);
+	assert_eq!(state.on_peer_message_stats(&peer_b), None);
+}
+
+#[test]
+fn oversized_peer_views_are_capped() {
+	let test_state = TestState::default();
+
+	let peer = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+
+	let registry = prometheus::Registry::new();
+	let metrics = <Metrics as metrics::Metrics>::try_register(&registry).unwrap();
+	let inner = metrics.0.clone().unwrap();
+
+	let config = Config {
+		max_peer_view_heads: 4,
+		..Default::default()
+	};
+
+	let heads: Vec<Hash> = (0..10).map(|i| Hash::repeat_byte(i)).collect();
+	let expected_heads = heads[..4].to_vec();
+
+	let peer_2 = peer.clone();
+	let state = test_harness_with_metrics(keystore, config, metrics, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		setup_peer_with_view(&mut virtual_overseer, peer_2, View { heads, finalized_number: 0 }).await;
+	});
+
+	assert_eq!(state.peer_views[&peer].heads, expected_heads);
+	assert_eq!(inner.oversized_peer_views.get(), 1);
+}
+
+#[test]
+fn validator_index_is_resolved_among_multiple_keystore_keys() {
+	use sp_application_crypto::AppKey;
+	use sp_keyring::Sr25519Keyring;
+	use sp_keystore::SyncCryptoStore;
+
+	let test_state = TestState::default();
+	let validators = super::testing::validator_pubkeys(&test_state.validators);
+
+	let keystore: SyncCryptoStorePtr = Arc::new(sc_keystore::LocalKeystore::in_memory());
+
+	// Eve is not part of the validator set.
+	SyncCryptoStore::sr25519_generate_new(
+		&*keystore,
+		ValidatorId::ID,
+		Some(&Sr25519Keyring::Eve.to_seed()),
+	)
+	.expect("Insert key into keystore");
+
+	assert_eq!(executor::block_on(obtain_our_validator_index(&validators, keystore.clone())), None);
+
+	SyncCryptoStore::sr25519_generate_new(
+		&*keystore,
+		ValidatorId::ID,
+		Some(&Sr25519Keyring::Charlie.to_seed()),
+	)
+	.expect("Insert key into keystore");
+
+	assert_eq!(executor::block_on(obtain_our_validator_index(&validators, keystore)), Some(3));
+}
+
+#[test]
+fn availability_events_are_published_to_the_event_sink() {
+	let test_state = TestState::default();
+
+	let peer = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+	let (tx, mut rx) = futures::channel::mpsc::channel(16);
+	let config = Config { event_sink: Some(tx), ..Default::default() };
+
+	let relay_parent = test_state.relay_parent;
+	let candidate_hashes: HashSet<_> = test_state.candidates.iter().map(|c| c.hash()).collect();
+	let candidate_hash = test_state.candidates[0].hash();
+
+	test_harness_with_config(keystore, config, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let script = test_state.view_change_script();
+		testing::drive_view_change(&mut virtual_overseer, view![test_state.relay_parent], &script).await;
+
+		// chunk `0` is the chunk of this node.
+		let own = make_valid_availability_gossip(&test_state, 0, 0);
+		overseer_send(
+			&mut virtual_overseer,
+			NetworkBridgeEvent::PeerMessage(peer.clone(), chunk_protocol_message(own)),
+		).await;
+		expect_report(&mut virtual_overseer, &peer, BENEFIT_VALID_MESSAGE_FIRST).await;
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::AvailabilityStore(
+				AvailabilityStoreMessage::StoreChunk { validator_index: 0, tx, .. }
+			) => {
+				tx.send(Ok(())).unwrap();
+			}
 		);
 
-		chunks.push(valid.erasure_chunk);
+		let other = make_valid_availability_gossip(&test_state, 0, 1);
+		peer_send_message(&mut virtual_overseer, peer.clone(), other, BENEFIT_VALID_MESSAGE_FIRST).await;
+		assert!(fetch_available_data(&mut virtual_overseer, candidate_hash).await.is_some());
+
+		testing::drive_view_change(&mut virtual_overseer, view![], &script).await;
+	});
+
+	let mut events = Vec::new();
+	while let Ok(Some(event)) = rx.try_next() {
+		events.push(event);
+	}
+	assert_eq!(events.len(), 2 * candidate_hashes.len() + 2);
+
+	let (added, rest) = events.split_at(candidate_hashes.len());
+	let added: HashSet<_> = added.iter().map(|event| assert_matches!(
+		event,
+		AvailabilityEvent::CandidateAdded { candidate_hash, relay_parent: rp } => {
+			assert_eq!(*rp, relay_parent);
+			*candidate_hash
+		}
+	)).collect();
+	assert_eq!(added, candidate_hashes);
+
+	assert_eq!(rest[0], AvailabilityEvent::ChunkStored { candidate_hash, index: 0 });
+	assert_eq!(rest[1], AvailabilityEvent::ReconstructionComplete { candidate_hash });
+
+	let removed: HashSet<_> = rest[2..].iter().map(|event| assert_matches!(
+		event,
+		AvailabilityEvent::CandidateRemoved { candidate_hash } => *candidate_hash
+	)).collect();
+	assert_eq!(removed, candidate_hashes);
+}
+
+#[test]
+fn repeated_chunks_of_candidates_which_are_not_live_are_dropped() {
+	let test_state = TestState::default();
+
+	let peer = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+	let config = Config { max_rejected_candidates: 16, ..Default::default() };
+
+	let message = make_valid_availability_gossip(&test_state, 0, 2);
+	let candidate_hash = message.candidate_hash;
+
+	let peer_2 = peer.clone();
+	let state = test_harness_with_config(keystore, config, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		// no view change, so the candidate is not live
+		peer_send_message(&mut virtual_overseer, peer_2.clone(), message.clone(), COST_NOT_A_LIVE_CANDIDATE).await;
+		peer_send_message(
+			&mut virtual_overseer,
+			peer_2.clone(),
+			message.clone(),
+			COST_REPEATED_NOT_A_LIVE_CANDIDATE,
+		).await;
+
+		for _ in 0..3 {
+			overseer_send(
+				&mut virtual_overseer,
+				NetworkBridgeEvent::PeerMessage(peer_2.clone(), chunk_protocol_message(message.clone())),
+			).await;
+		}
+		assert!(
+			overseer_recv(&mut virtual_overseer)
+				.timeout(Duration::from_millis(100))
+				.await
+				.is_none()
+		);
+	});
+
+	assert_eq!(state.recently_rejected.get(&(peer.clone(), candidate_hash)).map(|(_, n)| *n), Some(5));
+
+	// the bound is kept, forgetting the least recently rejected pairs first
+	let mut state = ProtocolState::with_config(Config { max_rejected_candidates: 2, ..Default::default() });
+	let now = Instant::now();
+	let candidates: Vec<_> = (0..3u8).map(|i| CandidateHash(Hash::repeat_byte(i))).collect();
+	for (i, candidate) in candidates.iter().enumerate() {
+		assert_eq!(state.note_rejected_candidate(&peer, *candidate, now + Duration::from_secs(i as u64)), 0);
+	}
+	assert_eq!(state.recently_rejected.len(), 2);
+	assert!(!state.recently_rejected.contains_key(&(peer.clone(), candidates[0])));
+
+	// and rejections are forgotten after the TTL
+	let ttl = state.config.rejected_candidate_ttl;
+	assert_eq!(state.note_rejected_candidate(&peer, candidates[2], now + ttl), 1);
+	assert_eq!(state.note_rejected_candidate(&peer, candidates[2], now + ttl * 3), 0);
+}
+
+#[test]
+fn conclude_during_relay_parent_fetch_keeps_the_fetched_relay_parent() {
+	let test_state = TestState::default();
+
+	let pool = sp_core::testing::TaskExecutor::new();
+	let (context, mut virtual_overseer) = test_helpers::make_subsystem_context(pool);
+
+	let subsystem = AvailabilityDistributionSubsystem::new(test_state.keystore.clone(), Default::default());
+	let mut state = ProtocolState::default();
+
+	let view = view![test_state.relay_parent, test_state.ancestors[0]];
+	let validator_public = test_state.validator_public.clone();
+
+	let test_fut = async move {
+		overseer_send(&mut virtual_overseer, NetworkBridgeEvent::OurViewChange(view)).await;
+
+		let fetched = assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				hash,
+				RuntimeApiRequest::Validators(tx),
+			)) => {
+				tx.send(Ok(validator_public)).unwrap();
+				hash
+			}
+		);
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::ChainApi(ChainApiMessage::Ancestors { response_channel, .. }) => {
+				response_channel.send(Ok(vec![])).unwrap();
+			}
+		);
+
+		let cores_tx = loop {
+			match overseer_recv(&mut virtual_overseer).await {
+				AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+					_,
+					RuntimeApiRequest::SessionIndexForChild(tx),
+				)) => {
+					tx.send(Ok(1)).unwrap();
+				}
+				AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+					hash,
+					RuntimeApiRequest::AvailabilityCores(tx),
+				)) => {
+					assert_eq!(hash, fetched);
+					break tx;
+				}
+				msg => panic!("Unexpected message while fetching relay parent: {:?}", msg),
+			}
+		};
+
+		// the node shuts down while the fetch is in progress
+		let conclude = virtual_overseer.send(FromOverseer::Signal(OverseerSignal::Conclude));
+		futures::pin_mut!(conclude);
+		assert!(futures::poll!(conclude.as_mut()).is_pending());
+
+		cores_tx.send(Ok(Vec::new())).unwrap();
+		conclude.await;
+
+		// no other relay parent is fetched
+		assert!(virtual_overseer.try_recv().await.is_none());
+
+		fetched
+	};
+
+	let (fetched, result) = executor::block_on(
+		future::join(test_fut, subsystem.run_inner(context, &mut state))
+			.timeout(Duration::from_secs(2))
+	).expect("Subsystem concluded in time");
+
+	assert!(result.is_ok());
+	assert!(state.per_relay_parent.contains_key(&fetched));
+	assert_eq!(state.per_relay_parent.len(), 1);
+}
+
+#[test]
+fn stored_own_chunk_is_gossiped_on_candidate_discovery() {
+	let test_state = TestState::default();
+
+	let peer = PeerId::random();
+
+	let keystore = test_state.keystore.clone();
+	let config = Config { gossip_stored_own_chunk: true, ..Default::default() };
+	let candidate_hash = test_state.candidates[0].hash();
+
+	let state = test_harness_with_config(keystore, config, move |test_harness| async move {
+		let mut virtual_overseer = test_harness.virtual_overseer;
+
+		let current = test_state.relay_parent;
+		let candidate = test_state.candidates[0].clone();
+
+		setup_peer_with_view(&mut virtual_overseer, peer.clone(), view![current]).await;
+
+		overseer_send(&mut virtual_overseer, NetworkBridgeEvent::OurViewChange(view![current])).await;
+		expect_relay_parent_fetch(
+			&mut virtual_overseer,
+			current,
+			&test_state.validator_public,
+			vec![],
+			1,
+			hashmap! { current => vec![occupied_core_from_candidate(&candidate)] },
+		).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::AvailabilityStore(
+				AvailabilityStoreMessage::QueryDataAvailability(hash, tx)
+			) => {
+				assert_eq!(hash, candidate_hash);
+				tx.send(false).unwrap();
+			}
+		);
+
+		// the store lacks the available data, but holds our own chunk `0`
+		let own = make_valid_availability_gossip(&test_state, 0, 0);
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::AvailabilityStore(
+				AvailabilityStoreMessage::QueryChunk(hash, 0, tx)
+			) => {
+				assert_eq!(hash, candidate_hash);
+				tx.send(Some(own.erasure_chunk.clone())).unwrap();
+			}
+		);
 
 		expect_chunks_network_message(
 			&mut virtual_overseer,
-			&[peer_a],
-			&[candidates[0].hash(), candidates[1].hash()],
-			&chunks,
+			&[peer.clone()],
+			&[candidate_hash],
+			&[own.erasure_chunk],
+			MessagePriority::Normal,
 		).await;
+
+		assert!(
+			overseer_recv(&mut virtual_overseer)
+				.timeout(Duration::from_millis(100))
+				.await
+				.is_none()
+		);
 	});
+
+	assert!(state.per_candidate[&candidate_hash].servable_chunk(&0).is_some());
 }
@@ -0,0 +1,47 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+use polkadot_availability_distribution::testing::{
+	derive_erasure_chunks_with_proofs, make_available_data, make_valid_availability_gossip,
+	TestState,
+};
+use polkadot_erasure_coding::branch_hash;
+use polkadot_primitives::v1::{BlakeTwo256, HashT};
+
+#[test]
+fn test_state_yields_valid_gossip() {
+	let test_state = TestState::default();
+
+	let message = make_valid_availability_gossip(&test_state, 0, 2);
+	assert_eq!(message.candidate_hash, test_state.candidates[0].hash());
+	assert_eq!(message.erasure_chunk.index, 2);
+
+	let erasure_root = &test_state.candidates[0].descriptor.erasure_root;
+	let anticipated_hash = branch_hash(
+		erasure_root,
+		&message.erasure_chunk.proof,
+		message.erasure_chunk.index as usize,
+	).expect("Chunk is part of the erasure root");
+	assert_eq!(anticipated_hash, BlakeTwo256::hash(&message.erasure_chunk.chunk));
+
+	let available_data = make_available_data(
+		test_state.persisted_validation_data.clone(),
+		test_state.pov_blocks[0].clone(),
+	);
+	let chunks = derive_erasure_chunks_with_proofs(test_state.validator_public.len(), &available_data);
+	assert_eq!(chunks.len(), test_state.validator_public.len());
+	assert_eq!(chunks[2], message.erasure_chunk);
+}
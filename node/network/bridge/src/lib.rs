@@ -35,14 +35,14 @@ use polkadot_subsystem::{
 use polkadot_subsystem::messages::{
 	NetworkBridgeMessage, AllMessages, AvailabilityDistributionMessage,
 	BitfieldDistributionMessage, PoVDistributionMessage, StatementDistributionMessage,
-	CollatorProtocolMessage,
+	CollatorProtocolMessage, MessagePriority,
 };
 use polkadot_primitives::v1::{AuthorityDiscoveryId, Block, Hash, BlockNumber};
 use polkadot_node_network_protocol::{
 	ObservedRole, ReputationChange, PeerId, PeerSet, View, NetworkBridgeEvent, v1 as protocol_v1
 };
 
-use std::collections::{HashMap, hash_map};
+use std::collections::{HashMap, VecDeque, hash_map};
 use std::iter::ExactSizeIterator;
 use std::pin::Pin;
 use std::sync::Arc;
@@ -55,6 +55,10 @@ mod validator_discovery;
 /// We use the same limit to compute the view sent to peers locally.
 const MAX_VIEW_HEADS: usize = 5;
 
+/// The maximum amount of low priority validation messages held back in favour of other traffic.
+/// Further low priority messages are sent right away.
+const MAX_LOW_PRIORITY_MESSAGES: usize = 1024;
+
 /// The protocol name for the validation peer-set.
 pub const VALIDATION_PROTOCOL_NAME: &'static str = "/polkadot/validation/1";
 /// The protocol name for the collation peer-set.
@@ -246,6 +250,8 @@ struct PeerData {
 #[derive(Debug)]
 enum Action {
 	SendValidationMessage(Vec<PeerId>, protocol_v1::ValidationProtocol),
+	QueueLowPriorityValidationMessage(Vec<PeerId>, protocol_v1::ValidationProtocol),
+	SendLowPriorityValidationMessage,
 	SendCollationMessage(Vec<PeerId>, protocol_v1::CollationProtocol),
 	ConnectToValidators {
 		validator_ids: Vec<AuthorityDiscoveryId>,
@@ -282,6 +288,10 @@ fn action_from_overseer_message(
 			NetworkBridgeMessage::ReportPeer(peer, rep) => Action::ReportPeer(peer, rep),
 			NetworkBridgeMessage::SendValidationMessage(peers, msg)
 				=> Action::SendValidationMessage(peers, msg),
+			NetworkBridgeMessage::SendPrioritizedValidationMessage(peers, msg, MessagePriority::Normal)
+				=> Action::SendValidationMessage(peers, msg),
+			NetworkBridgeMessage::SendPrioritizedValidationMessage(peers, msg, MessagePriority::Low)
+				=> Action::QueueLowPriorityValidationMessage(peers, msg),
 			NetworkBridgeMessage::SendCollationMessage(peers, msg)
 				=> Action::SendCollationMessage(peers, msg),
 			NetworkBridgeMessage::ConnectToValidators { validator_ids, connected }
@@ -593,6 +603,9 @@ where
 
 	let mut validator_discovery = validator_discovery::Service::<N, AD>::new();
 
+	// Low priority validation messages, which are sent once no other traffic is pending.
+	let mut low_priority_messages: VecDeque<(Vec<PeerId>, protocol_v1::ValidationProtocol)> = VecDeque::new();
+
 	loop {
 
 		let action = {
@@ -600,9 +613,17 @@ where
 			let mut net_event_next = event_stream.next().fuse();
 			futures::pin_mut!(subsystem_next);
 
-			futures::select! {
-				subsystem_msg = subsystem_next => action_from_overseer_message(subsystem_msg),
-				net_event = net_event_next => action_from_network_message(net_event),
+			if low_priority_messages.is_empty() {
+				futures::select! {
+					subsystem_msg = subsystem_next => action_from_overseer_message(subsystem_msg),
+					net_event = net_event_next => action_from_network_message(net_event),
+				}
+			} else {
+				futures::select! {
+					subsystem_msg = subsystem_next => action_from_overseer_message(subsystem_msg),
+					net_event = net_event_next => action_from_network_message(net_event),
+					default => Action::SendLowPriorityValidationMessage,
+				}
 			}
 		};
 
@@ -617,6 +638,30 @@ where
 					WireMessage::ProtocolMessage(msg),
 			).await?,
 
+			Action::QueueLowPriorityValidationMessage(peers, msg) => {
+				if low_priority_messages.len() < MAX_LOW_PRIORITY_MESSAGES {
+					low_priority_messages.push_back((peers, msg));
+				} else {
+					send_message(
+						&mut network_service,
+						peers,
+						PeerSet::Validation,
+						WireMessage::ProtocolMessage(msg),
+					).await?
+				}
+			}
+
+			Action::SendLowPriorityValidationMessage => {
+				if let Some((peers, msg)) = low_priority_messages.pop_front() {
+					send_message(
+						&mut network_service,
+						peers,
+						PeerSet::Validation,
+						WireMessage::ProtocolMessage(msg),
+					).await?
+				}
+			}
+
 			Action::SendCollationMessage(peers, msg) => send_message(
 					&mut network_service,
 					peers,
@@ -1521,4 +1566,46 @@ mod tests {
 			}
 		});
 	}
+
+	#[test]
+	fn low_priority_messages_yield_to_pending_traffic() {
+		test_harness(|test_harness| async move {
+			let TestHarness {
+				mut network_handle,
+				mut virtual_overseer,
+			} = test_harness;
+
+			let peer = PeerId::random();
+
+			let message = protocol_v1::ValidationProtocol::PoVDistribution(
+				protocol_v1::PoVDistributionMessage::Awaiting([0; 32].into(), vec![[1; 32].into()]),
+			);
+
+			// a malformed message of the peer is pending already
+			network_handle.net_tx.feed(NetworkEvent::NotificationsReceived {
+				remote: peer.clone(),
+				messages: vec![(peer_set_protocol(PeerSet::Validation), vec![0xff].into())],
+			}).await.unwrap();
+
+			virtual_overseer.send(FromOverseer::Communication {
+				msg: NetworkBridgeMessage::SendPrioritizedValidationMessage(
+					vec![peer.clone()],
+					message.clone(),
+					MessagePriority::Low,
+				)
+			}).await;
+
+			assert_eq!(
+				network_handle.next_network_actions(2).await,
+				vec![
+					NetworkAction::ReputationChange(peer.clone(), MALFORMED_MESSAGE_COST),
+					NetworkAction::WriteNotification(
+						peer.clone(),
+						PeerSet::Validation,
+						WireMessage::ProtocolMessage(message).encode(),
+					),
+				],
+			);
+		});
+	}
 }
@@ -216,19 +216,35 @@ impl View {
 pub mod v1 {
 	use polkadot_primitives::v1::{
 		Hash, CollatorId, Id as ParaId, ErasureChunk, CandidateReceipt,
-		SignedAvailabilityBitfield, PoV, CandidateHash,
+		SignedAvailabilityBitfield, PoV, CandidateHash, ValidatorIndex,
 	};
 	use polkadot_node_primitives::SignedFullStatement;
 	use parity_scale_codec::{Encode, Decode};
 	use std::convert::TryFrom;
 	use super::RequestId;
 
+	/// The maximum number of erasure chunks carried by a single
+	/// [`AvailabilityDistributionMessage::BatchChunks`] message.
+	pub const MAX_BATCHED_CHUNKS: usize = 32;
+
 	/// Network messages used by the availability distribution subsystem
 	#[derive(Debug, Clone, Encode, Decode, PartialEq)]
 	pub enum AvailabilityDistributionMessage {
 		/// An erasure chunk for a given candidate hash.
 		#[codec(index = "0")]
 		Chunk(CandidateHash, ErasureChunk),
+		/// Several erasure chunks, each for a given candidate hash, up to [`MAX_BATCHED_CHUNKS`].
+		///
+		/// Nodes which predate this message can not decode it, so it must only be sent once
+		/// the network upgraded to a version understanding it.
+		#[codec(index = "1")]
+		BatchChunks(Vec<(CandidateHash, ErasureChunk)>),
+		/// A request for the erasure chunk of a given candidate hash at a given index, answered
+		/// with a [`AvailabilityDistributionMessage::Chunk`] if the chunk is held.
+		///
+		/// Nodes which predate this message can not decode it either.
+		#[codec(index = "2")]
+		RequestChunk(CandidateHash, ValidatorIndex),
 	}
 
 	/// Network messages used by the bitfield distribution subsystem.
@@ -191,6 +191,15 @@ impl CollatorProtocolMessage {
 	}
 }
 
+/// A hint on how urgently a message should be delivered relative to other traffic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessagePriority {
+	/// The message should be delivered as soon as possible.
+	Normal,
+	/// The message may be delayed in favour of latency-critical traffic.
+	Low,
+}
+
 /// Messages received by the network bridge subsystem.
 #[derive(Debug)]
 pub enum NetworkBridgeMessage {
@@ -200,6 +209,10 @@ pub enum NetworkBridgeMessage {
 	/// Send a message to one or more peers on the validation peer-set.
 	SendValidationMessage(Vec<PeerId>, protocol_v1::ValidationProtocol),
 
+	/// Send a message to one or more peers on the validation peer-set, hinting at
+	/// the priority with which it should be delivered.
+	SendPrioritizedValidationMessage(Vec<PeerId>, protocol_v1::ValidationProtocol, MessagePriority),
+
 	/// Send a message to one or more peers on the collation peer-set.
 	SendCollationMessage(Vec<PeerId>, protocol_v1::CollationProtocol),
 
@@ -224,6 +237,7 @@ impl NetworkBridgeMessage {
 		match self {
 			Self::ReportPeer(_, _) => None,
 			Self::SendValidationMessage(_, _) => None,
+			Self::SendPrioritizedValidationMessage(_, _, _) => None,
 			Self::SendCollationMessage(_, _) => None,
 			Self::ConnectToValidators { .. } => None,
 		}
@@ -235,6 +249,31 @@ impl NetworkBridgeMessage {
 pub enum AvailabilityDistributionMessage {
 	/// Event from the network bridge.
 	NetworkBridgeUpdateV1(NetworkBridgeEvent<protocol_v1::AvailabilityDistributionMessage>),
+	/// Fetch the candidates pending availability at the given relay parent
+	/// ahead of it entering our view.
+	Prefetch(Hash),
+	/// Obtain the `AvailableData` of a candidate, reconstructed from the chunks we hold.
+	/// Yields `None` if we do not hold enough of them.
+	FetchAvailableData {
+		/// The candidate to obtain the data of.
+		candidate_hash: CandidateHash,
+		/// The response channel.
+		tx: oneshot::Sender<Option<AvailableData>>,
+	},
+	/// Obtain all chunks of a candidate we hold, in bulk.
+	/// Meant for trusted in-process callers such as recovery, thus not rate limited.
+	FetchChunks {
+		/// The candidate to obtain the chunks of.
+		candidate_hash: CandidateHash,
+		/// The response channel.
+		tx: oneshot::Sender<Vec<ErasureChunk>>,
+	},
+	/// Gossip all chunks we hold anew to the interested peers not known to have them,
+	/// e.g. to recover from a network outage.
+	ReGossipAll,
+	/// Drop all chunks held in the message vaults to reclaim memory, e.g. under memory pressure.
+	/// The candidates remain tracked, so the chunks can be obtained again.
+	ClearMessageVaults,
 }
 
 impl AvailabilityDistributionMessage {
@@ -242,6 +281,11 @@ impl AvailabilityDistributionMessage {
 	pub fn relay_parent(&self) -> Option<Hash> {
 		match self {
 			Self::NetworkBridgeUpdateV1(_) => None,
+			Self::Prefetch(relay_parent) => Some(*relay_parent),
+			Self::FetchAvailableData { .. } => None,
+			Self::FetchChunks { .. } => None,
+			Self::ReGossipAll => None,
+			Self::ClearMessageVaults => None,
 		}
 	}
 }